@@ -0,0 +1,155 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+
+const CACHE_VERSION: u32 = 12;
+const CACHE_FILE: &str = "index.json";
+
+/// Identifies the on-disk state a cache entry was built from.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Fingerprint {
+    mtime_ns: u64,
+    len: u64,
+    hash: u64,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct CachedFile {
+    fingerprint: Fingerprint,
+    /// Serialized parse data keyed by plugin name; `null` for files a plugin ignores.
+    pub plugins: HashMap<String, serde_json::Value>,
+}
+
+/// Plugin parse data persisted between runs, so unchanged files don't need to be parsed again.
+#[derive(Default, Serialize, Deserialize)]
+pub struct IndexCache {
+    version: u32,
+    key: String,
+    files: HashMap<PathBuf, CachedFile>,
+    #[serde(skip)]
+    modified: bool,
+}
+
+impl Fingerprint {
+    /// Reads `path`, returning its fingerprint along with the contents that were hashed.
+    pub fn read(path: &Path) -> Option<(Self, Vec<u8>)> {
+        let metadata = fs::metadata(path).ok()?;
+        let bytes = fs::read(path).ok()?;
+        let fingerprint = Self {
+            mtime_ns: mtime_ns(&metadata)?,
+            len: metadata.len(),
+            hash: fnv1a(&bytes),
+        };
+        Some((fingerprint, bytes))
+    }
+}
+
+impl CachedFile {
+    pub fn new(fingerprint: Fingerprint, plugins: HashMap<String, serde_json::Value>) -> Self {
+        Self {
+            fingerprint,
+            plugins,
+        }
+    }
+}
+
+impl IndexCache {
+    /// Loads the cache in `dir`, starting empty if it is missing, unreadable or was built by a
+    /// different version or with different settings (`key`).
+    pub fn load(dir: &Path, key: &str) -> Self {
+        let empty = Self {
+            version: CACHE_VERSION,
+            key: key.to_string(),
+            files: HashMap::new(),
+            modified: false,
+        };
+        let Ok(text) = fs::read_to_string(dir.join(CACHE_FILE)) else {
+            return empty;
+        };
+        match serde_json::from_str::<Self>(&text) {
+            Ok(cache) if cache.version == CACHE_VERSION && cache.key == key => cache,
+            Ok(_) => empty,
+            Err(err) => {
+                tracing::warn!("ignoring unreadable index cache: {err}");
+                empty
+            }
+        }
+    }
+
+    pub fn save(&self, dir: &Path) -> Result<()> {
+        fs::create_dir_all(dir).with_context(|| format!("creating {}", dir.display()))?;
+        let path = dir.join(CACHE_FILE);
+        let temp = path.with_extension("json.tmp");
+        fs::write(&temp, serde_json::to_vec(self)?)
+            .with_context(|| format!("writing {}", temp.display()))?;
+        fs::rename(&temp, &path).with_context(|| format!("writing {}", path.display()))?;
+        Ok(())
+    }
+
+    pub fn entries(&self) -> impl Iterator<Item = (&PathBuf, &CachedFile)> {
+        self.files.iter()
+    }
+
+    /// Whether the entry for `path` still matches the file on disk. Files whose size and mtime
+    /// are unchanged are trusted; otherwise the content hash decides, and a matching entry has its
+    /// mtime refreshed.
+    pub fn is_current(&mut self, path: &Path) -> bool {
+        let Some(entry) = self.files.get_mut(path) else {
+            return false;
+        };
+        let Ok(metadata) = fs::metadata(path) else {
+            return false;
+        };
+        if entry.fingerprint.len != metadata.len() {
+            return false;
+        }
+        let mtime = mtime_ns(&metadata);
+        if mtime != Some(entry.fingerprint.mtime_ns) {
+            let unchanged =
+                fs::read(path).is_ok_and(|bytes| fnv1a(&bytes) == entry.fingerprint.hash);
+            if !unchanged {
+                return false;
+            }
+            if let Some(mtime) = mtime {
+                entry.fingerprint.mtime_ns = mtime;
+                self.modified = true;
+            }
+        }
+        true
+    }
+
+    pub fn insert(&mut self, path: PathBuf, file: CachedFile) {
+        self.files.insert(path, file);
+        self.modified = true;
+    }
+
+    pub fn remove(&mut self, path: &Path) {
+        self.modified |= self.files.remove(path).is_some();
+    }
+
+    /// Whether entries changed since the last call, i.e. whether the cache needs saving.
+    pub fn take_modified(&mut self) -> bool {
+        std::mem::take(&mut self.modified)
+    }
+}
+
+/// A hash of the contents of `path`, or `None` if it can't be read.
+pub fn file_hash(path: &Path) -> Option<u64> {
+    fs::read(path).ok().map(|bytes| fnv1a(&bytes))
+}
+
+fn mtime_ns(metadata: &fs::Metadata) -> Option<u64> {
+    let modified = metadata.modified().ok()?;
+    u64::try_from(modified.duration_since(UNIX_EPOCH).ok()?.as_nanos()).ok()
+}
+
+/// FNV-1a, chosen over `DefaultHasher` because its output is stable across Rust releases.
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+        (hash ^ u64::from(*byte)).wrapping_mul(0x0000_0100_0000_01b3)
+    })
+}
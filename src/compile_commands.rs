@@ -3,6 +3,7 @@ use std::{
     collections::HashMap,
     fs,
     path::{Path, PathBuf},
+    sync::OnceLock,
 };
 
 #[derive(Deserialize)]
@@ -16,6 +17,10 @@ pub struct CompileCommands {
     root: PathBuf,
     entries: HashMap<PathBuf, Vec<String>>,
     default_args: Vec<String>,
+    /// Appended to the arguments of every file.
+    extra_args: Vec<String>,
+    /// `#include` spellings found in each entry's source, scanned on first use.
+    includes: OnceLock<Vec<(PathBuf, PathBuf)>>,
 }
 
 impl CompileCommandEntry {
@@ -54,23 +59,97 @@ impl CompileCommands {
             root,
             entries,
             default_args,
+            extra_args: Vec::new(),
+            includes: OnceLock::new(),
         }
     }
 
+    pub fn with_extra_args(mut self, args: Vec<String>) -> Self {
+        self.extra_args = args;
+        self
+    }
+
+    pub fn extra_args(&self) -> &[String] {
+        &self.extra_args
+    }
+
     pub fn args_for(&self, file: &Path) -> Vec<String> {
+        let mut args = self
+            .entry_args(file)
+            .map(<[String]>::to_vec)
+            .unwrap_or_else(|| self.default_args.clone());
+        args.extend_from_slice(&self.extra_args);
+        args
+    }
+
+    /// The arguments `compile_commands.json` lists for `file`, if it has an entry.
+    pub fn entry_args(&self, file: &Path) -> Option<&[String]> {
         let canonical = file.canonicalize().unwrap_or_else(|_| file.to_path_buf());
 
         if let Some(args) = self.entries.get(&canonical) {
-            return args.clone();
+            return Some(args);
         }
 
-        if let Ok(rel) = canonical.strip_prefix(&self.root) {
-            let candidate = self.root.join(rel);
-            if let Some(args) = self.entries.get(&candidate) {
-                return args.clone();
+        let rel = canonical.strip_prefix(&self.root).ok()?;
+        self.entries.get(&self.root.join(rel)).map(Vec::as_slice)
+    }
+
+    /// Arguments for a header without an entry of its own, borrowed from a source including it.
+    /// A source with the same stem next to the header wins, as `foo.c` for `foo.h`. The source
+    /// itself is dropped from the borrowed arguments.
+    pub fn header_args(&self, header: &Path) -> Option<Vec<String>> {
+        let header = header
+            .canonicalize()
+            .unwrap_or_else(|_| header.to_path_buf());
+        let includes = self.includes.get_or_init(|| self.scan_includes());
+        let mut includers = (includes.iter())
+            .filter(|(spelling, _)| header.ends_with(spelling))
+            .map(|(_, source)| source);
+        let first = includers.next()?;
+        let sibling = |source: &&PathBuf| {
+            source.parent() == header.parent() && source.file_stem() == header.file_stem()
+        };
+        let source = std::iter::once(first)
+            .chain(includers)
+            .find(sibling)
+            .unwrap_or(first);
+        let args = self.entries.get(source)?;
+        Some(
+            (args.iter())
+                .filter(|arg| {
+                    arg.starts_with('-') || Path::new(arg).file_name() != source.file_name()
+                })
+                .cloned()
+                .collect(),
+        )
+    }
+
+    /// Pairs of an `#include` spelling and the entry source it appears in.
+    fn scan_includes(&self) -> Vec<(PathBuf, PathBuf)> {
+        let mut includes = Vec::new();
+        for source in self.entries.keys() {
+            let Ok(text) = fs::read_to_string(source) else {
+                continue;
+            };
+            for line in text.lines() {
+                let Some(directive) = line.trim_start().strip_prefix('#') else {
+                    continue;
+                };
+                let Some(target) = directive.trim_start().strip_prefix("include") else {
+                    continue;
+                };
+                let target = target.trim_start();
+                let close = match target.chars().next() {
+                    Some('"') => '"',
+                    Some('<') => '>',
+                    _ => continue,
+                };
+                if let Some(spelling) = target[1..].split(close).next() {
+                    let spelling = spelling.trim_start_matches("./");
+                    includes.push((PathBuf::from(spelling), source.clone()));
+                }
             }
         }
-
-        self.default_args.clone()
+        includes
     }
 }
@@ -0,0 +1,138 @@
+use std::ffi::OsStr;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use clap::ValueEnum;
+use globset::GlobSet;
+
+use crate::diagnostics::{DiagnosticsCap, DiagnosticsIgnore, SeverityOverrides};
+use crate::paths::PathStyle;
+use crate::scaffold::DEFAULT_INIT_TARGET_TEMPLATE;
+
+/// Adjustments for running next to clangd, enabled with `--clangd-companion`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+#[value(rename_all = "kebab_case")]
+pub enum CompanionFeature {
+    /// Leave out diagnostics clangd reports as well, such as files libclang can't parse.
+    Diagnostics,
+    /// Prefix every diagnostic source with `elysium-lsp` so it can't be mistaken for clangd's.
+    Source,
+    /// Answer hovers only for hook and init names, leaving the macro names themselves, whose
+    /// `#define` clangd shows as well, to clangd.
+    Capabilities,
+}
+
+pub struct Config {
+    pub debounce: Duration,
+    /// Reparse open documents when they are saved instead of as they are edited.
+    pub reindex_on_save: bool,
+    /// Answer `textDocument/willSaveWaitUntil` with the plugins' formatting edits.
+    pub format_on_save: bool,
+    /// Contents of files created by `elysium.newInitTargetFile`.
+    pub init_template: String,
+    pub index_extensions: Vec<String>,
+    /// Extensions of headers, a change to which can change how any file parses.
+    pub header_extensions: Vec<String>,
+    /// Directories indexed at startup and watched for changes; empty means the project root.
+    pub index_roots: Vec<PathBuf>,
+    pub excluded_dirs: Vec<String>,
+    /// Files and directories left out of indexing, matched relative to the project root.
+    pub exclude_globs: GlobSet,
+    pub diagnostics_ignore: DiagnosticsIgnore,
+    pub severity_overrides: SeverityOverrides,
+    pub diagnostics_cap: DiagnosticsCap,
+    /// Publish diagnostics only for documents open in the editor.
+    pub open_files_only: bool,
+    pub companion: Vec<CompanionFeature>,
+    pub respect_ignore_files: bool,
+    /// Index only files git tracks.
+    pub git_tracked_only: bool,
+    pub path_style: PathStyle,
+    pub parse_jobs: usize,
+    /// Parses running longer than this are abandoned.
+    pub parse_timeout: Option<Duration>,
+    /// Bytes of parse data to keep before evicting closed files.
+    pub memory_budget: Option<usize>,
+    /// The `compile_commands.json` the plugins read, whether it exists or not. A cache built from
+    /// a different one is discarded.
+    pub compile_commands: PathBuf,
+    /// Directory holding the persistent index cache; `None` disables it.
+    pub cache_dir: Option<PathBuf>,
+    /// Describes the plugin settings; a cache built with different settings is discarded.
+    pub cache_key: String,
+    /// Collect timings of requests, parses and queue waits.
+    pub profile: bool,
+    /// Send anonymous usage counters as `telemetry/event` notifications.
+    pub telemetry: bool,
+    /// Why libclang couldn't be loaded; nothing is indexed then.
+    pub libclang_error: Option<String>,
+}
+
+/// The command line defaults, indexing everything below the project root.
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            debounce: Duration::from_millis(200),
+            reindex_on_save: false,
+            format_on_save: false,
+            init_template: DEFAULT_INIT_TARGET_TEMPLATE.to_string(),
+            index_extensions: vec!["c".into(), "h".into()],
+            header_extensions: vec!["h".into()],
+            index_roots: Vec::new(),
+            excluded_dirs: [".git", ".cache", "build", "target"]
+                .map(String::from)
+                .to_vec(),
+            exclude_globs: GlobSet::empty(),
+            diagnostics_ignore: DiagnosticsIgnore::default(),
+            severity_overrides: SeverityOverrides::default(),
+            diagnostics_cap: DiagnosticsCap::default(),
+            open_files_only: false,
+            companion: Vec::new(),
+            respect_ignore_files: true,
+            git_tracked_only: false,
+            path_style: PathStyle::default(),
+            parse_jobs: 1,
+            parse_timeout: Some(Duration::from_secs(30)),
+            memory_budget: None,
+            compile_commands: PathBuf::from("compile_commands.json"),
+            cache_dir: None,
+            cache_key: String::new(),
+            profile: false,
+            telemetry: false,
+            libclang_error: None,
+        }
+    }
+}
+
+impl Config {
+    pub fn companion(&self, feature: CompanionFeature) -> bool {
+        self.companion.contains(&feature)
+    }
+
+    pub fn is_indexed_file(&self, path: &Path) -> bool {
+        path.extension()
+            .and_then(OsStr::to_str)
+            .is_some_and(|ext| self.index_extensions.iter().any(|e| e == ext))
+    }
+
+    pub fn is_excluded_dir(&self, name: &OsStr) -> bool {
+        self.excluded_dirs.iter().any(|dir| OsStr::new(dir) == name)
+    }
+
+    pub fn is_excluded_path(&self, project_root: &Path, path: &Path) -> bool {
+        path.strip_prefix(project_root)
+            .is_ok_and(|relative| self.exclude_globs.is_match(relative))
+    }
+
+    /// Whether a file changed on disk belongs in the index when indexing `roots`.
+    pub fn is_indexed_path(&self, project_root: &Path, roots: &[PathBuf], path: &Path) -> bool {
+        self.is_indexed_file(path)
+            && roots.iter().any(|root| path.starts_with(root))
+            && !self.is_excluded_path(project_root, path)
+            && !path
+                .strip_prefix(project_root)
+                .unwrap_or(path)
+                .iter()
+                .any(|component| self.is_excluded_dir(component))
+    }
+}
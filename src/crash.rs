@@ -0,0 +1,116 @@
+use std::backtrace::Backtrace;
+use std::cell::Cell;
+use std::fmt::Write as _;
+use std::fs;
+use std::panic::{self, PanicHookInfo};
+use std::path::PathBuf;
+use std::sync::OnceLock;
+use std::thread;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use tokio::runtime::Handle;
+use tower_lsp::lsp_types::MessageType;
+use tower_lsp::Client;
+
+static CLIENT: OnceLock<(Client, Handle)> = OnceLock::new();
+
+thread_local! {
+    /// Set while running work whose panics are caught and recovered from.
+    static RECOVERABLE: Cell<bool> = const { Cell::new(false) };
+}
+
+/// Makes panics write a crash log with a backtrace and, once `set_client` was called, tell the
+/// user where to find it. The default hook still prints the panic to stderr.
+pub fn install_panic_hook() {
+    let default_hook = panic::take_hook();
+    panic::set_hook(Box::new(move |info| {
+        default_hook(info);
+        let reason = panic_reason(info);
+        let log = write_crash_log(info, &reason);
+        let what = if RECOVERABLE.with(Cell::get) {
+            "elysium-lsp recovered from a panic"
+        } else {
+            "elysium-lsp crashed"
+        };
+        notify_client(match &log {
+            Some(path) => format!("{what}: {reason}, see {}", path.display()),
+            None => format!("{what}: {reason}"),
+        });
+    }));
+}
+
+/// Runs `f`, reporting a panic in it as recovered from instead of as a crash. Only for work
+/// whose panics the caller catches, such as a `spawn_blocking` closure whose `JoinError` is
+/// handled.
+pub fn recoverable<T>(f: impl FnOnce() -> T) -> T {
+    struct Restore(bool);
+    impl Drop for Restore {
+        fn drop(&mut self) {
+            RECOVERABLE.with(|recoverable| recoverable.set(self.0));
+        }
+    }
+    let _restore = Restore(RECOVERABLE.with(|recoverable| recoverable.replace(true)));
+    f()
+}
+
+/// Client that panics are reported to. Must be called from within the runtime.
+pub fn set_client(client: Client) {
+    let _ = CLIENT.set((client, Handle::current()));
+}
+
+fn panic_reason(info: &PanicHookInfo) -> String {
+    let payload = info.payload();
+    let message = payload
+        .downcast_ref::<&str>()
+        .copied()
+        .or_else(|| payload.downcast_ref::<String>().map(String::as_str))
+        .unwrap_or("unknown panic");
+    match info.location() {
+        Some(location) => format!("{message} at {location}"),
+        None => message.to_string(),
+    }
+}
+
+fn write_crash_log(info: &PanicHookInfo, reason: &str) -> Option<PathBuf> {
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |time| time.as_secs());
+    let path = std::env::temp_dir().join(format!(
+        "elysium-lsp-crash-{timestamp}-{}.log",
+        std::process::id()
+    ));
+
+    let mut log = String::new();
+    let _ = writeln!(log, "elysium-lsp {}", env!("CARGO_PKG_VERSION"));
+    let _ = writeln!(
+        log,
+        "thread '{}' panicked: {reason}",
+        thread::current().name().unwrap_or("<unnamed>")
+    );
+    if let Some(location) = info.location() {
+        let _ = writeln!(log, "location: {location}");
+    }
+    let _ = writeln!(log, "\n{}", Backtrace::force_capture());
+
+    match fs::write(&path, log) {
+        Ok(()) => Some(path),
+        Err(err) => {
+            eprintln!("failed to write crash log {}: {err}", path.display());
+            None
+        }
+    }
+}
+
+/// Sends the message from a fresh thread, since the panicking one may be a runtime worker that
+/// can't block on a future.
+fn notify_client(message: String) {
+    let Some((client, handle)) = CLIENT.get() else {
+        return;
+    };
+    let client = client.clone();
+    let handle = handle.clone();
+    let sent = thread::spawn(move || {
+        handle.block_on(client.show_message(MessageType::ERROR, message));
+    });
+    let _ = sent.join();
+}
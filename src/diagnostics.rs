@@ -0,0 +1,159 @@
+use std::collections::HashMap;
+use std::path::Path;
+
+use anyhow::{bail, Context, Result};
+use globset::{Glob, GlobSet, GlobSetBuilder};
+use tower_lsp::lsp_types::{Diagnostic, DiagnosticSeverity, NumberOrString, Range};
+
+/// Files that never get diagnostics published, although they are still indexed. Globs are
+/// matched relative to the project root and apply to every plugin unless prefixed with a plugin
+/// name, as in `hooks:generated/**`.
+#[derive(Default)]
+pub struct DiagnosticsIgnore {
+    all: GlobSet,
+    by_plugin: HashMap<String, GlobSet>,
+}
+
+impl DiagnosticsIgnore {
+    pub fn parse(specs: &[String]) -> Result<Self> {
+        let mut all = GlobSetBuilder::new();
+        let mut by_plugin: HashMap<String, GlobSetBuilder> = HashMap::new();
+        for spec in specs {
+            let (plugin, pattern) = match spec.split_once(':') {
+                Some((plugin, pattern)) if is_plugin_name(plugin) => (Some(plugin), pattern),
+                _ => (None, spec.as_str()),
+            };
+            let glob =
+                Glob::new(pattern).with_context(|| format!("--diagnostics-ignore {spec}"))?;
+            match plugin {
+                Some(plugin) => by_plugin
+                    .entry(plugin.to_string())
+                    .or_insert_with(GlobSetBuilder::new)
+                    .add(glob),
+                None => all.add(glob),
+            };
+        }
+        Ok(Self {
+            all: all.build()?,
+            by_plugin: by_plugin
+                .into_iter()
+                .map(|(plugin, globs)| Ok((plugin, globs.build()?)))
+                .collect::<Result<_>>()?,
+        })
+    }
+
+    pub fn is_ignored(&self, plugin: &str, relative: &Path) -> bool {
+        self.all.is_match(relative)
+            || self
+                .by_plugin
+                .get(plugin)
+                .is_some_and(|globs| globs.is_match(relative))
+    }
+}
+
+/// Severities replacing the ones plugins chose, keyed by diagnostic code (e.g.
+/// `init/duplicate-dependency`).
+#[derive(Default)]
+pub struct SeverityOverrides(HashMap<String, DiagnosticSeverity>);
+
+impl SeverityOverrides {
+    /// Parses `code=severity` pairs.
+    pub fn parse(specs: &[String]) -> Result<Self> {
+        let mut overrides = HashMap::new();
+        for spec in specs {
+            let (code, severity) = spec
+                .split_once('=')
+                .with_context(|| format!("--severity {spec}: expected <code>=<severity>"))?;
+            let severity = match severity {
+                "error" => DiagnosticSeverity::ERROR,
+                "warning" => DiagnosticSeverity::WARNING,
+                "information" | "info" => DiagnosticSeverity::INFORMATION,
+                "hint" => DiagnosticSeverity::HINT,
+                _ => {
+                    bail!("--severity {spec}: severity must be error, warning, information or hint")
+                }
+            };
+            overrides.insert(code.to_string(), severity);
+        }
+        Ok(Self(overrides))
+    }
+
+    pub fn apply(&self, diagnostic: &mut Diagnostic) {
+        let Some(NumberOrString::String(code)) = &diagnostic.code else {
+            return;
+        };
+        if let Some(severity) = self.0.get(code) {
+            diagnostic.severity = Some(*severity);
+        }
+    }
+}
+
+/// Limits on how many diagnostics one file gets, overall and from each plugin.
+#[derive(Default)]
+pub struct DiagnosticsCap {
+    all: Option<usize>,
+    by_plugin: HashMap<String, usize>,
+}
+
+impl DiagnosticsCap {
+    /// Parses limits given as `n` for all diagnostics of a file or `plugin=n` for one plugin's.
+    pub fn parse(specs: &[String]) -> Result<Self> {
+        let mut cap = Self::default();
+        for spec in specs {
+            let parse_limit = |limit: &str| {
+                limit
+                    .parse::<usize>()
+                    .with_context(|| format!("--max-diagnostics {spec}: invalid limit"))
+            };
+            match spec.split_once('=') {
+                Some((plugin, limit)) => {
+                    cap.by_plugin
+                        .insert(plugin.to_string(), parse_limit(limit)?);
+                }
+                None => cap.all = Some(parse_limit(spec)?),
+            }
+        }
+        Ok(cap)
+    }
+
+    pub fn apply_plugin(&self, plugin: &str, diagnostics: &mut Vec<Diagnostic>) {
+        if let Some(&limit) = self.by_plugin.get(plugin) {
+            truncate(diagnostics, limit);
+        }
+    }
+
+    pub fn apply(&self, diagnostics: &mut Vec<Diagnostic>) {
+        if let Some(limit) = self.all {
+            truncate(diagnostics, limit);
+        }
+    }
+}
+
+/// Keeps the first `limit` diagnostics in file order and notes how many were dropped.
+fn truncate(diagnostics: &mut Vec<Diagnostic>, limit: usize) {
+    if diagnostics.len() <= limit {
+        return;
+    }
+    diagnostics.sort_by_key(|diagnostic| {
+        (
+            diagnostic.range.start.line,
+            diagnostic.range.start.character,
+        )
+    });
+    let suppressed = diagnostics.len() - limit;
+    diagnostics.truncate(limit);
+    diagnostics.push(Diagnostic {
+        range: Range::default(),
+        severity: Some(DiagnosticSeverity::INFORMATION),
+        message: format!("{suppressed} more issues suppressed"),
+        source: Some("elysium-lsp".into()),
+        ..Diagnostic::default()
+    });
+}
+
+fn is_plugin_name(name: &str) -> bool {
+    !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-')
+}
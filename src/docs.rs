@@ -0,0 +1,146 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Write as _;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use serde::Serialize;
+
+use crate::plugins::{SymbolInfo, SymbolLocation};
+use crate::symbol::Symbol;
+
+/// A symbol as written to `symbols.json`.
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct DocEntry<'a> {
+    plugin: &'a str,
+    name: &'a Symbol,
+    kind: &'static str,
+    location: DocLocation,
+    documentation: Option<&'a str>,
+    fields: BTreeMap<&'static str, &'a str>,
+    dependencies: &'a [Symbol],
+    dependents: Vec<&'a Symbol>,
+    references: Vec<DocLocation>,
+}
+
+/// A project-relative file and one-based line.
+#[derive(Serialize)]
+struct DocLocation {
+    file: PathBuf,
+    line: u32,
+}
+
+/// Writes a Markdown page per plugin plus `symbols.json` with the same data into `out`.
+pub fn write(
+    out: &Path,
+    project_root: &Path,
+    symbols: &[(&'static str, SymbolInfo)],
+) -> Result<()> {
+    fs::create_dir_all(out).with_context(|| format!("creating {}", out.display()))?;
+
+    let mut by_plugin: BTreeMap<&str, Vec<&SymbolInfo>> = BTreeMap::new();
+    for (plugin, symbol) in symbols {
+        by_plugin.entry(plugin).or_default().push(symbol);
+    }
+    for symbols in by_plugin.values_mut() {
+        symbols.sort_by(|a, b| a.name.cmp(&b.name));
+    }
+
+    let mut entries = Vec::new();
+    let mut index = String::from("# Cronus symbol reference\n\n");
+    for (plugin, symbols) in &by_plugin {
+        let mut dependents: HashMap<&Symbol, Vec<&Symbol>> = HashMap::new();
+        for symbol in symbols {
+            for dependency in &symbol.dependencies {
+                dependents.entry(dependency).or_default().push(&symbol.name);
+            }
+        }
+
+        let mut page = format!("# {plugin}\n");
+        for symbol in symbols {
+            let dependents = dependents.get(&symbol.name).cloned().unwrap_or_default();
+            render_symbol(&mut page, project_root, symbol, &dependents);
+            entries.push(DocEntry {
+                plugin,
+                name: &symbol.name,
+                kind: symbol.kind_name(),
+                location: doc_location(project_root, &symbol.location),
+                documentation: symbol.documentation.as_deref(),
+                fields: (symbol.fields.iter())
+                    .map(|(key, value)| (*key, value.as_str()))
+                    .collect(),
+                dependencies: &symbol.dependencies,
+                dependents,
+                references: (symbol.references.iter())
+                    .map(|location| doc_location(project_root, location))
+                    .collect(),
+            });
+        }
+        let page_path = out.join(format!("{plugin}.md"));
+        fs::write(&page_path, page).with_context(|| format!("writing {}", page_path.display()))?;
+        let _ = writeln!(
+            index,
+            "- [{plugin}]({plugin}.md) ({} symbols)",
+            symbols.len()
+        );
+    }
+
+    fs::write(out.join("README.md"), index).context("writing the docs index")?;
+    let json = serde_json::to_string_pretty(&entries)?;
+    fs::write(out.join("symbols.json"), json).context("writing symbols.json")?;
+    Ok(())
+}
+
+fn render_symbol(page: &mut String, root: &Path, symbol: &SymbolInfo, dependents: &[&Symbol]) {
+    let _ = writeln!(page, "\n## {}\n", symbol.name);
+    if let Some(documentation) = &symbol.documentation {
+        let _ = writeln!(page, "{documentation}\n");
+    }
+    let _ = writeln!(
+        page,
+        "- Defined at {}",
+        location_text(root, &symbol.location)
+    );
+    for (key, value) in &symbol.fields {
+        let _ = writeln!(page, "- {key}: `{value}`");
+    }
+    if !symbol.dependencies.is_empty() {
+        let _ = writeln!(page, "- Depends on: {}", symbol_links(&symbol.dependencies));
+    }
+    if !dependents.is_empty() {
+        let _ = writeln!(
+            page,
+            "- Required by: {}",
+            symbol_links(dependents.iter().copied())
+        );
+    }
+    if !symbol.references.is_empty() {
+        let _ = writeln!(page, "- Referenced from:");
+        for reference in &symbol.references {
+            let _ = writeln!(page, "  - {}", location_text(root, reference));
+        }
+    }
+}
+
+fn symbol_links<'a>(names: impl IntoIterator<Item = &'a Symbol>) -> String {
+    names
+        .into_iter()
+        .map(|name| format!("[`{name}`](#{})", name.to_lowercase()))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+fn location_text(root: &Path, location: &SymbolLocation) -> String {
+    let location = doc_location(root, location);
+    format!("`{}:{}`", location.file.display(), location.line)
+}
+
+fn doc_location(root: &Path, location: &SymbolLocation) -> DocLocation {
+    DocLocation {
+        file: (location.path.strip_prefix(root))
+            .unwrap_or(&location.path)
+            .to_path_buf(),
+        line: location.range.start.line + 1,
+    }
+}
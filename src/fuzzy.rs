@@ -0,0 +1,99 @@
+/// Points for each query character matched.
+const MATCH: i32 = 16;
+/// Extra points for matching the first character of the candidate.
+const PREFIX_BONUS: i32 = 24;
+/// Extra points for matching the first character of a word, e.g. after `_` or in camel case.
+const BOUNDARY_BONUS: i32 = 16;
+/// Extra points for matching right after the previous match.
+const CONSECUTIVE_BONUS: i32 = 16;
+/// Points lost per candidate character skipped between two matches.
+const GAP_PENALTY: i32 = 1;
+
+const NO_MATCH: i32 = i32::MIN / 2;
+
+/// Scores `candidate` against `query`, or returns `None` if the query's characters don't all
+/// appear in it in order. Matching ignores case; matches at the start of the candidate or of its
+/// words and runs of adjacent matches score higher, so `vmint` ranks `vmm_interrupt_init` above
+/// `avm_point`.
+pub fn score(query: &str, candidate: &str) -> Option<i32> {
+    let query: Vec<char> = query.chars().flat_map(char::to_lowercase).collect();
+    if query.is_empty() {
+        return Some(0);
+    }
+    let chars: Vec<char> = candidate.chars().collect();
+    let bonuses: Vec<i32> = (0..chars.len())
+        .map(|j| position_bonus(&chars, j))
+        .collect();
+    let lower: Vec<char> = (chars.iter())
+        .map(|c| c.to_lowercase().next().unwrap_or(*c))
+        .collect();
+
+    // `row[j]`: best score of matching the query so far with its last character at `j`.
+    let mut row = vec![NO_MATCH; chars.len()];
+    for (i, wanted) in query.iter().enumerate() {
+        let mut next = vec![NO_MATCH; chars.len()];
+        // Best score of an earlier match at least one character before `j - 1`, net of the gap.
+        let mut gapped = NO_MATCH;
+        for j in 0..chars.len() {
+            if lower[j] == *wanted {
+                let before = if i == 0 {
+                    0
+                } else if j == 0 {
+                    NO_MATCH
+                } else {
+                    (row[j - 1] + CONSECUTIVE_BONUS).max(gapped)
+                };
+                next[j] = before + MATCH + bonuses[j];
+            }
+            if j > 0 {
+                gapped = gapped.max(row[j - 1]);
+            }
+            gapped -= GAP_PENALTY;
+        }
+        row = next;
+    }
+    row.into_iter().max().filter(|best| *best > NO_MATCH / 2)
+}
+
+fn position_bonus(chars: &[char], j: usize) -> i32 {
+    let Some(previous) = j.checked_sub(1).map(|i| chars[i]) else {
+        return PREFIX_BONUS + BOUNDARY_BONUS;
+    };
+    let current = chars[j];
+    let boundary = !previous.is_alphanumeric()
+        || (previous.is_lowercase() && current.is_uppercase())
+        || (!previous.is_ascii_digit() && current.is_ascii_digit());
+    if boundary {
+        BOUNDARY_BONUS
+    } else {
+        0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::score;
+
+    #[test]
+    fn matches_subsequences() {
+        assert!(score("vmint", "vmm_interrupt_init").is_some());
+        assert!(score("VMINT", "vmm_interrupt_init").is_some());
+    }
+
+    #[test]
+    fn ranks_word_starts_higher() {
+        assert!(score("vmint", "vmm_interrupt_init") > score("vmint", "avm_point"));
+    }
+
+    #[test]
+    fn rejects_non_subsequences() {
+        assert_eq!(score("intvm", "vmm_interrupt_init"), None);
+        assert_eq!(score("vmx", "vmm"), None);
+    }
+
+    #[test]
+    fn empty_query_matches_everything() {
+        assert_eq!(score("", "vmm_interrupt_init"), Some(0));
+        assert_eq!(score("", ""), Some(0));
+    }
+}
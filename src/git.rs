@@ -0,0 +1,84 @@
+//! The files git tracks, for keeping untracked build outputs and editor backups out of the index.
+
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::Mutex;
+
+use anyhow::{ensure, Context, Result};
+
+/// The files git tracks below a root. Listing them runs git, so callers on the async runtime do
+/// that in `spawn_blocking`.
+pub struct TrackedFiles {
+    root: PathBuf,
+    /// `None` until listed, or if listing failed, e.g. because the root isn't in a git work tree.
+    files: Mutex<Option<HashSet<PathBuf>>>,
+}
+
+impl TrackedFiles {
+    /// Tracked files of `root`, not listed yet.
+    pub fn new(root: &Path) -> Self {
+        Self {
+            root: root.to_path_buf(),
+            files: Mutex::new(None),
+        }
+    }
+
+    /// Lists the tracked files (again), picking up files added since.
+    pub fn reload(&self) {
+        let listed = ls_files(&self.root, &[]);
+        let mut files = self.files.lock().unwrap();
+        match listed {
+            Ok(listed) => *files = Some(listed),
+            Err(err) if files.is_none() => tracing::warn!("indexing untracked files too: {err:#}"),
+            Err(err) => tracing::warn!("keeping the previous list of tracked files: {err:#}"),
+        }
+    }
+
+    /// Whether `path` was tracked when the files were listed. Files outside the root, and every
+    /// file while there is no listing, are taken as tracked.
+    pub fn contains(&self, path: &Path) -> bool {
+        !path.starts_with(&self.root)
+            || (self.files.lock().unwrap().as_ref()).is_none_or(|files| files.contains(path))
+    }
+
+    /// Asks git about those of `paths` missing from the listing, with a single `git ls-files`,
+    /// adding the ones tracked since to it.
+    pub fn refresh(&self, paths: &[PathBuf]) {
+        let unlisted: Vec<&Path> = (paths.iter())
+            .filter(|path| !self.contains(path))
+            .filter_map(|path| path.strip_prefix(&self.root).ok())
+            .collect();
+        if unlisted.is_empty() {
+            return;
+        }
+        match ls_files(&self.root, &unlisted) {
+            Ok(tracked) => {
+                if let Some(files) = self.files.lock().unwrap().as_mut() {
+                    files.extend(tracked);
+                }
+            }
+            Err(err) => tracing::debug!("checking for newly tracked files: {err:#}"),
+        }
+    }
+}
+
+/// The tracked files among `paths`, or all of them if `paths` is empty, as paths below `root`.
+fn ls_files(root: &Path, paths: &[&Path]) -> Result<HashSet<PathBuf>> {
+    let output = Command::new("git")
+        .arg("-C")
+        .arg(root)
+        .args(["ls-files", "-z", "--cached", "--recurse-submodules", "--"])
+        .args(paths)
+        .output()
+        .context("running git ls-files")?;
+    ensure!(
+        output.status.success(),
+        "git ls-files failed: {}",
+        String::from_utf8_lossy(&output.stderr).trim()
+    );
+    Ok((output.stdout.split(|&byte| byte == 0))
+        .filter(|path| !path.is_empty())
+        .map(|path| root.join(&*String::from_utf8_lossy(path)))
+        .collect())
+}
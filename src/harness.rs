@@ -0,0 +1,350 @@
+//! Runs the server in-process over in-memory streams and drives it like an editor would, for
+//! integration tests of this crate, of custom plugins, and of editor extensions pinned to a
+//! server version.
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{anyhow, bail, Context, Result};
+use serde_json::{json, Value};
+use tokio::io::{
+    AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader, DuplexStream, ReadHalf, WriteHalf,
+};
+use tokio::sync::{mpsc, Mutex};
+use tokio::task::JoinHandle;
+use tower_lsp::lsp_types::{
+    CompletionItem, CompletionResponse, Diagnostic, Position, PublishDiagnosticsParams, Url,
+};
+use tower_lsp::Server;
+
+use crate::config::Config;
+use crate::lsp::{ElysiumLsp, ServerState};
+use crate::plugins::LspPlugin;
+
+const PIPE_CAPACITY: usize = 1 << 16;
+
+/// A server with a scripted client attached.
+pub struct TestServer {
+    server: ElysiumLsp,
+    project_root: PathBuf,
+    writer: Arc<Mutex<WriteHalf<DuplexStream>>>,
+    messages: mpsc::UnboundedReceiver<Value>,
+    diagnostics: HashMap<Url, Vec<Diagnostic>>,
+    state: Option<ServerState>,
+    next_id: i64,
+    tasks: Vec<JoinHandle<()>>,
+    /// How long diagnostics must stay unchanged before they count as settled.
+    pub settle_time: Duration,
+    /// Longest wait for a response or for indexing to finish.
+    pub timeout: Duration,
+}
+
+impl TestServer {
+    /// Starts a server on `project_root` and completes the `initialize` handshake, which starts
+    /// indexing the workspace.
+    pub async fn start(
+        project_root: impl Into<PathBuf>,
+        config: Config,
+        plugins: Vec<Box<dyn LspPlugin>>,
+    ) -> Result<Self> {
+        let project_root = project_root.into();
+        let (service, socket) = ElysiumLsp::service(|client| {
+            ElysiumLsp::new(client, config, project_root.clone(), plugins, None)
+        });
+        let server = service.inner().clone();
+
+        let (client_end, server_end) = tokio::io::duplex(PIPE_CAPACITY);
+        let (server_read, server_write) = tokio::io::split(server_end);
+        let (client_read, client_write) = tokio::io::split(client_end);
+        let writer = Arc::new(Mutex::new(client_write));
+        let (sender, messages) = mpsc::unbounded_channel();
+        let tasks = vec![
+            tokio::spawn(Server::new(server_read, server_write, socket).serve(service)),
+            tokio::spawn(read_messages(client_read, writer.clone(), sender)),
+        ];
+
+        let mut test = Self {
+            server,
+            project_root,
+            writer,
+            messages,
+            diagnostics: HashMap::new(),
+            state: None,
+            next_id: 0,
+            tasks,
+            settle_time: Duration::from_millis(300),
+            timeout: Duration::from_secs(30),
+        };
+        let root_uri = test.uri(Path::new(""))?;
+        test.request(
+            "initialize",
+            json!({ "processId": null, "rootUri": root_uri, "capabilities": {} }),
+        )
+        .await?;
+        test.notify("initialized", json!({})).await?;
+        Ok(test)
+    }
+
+    /// The server itself, for calling its methods directly.
+    pub fn server(&self) -> &ElysiumLsp {
+        &self.server
+    }
+
+    /// The last `elysium/state` notification handled.
+    pub fn state(&self) -> Option<&ServerState> {
+        self.state.as_ref()
+    }
+
+    /// The URI of `path`, resolved against the project root.
+    pub fn uri(&self, path: &Path) -> Result<Url> {
+        Url::from_file_path(self.project_root.join(path))
+            .map_err(|()| anyhow!("{} is not an absolute path", path.display()))
+    }
+
+    /// Opens `path` with its contents on disk.
+    pub async fn open(&mut self, path: impl AsRef<Path>) -> Result<Url> {
+        let path = self.project_root.join(path);
+        let text = std::fs::read_to_string(&path)
+            .with_context(|| format!("reading {}", path.display()))?;
+        self.open_text(path, &text).await
+    }
+
+    /// Opens `path` as if the editor had `text` in it, whether or not the file exists.
+    pub async fn open_text(&mut self, path: impl AsRef<Path>, text: &str) -> Result<Url> {
+        let uri = self.uri(path.as_ref())?;
+        self.notify(
+            "textDocument/didOpen",
+            json!({
+                "textDocument": { "uri": uri, "languageId": "c", "version": 1, "text": text },
+            }),
+        )
+        .await?;
+        Ok(uri)
+    }
+
+    /// Replaces the whole text of an open document.
+    pub async fn change(&mut self, uri: &Url, version: i32, text: &str) -> Result<()> {
+        self.notify(
+            "textDocument/didChange",
+            json!({
+                "textDocument": { "uri": uri, "version": version },
+                "contentChanges": [{ "text": text }],
+            }),
+        )
+        .await
+    }
+
+    pub async fn save(&mut self, uri: &Url, text: &str) -> Result<()> {
+        self.notify(
+            "textDocument/didSave",
+            json!({ "textDocument": { "uri": uri }, "text": text }),
+        )
+        .await
+    }
+
+    pub async fn close(&mut self, uri: &Url) -> Result<()> {
+        self.notify(
+            "textDocument/didClose",
+            json!({ "textDocument": { "uri": uri } }),
+        )
+        .await
+    }
+
+    pub async fn completions(
+        &mut self,
+        uri: &Url,
+        position: Position,
+    ) -> Result<Vec<CompletionItem>> {
+        let result = self
+            .request(
+                "textDocument/completion",
+                json!({ "textDocument": { "uri": uri }, "position": position }),
+            )
+            .await?;
+        Ok(
+            match serde_json::from_value::<Option<CompletionResponse>>(result)? {
+                Some(CompletionResponse::Array(items)) => items,
+                Some(CompletionResponse::List(list)) => list.items,
+                None => Vec::new(),
+            },
+        )
+    }
+
+    /// Waits until the workspace is indexed and no parse is queued.
+    pub async fn wait_for_indexing(&mut self) -> Result<()> {
+        let deadline = tokio::time::Instant::now() + self.timeout;
+        loop {
+            let status = self.server.status().await.map_err(|err| anyhow!("{err}"))?;
+            if !status.initial_indexing && status.queued_jobs == 0 {
+                return Ok(());
+            }
+            if tokio::time::Instant::now() > deadline {
+                bail!("indexing did not finish within {:?}", self.timeout);
+            }
+            self.drain(Duration::from_millis(20)).await?;
+        }
+    }
+
+    /// Waits for the first `elysium/state` notification, which the server sends once initialized.
+    pub async fn wait_for_state(&mut self) -> Result<&ServerState> {
+        let deadline = tokio::time::Instant::now() + self.timeout;
+        while self.state.is_none() {
+            if tokio::time::Instant::now() > deadline {
+                bail!("no elysium/state notification within {:?}", self.timeout);
+            }
+            self.drain(Duration::from_millis(20)).await?;
+        }
+        self.state.as_ref().context("no elysium/state notification")
+    }
+
+    /// Waits for indexing and for diagnostics to settle, then returns the last diagnostics
+    /// published for `uri`.
+    pub async fn diagnostics(&mut self, uri: &Url) -> Result<Vec<Diagnostic>> {
+        self.wait_for_indexing().await?;
+        while self.drain(self.settle_time).await? {}
+        Ok(self.diagnostics.get(uri).cloned().unwrap_or_default())
+    }
+
+    /// Every file's last published diagnostics, once settled; files without any are left out.
+    pub async fn all_diagnostics(&mut self) -> Result<HashMap<Url, Vec<Diagnostic>>> {
+        self.wait_for_indexing().await?;
+        while self.drain(self.settle_time).await? {}
+        let mut all = self.diagnostics.clone();
+        all.retain(|_, diagnostics| !diagnostics.is_empty());
+        Ok(all)
+    }
+
+    /// Sends a request, handling whatever else arrives until its response does. `Value::Null`
+    /// params are left out, as `shutdown` requires.
+    pub async fn request(&mut self, method: &str, params: Value) -> Result<Value> {
+        self.next_id += 1;
+        let id = self.next_id;
+        let mut message = json!({ "jsonrpc": "2.0", "id": id, "method": method });
+        if !params.is_null() {
+            message["params"] = params;
+        }
+        write_message(&self.writer, &message).await?;
+        loop {
+            let message = tokio::time::timeout(self.timeout, self.messages.recv())
+                .await
+                .with_context(|| format!("no response to {method}"))?
+                .context("the server closed the connection")?;
+            if message.get("id") != Some(&json!(id)) {
+                self.handle(message)?;
+                continue;
+            }
+            if let Some(error) = message.get("error") {
+                bail!("{method} failed: {error}");
+            }
+            return Ok(message.get("result").cloned().unwrap_or(Value::Null));
+        }
+    }
+
+    pub async fn notify(&mut self, method: &str, params: Value) -> Result<()> {
+        let mut message = json!({ "jsonrpc": "2.0", "method": method });
+        if !params.is_null() {
+            message["params"] = params;
+        }
+        write_message(&self.writer, &message).await
+    }
+
+    /// Sends `shutdown` and `exit` and waits for the server to stop.
+    pub async fn shut_down(mut self) -> Result<()> {
+        self.request("shutdown", Value::Null).await?;
+        self.notify("exit", Value::Null).await?;
+        // The server only stops serving once its input ends.
+        self.writer.lock().await.shutdown().await?;
+        let server = self.tasks.remove(0);
+        tokio::time::timeout(self.timeout, server)
+            .await
+            .context("the server did not exit")??;
+        Ok(())
+    }
+
+    /// Handles the messages arriving within `quiet`; returns whether any did.
+    async fn drain(&mut self, quiet: Duration) -> Result<bool> {
+        let mut received = false;
+        while let Ok(message) = tokio::time::timeout(quiet, self.messages.recv()).await {
+            self.handle(message.context("the server closed the connection")?)?;
+            received = true;
+        }
+        Ok(received)
+    }
+
+    fn handle(&mut self, message: Value) -> Result<()> {
+        match message.get("method").and_then(Value::as_str) {
+            Some("textDocument/publishDiagnostics") => {
+                let params: PublishDiagnosticsParams =
+                    serde_json::from_value(message["params"].clone())?;
+                self.diagnostics.insert(params.uri, params.diagnostics);
+            }
+            Some("elysium/state") => {
+                self.state = Some(serde_json::from_value(message["params"].clone())?);
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+}
+
+impl Drop for TestServer {
+    fn drop(&mut self) {
+        for task in &self.tasks {
+            task.abort();
+        }
+    }
+}
+
+/// Forwards the server's messages, answering its requests (such as capability registrations)
+/// right away so it never waits on the test.
+async fn read_messages(
+    reader: ReadHalf<DuplexStream>,
+    writer: Arc<Mutex<WriteHalf<DuplexStream>>>,
+    sender: mpsc::UnboundedSender<Value>,
+) {
+    let mut reader = BufReader::new(reader);
+    while let Ok(Some(message)) = read_message(&mut reader).await {
+        if let (Some(id), Some(_)) = (message.get("id"), message.get("method")) {
+            let response = json!({ "jsonrpc": "2.0", "id": id, "result": null });
+            if write_message(&writer, &response).await.is_err() {
+                return;
+            }
+            continue;
+        }
+        if sender.send(message).is_err() {
+            return;
+        }
+    }
+}
+
+async fn read_message(reader: &mut BufReader<ReadHalf<DuplexStream>>) -> Result<Option<Value>> {
+    let mut length = None;
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line).await? == 0 {
+            return Ok(None);
+        }
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+        if let Some(value) = line.strip_prefix("Content-Length:") {
+            length = Some(value.trim().parse::<usize>()?);
+        }
+    }
+    let mut body = vec![0; length.context("message without Content-Length")?];
+    reader.read_exact(&mut body).await?;
+    Ok(Some(serde_json::from_slice(&body)?))
+}
+
+async fn write_message(writer: &Mutex<WriteHalf<DuplexStream>>, message: &Value) -> Result<()> {
+    let body = serde_json::to_string(message)?;
+    let mut writer = writer.lock().await;
+    writer
+        .write_all(format!("Content-Length: {}\r\n\r\n{body}", body.len()).as_bytes())
+        .await?;
+    writer.flush().await?;
+    Ok(())
+}
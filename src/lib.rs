@@ -0,0 +1,30 @@
+//! The Elysium language server as a library, for embedding it or building a binary with
+//! additional [`LspPlugin`]s.
+
+mod cache;
+pub mod compile_commands;
+pub mod config;
+pub mod crash;
+pub mod diagnostics;
+pub mod docs;
+mod fuzzy;
+mod git;
+pub mod harness;
+pub mod lsif;
+pub mod lsp;
+mod memory;
+pub mod metrics;
+pub mod paths;
+pub mod placeholders;
+pub mod plugins;
+mod profile;
+mod progress;
+pub mod scaffold;
+mod scheduler;
+pub mod symbol;
+pub mod tags;
+pub mod workers;
+
+pub use compile_commands::CompileCommands;
+pub use lsp::ElysiumLsp;
+pub use plugins::LspPlugin;
@@ -0,0 +1,171 @@
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use serde_json::{json, Value};
+use tower_lsp::lsp_types::{Range, Url};
+
+use crate::plugins::SymbolInfo;
+
+const LSIF_VERSION: &str = "0.4.3";
+
+/// Writes an LSIF dump with the definitions, references and documentation of `symbols` to `out`.
+pub fn write(
+    out: &Path,
+    project_root: &Path,
+    symbols: &[(&'static str, SymbolInfo)],
+) -> Result<()> {
+    let file = File::create(out).with_context(|| format!("creating {}", out.display()))?;
+    let mut dump = Dump {
+        out: BufWriter::new(file),
+        next_id: 0,
+        documents: BTreeMap::new(),
+    };
+    dump.write(project_root, symbols)
+        .with_context(|| format!("writing {}", out.display()))
+}
+
+struct Dump {
+    out: BufWriter<File>,
+    next_id: u64,
+    /// Document vertex and contained range vertices of every file mentioned.
+    documents: BTreeMap<PathBuf, (u64, Vec<u64>)>,
+}
+
+impl Dump {
+    fn write(&mut self, project_root: &Path, symbols: &[(&'static str, SymbolInfo)]) -> Result<()> {
+        self.vertex(json!({
+            "label": "metaData",
+            "version": LSIF_VERSION,
+            "projectRoot": file_uri(project_root),
+            "positionEncoding": "utf-16",
+            "toolInfo": { "name": "elysium-lsp", "version": env!("CARGO_PKG_VERSION") },
+        }))?;
+        let project = self.vertex(json!({ "label": "project", "kind": "c" }))?;
+
+        for (_, symbol) in symbols {
+            self.symbol(symbol)?;
+        }
+
+        let documents: Vec<(u64, Vec<u64>)> =
+            std::mem::take(&mut self.documents).into_values().collect();
+        for (document, ranges) in &documents {
+            self.edge("contains", *document, ranges)?;
+        }
+        let documents: Vec<u64> = documents.iter().map(|(document, _)| *document).collect();
+        self.edge("contains", project, &documents)?;
+        self.out.flush()?;
+        Ok(())
+    }
+
+    fn symbol(&mut self, symbol: &SymbolInfo) -> Result<()> {
+        let result_set = self.vertex(json!({ "label": "resultSet" }))?;
+
+        let definition = self.range(&symbol.location.path, symbol.location.range)?;
+        self.edge("next", definition, &[result_set])?;
+        let definition_result = self.vertex(json!({ "label": "definitionResult" }))?;
+        self.edge("textDocument/definition", result_set, &[definition_result])?;
+        let document = self.documents[&symbol.location.path].0;
+        self.item(definition_result, &[definition], document, None)?;
+
+        let reference_result = self.vertex(json!({ "label": "referenceResult" }))?;
+        self.edge("textDocument/references", result_set, &[reference_result])?;
+        self.item(
+            reference_result,
+            &[definition],
+            document,
+            Some("definitions"),
+        )?;
+        let mut references: BTreeMap<u64, Vec<u64>> = BTreeMap::new();
+        for reference in &symbol.references {
+            let range = self.range(&reference.path, reference.range)?;
+            self.edge("next", range, &[result_set])?;
+            let document = self.documents[&reference.path].0;
+            references.entry(document).or_default().push(range);
+        }
+        for (document, ranges) in references {
+            self.item(reference_result, &ranges, document, Some("references"))?;
+        }
+
+        if let Some(documentation) = &symbol.documentation {
+            let hover = self.vertex(json!({
+                "label": "hoverResult",
+                "result": { "contents": { "kind": "markdown", "value": documentation } },
+            }))?;
+            self.edge("textDocument/hover", result_set, &[hover])?;
+        }
+        Ok(())
+    }
+
+    /// A range vertex in the document of `path`, emitting the document on first use.
+    fn range(&mut self, path: &Path, range: Range) -> Result<u64> {
+        if !self.documents.contains_key(path) {
+            let document = self.vertex(json!({
+                "label": "document",
+                "uri": file_uri(path),
+                "languageId": "c",
+            }))?;
+            self.documents
+                .insert(path.to_path_buf(), (document, Vec::new()));
+        }
+        let id =
+            self.vertex(json!({ "label": "range", "start": range.start, "end": range.end }))?;
+        self.documents.get_mut(path).unwrap().1.push(id);
+        Ok(id)
+    }
+
+    fn item(&mut self, from: u64, to: &[u64], document: u64, property: Option<&str>) -> Result<()> {
+        let id = self.next_id();
+        let mut edge = json!({
+            "id": id,
+            "type": "edge",
+            "label": "item",
+            "outV": from,
+            "inVs": to,
+            "document": document,
+        });
+        if let Some(property) = property {
+            edge["property"] = property.into();
+        }
+        self.emit(edge)
+    }
+
+    fn vertex(&mut self, mut vertex: Value) -> Result<u64> {
+        let id = self.next_id();
+        vertex["id"] = id.into();
+        vertex["type"] = "vertex".into();
+        self.emit(vertex)?;
+        Ok(id)
+    }
+
+    /// `contains` edges point at several vertices, every other edge at exactly one.
+    fn edge(&mut self, label: &str, from: u64, to: &[u64]) -> Result<()> {
+        if to.is_empty() {
+            return Ok(());
+        }
+        let id = self.next_id();
+        let mut edge = json!({ "id": id, "type": "edge", "label": label, "outV": from });
+        match to {
+            [to] if label != "contains" => edge["inV"] = (*to).into(),
+            _ => edge["inVs"] = to.into(),
+        }
+        self.emit(edge)
+    }
+
+    fn next_id(&mut self) -> u64 {
+        self.next_id += 1;
+        self.next_id
+    }
+
+    fn emit(&mut self, element: Value) -> Result<()> {
+        serde_json::to_writer(&mut self.out, &element)?;
+        self.out.write_all(b"\n")?;
+        Ok(())
+    }
+}
+
+fn file_uri(path: &Path) -> String {
+    Url::from_file_path(path).map_or_else(|()| path.display().to_string(), String::from)
+}
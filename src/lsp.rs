@@ -1,44 +1,460 @@
-use std::collections::{HashMap, HashSet};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt;
+use std::future::Future;
+use std::hash::{Hash, Hasher};
 use std::path::{Path, PathBuf};
-use std::process;
-use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
 
 use anyhow::{anyhow, Result};
-use tokio::sync::Mutex;
+use ignore::WalkBuilder;
+use serde::{Deserialize, Serialize};
+use tokio::sync::Notify;
+use tokio::task::{JoinHandle, JoinSet};
 use tower_lsp::jsonrpc::Result as LspResult;
 use tower_lsp::lsp_types::{
-    CompletionItem, CompletionResponse, Diagnostic, DidChangeTextDocumentParams,
-    DidChangeWatchedFilesParams, DidCloseTextDocumentParams, DidOpenTextDocumentParams,
-    FileChangeType, InitializeParams, InitializeResult, InitializedParams, Position,
-    ServerCapabilities, TextDocumentContentChangeEvent, TextDocumentSyncCapability,
-    TextDocumentSyncKind,
+    notification::Notification, CallHierarchyIncomingCall, CallHierarchyIncomingCallsParams,
+    CallHierarchyItem, CallHierarchyOutgoingCall, CallHierarchyOutgoingCallsParams,
+    CallHierarchyPrepareParams, CallHierarchyServerCapability, CodeAction, CodeActionKind,
+    CodeActionOptions, CodeActionOrCommand, CodeActionParams, CodeActionProviderCapability,
+    CodeActionResponse, CodeLens, CodeLensOptions, CodeLensParams, Command, CompletionItem,
+    CompletionOptions, CompletionResponse, Diagnostic, DiagnosticOptions,
+    DiagnosticServerCapabilities, DiagnosticSeverity, DidChangeTextDocumentParams,
+    DidChangeWatchedFilesParams, DidChangeWatchedFilesRegistrationOptions,
+    DidChangeWorkspaceFoldersParams, DidCloseTextDocumentParams, DidOpenTextDocumentParams,
+    DidSaveTextDocumentParams, DocumentDiagnosticParams, DocumentDiagnosticReport,
+    DocumentDiagnosticReportKind, DocumentDiagnosticReportResult, DocumentLink,
+    DocumentLinkOptions, DocumentLinkParams, DocumentSymbol, DocumentSymbolParams,
+    DocumentSymbolResponse, Documentation, ExecuteCommandOptions, ExecuteCommandParams,
+    FileChangeType, FileSystemWatcher, FullDocumentDiagnosticReport, GlobPattern,
+    GotoDefinitionParams, GotoDefinitionResponse, Hover, HoverContents, HoverParams,
+    HoverProviderCapability, InitializeParams, InitializeResult, InitializedParams, InlayHint,
+    InlayHintParams, Location, MarkupContent, MarkupKind, MessageType, NumberOrString, OneOf,
+    ParameterInformation, ParameterLabel, Position, PrepareRenameResponse, Range, ReferenceParams,
+    Registration, RelatedFullDocumentDiagnosticReport, RelatedUnchangedDocumentDiagnosticReport,
+    RenameOptions, RenameParams, SaveOptions, SemanticToken, SemanticTokenModifier, SemanticTokens,
+    SemanticTokensFullOptions, SemanticTokensLegend, SemanticTokensOptions, SemanticTokensParams,
+    SemanticTokensResult, SemanticTokensServerCapabilities, ServerCapabilities, ShowDocumentParams,
+    SignatureHelp, SignatureHelpOptions, SignatureHelpParams, SignatureInformation,
+    SymbolInformation, TextDocumentContentChangeEvent, TextDocumentPositionParams,
+    TextDocumentSyncCapability, TextDocumentSyncKind, TextDocumentSyncOptions,
+    TextDocumentSyncSaveOptions, TextEdit, UnchangedDocumentDiagnosticReport, Unregistration,
+    WillSaveTextDocumentParams, WorkDoneProgressCancelParams, WorkDoneProgressOptions,
+    WorkspaceDiagnosticParams, WorkspaceDiagnosticReport, WorkspaceDiagnosticReportResult,
+    WorkspaceDocumentDiagnosticReport, WorkspaceEdit, WorkspaceFolder,
+    WorkspaceFoldersServerCapabilities, WorkspaceFullDocumentDiagnosticReport,
+    WorkspaceServerCapabilities, WorkspaceSymbolParams, WorkspaceUnchangedDocumentDiagnosticReport,
 };
-use tower_lsp::{Client, LanguageServer};
-use walkdir::WalkDir;
+use tower_lsp::{Client, ClientSocket, LanguageServer, LspService};
 
-use crate::plugins::LspPlugin;
+use crate::cache::{file_hash, CachedFile, Fingerprint, IndexCache};
+use crate::config::{CompanionFeature, Config};
+use crate::crash;
+use crate::diagnostics::DiagnosticsCap;
+use crate::fuzzy;
+use crate::git::TrackedFiles;
+use crate::memory::MemoryTracker;
+use crate::metrics::{Metrics, MetricsReport, ParseResult};
+use crate::plugins::{
+    disabled_plugins, enclosing_call, group_by_prefix, offset_at, range_contains,
+    unknown_disabled_plugins, CancelToken, FileData, FileParser, HierarchyCall, HierarchyItem,
+    Highlight, HighlightKind, Impact, LspPlugin, MacroExpansion, MacroSignature, MacroSource,
+    NameLink, ParseCancelled, ParseTimeout, PositionEncoding, QuickFix, ReferenceLens, SymbolInfo,
+    SymbolLocation,
+};
+use crate::profile::{ProfileEntry, ProfileTimer, Profiler};
+use crate::progress::RequestProgress;
+use crate::scaffold::NewInitTargetFile;
+use crate::scheduler::{Job, JobKind, JobQueue, Priority};
+use crate::symbol::Symbol;
+use crate::workers::WorkerPool;
 
-struct State {
-    project_root: PathBuf,
-    documents: HashMap<tower_lsp::lsp_types::Url, String>,
-    plugins: Vec<Box<dyn LspPlugin>>,
-    published_paths: HashSet<PathBuf>,
+/// `workspace/executeCommand` returning every current diagnostic as JSON.
+const EXPORT_DIAGNOSTICS_COMMAND: &str = "elysium.exportDiagnostics";
+const EXPAND_MACRO_COMMAND: &str = "elysium.expandMacro";
+const NEW_INIT_TARGET_FILE_COMMAND: &str = "elysium.newInitTargetFile";
+/// Client command listing locations, provided by VS Code; code lenses run it to show what they
+/// count.
+const SHOW_REFERENCES_COMMAND: &str = "editor.action.showReferences";
+/// Custom requests and notifications with their versions, advertised under
+/// `experimental.elysium` so editor extensions can feature-detect them. A version is bumped when
+/// a method's params or result change incompatibly.
+const CUSTOM_REQUESTS: &[(&str, u32)] = &[
+    ("elysium/status", 1),
+    ("elysium/metrics", 1),
+    ("elysium/hookGraph", 1),
+];
+const CUSTOM_NOTIFICATIONS: &[(&str, u32)] = &[(StateNotification::METHOD, 1)];
+
+/// Most symbols returned for a `workspace/symbol` query, best matches first.
+const MAX_WORKSPACE_SYMBOLS: usize = 256;
+
+fn lsp_location(location: &SymbolLocation) -> Option<Location> {
+    let uri = tower_lsp::lsp_types::Url::from_file_path(&location.path).ok()?;
+    Some(Location::new(uri, location.range))
+}
+
+/// Registration ID of the file watchers for the configured index roots. Workspace folders added
+/// later get their own registration, suffixed with the folder.
+const WATCHED_FILES_ID: &str = "elysium-lsp/watched-files";
+
+/// How long a publish request waits for further requests to fold into the same pass.
+const PUBLISH_COALESCE_DELAY: Duration = Duration::from_millis(30);
+/// How often diagnostics are published while background jobs are still queued, so a long
+/// indexing run shows results as it goes.
+const BACKGROUND_PUBLISH_INTERVAL: Duration = Duration::from_secs(1);
+/// Least time between `elysium/state` notifications that only report indexing progress.
+const STATE_PROGRESS_INTERVAL: Duration = Duration::from_millis(250);
+
+#[derive(Default)]
+struct Documents {
+    texts: HashMap<tower_lsp::lsp_types::Url, String>,
+    generations: HashMap<tower_lsp::lsp_types::Url, u64>,
+}
+
+struct PluginSet {
+    plugins: Vec<RwLock<Box<dyn LspPlugin>>>,
+    parsers: Vec<Arc<dyn FileParser>>,
+    names: Vec<&'static str>,
+    profiler: Option<Arc<Profiler>>,
+    /// Runs parses out of process when set.
+    workers: Option<WorkerPool>,
+    /// The position encoding negotiated with the client, entered around every call into the
+    /// plugins that computes positions.
+    encoding: Mutex<PositionEncoding>,
+}
+
+#[derive(Default)]
+struct PublishState {
+    /// What the client was last sent for each file, so unchanged files aren't sent again.
+    published: HashMap<PathBuf, Vec<Diagnostic>>,
+    dirty_paths: HashSet<PathBuf>,
+    dirty_all: bool,
+}
+
+struct JobOutcome {
+    path: PathBuf,
+    generation: u64,
+    removed: bool,
+    result: Result<Impact>,
+    cached: Option<CachedFile>,
+    /// Whether the job looked at the file's bytes, and what `encoding_issue` and `pragma_issue`
+    /// found in them.
+    encoding_checked: bool,
+    encoding: Option<String>,
+    pragma: Option<String>,
+}
+
+/// A parse some plugins failed, after the data of the others was applied.
+#[derive(Debug)]
+struct PluginsFailed {
+    impact: Impact,
+    failures: Vec<(&'static str, anyhow::Error)>,
+}
+
+impl fmt::Display for PluginsFailed {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, (name, err)) in self.failures.iter().enumerate() {
+            if i > 0 {
+                f.write_str("; ")?;
+            }
+            write!(f, "{name}: {err:#}")?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for PluginsFailed {}
+
+/// Problems with a file as a whole, reported as warnings at its start.
+#[derive(Default)]
+struct FileIssues {
+    parse_failure: Option<String>,
+    encoding: Option<String>,
+    pragma: Option<String>,
+}
+
+impl JobOutcome {
+    fn failed(path: PathBuf, generation: u64, removed: bool, err: anyhow::Error) -> Self {
+        Self {
+            path,
+            generation,
+            removed,
+            result: Err(err),
+            cached: None,
+            encoding_checked: false,
+            encoding: None,
+            pragma: None,
+        }
+    }
+}
+
+impl FileIssues {
+    /// Each issue's diagnostic code and message.
+    fn messages(&self) -> impl Iterator<Item = (&'static str, &String)> {
+        let parse_failure = self.parse_failure.iter();
+        let encoding = self.encoding.iter();
+        let pragma = self.pragma.iter();
+        (parse_failure.map(|message| ("elysium-lsp/parse-failure", message)))
+            .chain(encoding.map(|message| ("elysium-lsp/encoding", message)))
+            .chain(pragma.map(|message| ("elysium-lsp/unknown-plugin", message)))
+    }
+
+    fn is_empty(&self) -> bool {
+        self.parse_failure.is_none() && self.encoding.is_none() && self.pragma.is_none()
+    }
 }
 
+#[derive(Clone)]
 pub struct ElysiumLsp {
     client: Client,
-    state: Arc<Mutex<State>>,
+    config: Arc<Config>,
+    project_root: PathBuf,
+    documents: Arc<tokio::sync::RwLock<Documents>>,
+    /// The configured index roots plus workspace folders the client added since.
+    index_roots: Arc<RwLock<Vec<PathBuf>>>,
+    plugins: Arc<PluginSet>,
+    publish: Arc<Mutex<PublishState>>,
+    memory: Arc<Mutex<MemoryTracker>>,
+    /// Held while evicted files are reloaded, so concurrent requests don't parse them twice.
+    reloading: Arc<tokio::sync::Mutex<()>>,
+    jobs: Arc<JobQueue>,
+    cache: Arc<Mutex<IndexCache>>,
+    file_issues: Arc<Mutex<HashMap<PathBuf, FileIssues>>>,
+    profiler: Option<Arc<Profiler>>,
+    metrics: Arc<Metrics>,
+    publish_requests: Arc<Notify>,
+    /// The job runner, the publisher and tasks added with `spawn`, aborted on shutdown.
+    tasks: Arc<Mutex<Vec<JoinHandle<()>>>>,
+    stopped: Arc<AtomicBool>,
+    /// Set until the startup walk has been indexed; meanwhile diagnostics about names defined in
+    /// other files are held back, since those files may just not be parsed yet.
+    initial_indexing: Arc<AtomicBool>,
+    /// Files of the startup walk not indexed yet. A job superseded by an edit doesn't complete,
+    /// so this is tracked by path rather than by counting jobs.
+    initial_files: Arc<Mutex<HashSet<PathBuf>>>,
+    /// Notified whenever the job queue runs empty.
+    jobs_drained: Arc<Notify>,
+    state_sent: Arc<Mutex<StateSent>>,
+    started: Instant,
+    progress: Arc<RequestProgress>,
+    /// Listed at startup with `--git-tracked-only`.
+    tracked_files: Option<Arc<TrackedFiles>>,
+    /// Whether the client takes `workspace/codeLens/refresh`, sent when counts in other files
+    /// may have changed.
+    code_lens_refresh: Arc<AtomicBool>,
+    /// Likewise for `workspace/inlayHint/refresh`.
+    inlay_hint_refresh: Arc<AtomicBool>,
+    /// Whether the client pulls diagnostics, in which case none are pushed.
+    pull_diagnostics: Arc<AtomicBool>,
+    /// Likewise for `workspace/diagnostic/refresh`, sent to pulling clients instead.
+    diagnostic_refresh: Arc<AtomicBool>,
+}
+
+/// One entry of a diagnostics export.
+#[derive(Serialize)]
+pub struct ExportedDiagnostic {
+    file: PathBuf,
+    range: Range,
+    severity: Option<&'static str>,
+    code: Option<NumberOrString>,
+    source: Option<String>,
+    message: String,
+}
+
+/// Response to the `elysium/status` request.
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct StatusReport {
+    pub indexed_files: usize,
+    pub queued_jobs: usize,
+    pub initial_indexing: bool,
+    /// Timings collected with `--profile`, most total time first.
+    pub profile: Option<Vec<ProfileEntry>>,
+}
+
+/// The `elysium/state` notification, sent whenever the state changes so editors can show it.
+enum StateNotification {}
+
+impl Notification for StateNotification {
+    type Params = ServerState;
+    const METHOD: &'static str = "elysium/state";
+}
+
+#[derive(Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ServerState {
+    /// Whether parse jobs are queued.
+    pub indexing: bool,
+    pub initial_indexing: bool,
+    pub indexed_files: usize,
+    pub queued_jobs: usize,
+    /// Files whose last parse failed.
+    pub parse_failures: usize,
+    pub degraded_plugins: Vec<DegradedPlugin>,
+}
+
+#[derive(Clone, PartialEq, Serialize, Deserialize)]
+pub struct DegradedPlugin {
+    pub name: String,
+    pub reason: String,
+}
+
+#[derive(Default)]
+struct StateSent {
+    last: Option<ServerState>,
+    at: Option<Instant>,
+}
+
+/// Response to the `elysium/hookGraph` request.
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct HookGraph {
+    pub hooks: Vec<HookNode>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct HookNode {
+    pub name: Symbol,
+    pub definition: Location,
+    pub documentation: Option<String>,
+    pub run_sites: Vec<RunSite>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RunSite {
+    pub location: Location,
+    /// The function running the hook, if it is run from one.
+    pub function: Option<Symbol>,
 }
 
 #[tower_lsp::async_trait]
 impl LanguageServer for ElysiumLsp {
-    async fn initialize(&self, _: InitializeParams) -> LspResult<InitializeResult> {
+    async fn initialize(&self, params: InitializeParams) -> LspResult<InitializeResult> {
+        let code_lens = (params.capabilities.workspace.as_ref())
+            .and_then(|workspace| workspace.code_lens.as_ref());
+        (self.code_lens_refresh).store(
+            code_lens.and_then(|c| c.refresh_support) == Some(true),
+            Ordering::SeqCst,
+        );
+        let inlay_hint = (params.capabilities.workspace.as_ref())
+            .and_then(|workspace| workspace.inlay_hint.as_ref());
+        (self.inlay_hint_refresh).store(
+            inlay_hint.and_then(|c| c.refresh_support) == Some(true),
+            Ordering::SeqCst,
+        );
+        let diagnostic = (params.capabilities.workspace.as_ref())
+            .and_then(|workspace| workspace.diagnostic.as_ref());
+        (self.diagnostic_refresh).store(
+            diagnostic.and_then(|c| c.refresh_support) == Some(true),
+            Ordering::SeqCst,
+        );
+        let pull_diagnostics = (params.capabilities.text_document.as_ref())
+            .is_some_and(|text_document| text_document.diagnostic.is_some());
+        (self.pull_diagnostics).store(pull_diagnostics, Ordering::SeqCst);
+        let encoding = PositionEncoding::negotiate(
+            (params.capabilities.general.as_ref())
+                .and_then(|general| general.position_encodings.as_deref()),
+        );
+        self.plugins.set_encoding(encoding);
+        let window = params.capabilities.window;
+        (self.progress).set_server_tokens(window.and_then(|w| w.work_done_progress) == Some(true));
         Ok(InitializeResult {
             capabilities: ServerCapabilities {
-                text_document_sync: Some(TextDocumentSyncCapability::Kind(
-                    TextDocumentSyncKind::FULL,
+                position_encoding: Some(encoding.kind()),
+                text_document_sync: Some(TextDocumentSyncCapability::Options(
+                    TextDocumentSyncOptions {
+                        open_close: Some(true),
+                        change: Some(TextDocumentSyncKind::FULL),
+                        save: Some(TextDocumentSyncSaveOptions::SaveOptions(SaveOptions {
+                            include_text: Some(true),
+                        })),
+                        will_save_wait_until: Some(self.config.format_on_save),
+                        ..TextDocumentSyncOptions::default()
+                    },
+                )),
+                completion_provider: Some(CompletionOptions {
+                    resolve_provider: Some(true),
+                    ..CompletionOptions::default()
+                }),
+                hover_provider: Some(HoverProviderCapability::Simple(true)),
+                definition_provider: Some(OneOf::Left(true)),
+                references_provider: Some(OneOf::Left(true)),
+                rename_provider: Some(OneOf::Right(RenameOptions {
+                    prepare_provider: Some(true),
+                    work_done_progress_options: WorkDoneProgressOptions {
+                        work_done_progress: Some(true),
+                    },
+                })),
+                document_symbol_provider: Some(OneOf::Left(true)),
+                semantic_tokens_provider: Some(
+                    SemanticTokensServerCapabilities::SemanticTokensOptions(
+                        SemanticTokensOptions {
+                            legend: SemanticTokensLegend {
+                                token_types: (HighlightKind::ALL.iter())
+                                    .map(|kind| kind.token_type())
+                                    .collect(),
+                                token_modifiers: vec![SemanticTokenModifier::DECLARATION],
+                            },
+                            full: Some(SemanticTokensFullOptions::Bool(true)),
+                            ..SemanticTokensOptions::default()
+                        },
+                    ),
+                ),
+                signature_help_provider: Some(SignatureHelpOptions {
+                    trigger_characters: Some(vec!["(".into(), ",".into()]),
+                    ..SignatureHelpOptions::default()
+                }),
+                code_action_provider: Some(CodeActionProviderCapability::Options(
+                    CodeActionOptions {
+                        code_action_kinds: Some(vec![CodeActionKind::QUICKFIX]),
+                        ..CodeActionOptions::default()
+                    },
                 )),
-                completion_provider: Some(Default::default()),
+                inlay_hint_provider: Some(OneOf::Left(true)),
+                document_link_provider: Some(DocumentLinkOptions {
+                    resolve_provider: Some(false),
+                    work_done_progress_options: WorkDoneProgressOptions::default(),
+                }),
+                call_hierarchy_provider: Some(CallHierarchyServerCapability::Simple(true)),
+                code_lens_provider: Some(CodeLensOptions {
+                    resolve_provider: Some(false),
+                }),
+                workspace_symbol_provider: Some(OneOf::Left(true)),
+                workspace: Some(WorkspaceServerCapabilities {
+                    workspace_folders: Some(WorkspaceFoldersServerCapabilities {
+                        supported: Some(true),
+                        change_notifications: Some(OneOf::Left(true)),
+                    }),
+                    file_operations: None,
+                }),
+                diagnostic_provider: pull_diagnostics.then(|| {
+                    DiagnosticServerCapabilities::Options(DiagnosticOptions {
+                        identifier: Some("elysium-lsp".into()),
+                        inter_file_dependencies: true,
+                        workspace_diagnostics: true,
+                        ..DiagnosticOptions::default()
+                    })
+                }),
+                execute_command_provider: Some(ExecuteCommandOptions {
+                    commands: [
+                        EXPORT_DIAGNOSTICS_COMMAND,
+                        EXPAND_MACRO_COMMAND,
+                        NEW_INIT_TARGET_FILE_COMMAND,
+                    ]
+                    .into_iter()
+                    .chain(self.plugins.commands())
+                    .map(String::from)
+                    .collect(),
+                    work_done_progress_options: WorkDoneProgressOptions {
+                        work_done_progress: Some(true),
+                    },
+                }),
+                experimental: Some(protocol_extensions()),
                 ..ServerCapabilities::default()
             },
             ..InitializeResult::default()
@@ -46,39 +462,88 @@ impl LanguageServer for ElysiumLsp {
     }
 
     async fn initialized(&self, _: InitializedParams) {
-        let mut state = self.state.lock().await;
-        for entry in WalkDir::new(&state.project_root)
-            .into_iter()
-            .filter_map(|e| e.ok())
-        {
-            if let Err(err) = state.file_updated(entry.path(), None) {
-                fatal_parse_error(&err);
-            }
+        self.send_state().await;
+        if let Some(err) = &self.config.libclang_error {
+            self.client
+                .show_message(
+                    MessageType::ERROR,
+                    format!("elysium-lsp can't index without libclang: {err}"),
+                )
+                .await;
+            return;
+        }
+
+        self.tasks.lock().unwrap().extend([
+            tokio::spawn(self.clone().run_jobs()),
+            tokio::spawn(self.clone().run_publisher()),
+        ]);
+
+        let roots = self.config.index_roots.clone();
+        self.register_file_watchers(WATCHED_FILES_ID.into(), &roots)
+            .await;
+        // A restored index is complete enough to trust cross-file references right away.
+        if self.warm_start().await {
+            self.finish_initial_indexing();
         }
-        drop(state);
 
-        self.publish_all_diagnostics().await;
+        let root = self.project_root.clone();
+        let config = self.config.clone();
+        let (cache, metrics) = (self.cache.clone(), self.metrics.clone());
+        let tracked = self.tracked_files.clone();
+        let jobs = tokio::task::spawn_blocking(move || {
+            if let Some(tracked) = &tracked {
+                tracked.reload();
+            }
+            let files = workspace_files(&root, &config, &config.index_roots, tracked.as_deref());
+            if config.cache_dir.is_none() {
+                return files
+                    .into_iter()
+                    .map(|path| Job::update(path, None, Priority::Background))
+                    .collect();
+            }
+            let file_count = files.len() as u64;
+            let jobs =
+                revalidation_jobs(&mut cache.lock().unwrap(), files, &config.header_extensions);
+            let parses = (jobs.iter())
+                .filter(|job| matches!(job.kind, JobKind::Update(_)))
+                .count() as u64;
+            metrics.record_cache(file_count - parses, parses);
+            jobs
+        })
+        .await
+        .unwrap_or_default();
+        let nothing_to_index = jobs.is_empty();
+        *self.initial_files.lock().unwrap() = jobs.iter().map(|job| job.path.clone()).collect();
+        self.jobs.extend(jobs);
+        self.send_state().await;
+        if nothing_to_index {
+            self.finish_initial_indexing();
+            self.jobs_drained.notify_waiters();
+        }
     }
 
     async fn shutdown(&self) -> LspResult<()> {
+        self.shut_down().await;
         Ok(())
     }
 
     async fn did_open(&self, params: DidOpenTextDocumentParams) {
+        let _timer = self.timer("notification/textDocument/didOpen");
         let uri = params.text_document.uri.clone();
         let text = params.text_document.text;
 
-        let mut state = self.state.lock().await;
-        state.documents.insert(uri.clone(), text.clone());
-        drop(state);
+        self.documents
+            .write()
+            .await
+            .texts
+            .insert(uri.clone(), text.clone());
 
-        if let Err(err) = self.reindex(&uri, Some(text)).await {
-            fatal_parse_error(&err);
-        }
-        self.publish_all_diagnostics().await;
+        self.visibility_changed(&uri);
+        self.enqueue(&uri, Some(text));
     }
 
     async fn did_change(&self, params: DidChangeTextDocumentParams) {
+        let _timer = self.timer("notification/textDocument/didChange");
         let uri = params.text_document.uri.clone();
 
         let mut latest = None;
@@ -87,68 +552,628 @@ impl LanguageServer for ElysiumLsp {
         }
 
         if let Some(TextDocumentContentChangeEvent { text, .. }) = latest {
-            let mut state = self.state.lock().await;
-            state.documents.insert(uri.clone(), text.clone());
-            drop(state);
+            let mut documents = self.documents.write().await;
+            documents.texts.insert(uri.clone(), text);
+            let generation = documents.generations.entry(uri.clone()).or_default();
+            *generation += 1;
+            let generation = *generation;
+            drop(documents);
 
-            if let Err(err) = self.reindex(&uri, Some(text)).await {
-                fatal_parse_error(&err);
+            if self.config.reindex_on_save {
+                return;
             }
-            self.publish_all_diagnostics().await;
+            let server = self.clone();
+            tokio::spawn(async move { server.reindex_debounced(uri, generation).await });
         }
     }
 
-    async fn did_close(&self, params: DidCloseTextDocumentParams) {
+    async fn did_save(&self, params: DidSaveTextDocumentParams) {
+        let _timer = self.timer("notification/textDocument/didSave");
+        if !self.config.reindex_on_save {
+            return;
+        }
         let uri = params.text_document.uri;
 
-        let mut state = self.state.lock().await;
-        state.documents.remove(&uri);
-        drop(state);
+        let mut documents = self.documents.write().await;
+        let text = match params.text {
+            Some(text) => {
+                documents.texts.insert(uri.clone(), text.clone());
+                text
+            }
+            None => match documents.texts.get(&uri) {
+                Some(text) => text.clone(),
+                None => return,
+            },
+        };
+        drop(documents);
+
+        self.enqueue(&uri, Some(text));
+    }
 
-        if let Err(err) = self.reindex(&uri, None).await {
-            fatal_parse_error(&err);
+    async fn will_save_wait_until(
+        &self,
+        params: WillSaveTextDocumentParams,
+    ) -> LspResult<Option<Vec<TextEdit>>> {
+        let _timer = self.timer("request/textDocument/willSaveWaitUntil");
+        if !self.config.format_on_save {
+            return Ok(None);
         }
-        self.publish_all_diagnostics().await;
+        let uri = params.text_document.uri;
+        let Some(path) = self.file_path(&uri) else {
+            return Ok(None);
+        };
+        let Some(text) = self.documents.read().await.texts.get(&uri).cloned() else {
+            return Ok(None);
+        };
+        let edits = self.plugins.format_edits(&path, &text);
+        Ok((!edits.is_empty()).then_some(edits))
+    }
+
+    async fn did_close(&self, params: DidCloseTextDocumentParams) {
+        let _timer = self.timer("notification/textDocument/didClose");
+        let uri = params.text_document.uri;
+
+        let mut documents = self.documents.write().await;
+        documents.texts.remove(&uri);
+        documents.generations.remove(&uri);
+        drop(documents);
+
+        self.visibility_changed(&uri);
+        self.enqueue(&uri, None);
     }
 
     async fn did_change_watched_files(&self, params: DidChangeWatchedFilesParams) {
-        for change in params.changes {
-            if let Ok(path) = change.uri.to_file_path() {
-                let result = {
-                    let mut state = self.state.lock().await;
-                    match change.typ {
-                        FileChangeType::DELETED => {
-                            state.file_removed(&path);
-                            Ok(())
-                        }
-                        _ => state.file_updated(&path, None),
-                    }
+        let _timer = self.timer("notification/workspace/didChangeWatchedFiles");
+        let mut updates = Vec::new();
+        {
+            let documents = self.documents.read().await;
+            for change in params.changes {
+                let Some(path) = self.file_path(&change.uri) else {
+                    continue;
                 };
-                if let Err(err) = result {
-                    fatal_parse_error(&err);
+                // Open documents are indexed from the editor's copy, which may have unsaved edits
+                // the file on disk doesn't and outlives the file being deleted.
+                let text = documents.texts.get(&change.uri).cloned();
+                match change.typ {
+                    FileChangeType::DELETED if text.is_none() => {
+                        self.jobs.push(Job::remove(path, Priority::Background));
+                    }
+                    _ if self.is_covered_path(&path) => updates.push((path, text)),
+                    _ => {}
+                }
+            }
+        }
+        if let Some(tracked) = self.tracked_files.clone() {
+            // Files git started tracking since the listing are looked up in one go, off the
+            // runtime; build output bursts are mostly untracked files that miss the listing.
+            let paths: Vec<PathBuf> = updates.iter().map(|(path, _)| path.clone()).collect();
+            let refreshed = tracked.clone();
+            let _ = tokio::task::spawn_blocking(move || refreshed.refresh(&paths)).await;
+            updates.retain(|(path, _)| tracked.contains(path));
+        }
+        self.jobs.extend(
+            (updates.into_iter()).map(|(path, text)| Job::update(path, text, Priority::Background)),
+        );
+    }
+
+    async fn did_change_workspace_folders(&self, params: DidChangeWorkspaceFoldersParams) {
+        let _timer = self.timer("notification/workspace/didChangeWorkspaceFolders");
+        if self.config.libclang_error.is_some() {
+            return;
+        }
+        let paths = |folders: Vec<WorkspaceFolder>| -> Vec<PathBuf> {
+            (folders.iter())
+                .filter_map(|folder| self.file_path(&folder.uri))
+                .collect()
+        };
+        let removed = paths(params.event.removed);
+        if !removed.is_empty() {
+            self.remove_index_roots(&removed).await;
+        }
+        for folder in paths(params.event.added) {
+            self.add_index_root(folder).await;
+        }
+        self.send_state().await;
+    }
+
+    async fn execute_command(
+        &self,
+        params: ExecuteCommandParams,
+    ) -> LspResult<Option<serde_json::Value>> {
+        let _timer = self.timer("request/workspace/executeCommand");
+        let token = params.work_done_progress_params.work_done_token;
+        let title = params.command.clone();
+        let quick = params.command == NEW_INIT_TARGET_FILE_COMMAND;
+        let work = self.run_command(params.command, params.arguments);
+        if quick {
+            return self
+                .progress
+                .run_quick(&self.client, &title, token, work)
+                .await;
+        }
+        self.progress.run(&self.client, &title, token, work).await
+    }
+
+    async fn diagnostic(
+        &self,
+        params: DocumentDiagnosticParams,
+    ) -> LspResult<DocumentDiagnosticReportResult> {
+        let _timer = self.timer("request/textDocument/diagnostic");
+        let diagnostics = (self.file_path(&params.text_document.uri))
+            .and_then(|path| self.collect_diagnostics(Some(&path)).remove(&path))
+            .unwrap_or_default();
+        let report = match diagnostic_report(diagnostics, params.previous_result_id.as_deref()) {
+            DocumentDiagnosticReportKind::Full(report) => {
+                DocumentDiagnosticReport::Full(RelatedFullDocumentDiagnosticReport {
+                    related_documents: None,
+                    full_document_diagnostic_report: report,
+                })
+            }
+            DocumentDiagnosticReportKind::Unchanged(report) => {
+                DocumentDiagnosticReport::Unchanged(RelatedUnchangedDocumentDiagnosticReport {
+                    related_documents: None,
+                    unchanged_document_diagnostic_report: report,
+                })
+            }
+        };
+        Ok(DocumentDiagnosticReportResult::Report(report))
+    }
+
+    async fn workspace_diagnostic(
+        &self,
+        params: WorkspaceDiagnosticParams,
+    ) -> LspResult<WorkspaceDiagnosticReportResult> {
+        let _timer = self.timer("request/workspace/diagnostic");
+        let mut previous: HashMap<PathBuf, (tower_lsp::lsp_types::Url, String)> = params
+            .previous_result_ids
+            .into_iter()
+            .filter_map(|id| Some((self.file_path(&id.uri)?, (id.uri, id.value))))
+            .collect();
+        let mut diagnostics = self.collect_diagnostics(None);
+        if self.config.open_files_only {
+            let open = self.open_paths().await;
+            diagnostics.retain(|path, _| open.contains(path));
+        }
+
+        let mut items = Vec::new();
+        {
+            // Evicted files can't recompute their diagnostics, so the client keeps what it has.
+            let memory = self.memory.lock().unwrap();
+            diagnostics.retain(|path, _| !memory.is_evicted(path));
+            previous.retain(|path, (uri, result_id)| {
+                let keep = self.config.open_files_only || !memory.is_evicted(path);
+                if !keep {
+                    items.push(WorkspaceDocumentDiagnosticReport::Unchanged(
+                        WorkspaceUnchangedDocumentDiagnosticReport {
+                            uri: uri.clone(),
+                            version: None,
+                            unchanged_document_diagnostic_report:
+                                UnchangedDocumentDiagnosticReport {
+                                    result_id: result_id.clone(),
+                                },
+                        },
+                    ));
                 }
+                keep
+            });
+        }
+        // Files reported before but without diagnostics now get an empty report to clear them.
+        for path in previous.keys() {
+            diagnostics.entry(path.clone()).or_default();
+        }
+
+        for (path, diagnostics) in diagnostics {
+            let previous = previous.remove(&path);
+            if diagnostics.is_empty() && previous.is_none() {
+                continue;
             }
+            let uri = match previous.as_ref() {
+                Some((uri, _)) => uri.clone(),
+                None => match tower_lsp::lsp_types::Url::from_file_path(&path) {
+                    Ok(uri) => uri,
+                    Err(()) => continue,
+                },
+            };
+            let previous_result_id = previous.as_ref().map(|(_, result_id)| result_id.as_str());
+            items.push(match diagnostic_report(diagnostics, previous_result_id) {
+                DocumentDiagnosticReportKind::Full(report) => {
+                    WorkspaceDocumentDiagnosticReport::Full(WorkspaceFullDocumentDiagnosticReport {
+                        uri,
+                        version: None,
+                        full_document_diagnostic_report: report,
+                    })
+                }
+                DocumentDiagnosticReportKind::Unchanged(report) => {
+                    WorkspaceDocumentDiagnosticReport::Unchanged(
+                        WorkspaceUnchangedDocumentDiagnosticReport {
+                            uri,
+                            version: None,
+                            unchanged_document_diagnostic_report: report,
+                        },
+                    )
+                }
+            });
+        }
+        Ok(WorkspaceDiagnosticReportResult::Report(
+            WorkspaceDiagnosticReport { items },
+        ))
+    }
+
+    async fn document_symbol(
+        &self,
+        params: DocumentSymbolParams,
+    ) -> LspResult<Option<DocumentSymbolResponse>> {
+        let _timer = self.timer("request/textDocument/documentSymbol");
+        let Some(path) = self.file_path(&params.text_document.uri) else {
+            return Ok(None);
+        };
+        let symbols = self.plugins.document_symbols(&path);
+        Ok(Some(DocumentSymbolResponse::Nested(group_by_prefix(
+            symbols,
+        ))))
+    }
+
+    #[allow(deprecated)] // `SymbolInformation::deprecated` has to be set, if only to `None`.
+    async fn symbol(
+        &self,
+        params: WorkspaceSymbolParams,
+    ) -> LspResult<Option<Vec<SymbolInformation>>> {
+        let _timer = self.timer("request/workspace/symbol");
+        let mut matches: Vec<(i32, &'static str, SymbolInfo)> = (self.plugins.symbols())
+            .into_iter()
+            .filter_map(|(plugin, symbol)| {
+                Some((fuzzy::score(&params.query, &symbol.name)?, plugin, symbol))
+            })
+            .collect();
+        matches.sort_by(|(a_score, _, a), (b_score, _, b)| {
+            (b_score.cmp(a_score))
+                .then(a.name.len().cmp(&b.name.len()))
+                .then(a.name.cmp(&b.name))
+        });
+        let symbols = (matches.into_iter())
+            .filter_map(|(_, plugin, symbol)| {
+                Some(SymbolInformation {
+                    name: symbol.name.to_string(),
+                    kind: symbol.kind,
+                    tags: None,
+                    deprecated: None,
+                    location: lsp_location(&symbol.location)?,
+                    container_name: Some(plugin.into()),
+                })
+            })
+            .take(MAX_WORKSPACE_SYMBOLS)
+            .collect();
+        Ok(Some(symbols))
+    }
+
+    async fn hover(&self, params: HoverParams) -> LspResult<Option<Hover>> {
+        let _timer = self.timer("request/textDocument/hover");
+        let params = params.text_document_position_params;
+        let Some(path) = self.file_path(&params.text_document.uri) else {
+            return Ok(None);
+        };
+        if let Some(hover) = self.whole_index().await.hover(&path, &params.position) {
+            return Ok(Some(hover));
+        }
+        if self.config.companion(CompanionFeature::Capabilities) {
+            return Ok(None);
         }
+        // Only the macro names themselves are left, which need a reparse.
+        let content = (self.documents.read().await)
+            .texts
+            .get(&params.text_document.uri)
+            .cloned();
+        let plugins = self.plugins.clone();
+        let source = tokio::task::spawn_blocking(move || {
+            crash::recoverable(|| plugins.macro_source(&path, content.as_deref(), &params.position))
+        })
+        .await
+        .ok()
+        .flatten();
+        Ok(source.map(|source| self.macro_hover(&source)))
+    }
+
+    async fn goto_definition(
+        &self,
+        params: GotoDefinitionParams,
+    ) -> LspResult<Option<GotoDefinitionResponse>> {
+        let _timer = self.timer("request/textDocument/definition");
+        let params = params.text_document_position_params;
+        let Some(path) = self.file_path(&params.text_document.uri) else {
+            return Ok(None);
+        };
+        let locations: Vec<Location> = (self.plugins.definition(&path, &params.position))
+            .iter()
+            .filter_map(lsp_location)
+            .collect();
+        Ok((!locations.is_empty()).then_some(GotoDefinitionResponse::Array(locations)))
+    }
+
+    async fn references(&self, params: ReferenceParams) -> LspResult<Option<Vec<Location>>> {
+        let _timer = self.timer("request/textDocument/references");
+        let position = params.text_document_position;
+        let Some(path) = self.file_path(&position.text_document.uri) else {
+            return Ok(None);
+        };
+        let include_declaration = params.context.include_declaration;
+        let locations: Vec<Location> = (self.whole_index().await)
+            .references(&path, &position.position, include_declaration)
+            .iter()
+            .filter_map(lsp_location)
+            .collect();
+        Ok((!locations.is_empty()).then_some(locations))
+    }
+
+    async fn semantic_tokens_full(
+        &self,
+        params: SemanticTokensParams,
+    ) -> LspResult<Option<SemanticTokensResult>> {
+        let _timer = self.timer("request/textDocument/semanticTokens/full");
+        let Some(path) = self.file_path(&params.text_document.uri) else {
+            return Ok(None);
+        };
+        let data = semantic_tokens(self.plugins.highlights(&path));
+        Ok(Some(SemanticTokensResult::Tokens(SemanticTokens {
+            result_id: None,
+            data,
+        })))
+    }
+
+    async fn signature_help(
+        &self,
+        params: SignatureHelpParams,
+    ) -> LspResult<Option<SignatureHelp>> {
+        let _timer = self.timer("request/textDocument/signatureHelp");
+        let params = params.text_document_position_params;
+        // Arguments being typed aren't parsed yet, so this works on the document's text.
+        let documents = self.documents.read().await;
+        let Some(text) = documents.texts.get(&params.text_document.uri) else {
+            return Ok(None);
+        };
+        let _encoding = self.plugins.encoding().enter();
+        let Some(offset) = offset_at(text, params.position) else {
+            return Ok(None);
+        };
+        let signatures = self.plugins.signatures();
+        let names: Vec<&str> = signatures.iter().map(|signature| signature.name).collect();
+        let Some(call) = enclosing_call(text, offset, &names) else {
+            return Ok(None);
+        };
+        let Some(signature) = signatures.iter().find(|s| s.name == call.name) else {
+            return Ok(None);
+        };
+        let parameter = match call.field {
+            // A designator being typed picks the first parameter it could become.
+            Some(field) => signature
+                .parameters
+                .iter()
+                .position(|p| p.starts_with(field)),
+            None => Some(
+                call.argument
+                    .min(signature.parameters.len().saturating_sub(1)),
+            ),
+        };
+        Ok(Some(SignatureHelp {
+            signatures: vec![signature_information(signature)],
+            active_signature: Some(0),
+            active_parameter: parameter.map(|parameter| parameter as u32),
+        }))
+    }
+
+    /// Offers the fixes plugins attached to the diagnostics the client sends along.
+    async fn code_action(&self, params: CodeActionParams) -> LspResult<Option<CodeActionResponse>> {
+        let _timer = self.timer("request/textDocument/codeAction");
+        let uri = params.text_document.uri;
+        let actions: CodeActionResponse = (params.context.diagnostics.iter())
+            .flat_map(|diagnostic| {
+                let uri = uri.clone();
+                QuickFix::from_diagnostic(diagnostic)
+                    .into_iter()
+                    .map(move |fix| {
+                        CodeActionOrCommand::CodeAction(CodeAction {
+                            title: fix.title,
+                            kind: Some(CodeActionKind::QUICKFIX),
+                            diagnostics: Some(vec![diagnostic.clone()]),
+                            edit: Some(WorkspaceEdit::new(HashMap::from([(
+                                uri.clone(),
+                                fix.edits,
+                            )]))),
+                            is_preferred: Some(true),
+                            ..CodeAction::default()
+                        })
+                    })
+            })
+            .collect();
+        Ok((!actions.is_empty()).then_some(actions))
+    }
+
+    async fn prepare_call_hierarchy(
+        &self,
+        params: CallHierarchyPrepareParams,
+    ) -> LspResult<Option<Vec<CallHierarchyItem>>> {
+        let _timer = self.timer("request/textDocument/prepareCallHierarchy");
+        let params = params.text_document_position_params;
+        let Some(path) = self.file_path(&params.text_document.uri) else {
+            return Ok(None);
+        };
+        let items: Vec<CallHierarchyItem> = (self.whole_index().await)
+            .prepare_call_hierarchy(&path, &params.position)
+            .into_iter()
+            .filter_map(|(plugin, item)| call_hierarchy_item(plugin, item))
+            .collect();
+        Ok((!items.is_empty()).then_some(items))
+    }
+
+    async fn incoming_calls(
+        &self,
+        params: CallHierarchyIncomingCallsParams,
+    ) -> LspResult<Option<Vec<CallHierarchyIncomingCall>>> {
+        let _timer = self.timer("request/callHierarchy/incomingCalls");
+        let Some((plugin, data)) = hierarchy_key(&params.item) else {
+            return Ok(None);
+        };
+        let calls = (self.whole_index().await)
+            .incoming_calls(&plugin, &data)
+            .into_iter()
+            .filter_map(|call| {
+                Some(CallHierarchyIncomingCall {
+                    from: call_hierarchy_item(&plugin, call.item)?,
+                    from_ranges: call.ranges,
+                })
+            })
+            .collect();
+        Ok(Some(calls))
+    }
+
+    async fn outgoing_calls(
+        &self,
+        params: CallHierarchyOutgoingCallsParams,
+    ) -> LspResult<Option<Vec<CallHierarchyOutgoingCall>>> {
+        let _timer = self.timer("request/callHierarchy/outgoingCalls");
+        let Some((plugin, data)) = hierarchy_key(&params.item) else {
+            return Ok(None);
+        };
+        let calls = (self.whole_index().await)
+            .outgoing_calls(&plugin, &data)
+            .into_iter()
+            .filter_map(|call| {
+                Some(CallHierarchyOutgoingCall {
+                    to: call_hierarchy_item(&plugin, call.item)?,
+                    from_ranges: call.ranges,
+                })
+            })
+            .collect();
+        Ok(Some(calls))
+    }
+
+    async fn document_link(
+        &self,
+        params: DocumentLinkParams,
+    ) -> LspResult<Option<Vec<DocumentLink>>> {
+        let _timer = self.timer("request/textDocument/documentLink");
+        let Some(path) = self.file_path(&params.text_document.uri) else {
+            return Ok(None);
+        };
+        let links = (self.plugins.name_links(&path).into_iter())
+            .filter_map(|link| {
+                let location = &link.target;
+                let mut target = tower_lsp::lsp_types::Url::from_file_path(&location.path).ok()?;
+                // Editors that understand the fragment open the file at the definition.
+                target.set_fragment(Some(&format!("L{}", location.range.start.line + 1)));
+                let file = (location.path)
+                    .strip_prefix(&self.project_root)
+                    .unwrap_or(&location.path);
+                Some(DocumentLink {
+                    range: link.range,
+                    target: Some(target),
+                    tooltip: Some(format!("Defined in {}", file.display())),
+                    data: None,
+                })
+            })
+            .collect();
+        Ok(Some(links))
+    }
+
+    async fn inlay_hint(&self, params: InlayHintParams) -> LspResult<Option<Vec<InlayHint>>> {
+        let _timer = self.timer("request/textDocument/inlayHint");
+        let Some(path) = self.file_path(&params.text_document.uri) else {
+            return Ok(None);
+        };
+        let mut hints = self.whole_index().await.inlay_hints(&path);
+        hints.retain(|hint| range_contains(&params.range, &hint.position));
+        Ok(Some(hints))
+    }
+
+    async fn code_lens(&self, params: CodeLensParams) -> LspResult<Option<Vec<CodeLens>>> {
+        let _timer = self.timer("request/textDocument/codeLens");
+        let uri = params.text_document.uri;
+        let Some(path) = self.file_path(&uri) else {
+            return Ok(None);
+        };
+        let lenses = (self.whole_index().await)
+            .reference_lenses(&path)
+            .into_iter()
+            .map(|lens| {
+                let locations: Vec<Location> =
+                    lens.locations.iter().filter_map(lsp_location).collect();
+                let arguments = [
+                    serde_json::to_value(&uri),
+                    serde_json::to_value(lens.range.start),
+                    serde_json::to_value(locations),
+                ];
+                CodeLens {
+                    range: lens.range,
+                    command: Some(Command {
+                        title: lens.title,
+                        command: SHOW_REFERENCES_COMMAND.into(),
+                        arguments: arguments.into_iter().collect::<Result<_, _>>().ok(),
+                    }),
+                    data: None,
+                }
+            })
+            .collect();
+        Ok(Some(lenses))
+    }
+
+    async fn prepare_rename(
+        &self,
+        params: TextDocumentPositionParams,
+    ) -> LspResult<Option<PrepareRenameResponse>> {
+        let Some(path) = self.file_path(&params.text_document.uri) else {
+            return Ok(None);
+        };
+        let range = self.plugins.prepare_rename(&path, &params.position);
+        Ok(range.map(PrepareRenameResponse::Range))
+    }
+
+    async fn rename(&self, params: RenameParams) -> LspResult<Option<WorkspaceEdit>> {
+        let _timer = self.timer("request/textDocument/rename");
+        let position = params.text_document_position;
+        let Some(path) = self.file_path(&position.text_document.uri) else {
+            return Ok(None);
+        };
+        let token = params.work_done_progress_params.work_done_token;
+        let work = async {
+            let edits = (self.whole_index().await)
+                .rename(&path, &position.position, &params.new_name)
+                .map_err(|err| tower_lsp::jsonrpc::Error::invalid_params(format!("{err:#}")))?;
+            let Some(edits) = edits else {
+                return Ok(None);
+            };
+            let changes = (edits.into_iter())
+                .filter_map(|(path, edits)| {
+                    let uri = tower_lsp::lsp_types::Url::from_file_path(path).ok()?;
+                    Some((uri, edits))
+                })
+                .collect();
+            Ok(Some(WorkspaceEdit::new(changes)))
+        };
+        self.progress
+            .run(&self.client, "Renaming", token, work)
+            .await
+    }
 
-        self.publish_all_diagnostics().await;
+    async fn completion_resolve(&self, item: CompletionItem) -> LspResult<CompletionItem> {
+        let _timer = self.timer("request/completionItem/resolve");
+        Ok(self.whole_index().await.resolve_completion(item))
     }
 
     async fn completion(
         &self,
         params: tower_lsp::lsp_types::CompletionParams,
     ) -> LspResult<Option<CompletionResponse>> {
-        let path = match params
-            .text_document_position
-            .text_document
-            .uri
-            .to_file_path()
-        {
-            Ok(path) => path.canonicalize().unwrap_or_else(|_| path.to_path_buf()),
-            Err(_) => return Ok(None),
+        let _timer = self.timer("request/textDocument/completion");
+        let Some(path) = self.file_path(&params.text_document_position.text_document.uri) else {
+            return Ok(None);
         };
 
-        let state = self.state.lock().await;
-        if let Some(items) = state.completions(&path, &params.text_document_position.position) {
+        let uri = &params.text_document_position.text_document.uri;
+        let text = self.documents.read().await.texts.get(uri).cloned();
+        if let Some(items) = self.whole_index().await.completions(
+            &path,
+            &params.text_document_position.position,
+            text.as_deref(),
+        ) {
             return Ok(Some(CompletionResponse::Array(items)));
         }
 
@@ -157,101 +1182,1813 @@ impl LanguageServer for ElysiumLsp {
 }
 
 impl ElysiumLsp {
-    pub fn new(client: Client, project_root: PathBuf, plugins: Vec<Box<dyn LspPlugin>>) -> Self {
+    pub fn new(
+        client: Client,
+        mut config: Config,
+        project_root: PathBuf,
+        plugins: Vec<Box<dyn LspPlugin>>,
+        workers: Option<WorkerPool>,
+    ) -> Self {
+        if config.index_roots.is_empty() {
+            config.index_roots.push(project_root.clone());
+        }
+        let profiler = config.profile.then(Arc::default);
+        let tracked_files =
+            (config.git_tracked_only).then(|| Arc::new(TrackedFiles::new(&project_root)));
         Self {
+            index_roots: Arc::new(RwLock::new(config.index_roots.clone())),
             client,
-            state: Arc::new(Mutex::new(State::new(project_root, plugins))),
+            config: Arc::new(config),
+            project_root,
+            documents: Arc::default(),
+            plugins: Arc::new(PluginSet::new(plugins, workers, profiler.clone())),
+            publish: Arc::default(),
+            memory: Arc::default(),
+            reloading: Arc::default(),
+            jobs: Arc::new(JobQueue::default()),
+            cache: Arc::default(),
+            file_issues: Arc::default(),
+            profiler,
+            metrics: Arc::default(),
+            publish_requests: Arc::default(),
+            tasks: Arc::default(),
+            stopped: Arc::default(),
+            initial_indexing: Arc::new(AtomicBool::new(true)),
+            initial_files: Arc::default(),
+            state_sent: Arc::default(),
+            jobs_drained: Arc::default(),
+            started: Instant::now(),
+            progress: Arc::default(),
+            tracked_files,
+            code_lens_refresh: Arc::default(),
+            inlay_hint_refresh: Arc::default(),
+            pull_diagnostics: Arc::default(),
+            diagnostic_refresh: Arc::default(),
         }
     }
 
-    async fn reindex(
-        &self,
-        uri: &tower_lsp::lsp_types::Url,
-        content: Option<String>,
-    ) -> Result<()> {
-        let path = uri
-            .to_file_path()
-            .map_err(|_| anyhow!("URI is not a local file"))?;
-
-        self.state
-            .lock()
-            .await
-            .file_updated(&path, content.as_deref())
+    /// Wraps the server built by `init` in an LSP service answering the custom requests too.
+    pub fn service(init: impl FnOnce(Client) -> Self) -> (LspService<Self>, ClientSocket) {
+        LspService::build(init)
+            .custom_method("elysium/status", ElysiumLsp::status)
+            .custom_method("elysium/metrics", ElysiumLsp::metrics)
+            .custom_method("elysium/hookGraph", ElysiumLsp::hook_graph)
+            .custom_method(
+                "window/workDoneProgress/cancel",
+                ElysiumLsp::work_done_progress_cancel,
+            )
+            .finish()
     }
 
-    async fn publish_all_diagnostics(&self) {
-        let (diagnostics, published_paths) = {
-            let state = self.state.lock().await;
-            (state.diagnostics(), state.published_paths.clone())
-        };
-        let current_paths = diagnostics.keys().cloned().collect();
+    async fn work_done_progress_cancel(&self, params: WorkDoneProgressCancelParams) {
+        self.progress.cancel(&params.token);
+    }
 
-        for (path, diagnostics) in diagnostics {
-            if let Ok(uri) = tower_lsp::lsp_types::Url::from_file_path(&path) {
-                self.client
-                    .publish_diagnostics(uri, diagnostics, None)
-                    .await;
-            }
-        }
+    pub async fn status(&self) -> LspResult<StatusReport> {
+        let _timer = self.timer("request/elysium/status");
+        Ok(StatusReport {
+            indexed_files: self.memory.lock().unwrap().tracked_files(),
+            queued_jobs: self.jobs.len(),
+            initial_indexing: self.initial_indexing.load(Ordering::SeqCst),
+            profile: self.profiler.as_ref().map(|profiler| profiler.summary()),
+        })
+    }
 
-        let stale: Vec<PathBuf> = published_paths
-            .difference(&current_paths)
-            .cloned()
-            .collect();
-        for path in stale {
-            if let Ok(uri) = tower_lsp::lsp_types::Url::from_file_path(&path) {
-                self.client.publish_diagnostics(uri, vec![], None).await;
-            }
-        }
+    pub async fn metrics(&self) -> LspResult<MetricsReport> {
+        let _timer = self.timer("request/elysium/metrics");
+        Ok(self.metrics_report())
+    }
 
-        self.state.lock().await.published_paths = current_paths;
+    /// Runs `task` in the background until the server shuts down.
+    pub fn spawn(&self, task: impl Future<Output = ()> + Send + 'static) {
+        self.tasks.lock().unwrap().push(tokio::spawn(task));
     }
-}
 
-impl State {
-    fn new(project_root: PathBuf, plugins: Vec<Box<dyn LspPlugin>>) -> Self {
-        Self {
-            project_root,
-            documents: HashMap::new(),
-            plugins,
-            published_paths: HashSet::new(),
-        }
+    pub fn metrics_report(&self) -> MetricsReport {
+        let indexed_files = self.memory.lock().unwrap().tracked_files();
+        self.metrics.report(self.jobs.len(), indexed_files)
     }
 
-    fn file_updated(&mut self, path: &Path, content: Option<&str>) -> Result<()> {
-        for plugin in &mut self.plugins {
-            plugin.on_file_updated(path, content)?;
-        }
-        Ok(())
+    /// Expands the registration macro at `params`, reparsing the document for it.
+    async fn expand_macro(&self, params: TextDocumentPositionParams) -> Option<MacroExpansion> {
+        let path = self.file_path(&params.text_document.uri)?;
+        let content = (self.documents.read().await)
+            .texts
+            .get(&params.text_document.uri)
+            .cloned();
+        let plugins = self.plugins.clone();
+        tokio::task::spawn_blocking(move || {
+            crash::recoverable(|| plugins.expand_macro(&path, content.as_deref(), &params.position))
+        })
+        .await
+        .ok()
+        .flatten()
     }
 
-    fn file_removed(&mut self, path: &Path) {
-        for plugin in &mut self.plugins {
-            plugin.on_file_removed(path);
+    /// Markdown shown when hovering a registration macro's name.
+    fn macro_hover(&self, source: &MacroSource) -> Hover {
+        let file = source
+            .file
+            .strip_prefix(&self.project_root)
+            .unwrap_or(&source.file);
+        let mut value = format!("```c\n{}\n```\n", source.definition);
+        if let Some(documentation) = &source.documentation {
+            value.push_str(&format!("\n{documentation}\n"));
+        }
+        value.push_str(&format!("\nDefined in `{}`", file.display()));
+        Hover {
+            contents: HoverContents::Markup(MarkupContent {
+                kind: MarkupKind::Markdown,
+                value,
+            }),
+            range: Some(source.range),
         }
     }
 
-    fn diagnostics(&self) -> HashMap<PathBuf, Vec<Diagnostic>> {
-        let mut all: HashMap<PathBuf, Vec<Diagnostic>> = HashMap::new();
-        for plugin in &self.plugins {
-            for (path, diagnostics) in plugin.diagnostics() {
-                all.entry(path).or_default().extend(diagnostics.into_iter());
-            }
+    /// Creates a file from the init target template, indexes it right away rather than when the
+    /// file watcher reports it, and asks the editor to open it.
+    async fn new_init_target_file(&self, request: NewInitTargetFile) -> Result<serde_json::Value> {
+        // Git can't track the file yet, so only the roots and exclusions decide.
+        let (path, text) =
+            request.create(&self.project_root, &self.config.init_template, |path| {
+                self.is_covered_path(path)
+            })?;
+        let uri = tower_lsp::lsp_types::Url::from_file_path(&path)
+            .map_err(|()| anyhow!("{} has no file URI", path.display()))?;
+        self.enqueue(&uri, Some(text));
+        let shown = self
+            .client
+            .show_document(ShowDocumentParams {
+                uri: uri.clone(),
+                external: None,
+                take_focus: Some(true),
+                selection: None,
+            })
+            .await;
+        if let Err(err) = shown {
+            tracing::warn!("couldn't open {uri}: {err}");
         }
-        all
+        Ok(serde_json::json!({ "uri": uri }))
     }
 
-    fn completions(&self, path: &Path, position: &Position) -> Option<Vec<CompletionItem>> {
-        for plugin in &self.plugins {
-            if let Some(items) = plugin.completions(path, position) {
-                return Some(items);
+    async fn run_command(
+        &self,
+        command: String,
+        arguments: Vec<serde_json::Value>,
+    ) -> LspResult<Option<serde_json::Value>> {
+        match command.as_str() {
+            EXPORT_DIAGNOSTICS_COMMAND => Ok(serde_json::to_value(self.export_diagnostics()).ok()),
+            EXPAND_MACRO_COMMAND => {
+                let position = (arguments.into_iter().next())
+                    .and_then(|argument| serde_json::from_value(argument).ok())
+                    .ok_or_else(|| {
+                        tower_lsp::jsonrpc::Error::invalid_params(
+                            "expected a text document position argument",
+                        )
+                    })?;
+                Ok(serde_json::to_value(self.expand_macro(position).await).ok())
+            }
+            NEW_INIT_TARGET_FILE_COMMAND => {
+                let argument = arguments.into_iter().next().unwrap_or_default();
+                let request: NewInitTargetFile =
+                    serde_json::from_value(argument).map_err(|err| {
+                        tower_lsp::jsonrpc::Error::invalid_params(format!(
+                            "expected a new file description: {err}"
+                        ))
+                    })?;
+                self.new_init_target_file(request)
+                    .await
+                    .map(Some)
+                    .map_err(|err| tower_lsp::jsonrpc::Error::invalid_params(format!("{err:#}")))
+            }
+            _ => {
+                // The commands analyse the whole graph, which a file missing from it skews: a
+                // target only reachable through it would be reported dead.
+                self.reload_evicted().await;
+                if let Some(path) = self.memory.lock().unwrap().evicted_paths().next() {
+                    return Err(tower_lsp::jsonrpc::Error {
+                        code: tower_lsp::jsonrpc::ErrorCode::InternalError,
+                        message: format!(
+                            "{} couldn't be parsed again after being evicted under \
+                             --memory-budget-mb",
+                            path.display()
+                        )
+                        .into(),
+                        data: None,
+                    });
+                }
+                // Off the async runtime, so a cancelled command doesn't hold it up.
+                let plugins = self.plugins.clone();
+                tokio::task::spawn_blocking(move || {
+                    crash::recoverable(|| plugins.execute_command(&command, &arguments))
+                })
+                .await
+                .map_err(|_| tower_lsp::jsonrpc::Error::internal_error())?
+                .map(Some)
+                .map_err(|err| tower_lsp::jsonrpc::Error::invalid_params(format!("{err:#}")))
             }
         }
-        None
     }
-}
 
-fn fatal_parse_error(err: &anyhow::Error) -> ! {
-    eprintln!("elysium-lsp fatal error: {err:?}");
-    process::exit(1);
+    /// Every hook with its definition and run sites, ordered by name.
+    pub async fn hook_graph(&self) -> LspResult<HookGraph> {
+        let _timer = self.timer("request/elysium/hookGraph");
+        let work = async {
+            self.reload_evicted().await;
+            let plugins = self.plugins.clone();
+            // Stops the build once the request is cancelled.
+            let cancel = CancelToken::default();
+            let _cancel_on_drop = cancel.cancel_on_drop();
+            tokio::task::spawn_blocking(move || {
+                crash::recoverable(|| build_hook_graph(&plugins, &cancel))
+            })
+            .await
+            .map_err(|_| tower_lsp::jsonrpc::Error::internal_error())?
+            .ok_or_else(tower_lsp::jsonrpc::Error::request_cancelled)
+        };
+        (self.progress)
+            .run(&self.client, "Building the hook graph", None, work)
+            .await
+    }
+
+    /// The current diagnostics of every file, ordered by file and position.
+    pub fn export_diagnostics(&self) -> Vec<ExportedDiagnostic> {
+        let mut exported: Vec<ExportedDiagnostic> = self
+            .collect_diagnostics(None)
+            .into_iter()
+            .flat_map(|(file, diagnostics)| {
+                diagnostics
+                    .into_iter()
+                    .map(move |diagnostic| ExportedDiagnostic {
+                        file: file.clone(),
+                        range: diagnostic.range,
+                        severity: diagnostic.severity.map(severity_name),
+                        code: diagnostic.code,
+                        source: diagnostic.source,
+                        message: diagnostic.message,
+                    })
+            })
+            .collect();
+        exported.sort_by(|a, b| {
+            let position = |d: &ExportedDiagnostic| (d.range.start.line, d.range.start.character);
+            (&a.file, position(a)).cmp(&(&b.file, position(b)))
+        });
+        exported
+    }
+
+    /// Every symbol the plugins indexed, with the name of the plugin it came from.
+    pub async fn symbols(&self) -> Vec<(&'static str, SymbolInfo)> {
+        self.whole_index().await.symbols()
+    }
+
+    /// Indexes the workspace without a client connected, returning once every file was parsed.
+    pub async fn index_once(&self) -> Result<()> {
+        if let Some(err) = &self.config.libclang_error {
+            return Err(anyhow!("{err}"));
+        }
+        let drained = self.jobs_drained.notified();
+        self.initialized(InitializedParams {}).await;
+        drained.await;
+        Ok(())
+    }
+
+    /// Stops indexing, kills parse workers and flushes the cache. Runs on the `shutdown` request
+    /// and again once the connection closes, for clients that send `exit` without it; only the
+    /// first call does anything.
+    pub async fn shut_down(&self) {
+        if self.stopped.swap(true, Ordering::SeqCst) {
+            return;
+        }
+        let report = self.metrics_report();
+        let requests: BTreeMap<String, u64> = (report.requests.into_iter())
+            .map(|entry| (entry.name, entry.count))
+            .collect();
+        self.send_telemetry(serde_json::json!({
+            "event": "session",
+            "durationMs": self.started.elapsed().as_millis() as u64,
+            "files": report.indexed_files,
+            "parses": report.parses,
+            "parseFailures": report.parse_failures,
+            "parseTimeouts": report.parse_timeouts,
+            "requests": requests,
+        }))
+        .await;
+        for task in self.tasks.lock().unwrap().drain(..) {
+            task.abort();
+        }
+        self.jobs.clear();
+        self.plugins.shut_down();
+        self.save_cache().await;
+        if let Some(profiler) = &self.profiler {
+            profiler.log_summary();
+        }
+    }
+
+    /// Times a request or notification for the metrics and, with `--profile`, the profile.
+    fn timer(&self, name: &str) -> (ProfileTimer, Option<ProfileTimer>) {
+        let profiled = self.profiler.as_ref().map(|profiler| profiler.start(name));
+        (self.metrics.requests.start(name), profiled)
+    }
+
+    async fn reindex_debounced(&self, uri: tower_lsp::lsp_types::Url, generation: u64) {
+        tokio::time::sleep(self.config.debounce).await;
+
+        let text = {
+            let documents = self.documents.read().await;
+            if documents.generations.get(&uri) != Some(&generation) {
+                return;
+            }
+            match documents.texts.get(&uri) {
+                Some(text) => text.clone(),
+                None => return,
+            }
+        };
+
+        self.enqueue(&uri, Some(text));
+    }
+
+    /// Indexes the files below a workspace folder the client added and watches it for changes,
+    /// unless an index root already covers it.
+    async fn add_index_root(&self, folder: PathBuf) {
+        {
+            let mut roots = self.index_roots.write().unwrap();
+            if roots.iter().any(|root| folder.starts_with(root)) {
+                return;
+            }
+            roots.push(folder.clone());
+        }
+        let (root, config, walked) = (
+            self.project_root.clone(),
+            self.config.clone(),
+            folder.clone(),
+        );
+        let tracked = self.tracked_files.clone();
+        let files = tokio::task::spawn_blocking(move || {
+            if let Some(tracked) = &tracked {
+                tracked.reload();
+            }
+            workspace_files(&root, &config, &[walked], tracked.as_deref())
+        })
+        .await
+        .unwrap_or_default();
+        self.jobs
+            .extend((files.into_iter()).map(|path| Job::update(path, None, Priority::Background)));
+        self.register_file_watchers(watcher_id(&folder), std::slice::from_ref(&folder))
+            .await;
+    }
+
+    /// Stops indexing below workspace folders the client removed. Indexed files no remaining root
+    /// covers are dropped, except open documents, which stay until they are closed.
+    async fn remove_index_roots(&self, folders: &[PathBuf]) {
+        let below = |path: &Path| folders.iter().any(|folder| path.starts_with(folder));
+        let (dropped, roots): (Vec<PathBuf>, Vec<PathBuf>) = {
+            let mut roots = self.index_roots.write().unwrap();
+            let (dropped, kept) = roots.drain(..).partition(|root| below(root));
+            *roots = kept;
+            (dropped, roots.clone())
+        };
+
+        let open = self.open_paths().await;
+        let files: Vec<PathBuf> = (self.memory.lock().unwrap().tracked_paths())
+            .filter(|path| below(path) && !open.contains(*path))
+            .filter(|path| !roots.iter().any(|root| path.starts_with(root)))
+            .cloned()
+            .collect();
+        self.jobs
+            .extend((files.into_iter()).map(|path| Job::remove(path, Priority::Background)));
+
+        let unregistrations: Vec<Unregistration> = (dropped.iter())
+            .filter(|root| !self.config.index_roots.contains(root))
+            .map(|root| Unregistration {
+                id: watcher_id(root),
+                method: "workspace/didChangeWatchedFiles".into(),
+            })
+            .collect();
+        if unregistrations.is_empty() {
+            return;
+        }
+        if let Err(err) = self.client.unregister_capability(unregistrations).await {
+            tracing::debug!("file watcher unregistration failed: {err}");
+        }
+    }
+
+    /// Whether a file changed on disk belongs in the index, going by the tracked files as last
+    /// listed.
+    fn is_indexed_path(&self, path: &Path) -> bool {
+        self.is_covered_path(path)
+            && (self.tracked_files.as_ref()).is_none_or(|tracked| tracked.contains(path))
+    }
+
+    /// Whether the index roots and exclusions cover a file, whether git tracks it or not.
+    fn is_covered_path(&self, path: &Path) -> bool {
+        let roots = self.index_roots.read().unwrap();
+        (self.config).is_indexed_path(&self.project_root, &roots, path)
+    }
+
+    /// Asks the client to report changes to indexable files under `roots`. Clients without
+    /// dynamic registration reject this and rely on their own watcher configuration.
+    async fn register_file_watchers(&self, id: String, roots: &[PathBuf]) {
+        let extensions = self.config.index_extensions.join(",");
+        let watchers = roots
+            .iter()
+            .map(|root| FileSystemWatcher {
+                glob_pattern: GlobPattern::String(format!(
+                    "{}/**/*.{{{extensions}}}",
+                    root.display()
+                )),
+                kind: None,
+            })
+            .collect();
+        let options = DidChangeWatchedFilesRegistrationOptions { watchers };
+        let registration = Registration {
+            id,
+            method: "workspace/didChangeWatchedFiles".into(),
+            register_options: serde_json::to_value(options).ok(),
+        };
+        if let Err(err) = self.client.register_capability(vec![registration]).await {
+            tracing::debug!("file watcher registration failed: {err}");
+        }
+    }
+
+    /// The key a document is indexed under.
+    fn file_path(&self, uri: &tower_lsp::lsp_types::Url) -> Option<PathBuf> {
+        let path = uri.to_file_path().ok()?;
+        Some(self.config.path_style.normalize(&path))
+    }
+
+    fn enqueue(&self, uri: &tower_lsp::lsp_types::Url, content: Option<String>) {
+        if let Some(path) = self.file_path(uri) {
+            self.jobs
+                .push(Job::update(path, content, Priority::Foreground));
+        }
+    }
+
+    async fn run_jobs(self) {
+        let mut last_publish = Instant::now();
+        loop {
+            let batch = self.jobs.pop_batch(self.config.parse_jobs).await;
+            let foreground = batch.iter().any(|job| job.priority == Priority::Foreground);
+
+            let mut tasks = JoinSet::new();
+            for mut job in batch {
+                if self.jobs.is_superseded(&job) {
+                    continue;
+                }
+                // Delete/create bursts from editors and git can leave the last event for a path
+                // out of date by the time it runs, so for jobs reading the disk the disk decides.
+                // Removals of files no index root covers any more stand regardless.
+                let unindexed =
+                    matches!(job.kind, JobKind::Remove) && !self.is_indexed_path(&job.path);
+                if !matches!(job.kind, JobKind::Update(Some(_))) && !unindexed {
+                    job.kind = if job.path.is_file() {
+                        JobKind::Update(None)
+                    } else {
+                        JobKind::Remove
+                    };
+                }
+                if let Some(profiler) = &self.profiler {
+                    let queue = match job.priority {
+                        Priority::Foreground => "queue/foreground",
+                        Priority::Background => "queue/background",
+                    };
+                    profiler.record(queue, job.queued_at().elapsed());
+                }
+                let (path, generation) = (job.path.clone(), job.generation());
+                let removed = matches!(job.kind, JobKind::Remove);
+                let caching = self.config.cache_dir.is_some();
+                let cancel = job.cancel_token().clone();
+                let (plugins, jobs) = (self.plugins.clone(), self.jobs.clone());
+                let task = tokio::task::spawn_blocking(move || {
+                    let (path, generation) = (job.path.clone(), job.generation());
+                    // Fingerprint before parsing so a concurrent edit makes the entry stale.
+                    let disk = matches!(job.kind, JobKind::Update(None))
+                        .then(|| Fingerprint::read(&path))
+                        .flatten();
+                    let encoding = match &job.kind {
+                        JobKind::Update(Some(text)) => encoding_issue(text.as_bytes()),
+                        _ => disk.as_ref().and_then(|(_, bytes)| encoding_issue(bytes)),
+                    };
+                    let pragma = match &job.kind {
+                        JobKind::Update(content) => pragma_issue(&path, content.as_deref()),
+                        JobKind::Remove => None,
+                    };
+                    let result = crash::recoverable(|| plugins.run_job(&jobs, job));
+                    let cached =
+                        disk.filter(|_| caching && result.is_ok())
+                            .map(|(fingerprint, _)| {
+                                CachedFile::new(fingerprint, plugins.snapshot(&path))
+                            });
+                    JobOutcome {
+                        path,
+                        generation,
+                        removed,
+                        result,
+                        cached,
+                        encoding_checked: !removed,
+                        encoding,
+                        pragma,
+                    }
+                });
+
+                // libclang can't be interrupted, so a timed out parse is cancelled and left to
+                // stop at its next check in the background; its result is dropped.
+                let timeout = self.config.parse_timeout;
+                tasks.spawn(async move {
+                    let joined = match timeout {
+                        Some(limit) => match tokio::time::timeout(limit, task).await {
+                            Ok(joined) => joined,
+                            Err(_) => {
+                                cancel.cancel();
+                                let err = ParseTimeout(limit).into();
+                                return JobOutcome::failed(path, generation, removed, err);
+                            }
+                        },
+                        None => task.await,
+                    };
+                    joined.unwrap_or_else(|err| {
+                        let err = anyhow!("parse task panicked: {err}");
+                        JobOutcome::failed(path, generation, removed, err)
+                    })
+                });
+            }
+
+            let mut completed = false;
+            let mut walk_indexed = false;
+            while let Some(joined) = tasks.join_next().await {
+                let outcome = match joined {
+                    Ok(outcome) => outcome,
+                    Err(err) => {
+                        tracing::error!("job task failed: {err}");
+                        continue;
+                    }
+                };
+                if !outcome.removed {
+                    self.metrics.record_parse(match &outcome.result {
+                        Ok(_) => ParseResult::Parsed,
+                        Err(err) if err.is::<ParseTimeout>() => ParseResult::TimedOut,
+                        Err(_) => ParseResult::Failed,
+                    });
+                }
+                match &outcome.result {
+                    Ok(impact) => self
+                        .publish
+                        .lock()
+                        .unwrap()
+                        .mark_dirty(&outcome.path, *impact),
+                    Err(err) => {
+                        if let Some(failed) = err.downcast_ref::<PluginsFailed>() {
+                            (self.publish.lock().unwrap()).mark_dirty(&outcome.path, failed.impact);
+                        }
+                        self.record_parse_failure(&outcome.path, err).await
+                    }
+                }
+                if self.update_file_issues(&outcome) {
+                    self.publish
+                        .lock()
+                        .unwrap()
+                        .mark_dirty(&outcome.path, Impact::File);
+                }
+
+                if outcome.removed {
+                    self.memory.lock().unwrap().forget(&outcome.path);
+                } else {
+                    self.memory.lock().unwrap().touch(&outcome.path);
+                }
+                if self.jobs.complete(&outcome.path, outcome.generation) {
+                    completed = true;
+                    let mut initial_files = self.initial_files.lock().unwrap();
+                    walk_indexed |= initial_files.remove(&outcome.path) && initial_files.is_empty();
+                    drop(initial_files);
+                    let mut cache = self.cache.lock().unwrap();
+                    if outcome.removed {
+                        cache.remove(&outcome.path);
+                    } else if let Some(cached) = outcome.cached {
+                        cache.insert(outcome.path, cached);
+                    }
+                }
+            }
+            self.enforce_memory_budget().await;
+
+            let publish_due = last_publish.elapsed() >= BACKGROUND_PUBLISH_INTERVAL;
+            if completed && (foreground || publish_due || self.jobs.is_empty()) {
+                self.request_publish();
+                last_publish = Instant::now();
+            }
+            if walk_indexed {
+                self.finish_initial_indexing();
+            }
+            if completed && self.jobs.is_empty() {
+                self.save_cache().await;
+            }
+            if self.jobs.is_empty() {
+                self.jobs_drained.notify_waiters();
+            }
+            self.send_state().await;
+        }
+    }
+
+    fn server_state(&self) -> ServerState {
+        let parse_failures = (self.file_issues.lock().unwrap().values())
+            .filter(|issues| issues.parse_failure.is_some())
+            .count();
+        let degraded_plugins = match &self.config.libclang_error {
+            Some(err) => (self.plugins.names.iter())
+                .map(|name| DegradedPlugin {
+                    name: name.to_string(),
+                    reason: err.clone(),
+                })
+                .collect(),
+            None => Vec::new(),
+        };
+        let queued_jobs = self.jobs.len();
+        ServerState {
+            indexing: queued_jobs > 0,
+            initial_indexing: self.initial_indexing.load(Ordering::SeqCst),
+            indexed_files: self.memory.lock().unwrap().tracked_files(),
+            queued_jobs,
+            parse_failures,
+            degraded_plugins,
+        }
+    }
+
+    /// Sends `elysium/state` if the state changed, at most every `STATE_PROGRESS_INTERVAL` when
+    /// only the file counts did. Called between job batches, when no job is running.
+    async fn send_state(&self) {
+        let state = self.server_state();
+        {
+            let mut sent = self.state_sent.lock().unwrap();
+            if let Some(last) = &sent.last {
+                let progress_only = ServerState {
+                    indexed_files: state.indexed_files,
+                    queued_jobs: state.queued_jobs,
+                    ..last.clone()
+                } == state;
+                let recent = sent
+                    .at
+                    .is_some_and(|at| at.elapsed() < STATE_PROGRESS_INTERVAL);
+                if *last == state || progress_only && recent {
+                    return;
+                }
+            }
+            sent.last = Some(state.clone());
+            sent.at = Some(Instant::now());
+        }
+        self.client
+            .send_notification::<StateNotification>(state)
+            .await;
+    }
+
+    /// Serves the persisted index right away; `initialized` then checks it against the disk in
+    /// the background and queues whatever changed. Returns whether anything was restored.
+    async fn warm_start(&self) -> bool {
+        let Some(cache_dir) = self.config.cache_dir.clone() else {
+            return false;
+        };
+        let (plugins, cache) = (self.plugins.clone(), self.cache.clone());
+        let config = self.config.clone();
+        let restored = tokio::task::spawn_blocking(move || {
+            // Every parse depends on the compile commands.
+            let key = format!(
+                "{} {} {:?}",
+                config.cache_key,
+                plugins.encoding().cache_tag(),
+                file_hash(&config.compile_commands)
+            );
+            let mut cache = cache.lock().unwrap();
+            *cache = IndexCache::load(&cache_dir, &key);
+            restore_cached_files(&plugins, &mut cache)
+        })
+        .await
+        .unwrap_or_default();
+        if restored.is_empty() {
+            return false;
+        }
+
+        {
+            let mut memory = self.memory.lock().unwrap();
+            let mut publish = self.publish.lock().unwrap();
+            for (path, impact) in &restored {
+                memory.touch(path);
+                publish.mark_dirty(path, *impact);
+            }
+        }
+        self.request_publish();
+        true
+    }
+
+    /// Ends the startup phase in which cross-file diagnostics are held back, republishing
+    /// everything with them included.
+    fn finish_initial_indexing(&self) {
+        if !self.initial_indexing.swap(false, Ordering::SeqCst) {
+            return;
+        }
+        tracing::info!("initial indexing finished");
+        self.publish.lock().unwrap().dirty_all = true;
+        self.request_publish();
+
+        let report = self.metrics_report();
+        let event = serde_json::json!({
+            "event": "initialIndexing",
+            "durationMs": self.started.elapsed().as_millis() as u64,
+            "files": report.indexed_files,
+            "parses": report.parses,
+            "parseFailures": report.parse_failures,
+            "cacheHitRate": report.cache_hit_rate,
+        });
+        let server = self.clone();
+        tokio::spawn(async move { server.send_telemetry(event).await });
+    }
+
+    /// Sends `event` as `telemetry/event` if enabled with `--telemetry`. Events carry counts and
+    /// durations only, never paths or names from the project.
+    async fn send_telemetry(&self, event: serde_json::Value) {
+        if self.config.telemetry {
+            self.client.telemetry_event(event).await;
+        }
+    }
+
+    async fn save_cache(&self) {
+        let Some(cache_dir) = self.config.cache_dir.clone() else {
+            return;
+        };
+        let cache = self.cache.clone();
+        let saved = tokio::task::spawn_blocking(move || {
+            let mut cache = cache.lock().unwrap();
+            if cache.take_modified() {
+                cache.save(&cache_dir)
+            } else {
+                Ok(())
+            }
+        })
+        .await;
+        if let Ok(Err(err)) = saved {
+            tracing::warn!("failed to save index cache: {err:?}");
+        }
+    }
+
+    /// Keeps serving after a failed parse: the file keeps its previous results and gets a
+    /// diagnostic explaining why they may be stale until it parses again.
+    async fn record_parse_failure(&self, path: &Path, err: &anyhow::Error) {
+        tracing::warn!("{}: {err:#}", path.display());
+        self.client
+            .log_message(
+                MessageType::WARNING,
+                format!("elysium-lsp: {}: {err:#}", path.display()),
+            )
+            .await;
+        self.file_issues
+            .lock()
+            .unwrap()
+            .entry(path.to_path_buf())
+            .or_default()
+            .parse_failure = Some(format!(
+            "elysium-lsp could not analyze this file: {err:#}; its hooks and init targets \
+                 may be out of date"
+        ));
+        self.publish.lock().unwrap().mark_dirty(path, Impact::File);
+    }
+
+    /// Clears a recorded parse failure once the file parses again and records what the job found
+    /// about its encoding and disable pragma. Returns whether the file's issues changed.
+    fn update_file_issues(&self, outcome: &JobOutcome) -> bool {
+        let mut file_issues = self.file_issues.lock().unwrap();
+        if outcome.removed {
+            return file_issues.remove(&outcome.path).is_some();
+        }
+        let issues = file_issues.entry(outcome.path.clone()).or_default();
+        let mut changed = false;
+        if outcome.result.is_ok() {
+            changed |= issues.parse_failure.take().is_some();
+        }
+        if outcome.encoding_checked && issues.encoding != outcome.encoding {
+            issues.encoding = outcome.encoding.clone();
+            changed = true;
+        }
+        if outcome.encoding_checked && issues.pragma != outcome.pragma {
+            issues.pragma = outcome.pragma.clone();
+            changed = true;
+        }
+        if issues.is_empty() {
+            file_issues.remove(&outcome.path);
+        }
+        changed
+    }
+
+    /// Evicts parse data of the least recently used closed files until the plugins fit in the
+    /// configured budget.
+    async fn enforce_memory_budget(&self) {
+        let Some(budget) = self.config.memory_budget else {
+            return;
+        };
+        let mut usage = self.plugins.memory_usage();
+        if usage <= budget {
+            return;
+        }
+
+        let open = self.open_paths().await;
+        let mut memory = self.memory.lock().unwrap();
+        for path in memory.eviction_candidates(&open) {
+            if usage <= budget {
+                break;
+            }
+            usage = usage.saturating_sub(self.plugins.evict(&path));
+            memory.mark_evicted(path);
+        }
+    }
+
+    /// The plugins, once files evicted under the memory budget are parsed again. Requests reading
+    /// what other files refer to go through here, since eviction drops exactly that.
+    async fn whole_index(&self) -> &PluginSet {
+        self.reload_evicted().await;
+        &self.plugins
+    }
+
+    /// Parses the files evicted under the memory budget again, for requests that must see every
+    /// use of a name. The next budget check evicts them again if needed.
+    async fn reload_evicted(&self) {
+        let _reloading = self.reloading.lock().await;
+        let evicted: Vec<PathBuf> = (self.memory.lock().unwrap().evicted_paths())
+            .cloned()
+            .collect();
+        if evicted.is_empty() {
+            return;
+        }
+        let plugins = self.plugins.clone();
+        // Stops reloading once the request waiting for it is cancelled.
+        let cancel = CancelToken::default();
+        let _cancel_on_drop = cancel.cancel_on_drop();
+        let reloaded = tokio::task::spawn_blocking(move || {
+            crash::recoverable(|| {
+                let mut reloaded = Vec::new();
+                for path in evicted {
+                    if cancel.is_cancelled() {
+                        break;
+                    }
+                    let disabled = disabled_plugins(&path, None);
+                    let result = (plugins.parse(&path, None, &disabled, &cancel))
+                        .and_then(|parsed| plugins.apply_parsed(&path, parsed, &disabled));
+                    match result {
+                        Ok(impact) => reloaded.push((path, impact, true)),
+                        Err(err) if err.is::<ParseCancelled>() => break,
+                        Err(err) => {
+                            tracing::warn!("failed to reload {}: {err:#}", path.display());
+                            // Stays evicted, but what the other plugins reloaded is published.
+                            if let Some(failed) = err.downcast_ref::<PluginsFailed>() {
+                                reloaded.push((path, failed.impact, false));
+                            }
+                        }
+                    }
+                }
+                reloaded
+            })
+        })
+        .await
+        .unwrap_or_default();
+
+        {
+            let mut memory = self.memory.lock().unwrap();
+            let mut publish = self.publish.lock().unwrap();
+            for (path, impact, complete) in &reloaded {
+                if *complete {
+                    memory.touch(path);
+                }
+                publish.mark_dirty(path, *impact);
+            }
+        }
+        self.request_publish();
+    }
+
+    /// Paths of the documents the client has open.
+    async fn open_paths(&self) -> HashSet<PathBuf> {
+        self.documents
+            .read()
+            .await
+            .texts
+            .keys()
+            .filter_map(|uri| self.file_path(uri))
+            .collect()
+    }
+
+    /// In open-files-only mode, opening or closing a document changes whether it gets
+    /// diagnostics even when reindexing it changes nothing.
+    fn visibility_changed(&self, uri: &tower_lsp::lsp_types::Url) {
+        if !self.config.open_files_only {
+            return;
+        }
+        if let Some(path) = self.file_path(uri) {
+            self.publish.lock().unwrap().mark_dirty(&path, Impact::File);
+            self.request_publish();
+        }
+    }
+
+    /// Asks the publisher for a publish pass. Requests made before a pending pass starts are served
+    /// by it, so bursts of updates publish once.
+    fn request_publish(&self) {
+        self.publish_requests.notify_one();
+    }
+
+    /// Single-flight publishing: at most one pass runs at a time.
+    async fn run_publisher(self) {
+        loop {
+            self.publish_requests.notified().await;
+            tokio::time::sleep(PUBLISH_COALESCE_DELAY).await;
+            self.publish_dirty_diagnostics().await;
+        }
+    }
+
+    /// Every file's diagnostics, or those of `only`, after the configured ignores, severity
+    /// overrides and caps.
+    fn collect_diagnostics(&self, only: Option<&Path>) -> HashMap<PathBuf, Vec<Diagnostic>> {
+        let ignored = |source: &str, path: &Path| {
+            path.strip_prefix(&self.project_root)
+                .is_ok_and(|relative| self.config.diagnostics_ignore.is_ignored(source, relative))
+        };
+        let mut diagnostics = self.plugins.diagnostics(
+            ignored,
+            &self.config.diagnostics_cap,
+            self.initial_indexing.load(Ordering::SeqCst),
+            only,
+        );
+        for (path, issues) in self.file_issues.lock().unwrap().iter() {
+            if ignored("elysium-lsp", path) || only.is_some_and(|only| only != path) {
+                continue;
+            }
+            let file_diagnostics = diagnostics.entry(path.clone()).or_default();
+            file_diagnostics.extend(issues.messages().map(|(code, message)| Diagnostic {
+                range: Range::default(),
+                severity: Some(DiagnosticSeverity::WARNING),
+                code: Some(NumberOrString::String(code.into())),
+                message: message.clone(),
+                source: Some("elysium-lsp".into()),
+                ..Diagnostic::default()
+            }));
+        }
+        if self.config.companion(CompanionFeature::Diagnostics) {
+            for file_diagnostics in diagnostics.values_mut() {
+                file_diagnostics.retain(|diagnostic| {
+                    !matches!(&diagnostic.code,
+                        Some(NumberOrString::String(code)) if code == "elysium-lsp/parse-failure")
+                });
+            }
+        }
+        let tag_source = self.config.companion(CompanionFeature::Source);
+        for diagnostic in diagnostics.values_mut().flatten() {
+            self.config.severity_overrides.apply(diagnostic);
+            if tag_source {
+                if let Some(source) = diagnostic.source.as_mut().filter(|s| *s != "elysium-lsp") {
+                    *source = format!("elysium-lsp: {source}");
+                }
+            }
+        }
+        for file_diagnostics in diagnostics.values_mut() {
+            self.config.diagnostics_cap.apply(file_diagnostics);
+        }
+        diagnostics
+    }
+
+    async fn publish_dirty_diagnostics(&self) {
+        let dirty = if self.pull_diagnostics.load(Ordering::SeqCst) {
+            let dirty = self.publish.lock().unwrap().take_dirty();
+            let nothing_dirty = dirty.as_ref().is_some_and(HashSet::is_empty);
+            if !nothing_dirty && self.diagnostic_refresh.load(Ordering::SeqCst) {
+                if let Err(err) = self.client.workspace_diagnostic_refresh().await {
+                    tracing::debug!("diagnostic refresh failed: {err}");
+                }
+            }
+            dirty
+        } else {
+            self.push_diagnostics().await
+        };
+
+        if dirty.is_none() && self.code_lens_refresh.load(Ordering::SeqCst) {
+            if let Err(err) = self.client.code_lens_refresh().await {
+                tracing::debug!("code lens refresh failed: {err}");
+            }
+        }
+        if dirty.is_none() && self.inlay_hint_refresh.load(Ordering::SeqCst) {
+            if let Err(err) = self.client.inlay_hint_refresh().await {
+                tracing::debug!("inlay hint refresh failed: {err}");
+            }
+        }
+    }
+
+    /// Sends the diagnostics of the files marked dirty to a client that doesn't pull them, and
+    /// returns which files those were (`None` for all).
+    async fn push_diagnostics(&self) -> Option<HashSet<PathBuf>> {
+        let mut diagnostics = self.collect_diagnostics(None);
+        if self.config.open_files_only {
+            let open = self.open_paths().await;
+            diagnostics.retain(|path, _| open.contains(path));
+        }
+        // Publishing passes run one at a time, so nothing else needs this meanwhile.
+        let (mut published, dirty) = {
+            let mut publish = self.publish.lock().unwrap();
+            (std::mem::take(&mut publish.published), publish.take_dirty())
+        };
+
+        // Evicted files can't recompute their diagnostics; keep what the client already has,
+        // unless they are closed files that shouldn't show any.
+        let mut current = HashMap::new();
+        {
+            let memory = self.memory.lock().unwrap();
+            diagnostics.retain(|path, _| !memory.is_evicted(path));
+            if !self.config.open_files_only {
+                published.retain(|path, previous| {
+                    let evicted = memory.is_evicted(path);
+                    if evicted {
+                        current.insert(path.clone(), std::mem::take(previous));
+                    }
+                    !evicted
+                });
+            }
+        }
+
+        for (path, diagnostics) in diagnostics {
+            let previous = published.remove(&path);
+            if dirty.as_ref().is_some_and(|dirty| !dirty.contains(&path)) {
+                if let Some(previous) = previous {
+                    current.insert(path, previous);
+                }
+                continue;
+            }
+            let unchanged = previous.as_deref().unwrap_or_default() == diagnostics.as_slice();
+            if !unchanged {
+                if let Ok(uri) = tower_lsp::lsp_types::Url::from_file_path(&path) {
+                    (self.client)
+                        .publish_diagnostics(uri, diagnostics.clone(), None)
+                        .await;
+                }
+            }
+            current.insert(path, diagnostics);
+        }
+
+        // Whatever is left was published before but has no diagnostics any more.
+        for (path, previous) in published {
+            if previous.is_empty() {
+                continue;
+            }
+            if let Ok(uri) = tower_lsp::lsp_types::Url::from_file_path(&path) {
+                self.client.publish_diagnostics(uri, vec![], None).await;
+            }
+        }
+
+        self.publish.lock().unwrap().published = current;
+        dirty
+    }
+}
+
+impl PluginSet {
+    fn new(
+        plugins: Vec<Box<dyn LspPlugin>>,
+        workers: Option<WorkerPool>,
+        profiler: Option<Arc<Profiler>>,
+    ) -> Self {
+        Self {
+            parsers: plugins.iter().map(|plugin| plugin.parser()).collect(),
+            names: plugins.iter().map(|plugin| plugin.name()).collect(),
+            profiler,
+            plugins: plugins.into_iter().map(RwLock::new).collect(),
+            workers,
+            encoding: Mutex::default(),
+        }
+    }
+
+    fn encoding(&self) -> PositionEncoding {
+        *self.encoding.lock().unwrap()
+    }
+
+    fn set_encoding(&self, encoding: PositionEncoding) {
+        *self.encoding.lock().unwrap() = encoding;
+    }
+
+    fn shut_down(&self) {
+        if let Some(workers) = &self.workers {
+            workers.shut_down();
+        }
+    }
+
+    /// Parses without holding any plugin lock, then applies the results unless `job` was
+    /// superseded in the meantime.
+    fn run_job(&self, jobs: &JobQueue, job: Job) -> Result<Impact> {
+        let _encoding = self.encoding().enter();
+        match &job.kind {
+            JobKind::Update(content) => {
+                let disabled = disabled_plugins(&job.path, content.as_deref());
+                let parsed = match self.parse(
+                    &job.path,
+                    content.as_deref(),
+                    &disabled,
+                    job.cancel_token(),
+                ) {
+                    Err(err) if err.is::<ParseCancelled>() => return Ok(Impact::Unchanged),
+                    parsed => parsed?,
+                };
+                if jobs.is_superseded(&job) {
+                    return Ok(Impact::Unchanged);
+                }
+                self.apply_parsed(&job.path, parsed, &disabled)
+            }
+            JobKind::Remove => Ok(self.file_removed(&job.path)),
+        }
+    }
+
+    /// Applies the cached data of every plugin to `path`. Returns `None` without touching any
+    /// plugin if some plugin's entry is missing or unreadable, so the file gets parsed instead.
+    fn restore(&self, path: &Path, cached: &CachedFile) -> Option<Impact> {
+        let _encoding = self.encoding().enter();
+        let mut loaded = Vec::with_capacity(self.plugins.len());
+        for plugin in &self.plugins {
+            let plugin = plugin.read().unwrap();
+            let value = cached.plugins.get(plugin.name())?;
+            loaded.push(match value {
+                serde_json::Value::Null => None,
+                value => Some(plugin.load_file(value.clone()).ok()?),
+            });
+        }
+        Some(self.apply(path, loaded, &[]))
+    }
+
+    fn snapshot(&self, path: &Path) -> HashMap<String, serde_json::Value> {
+        self.plugins
+            .iter()
+            .map(|plugin| {
+                let plugin = plugin.read().unwrap();
+                let value = plugin.save_file(path).unwrap_or_default();
+                (plugin.name().to_string(), value)
+            })
+            .collect()
+    }
+
+    /// Parses `path` with every plugin not in `disabled`, returning each plugin's data or why it
+    /// failed. Fails as a whole only if the parse was cancelled, timed out or crashed its worker.
+    fn parse(
+        &self,
+        path: &Path,
+        content: Option<&str>,
+        disabled: &[String],
+        cancel: &CancelToken,
+    ) -> Result<Vec<Result<Option<FileData>>>> {
+        let _encoding = self.encoding().enter();
+        if let Some(workers) = &self.workers {
+            let timer = self.timer("parse/workers");
+            // A crashed worker fails this parse only; the plugins keep their previous data.
+            let encoded = workers.parse(path, content, self.encoding(), cancel)?;
+            drop(timer);
+            return Ok((self.plugins.iter())
+                .zip(encoded)
+                .map(|(plugin, value)| {
+                    value?
+                        .map(|value| plugin.read().unwrap().load_file(value))
+                        .transpose()
+                })
+                .collect());
+        }
+        let mut parsed = Vec::with_capacity(self.parsers.len());
+        for (parser, name) in self.parsers.iter().zip(&self.names) {
+            if disabled.iter().any(|disabled| disabled == name) {
+                parsed.push(Ok(None));
+                continue;
+            }
+            // libclang can't be interrupted, so in-process parses stop between plugins.
+            cancel.check()?;
+            let _timer =
+                (self.profiler.as_ref()).map(|profiler| profiler.start(format!("parse/{name}")));
+            parsed.push(parser.parse_file(path, content));
+        }
+        Ok(parsed)
+    }
+
+    fn timer(&self, name: &str) -> Option<ProfileTimer> {
+        self.profiler.as_ref().map(|profiler| profiler.start(name))
+    }
+
+    /// Hands the data of the plugins that parsed `path` to them, failing with [`PluginsFailed`]
+    /// if any other plugin failed. Those keep what they had, as after a failed parse.
+    fn apply_parsed(
+        &self,
+        path: &Path,
+        parsed: Vec<Result<Option<FileData>>>,
+        disabled: &[String],
+    ) -> Result<Impact> {
+        let mut data = Vec::with_capacity(parsed.len());
+        let mut failures = Vec::new();
+        for (result, name) in parsed.into_iter().zip(&self.names) {
+            match result {
+                Ok(parsed) => data.push(parsed),
+                Err(err) => {
+                    data.push(None);
+                    failures.push((*name, err));
+                }
+            }
+        }
+        let impact = self.apply(path, data, disabled);
+        if failures.is_empty() {
+            Ok(impact)
+        } else {
+            Err(PluginsFailed { impact, failures }.into())
+        }
+    }
+
+    /// Hands parse results to the plugins. Plugins without data keep what they had, unless the
+    /// file disabled them, in which case they drop it.
+    fn apply(&self, path: &Path, parsed: Vec<Option<FileData>>, disabled: &[String]) -> Impact {
+        let _encoding = self.encoding().enter();
+        let mut impact = Impact::Unchanged;
+        for ((plugin, name), data) in self.plugins.iter().zip(&self.names).zip(parsed) {
+            let mut plugin = plugin.write().unwrap();
+            impact = impact.max(match data {
+                Some(data) => plugin.apply_file(path, data),
+                None if disabled.iter().any(|disabled| disabled == name) => {
+                    plugin.on_file_removed(path)
+                }
+                None => Impact::Unchanged,
+            });
+        }
+        impact
+    }
+
+    fn file_removed(&self, path: &Path) -> Impact {
+        let mut impact = Impact::Unchanged;
+        for plugin in &self.plugins {
+            impact = impact.max(plugin.write().unwrap().on_file_removed(path));
+        }
+        impact
+    }
+
+    fn memory_usage(&self) -> usize {
+        self.plugins
+            .iter()
+            .map(|plugin| plugin.read().unwrap().memory_usage())
+            .sum()
+    }
+
+    fn evict(&self, path: &Path) -> usize {
+        self.plugins
+            .iter()
+            .map(|plugin| plugin.write().unwrap().evict_file(path))
+            .sum()
+    }
+
+    /// Diagnostics of every plugin for every file or only `only`, skipping files
+    /// `ignored(plugin name, path)` excludes.
+    fn diagnostics(
+        &self,
+        ignored: impl Fn(&str, &Path) -> bool,
+        cap: &DiagnosticsCap,
+        skip_cross_file: bool,
+        only: Option<&Path>,
+    ) -> HashMap<PathBuf, Vec<Diagnostic>> {
+        let _encoding = self.encoding().enter();
+        let mut all: HashMap<PathBuf, Vec<Diagnostic>> = HashMap::new();
+        for (plugin, name) in self.plugins.iter().zip(&self.names) {
+            let plugin = plugin.read().unwrap();
+            let cross_file = plugin.cross_file_codes();
+            let diagnostics = match only {
+                Some(path) => HashMap::from([(path.to_path_buf(), plugin.file_diagnostics(path))]),
+                None => plugin.diagnostics(),
+            };
+            for (path, mut diagnostics) in diagnostics {
+                if skip_cross_file {
+                    diagnostics.retain(|diagnostic| {
+                        !matches!(&diagnostic.code,
+                            Some(NumberOrString::String(code)) if cross_file.contains(&code.as_str()))
+                    });
+                }
+                if !ignored(name, &path) {
+                    cap.apply_plugin(name, &mut diagnostics);
+                    all.entry(path).or_default().extend(diagnostics);
+                }
+            }
+        }
+        all
+    }
+
+    fn expand_macro(
+        &self,
+        path: &Path,
+        content: Option<&str>,
+        position: &Position,
+    ) -> Option<MacroExpansion> {
+        let _encoding = self.encoding().enter();
+        self.parsers.iter().find_map(|parser| {
+            parser
+                .expand_macro(path, content, position)
+                .inspect_err(|err| {
+                    tracing::warn!("expanding a macro in {}: {err:#}", path.display())
+                })
+                .ok()
+                .flatten()
+        })
+    }
+
+    fn macro_source(
+        &self,
+        path: &Path,
+        content: Option<&str>,
+        position: &Position,
+    ) -> Option<MacroSource> {
+        let _encoding = self.encoding().enter();
+        self.parsers.iter().find_map(|parser| {
+            parser
+                .macro_source(path, content, position)
+                .inspect_err(|err| {
+                    tracing::warn!("looking up a macro in {}: {err:#}", path.display())
+                })
+                .ok()
+                .flatten()
+        })
+    }
+
+    fn commands(&self) -> Vec<&'static str> {
+        (self.plugins.iter())
+            .flat_map(|plugin| plugin.read().unwrap().commands())
+            .copied()
+            .collect()
+    }
+
+    fn prepare_call_hierarchy(
+        &self,
+        path: &Path,
+        position: &Position,
+    ) -> Vec<(&'static str, HierarchyItem)> {
+        let _encoding = self.encoding().enter();
+        (self.plugins.iter().zip(&self.names))
+            .flat_map(|(plugin, name)| {
+                let items = plugin
+                    .read()
+                    .unwrap()
+                    .prepare_call_hierarchy(path, position);
+                items.into_iter().map(|item| (*name, item))
+            })
+            .collect()
+    }
+
+    fn incoming_calls(&self, plugin: &str, data: &serde_json::Value) -> Vec<HierarchyCall> {
+        let _encoding = self.encoding().enter();
+        (self.plugins.iter().zip(&self.names))
+            .find(|(_, name)| **name == plugin)
+            .map(|(plugin, _)| plugin.read().unwrap().incoming_calls(data))
+            .unwrap_or_default()
+    }
+
+    fn outgoing_calls(&self, plugin: &str, data: &serde_json::Value) -> Vec<HierarchyCall> {
+        let _encoding = self.encoding().enter();
+        (self.plugins.iter().zip(&self.names))
+            .find(|(_, name)| **name == plugin)
+            .map(|(plugin, _)| plugin.read().unwrap().outgoing_calls(data))
+            .unwrap_or_default()
+    }
+
+    fn execute_command(
+        &self,
+        command: &str,
+        arguments: &[serde_json::Value],
+    ) -> Result<serde_json::Value> {
+        let _encoding = self.encoding().enter();
+        for plugin in &self.plugins {
+            let plugin = plugin.read().unwrap();
+            if plugin.commands().contains(&command) {
+                return plugin.execute_command(command, arguments);
+            }
+        }
+        Err(anyhow!("unknown command {command}"))
+    }
+
+    fn symbols(&self) -> Vec<(&'static str, SymbolInfo)> {
+        let _encoding = self.encoding().enter();
+        let mut symbols = Vec::new();
+        for (plugin, name) in self.plugins.iter().zip(&self.names) {
+            let plugin = plugin.read().unwrap();
+            symbols.extend(plugin.symbols().into_iter().map(|symbol| (*name, symbol)));
+        }
+        symbols
+    }
+
+    fn hover(&self, path: &Path, position: &Position) -> Option<Hover> {
+        let _encoding = self.encoding().enter();
+        (self.plugins.iter()).find_map(|plugin| plugin.read().unwrap().hover(path, position))
+    }
+
+    fn definition(&self, path: &Path, position: &Position) -> Vec<SymbolLocation> {
+        let _encoding = self.encoding().enter();
+        (self.plugins.iter())
+            .flat_map(|plugin| plugin.read().unwrap().definition(path, position))
+            .collect()
+    }
+
+    fn references(
+        &self,
+        path: &Path,
+        position: &Position,
+        include_declaration: bool,
+    ) -> Vec<SymbolLocation> {
+        let _encoding = self.encoding().enter();
+        (self.plugins.iter())
+            .flat_map(|plugin| {
+                (plugin.read().unwrap()).references(path, position, include_declaration)
+            })
+            .collect()
+    }
+
+    fn signatures(&self) -> Vec<&'static MacroSignature> {
+        (self.plugins.iter())
+            .flat_map(|plugin| plugin.read().unwrap().signatures())
+            .collect()
+    }
+
+    fn name_links(&self, path: &Path) -> Vec<NameLink> {
+        let _encoding = self.encoding().enter();
+        (self.plugins.iter())
+            .flat_map(|plugin| plugin.read().unwrap().name_links(path))
+            .collect()
+    }
+
+    fn inlay_hints(&self, path: &Path) -> Vec<InlayHint> {
+        let _encoding = self.encoding().enter();
+        (self.plugins.iter())
+            .flat_map(|plugin| plugin.read().unwrap().inlay_hints(path))
+            .collect()
+    }
+
+    fn highlights(&self, path: &Path) -> Vec<Highlight> {
+        let _encoding = self.encoding().enter();
+        (self.plugins.iter())
+            .flat_map(|plugin| plugin.read().unwrap().highlights(path))
+            .collect()
+    }
+
+    fn reference_lenses(&self, path: &Path) -> Vec<ReferenceLens> {
+        let _encoding = self.encoding().enter();
+        (self.plugins.iter())
+            .flat_map(|plugin| plugin.read().unwrap().reference_lenses(path))
+            .collect()
+    }
+
+    fn prepare_rename(&self, path: &Path, position: &Position) -> Option<Range> {
+        let _encoding = self.encoding().enter();
+        (self.plugins.iter())
+            .find_map(|plugin| plugin.read().unwrap().prepare_rename(path, position))
+    }
+
+    /// Edits of the first plugin with a name at `position`.
+    fn rename(
+        &self,
+        path: &Path,
+        position: &Position,
+        new_name: &str,
+    ) -> Result<Option<HashMap<PathBuf, Vec<TextEdit>>>> {
+        let _encoding = self.encoding().enter();
+        for plugin in &self.plugins {
+            if let Some(edits) = plugin.read().unwrap().rename(path, position, new_name)? {
+                return Ok(Some(edits));
+            }
+        }
+        Ok(None)
+    }
+
+    fn document_symbols(&self, path: &Path) -> Vec<DocumentSymbol> {
+        let _encoding = self.encoding().enter();
+        (self.plugins.iter())
+            .flat_map(|plugin| plugin.read().unwrap().document_symbols(path))
+            .collect()
+    }
+
+    fn format_edits(&self, path: &Path, text: &str) -> Vec<TextEdit> {
+        let _encoding = self.encoding().enter();
+        (self.plugins.iter())
+            .flat_map(|plugin| plugin.read().unwrap().format_edits(path, text))
+            .collect()
+    }
+
+    fn completions(
+        &self,
+        path: &Path,
+        position: &Position,
+        text: Option<&str>,
+    ) -> Option<Vec<CompletionItem>> {
+        let _encoding = self.encoding().enter();
+        for (plugin, name) in self.plugins.iter().zip(&self.names) {
+            if let Some(mut items) = plugin.read().unwrap().completions(path, position, text) {
+                for item in &mut items {
+                    let data = item.data.take();
+                    item.data = Some(serde_json::json!({ "plugin": name, "data": data }));
+                }
+                return Some(items);
+            }
+        }
+        None
+    }
+
+    /// Hands `item` back to the plugin that made it, with the data `completions` wrapped.
+    fn resolve_completion(&self, mut item: CompletionItem) -> CompletionItem {
+        let _encoding = self.encoding().enter();
+        let Some(serde_json::Value::Object(mut data)) = item.data.take() else {
+            return item;
+        };
+        let plugin = (data.get("plugin").and_then(|plugin| plugin.as_str()))
+            .and_then(|plugin| self.names.iter().position(|name| *name == plugin));
+        item.data = data.remove("data").filter(|data| !data.is_null());
+        match plugin {
+            Some(index) => self.plugins[index].read().unwrap().resolve_completion(item),
+            None => item,
+        }
+    }
+}
+
+impl PublishState {
+    fn mark_dirty(&mut self, path: &Path, impact: Impact) {
+        match impact {
+            Impact::Unchanged => {}
+            Impact::File => {
+                self.dirty_paths.insert(path.to_path_buf());
+            }
+            Impact::Workspace => self.dirty_all = true,
+        }
+    }
+
+    /// Takes the set of files whose diagnostics may have changed; `None` means every file.
+    fn take_dirty(&mut self) -> Option<HashSet<PathBuf>> {
+        let paths = std::mem::take(&mut self.dirty_paths);
+        if std::mem::take(&mut self.dirty_all) {
+            None
+        } else {
+            Some(paths)
+        }
+    }
+}
+
+/// A pull report of `diagnostics`, identified by a hash of them so a client passing the same ID
+/// back learns they are unchanged.
+fn diagnostic_report(
+    diagnostics: Vec<Diagnostic>,
+    previous_result_id: Option<&str>,
+) -> DocumentDiagnosticReportKind {
+    let mut hasher = DefaultHasher::new();
+    serde_json::to_string(&diagnostics)
+        .unwrap_or_default()
+        .hash(&mut hasher);
+    let result_id = format!("{:016x}", hasher.finish());
+    if previous_result_id == Some(result_id.as_str()) {
+        return DocumentDiagnosticReportKind::Unchanged(UnchangedDocumentDiagnosticReport {
+            result_id,
+        });
+    }
+    DocumentDiagnosticReportKind::Full(FullDocumentDiagnosticReport {
+        result_id: Some(result_id),
+        items: diagnostics,
+    })
+}
+
+/// `item` for the editor, its data tagged with the plugin it came from so expanding it goes back
+/// to the same plugin.
+fn call_hierarchy_item(plugin: &str, item: HierarchyItem) -> Option<CallHierarchyItem> {
+    Some(CallHierarchyItem {
+        name: item.name,
+        kind: item.kind,
+        tags: None,
+        detail: item.detail,
+        uri: tower_lsp::lsp_types::Url::from_file_path(&item.path).ok()?,
+        range: item.range,
+        selection_range: item.selection_range,
+        data: Some(serde_json::json!({ "plugin": plugin, "data": item.data })),
+    })
+}
+
+/// The plugin and plugin data of an item made by `call_hierarchy_item`.
+fn hierarchy_key(item: &CallHierarchyItem) -> Option<(String, serde_json::Value)> {
+    let data = item.data.as_ref()?;
+    let plugin = data.get("plugin")?.as_str()?;
+    Some((plugin.to_string(), data.get("data")?.clone()))
+}
+
+/// `signature` as `NAME(parameter, ...)`, with each parameter located by its offsets in the label.
+fn signature_information(signature: &MacroSignature) -> SignatureInformation {
+    let mut label = format!("{}(", signature.name);
+    let mut parameters = Vec::new();
+    for (i, parameter) in signature.parameters.iter().enumerate() {
+        if i > 0 {
+            label.push_str(", ");
+        }
+        let start = label.len() as u32;
+        label.push_str(parameter);
+        parameters.push(ParameterInformation {
+            label: ParameterLabel::LabelOffsets([start, label.len() as u32]),
+            documentation: None,
+        });
+    }
+    label.push(')');
+    SignatureInformation {
+        label,
+        documentation: Some(Documentation::MarkupContent(MarkupContent {
+            kind: MarkupKind::Markdown,
+            value: signature.documentation.into(),
+        })),
+        parameters: Some(parameters),
+        active_parameter: None,
+    }
+}
+
+/// Encodes `highlights` relative to each other, as semantic tokens are sent. Tokens can't span
+/// lines, so highlights that do are dropped, as are ones overlapping an earlier highlight.
+fn semantic_tokens(mut highlights: Vec<Highlight>) -> Vec<SemanticToken> {
+    highlights.retain(|highlight| highlight.range.start.line == highlight.range.end.line);
+    highlights
+        .sort_by_key(|highlight| (highlight.range.start.line, highlight.range.start.character));
+    let mut tokens = Vec::with_capacity(highlights.len());
+    let mut previous = Position::new(0, 0);
+    let mut covered_until = Position::new(0, 0);
+    for highlight in highlights {
+        let Range { start, end } = highlight.range;
+        if start < covered_until || end <= start {
+            continue;
+        }
+        let delta_start = if start.line == previous.line {
+            start.character - previous.character
+        } else {
+            start.character
+        };
+        tokens.push(SemanticToken {
+            delta_line: start.line - previous.line,
+            delta_start,
+            length: end.character - start.character,
+            token_type: HighlightKind::ALL
+                .iter()
+                .position(|kind| *kind == highlight.kind)
+                .unwrap_or_default() as u32,
+            token_modifiers_bitset: u32::from(highlight.declaration),
+        });
+        (previous, covered_until) = (start, end);
+    }
+    tokens
+}
+
+fn severity_name(severity: DiagnosticSeverity) -> &'static str {
+    match severity {
+        DiagnosticSeverity::ERROR => "error",
+        DiagnosticSeverity::WARNING => "warning",
+        DiagnosticSeverity::INFORMATION => "information",
+        _ => "hint",
+    }
+}
+
+fn protocol_extensions() -> serde_json::Value {
+    let versions = |methods: &[(&str, u32)]| -> serde_json::Map<String, serde_json::Value> {
+        (methods.iter())
+            .map(|(method, version)| (method.to_string(), (*version).into()))
+            .collect()
+    };
+    serde_json::json!({
+        "elysium": {
+            "requests": versions(CUSTOM_REQUESTS),
+            "notifications": versions(CUSTOM_NOTIFICATIONS),
+        }
+    })
+}
+
+/// Registration ID of the file watcher for a workspace folder added by the client.
+fn watcher_id(folder: &Path) -> String {
+    format!("{WATCHED_FILES_ID}:{}", folder.display())
+}
+
+/// The indexable files below `roots`, only those in `tracked` if given.
+fn workspace_files(
+    root: &Path,
+    config: &Arc<Config>,
+    roots: &[PathBuf],
+    tracked: Option<&TrackedFiles>,
+) -> Vec<PathBuf> {
+    let Some((first, rest)) = roots.split_first() else {
+        return Vec::new();
+    };
+    let mut walk = WalkBuilder::new(first);
+    for index_root in rest {
+        walk.add(index_root);
+    }
+    let (filter_config, filter_root) = (config.clone(), root.to_path_buf());
+    walk.standard_filters(config.respect_ignore_files)
+        .hidden(false)
+        .filter_entry(move |entry| {
+            let is_dir = entry.file_type().is_some_and(|t| t.is_dir());
+            let excluded_dir = is_dir && filter_config.is_excluded_dir(entry.file_name());
+            !excluded_dir && !filter_config.is_excluded_path(&filter_root, entry.path())
+        })
+        .build()
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| {
+            entry.file_type().is_some_and(|t| t.is_file())
+                && config.is_indexed_file(entry.path())
+                && tracked.is_none_or(|tracked| tracked.contains(entry.path()))
+        })
+        .map(|entry| entry.into_path())
+        .collect()
+}
+
+/// Restores every cached file without checking it against the disk. Entries that can't be restored
+/// are dropped from the cache so revalidation parses them.
+fn restore_cached_files(plugins: &PluginSet, cache: &mut IndexCache) -> Vec<(PathBuf, Impact)> {
+    let mut restored = Vec::new();
+    let mut unreadable = Vec::new();
+    for (path, cached) in cache.entries() {
+        match plugins.restore(path, cached) {
+            Some(impact) => restored.push((path.clone(), impact)),
+            None => unreadable.push(path.clone()),
+        }
+    }
+    for path in unreadable {
+        cache.remove(&path);
+    }
+    restored
+}
+
+/// Jobs bringing a restored index up to date with the workspace: parses for files that changed or
+/// aren't cached, and removals for cached files that are gone. Entries don't record which headers
+/// their file includes, so once any header changed, appeared or is gone every file is parsed again.
+fn revalidation_jobs(
+    cache: &mut IndexCache,
+    files: Vec<PathBuf>,
+    header_extensions: &[String],
+) -> Vec<Job> {
+    let is_header = |path: &Path| {
+        (path.extension().and_then(|ext| ext.to_str()))
+            .is_some_and(|ext| header_extensions.iter().any(|header| header == ext))
+    };
+    let mut unseen: HashSet<PathBuf> = cache.entries().map(|(path, _)| path.clone()).collect();
+    let (mut current, mut stale) = (Vec::new(), Vec::new());
+    for path in files {
+        if unseen.remove(&path) && cache.is_current(&path) {
+            current.push(path);
+        } else {
+            stale.push(path);
+        }
+    }
+    let headers_changed = stale.iter().chain(&unseen).any(|path| is_header(path));
+    if headers_changed {
+        stale.append(&mut current);
+    }
+    let mut jobs: Vec<Job> = (stale.into_iter())
+        .map(|path| Job::update(path, None, Priority::Background))
+        .collect();
+    jobs.extend(
+        unseen
+            .into_iter()
+            .map(|path| Job::remove(path, Priority::Background)),
+    );
+    jobs
+}
+
+/// Every hook with its definition and run sites, ordered by name, or `None` once `cancel` is
+/// cancelled.
+fn build_hook_graph(plugins: &PluginSet, cancel: &CancelToken) -> Option<HookGraph> {
+    let location = |location: &SymbolLocation| {
+        let uri = tower_lsp::lsp_types::Url::from_file_path(&location.path).ok()?;
+        Some(Location::new(uri, location.range))
+    };
+    let symbols = plugins.symbols();
+    cancel.check().ok()?;
+    let mut hooks: Vec<HookNode> = (symbols.into_iter())
+        .filter(|(plugin, _)| *plugin == "hooks")
+        .filter_map(|(_, hook)| {
+            Some(HookNode {
+                definition: location(&hook.location)?,
+                run_sites: (hook.references.iter())
+                    .filter_map(|site| {
+                        Some(RunSite {
+                            location: location(site)?,
+                            function: site.container.clone(),
+                        })
+                    })
+                    .collect(),
+                name: hook.name,
+                documentation: hook.documentation,
+            })
+        })
+        .collect();
+    cancel.check().ok()?;
+    hooks.sort_by(|a, b| a.name.cmp(&b.name));
+    Some(HookGraph { hooks })
+}
+
+/// Names in the file's disable pragma that no plugin goes by, which would otherwise leave the
+/// plugin meant enabled without notice.
+fn pragma_issue(path: &Path, content: Option<&str>) -> Option<String> {
+    let unknown = unknown_disabled_plugins(path, content);
+    (!unknown.is_empty()).then(|| {
+        format!(
+            "`elysium-lsp: disable=` names no plugin called {}; plugins are `init-deps` (or \
+             `init`) and `hooks`",
+            unknown.join(", ")
+        )
+    })
+}
+
+/// libclang skips NUL bytes and reports byte columns, so such files still get indexed, with a
+/// warning that results may be off.
+fn encoding_issue(bytes: &[u8]) -> Option<String> {
+    if memchr::memchr(0, bytes).is_some() {
+        return Some("File contains NUL bytes, which libclang ignores".into());
+    }
+    if std::str::from_utf8(bytes).is_err() {
+        return Some("File is not valid UTF-8; names and positions may be inaccurate".into());
+    }
+    None
 }
@@ -1,20 +1,39 @@
+use std::ffi::OsString;
+use std::fs;
+use std::net::SocketAddr;
 use std::path::{Path, PathBuf};
+use std::time::Duration;
 
-use anyhow::Result;
-use clap::{Parser, ValueEnum};
+use anyhow::{ensure, Context, Result};
+use clap::error::ErrorKind;
+use clap::{CommandFactory, Parser, Subcommand, ValueEnum};
+use clap_complete::Shell;
+use globset::{Glob, GlobSetBuilder};
 use tokio::io::{stdin, stdout};
-use tower_lsp::{LspService, Server};
+use tokio::net::TcpListener;
+use tower_lsp::Server;
 use tracing_subscriber::EnvFilter;
 
-use crate::{
+use elysium_lsp::{
+    config::{CompanionFeature, Config},
+    crash,
+    diagnostics::{DiagnosticsCap, DiagnosticsIgnore, SeverityOverrides},
+    docs, lsif,
     lsp::ElysiumLsp,
-    plugins::{HookPlugin, InitDependencyPlugin, LspPlugin},
+    metrics,
+    paths::PathStyle,
+    placeholders::Placeholders,
+    plugins::{
+        load_libclang, parser, DependencyForms, DependencyStyle, HookPlugin, HookPriorities,
+        InitDependencyPlugin, LspPlugin, ParseOptions, StageScopes, CPP_EXTENSIONS,
+        DEFAULT_CLANG_ARGS,
+    },
+    scaffold::DEFAULT_INIT_TARGET_TEMPLATE,
+    tags::{self, TagsFormat},
+    workers::{self, WorkerPool},
+    CompileCommands,
 };
 
-mod compile_commands;
-mod lsp;
-mod plugins;
-
 #[derive(Clone, Debug, ValueEnum)]
 #[value(rename_all = "kebab_case")]
 enum PluginChoice {
@@ -22,12 +41,58 @@ enum PluginChoice {
     Hooks,
 }
 
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Index the workspace and write Markdown and JSON reference pages for hooks and init targets
+    Docs {
+        /// Directory the pages are written to
+        #[arg(long)]
+        out: PathBuf,
+    },
+    /// Index the workspace and write a tags file of hooks, init targets and other plugin symbols
+    Tags {
+        /// File the tags are written to
+        #[arg(long, default_value = "tags")]
+        out: PathBuf,
+
+        #[arg(long, value_enum, default_value_t = TagsFormat::Ctags)]
+        format: TagsFormat,
+    },
+    /// Index the workspace and write an LSIF dump of hook and init target definitions and
+    /// references, for code browsing platforms
+    Lsif {
+        /// File the dump is written to
+        #[arg(long, default_value = "dump.lsif")]
+        out: PathBuf,
+    },
+    /// Print a completion script for the given shell
+    Completions { shell: Shell },
+    /// Print the man page in roff format
+    Man,
+}
+
 #[derive(Parser, Debug)]
-#[command(author, version, about = "Elysium LSP")]
+#[command(author, version, about = "Elysium LSP", subcommand_negates_reqs = true)]
 struct Args {
-    /// Cronus repository root (required)
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    /// Cronus repository root (required except by `completions` and `man`)
+    #[arg(long, required = true)]
+    project_root: Option<PathBuf>,
+
+    /// Target architecture substituted for `${arch}` in other arguments (defaults to the host's)
     #[arg(long)]
-    project_root: PathBuf,
+    arch: Option<String>,
+
+    /// Directory holding compile_commands.json, relative to the project root (defaults to it)
+    #[arg(long)]
+    compile_commands_dir: Option<PathBuf>,
+
+    /// Argument passed to libclang for every file, after those from compile_commands.json, as in
+    /// `--clang-arg=--sysroot=${env:SYSROOT}` (repeatable)
+    #[arg(long = "clang-arg", value_name = "ARG", allow_hyphen_values = true)]
+    clang_args: Vec<String>,
 
     /// Set tracing log level (e.g. info, debug)
     #[arg(long)]
@@ -40,49 +105,572 @@ struct Args {
         default_values_t = [PluginChoice::InitDeps, PluginChoice::Hooks]
     )]
     plugins: Vec<PluginChoice>,
+
+    /// Delay in milliseconds before reparsing a changed document
+    #[arg(long, default_value_t = 200)]
+    debounce_ms: u64,
+
+    /// Template of the files elysium.newInitTargetFile creates
+    #[arg(long)]
+    init_template: Option<PathBuf>,
+
+    /// Send anonymous usage counters to the editor as telemetry/event notifications
+    #[arg(long)]
+    telemetry: bool,
+
+    /// Hook priority constant HOOK_PRIORITY accepts besides the HOOK_PRIO_* macros in headers,
+    /// optionally with its value (repeatable)
+    #[arg(long = "hook-priority", value_name = "NAME[=VALUE]")]
+    hook_priorities: Vec<String>,
+
+    /// Range numeric HOOK_PRIORITY priorities must fall in; defaults to that of the constants
+    #[arg(long, value_name = "MIN..MAX")]
+    hook_priority_range: Option<String>,
+
+    /// Lay out init dependency lists when documents are saved (textDocument/willSaveWaitUntil)
+    #[arg(long)]
+    format_on_save: bool,
+
+    /// Line length above which saved init dependency lists get one dependency per line
+    #[arg(long, default_value_t = 100)]
+    dependency_line_length: usize,
+
+    /// Sort init dependency lists alphabetically when documents are saved
+    #[arg(long)]
+    sort_dependencies: bool,
+
+    /// Reparse open documents only when they are saved, not on every change
+    #[arg(long)]
+    reindex_on_save: bool,
+
+    /// Parse function bodies instead of skipping them
+    #[arg(long)]
+    parse_function_bodies: bool,
+
+    /// Parse every file, even ones that never mention a plugin's macros
+    #[arg(long)]
+    no_prescan: bool,
+
+    /// Also parse C++ sources (.cpp, .cc, .hpp), as C++ when compile_commands.json has no entry
+    #[arg(long)]
+    cpp: bool,
+
+    /// Extensions of headers parsed as C, with arguments borrowed from a source including them
+    /// (repeatable)
+    #[arg(long = "header-extension", default_values = ["h"])]
+    header_extensions: Vec<String>,
+
+    /// File extensions indexed during the workspace walk (repeatable)
+    #[arg(long = "index-extension", default_values = ["c", "h"])]
+    index_extensions: Vec<String>,
+
+    /// Directories to index, relative to the project root (repeatable; defaults to the whole root)
+    #[arg(long = "index-root")]
+    index_roots: Vec<PathBuf>,
+
+    /// Glob of files and directories to leave out, relative to the project root (repeatable)
+    #[arg(long = "exclude")]
+    exclude_globs: Vec<String>,
+
+    /// Glob of files never given diagnostics, optionally prefixed with a plugin name as in
+    /// `hooks:generated/**` (repeatable)
+    #[arg(long = "diagnostics-ignore")]
+    diagnostics_ignore: Vec<String>,
+
+    /// Severity for a diagnostic code, as in `init/duplicate-dependency=hint` (repeatable)
+    #[arg(long = "severity")]
+    severity_overrides: Vec<String>,
+
+    /// Most diagnostics published per file, or per file and plugin as in `hooks=100` (repeatable)
+    #[arg(long = "max-diagnostics")]
+    diagnostics_cap: Vec<String>,
+
+    /// Publish diagnostics only for open documents; the whole workspace is still indexed
+    #[arg(long)]
+    open_files_only: bool,
+
+    /// Run alongside clangd, enabling the given adjustments or all of them when no list is given,
+    /// as in `--clangd-companion=diagnostics,source`
+    #[arg(
+        long,
+        value_enum,
+        value_delimiter = ',',
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_values = ["diagnostics", "source", "capabilities"]
+    )]
+    clangd_companion: Vec<CompanionFeature>,
+
+    /// Macro wrapping an init dependency name, as in `INIT_REF(pmm)` (repeatable)
+    #[arg(long = "init-ref-macro", default_values = ["INIT_REF"])]
+    init_ref_macros: Vec<String>,
+
+    /// Don't treat bare identifiers in init dependency lists as dependency names
+    #[arg(long)]
+    no_init_identifier_dependencies: bool,
+
+    /// Scopes an init stage allows, as in `INIT_STAGE_EARLY=INIT_SCOPE_BSP`; stages not listed
+    /// allow every scope (repeatable)
+    #[arg(long = "init-stage-scopes", value_name = "STAGE=SCOPE[,SCOPE...]")]
+    init_stage_scopes: Vec<String>,
+
+    /// Init target the boot path kicks off, a root of the dead target analysis (repeatable)
+    #[arg(long = "init-root")]
+    init_roots: Vec<String>,
+
+    /// Directory names skipped during the workspace walk (repeatable)
+    #[arg(long = "exclude-dir", default_values = [".git", ".cache", "build", "target"])]
+    excluded_dirs: Vec<String>,
+
+    /// Index files excluded by .gitignore/.ignore files
+    #[arg(long)]
+    no_ignore: bool,
+
+    /// Index only files git tracks, leaving out untracked build outputs and backups
+    #[arg(long)]
+    git_tracked_only: bool,
+
+    /// How file paths are keyed: `canonical` resolves symlinks, `logical` keeps the client's paths
+    #[arg(long, value_enum, default_value_t = PathStyle::Canonical)]
+    path_style: PathStyle,
+
+    /// Approximate memory budget in MiB for parse data of closed files
+    #[arg(long)]
+    memory_budget_mb: Option<usize>,
+
+    /// Translation units of open documents each plugin keeps for fast reparses
+    #[arg(long, default_value_t = 8)]
+    kept_units: usize,
+
+    /// Number of files parsed in parallel (defaults to the number of CPUs)
+    #[arg(long)]
+    jobs: Option<usize>,
+
+    /// Seconds after which a parse is abandoned and reported on the file (0 disables the limit)
+    #[arg(long, default_value_t = 30)]
+    parse_timeout_secs: u64,
+
+    /// Directory libclang writes the precompiled preambles of open documents to (defaults to the
+    /// system temporary directory)
+    #[arg(long)]
+    preamble_dir: Option<PathBuf>,
+
+    /// Hours after which preambles left in --preamble-dir by earlier sessions are deleted on
+    /// startup
+    #[arg(long, default_value_t = 24)]
+    preamble_max_age_hours: u64,
+
+    /// Directory for the persistent index cache (defaults to <project-root>/.cache/elysium-lsp)
+    #[arg(long)]
+    cache_dir: Option<PathBuf>,
+
+    /// Don't read or write the persistent index cache
+    #[arg(long)]
+    no_cache: bool,
+
+    /// Record request, parse and queue timings, logged on shutdown and reported by elysium/status
+    #[arg(long)]
+    profile: bool,
+
+    /// libclang shared library, or a directory containing it (overrides LIBCLANG_PATH)
+    #[arg(long)]
+    libclang_path: Option<PathBuf>,
+
+    /// Run libclang parses in worker subprocesses so a libclang crash only loses one parse
+    #[arg(long)]
+    isolate_parses: bool,
+
+    /// Serve Prometheus metrics over HTTP on this address, as in `127.0.0.1:9464`
+    #[arg(long)]
+    metrics_addr: Option<SocketAddr>,
+
+    /// Index the workspace, write its diagnostics as JSON to this file (`-` for stdout) and exit
+    #[arg(long)]
+    export_diagnostics: Option<PathBuf>,
+
+    /// Serve parse requests from a parent elysium-lsp over stdin/stdout
+    #[arg(long, hide = true)]
+    parse_worker: bool,
 }
 
-#[tokio::main]
-async fn main() -> Result<()> {
-    let args = Args::parse();
+fn main() -> Result<()> {
+    let mut args = Args::parse();
+    match args.command {
+        Some(Command::Completions { shell }) => {
+            let mut command = Args::command();
+            let name = command.get_name().to_string();
+            clap_complete::generate(shell, &mut command, name, &mut std::io::stdout());
+            return Ok(());
+        }
+        Some(Command::Man) => {
+            clap_mangen::Man::new(Args::command()).render(&mut std::io::stdout())?;
+            return Ok(());
+        }
+        _ => {}
+    }
+    // Required, but not enforced by clap so that the subcommands above can run without it.
+    let Some(mut project_root) = args.project_root.take() else {
+        Args::command()
+            .error(
+                ErrorKind::MissingRequiredArgument,
+                "the following required argument was not provided: --project-root <PROJECT_ROOT>",
+            )
+            .exit();
+    };
 
-    let level = args.log_level.unwrap_or_else(|| "info".into());
+    let level = args.log_level.clone().unwrap_or_else(|| "info".into());
     let filter = EnvFilter::try_new(level).unwrap_or_else(|_| EnvFilter::new("info"));
     tracing_subscriber::fmt()
         .with_writer(std::io::stderr)
         .with_env_filter(filter)
         .init();
+    crash::install_panic_hook();
+
+    // Workers get their arguments already expanded.
+    let arch = (args.arch.clone()).unwrap_or_else(|| std::env::consts::ARCH.to_string());
+    if !args.parse_worker {
+        let placeholders = Placeholders {
+            workspace_root: None,
+            arch: arch.clone(),
+        };
+        project_root = placeholders
+            .expand_path(&project_root)
+            .context("--project-root")?;
+    }
+    let project_root = args
+        .path_style
+        .normalize(&std::path::absolute(&project_root)?);
+    ensure!(
+        project_root.is_dir(),
+        "project root {} is not a directory",
+        project_root.display()
+    );
+    if !args.parse_worker {
+        args.expand_placeholders(&Placeholders {
+            workspace_root: Some(project_root.clone()),
+            arch,
+        })?;
+    }
+    let parse_options = ParseOptions {
+        skip_function_bodies: !args.parse_function_bodies,
+        prescan: !args.no_prescan,
+        cpp: args.cpp,
+        header_extensions: args.header_extensions.clone(),
+        kept_units: args.kept_units,
+    };
+    if let Some(dir) = &args.preamble_dir {
+        fs::create_dir_all(dir).with_context(|| format!("creating {}", dir.display()))?;
+        if !args.parse_worker {
+            let max_age = Duration::from_secs(args.preamble_max_age_hours * 60 * 60);
+            let evicted = parser::evict_stale_preambles(dir, max_age);
+            if evicted > 0 {
+                tracing::info!("deleted {evicted} stale preambles from {}", dir.display());
+            }
+        }
+        // libclang puts preambles in the temporary directory; parse workers inherit this.
+        std::env::set_var("TMPDIR", dir);
+    }
+    if let Some(path) = &args.libclang_path {
+        std::env::set_var("LIBCLANG_PATH", path);
+    }
+    let libclang = load_libclang();
+    match &libclang {
+        Ok(library) => tracing::info!("using {library}"),
+        Err(err) => tracing::error!("{err}"),
+    }
+    if args.parse_worker {
+        libclang?;
+        return workers::serve(instantiate_plugins(&args, &project_root, parse_options)?);
+    }
+    // Built only now: changing the environment above isn't sound once other threads exist.
+    tokio::runtime::Runtime::new()?.block_on(run(args, project_root, parse_options, libclang))
+}
 
+/// Serves the language server, or runs the subcommand given, once the runtime is up.
+async fn run(
+    mut args: Args,
+    project_root: PathBuf,
+    parse_options: ParseOptions,
+    libclang: Result<String>,
+) -> Result<()> {
+    let index_roots: Vec<PathBuf> = (args.index_roots.iter())
+        .map(|root| args.path_style.normalize(&project_root.join(root)))
+        .collect();
+    let mut exclude_globs = GlobSetBuilder::new();
+    for pattern in &args.exclude_globs {
+        exclude_globs.add(Glob::new(pattern).with_context(|| format!("--exclude {pattern}"))?);
+    }
+    let exclude_globs = exclude_globs.build()?;
+    let diagnostics_ignore = DiagnosticsIgnore::parse(&args.diagnostics_ignore)?;
+    let severity_overrides = SeverityOverrides::parse(&args.severity_overrides)?;
+    let diagnostics_cap = DiagnosticsCap::parse(&args.diagnostics_cap)?;
+    args.hook_priorities()?;
+    args.stage_scopes()?;
+    let init_template = match &args.init_template {
+        Some(path) => fs::read_to_string(path)
+            .with_context(|| format!("--init-template {}", path.display()))?,
+        None => DEFAULT_INIT_TARGET_TEMPLATE.to_string(),
+    };
+
+    let export_diagnostics = args.export_diagnostics.clone();
+    let command = args.command.take();
+    let metrics_addr = args.metrics_addr;
+    let output_root = project_root.clone();
     let (service, socket) = {
-        let project_root = args.project_root.canonicalize()?;
-        LspService::new(move |client| {
-            let plugins = instantiate_plugins(&args.plugins, project_root.as_path())
+        let parse_timeout =
+            (args.parse_timeout_secs > 0).then(|| Duration::from_secs(args.parse_timeout_secs));
+        let workers = args
+            .isolate_parses
+            .then(|| -> Result<WorkerPool> {
+                Ok(WorkerPool::new(
+                    std::env::current_exe()?,
+                    worker_args(&args, &project_root),
+                    parse_timeout,
+                ))
+            })
+            .transpose()?;
+        ElysiumLsp::service(move |client| {
+            crash::set_client(client.clone());
+            let plugins = instantiate_plugins(&args, project_root.as_path(), parse_options.clone())
                 .expect("failed to initialize plugins");
+            let config = Config {
+                debounce: Duration::from_millis(args.debounce_ms),
+                reindex_on_save: args.reindex_on_save,
+                format_on_save: args.format_on_save,
+                init_template,
+                index_extensions: args.index_extensions(),
+                header_extensions: args.header_extensions.clone(),
+                index_roots: index_roots.clone(),
+                exclude_globs: exclude_globs.clone(),
+                diagnostics_ignore,
+                severity_overrides,
+                diagnostics_cap,
+                open_files_only: args.open_files_only,
+                companion: args.clangd_companion.clone(),
+                excluded_dirs: args.excluded_dirs.clone(),
+                respect_ignore_files: !args.no_ignore,
+                git_tracked_only: args.git_tracked_only,
+                path_style: args.path_style,
+                parse_jobs: args
+                    .jobs
+                    .unwrap_or_else(|| std::thread::available_parallelism().map_or(1, |n| n.get())),
+                parse_timeout,
+                memory_budget: args.memory_budget_mb.map(|mb| mb * 1024 * 1024),
+                cache_dir: (!args.no_cache).then(|| {
+                    args.cache_dir
+                        .clone()
+                        .unwrap_or_else(|| project_root.join(".cache").join("elysium-lsp"))
+                }),
+                compile_commands: args
+                    .compile_commands_dir(&project_root)
+                    .join("compile_commands.json"),
+                cache_key: format!(
+                    "{parse_options:?} {:?} {:?} {:?}",
+                    args.dependency_forms(),
+                    args.compile_commands_dir,
+                    args.clang_args
+                ),
+                profile: args.profile,
+                telemetry: args.telemetry,
+                libclang_error: libclang.err().map(|err| err.to_string()),
+            };
 
-            ElysiumLsp::new(client, project_root.clone(), plugins)
+            ElysiumLsp::new(client, config, project_root.clone(), plugins, workers)
         })
     };
+    let server = service.inner().clone();
+    if let Some(command) = command {
+        server.index_once().await?;
+        let symbols = server.symbols().await;
+        server.shut_down().await;
+        return match command {
+            Command::Docs { out } => docs::write(&out, &output_root, &symbols),
+            Command::Tags { out, format } => tags::write(&out, format, &symbols),
+            Command::Lsif { out } => lsif::write(&out, &output_root, &symbols),
+            Command::Completions { .. } | Command::Man => unreachable!("printed before indexing"),
+        };
+    }
+    if let Some(output) = export_diagnostics {
+        server.index_once().await?;
+        let exported = server.export_diagnostics();
+        server.shut_down().await;
+        let json = serde_json::to_string_pretty(&exported)?;
+        if output == Path::new("-") {
+            println!("{json}");
+        } else {
+            std::fs::write(&output, json)
+                .with_context(|| format!("writing {}", output.display()))?;
+        }
+        return Ok(());
+    }
+    if let Some(addr) = metrics_addr {
+        let listener = TcpListener::bind(addr)
+            .await
+            .with_context(|| format!("binding the metrics endpoint to {addr}"))?;
+        let report = server.clone();
+        server.spawn(metrics::serve(listener, move || report.metrics_report()));
+    }
     Server::new(stdin(), stdout(), socket).serve(service).await;
+    server.shut_down().await;
 
     Ok(())
 }
 
+impl Args {
+    /// Expands the placeholders in every argument naming a path or passed on to libclang.
+    fn expand_placeholders(&mut self, placeholders: &Placeholders) -> Result<()> {
+        let path = |flag: &str, path: &mut PathBuf| -> Result<()> {
+            *path = placeholders.expand_path(path).context(flag.to_string())?;
+            Ok(())
+        };
+        let optional_paths = [
+            ("--compile-commands-dir", &mut self.compile_commands_dir),
+            ("--init-template", &mut self.init_template),
+            ("--cache-dir", &mut self.cache_dir),
+            ("--preamble-dir", &mut self.preamble_dir),
+            ("--libclang-path", &mut self.libclang_path),
+            ("--export-diagnostics", &mut self.export_diagnostics),
+        ];
+        for (flag, value) in optional_paths {
+            if let Some(value) = value {
+                path(flag, value)?;
+            }
+        }
+        for root in &mut self.index_roots {
+            path("--index-root", root)?;
+        }
+        for pattern in &mut self.exclude_globs {
+            *pattern = placeholders.expand(pattern).context("--exclude")?;
+        }
+        for arg in &mut self.clang_args {
+            *arg = placeholders.expand(arg).context("--clang-arg")?;
+        }
+        Ok(())
+    }
+
+    fn compile_commands(&self, project_root: &Path) -> CompileCommands {
+        let default_args = DEFAULT_CLANG_ARGS.iter().map(|s| s.to_string()).collect();
+        CompileCommands::load(self.compile_commands_dir(project_root), default_args)
+            .with_extra_args(self.clang_args.clone())
+    }
+
+    fn compile_commands_dir(&self, project_root: &Path) -> PathBuf {
+        (self.compile_commands_dir.as_deref())
+            .map_or_else(|| project_root.to_path_buf(), |dir| project_root.join(dir))
+    }
+
+    fn hook_priorities(&self) -> Result<HookPriorities> {
+        HookPriorities::parse(&self.hook_priorities, self.hook_priority_range.as_deref())
+    }
+
+    fn stage_scopes(&self) -> Result<StageScopes> {
+        StageScopes::parse(&self.init_stage_scopes)
+    }
+
+    fn index_extensions(&self) -> Vec<String> {
+        let mut extensions = self.index_extensions.clone();
+        let cpp = CPP_EXTENSIONS
+            .iter()
+            .filter(|_| self.cpp)
+            .map(|ext| ext.to_string());
+        for ext in self.header_extensions.iter().cloned().chain(cpp) {
+            if !extensions.contains(&ext) {
+                extensions.push(ext);
+            }
+        }
+        extensions
+    }
+
+    fn dependency_forms(&self) -> DependencyForms {
+        DependencyForms {
+            identifiers: !self.no_init_identifier_dependencies,
+            ref_macros: self.init_ref_macros.clone(),
+        }
+    }
+}
+
 impl PluginChoice {
-    fn instantiate(&self, project_root: &Path) -> Result<Box<dyn LspPlugin>> {
+    fn instantiate(
+        &self,
+        args: &Args,
+        project_root: &Path,
+        parse_options: ParseOptions,
+    ) -> Result<Box<dyn LspPlugin>> {
         match self {
-            PluginChoice::InitDeps => Ok(Box::new(InitDependencyPlugin::new(project_root)?)),
-            PluginChoice::Hooks => Ok(Box::new(HookPlugin::new(project_root)?)),
+            PluginChoice::InitDeps => Ok(Box::new(
+                InitDependencyPlugin::new(project_root, parse_options)?
+                    .with_compile_commands(args.compile_commands(project_root))
+                    .with_roots(args.init_roots.clone())
+                    .with_stage_scopes(args.stage_scopes()?)
+                    .with_dependency_forms(args.dependency_forms())
+                    .with_dependency_style(DependencyStyle {
+                        max_line_length: args.dependency_line_length,
+                        sort: args.sort_dependencies,
+                    }),
+            )),
+            PluginChoice::Hooks => Ok(Box::new(
+                HookPlugin::new(project_root, parse_options)?
+                    .with_compile_commands(args.compile_commands(project_root))
+                    .with_priorities(args.hook_priorities()?),
+            )),
+        }
+    }
+}
+
+/// Arguments starting a parse worker with the same plugins and parse options as this server.
+fn worker_args(args: &Args, project_root: &Path) -> Vec<OsString> {
+    let mut worker_args: Vec<OsString> = vec![
+        "--parse-worker".into(),
+        "--project-root".into(),
+        project_root.into(),
+    ];
+    for plugin in &args.plugins {
+        if let Some(value) = plugin.to_possible_value() {
+            worker_args.extend(["--plugin".into(), value.get_name().into()]);
         }
     }
+    if args.parse_function_bodies {
+        worker_args.push("--parse-function-bodies".into());
+    }
+    if args.no_prescan {
+        worker_args.push("--no-prescan".into());
+    }
+    if args.cpp {
+        worker_args.push("--cpp".into());
+    }
+    for ext in &args.header_extensions {
+        worker_args.extend(["--header-extension".into(), ext.into()]);
+    }
+    worker_args.extend(["--kept-units".into(), args.kept_units.to_string().into()]);
+    for name in &args.init_ref_macros {
+        worker_args.extend(["--init-ref-macro".into(), name.into()]);
+    }
+    if let Some(dir) = &args.compile_commands_dir {
+        worker_args.extend(["--compile-commands-dir".into(), dir.into()]);
+    }
+    for arg in &args.clang_args {
+        worker_args.push(format!("--clang-arg={arg}").into());
+    }
+    if args.no_init_identifier_dependencies {
+        worker_args.push("--no-init-identifier-dependencies".into());
+    }
+    if let Some(path) = &args.libclang_path {
+        worker_args.extend(["--libclang-path".into(), path.into()]);
+    }
+    if let Some(level) = &args.log_level {
+        worker_args.extend(["--log-level".into(), level.into()]);
+    }
+    worker_args
 }
 
 fn instantiate_plugins(
-    selections: &[PluginChoice],
+    args: &Args,
     project_root: &Path,
+    parse_options: ParseOptions,
 ) -> Result<Vec<Box<dyn LspPlugin>>> {
     let mut plugins: Vec<Box<dyn LspPlugin>> = Vec::new();
-    for selection in selections {
-        plugins.push(selection.instantiate(project_root)?);
+    for selection in &args.plugins {
+        plugins.push(selection.instantiate(args, project_root, parse_options.clone())?);
     }
     Ok(plugins)
 }
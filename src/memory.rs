@@ -0,0 +1,64 @@
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+
+/// Least-recently-used bookkeeping for per-file parse data.
+///
+/// Evicted files keep what they define but not what they refer to, so requests reading references
+/// across files parse them again first. Their last published diagnostics are left in place until
+/// the file is parsed again.
+#[derive(Default)]
+pub struct MemoryTracker {
+    clock: u64,
+    last_used: HashMap<PathBuf, u64>,
+    evicted: HashSet<PathBuf>,
+}
+
+impl MemoryTracker {
+    pub fn touch(&mut self, path: &Path) {
+        self.clock += 1;
+        self.last_used.insert(path.to_path_buf(), self.clock);
+        self.evicted.remove(path);
+    }
+
+    pub fn forget(&mut self, path: &Path) {
+        self.last_used.remove(path);
+        self.evicted.remove(path);
+    }
+
+    pub fn mark_evicted(&mut self, path: PathBuf) {
+        self.evicted.insert(path);
+    }
+
+    /// Every indexed file, evicted or not.
+    pub fn tracked_paths(&self) -> impl Iterator<Item = &PathBuf> {
+        self.last_used.keys()
+    }
+
+    /// Number of indexed files, evicted or not.
+    pub fn tracked_files(&self) -> usize {
+        self.last_used.len()
+    }
+
+    pub fn evicted_paths(&self) -> impl Iterator<Item = &PathBuf> {
+        self.evicted.iter()
+    }
+
+    pub fn is_evicted(&self, path: &Path) -> bool {
+        self.evicted.contains(path)
+    }
+
+    /// Resident files not in `keep`, least recently used first.
+    pub fn eviction_candidates(&self, keep: &HashSet<PathBuf>) -> Vec<PathBuf> {
+        let mut candidates: Vec<(&PathBuf, u64)> = self
+            .last_used
+            .iter()
+            .filter(|(path, _)| !keep.contains(*path) && !self.evicted.contains(*path))
+            .map(|(path, used)| (path, *used))
+            .collect();
+        candidates.sort_by_key(|(_, used)| *used);
+        candidates
+            .into_iter()
+            .map(|(path, _)| path.clone())
+            .collect()
+    }
+}
@@ -0,0 +1,187 @@
+use std::fmt::Write as _;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use serde::Serialize;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpListener;
+use tokio::time::timeout;
+
+use crate::profile::{ProfileEntry, Profiler};
+
+/// Counters kept for every session, unlike the opt-in `--profile` timings.
+#[derive(Default)]
+pub struct Metrics {
+    parses: AtomicU64,
+    parse_failures: AtomicU64,
+    parse_timeouts: AtomicU64,
+    cache_hits: AtomicU64,
+    cache_misses: AtomicU64,
+    /// Time spent handling each kind of request and notification.
+    pub requests: Arc<Profiler>,
+}
+
+/// Response to the `elysium/metrics` request.
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MetricsReport {
+    pub parses: u64,
+    pub parse_failures: u64,
+    pub parse_timeouts: u64,
+    /// Files whose cached parse data was still current when the workspace was revalidated.
+    pub cache_hits: u64,
+    /// Files that had to be parsed during revalidation.
+    pub cache_misses: u64,
+    pub cache_hit_rate: Option<f64>,
+    pub queued_jobs: usize,
+    pub indexed_files: usize,
+    pub requests: Vec<ProfileEntry>,
+}
+
+/// How a parse job ended, for counting.
+pub enum ParseResult {
+    Parsed,
+    Failed,
+    TimedOut,
+}
+
+impl Metrics {
+    pub fn record_parse(&self, result: ParseResult) {
+        let counter = match result {
+            ParseResult::Parsed => &self.parses,
+            ParseResult::Failed => &self.parse_failures,
+            ParseResult::TimedOut => &self.parse_timeouts,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_cache(&self, hits: u64, misses: u64) {
+        self.cache_hits.fetch_add(hits, Ordering::Relaxed);
+        self.cache_misses.fetch_add(misses, Ordering::Relaxed);
+    }
+
+    pub fn report(&self, queued_jobs: usize, indexed_files: usize) -> MetricsReport {
+        let cache_hits = self.cache_hits.load(Ordering::Relaxed);
+        let cache_misses = self.cache_misses.load(Ordering::Relaxed);
+        let lookups = cache_hits + cache_misses;
+        MetricsReport {
+            parses: self.parses.load(Ordering::Relaxed),
+            parse_failures: self.parse_failures.load(Ordering::Relaxed),
+            parse_timeouts: self.parse_timeouts.load(Ordering::Relaxed),
+            cache_hits,
+            cache_misses,
+            cache_hit_rate: (lookups > 0).then(|| cache_hits as f64 / lookups as f64),
+            queued_jobs,
+            indexed_files,
+            requests: self.requests.summary(),
+        }
+    }
+}
+
+impl MetricsReport {
+    /// The report in the Prometheus text exposition format.
+    pub fn to_prometheus(&self) -> String {
+        let mut text = String::new();
+        let counters = [
+            ("parses_total", "Files parsed", self.parses),
+            (
+                "parse_failures_total",
+                "Parses that failed",
+                self.parse_failures,
+            ),
+            (
+                "parse_timeouts_total",
+                "Parses abandoned after the timeout",
+                self.parse_timeouts,
+            ),
+            (
+                "cache_hits_total",
+                "Files served from the index cache",
+                self.cache_hits,
+            ),
+            (
+                "cache_misses_total",
+                "Files the index cache had no current entry for",
+                self.cache_misses,
+            ),
+        ];
+        for (name, help, value) in counters {
+            metric(&mut text, name, help, "counter");
+            let _ = writeln!(text, "elysium_{name} {value}");
+        }
+        let gauges = [
+            ("queued_jobs", "Parse jobs waiting to run", self.queued_jobs),
+            (
+                "indexed_files",
+                "Files currently indexed",
+                self.indexed_files,
+            ),
+        ];
+        for (name, help, value) in gauges {
+            metric(&mut text, name, help, "gauge");
+            let _ = writeln!(text, "elysium_{name} {value}");
+        }
+
+        metric(
+            &mut text,
+            "request_duration_seconds",
+            "Time spent handling LSP messages",
+            "summary",
+        );
+        for entry in &self.requests {
+            let labels = format!("{{method=\"{}\"}}", entry.name);
+            let _ = writeln!(
+                text,
+                "elysium_request_duration_seconds_sum{labels} {}",
+                entry.total_ms / 1000.0
+            );
+            let _ = writeln!(
+                text,
+                "elysium_request_duration_seconds_count{labels} {}",
+                entry.count
+            );
+        }
+        text
+    }
+}
+
+fn metric(text: &mut String, name: &str, help: &str, kind: &str) {
+    let _ = writeln!(text, "# HELP elysium_{name} {help}");
+    let _ = writeln!(text, "# TYPE elysium_{name} {kind}");
+}
+
+/// How long a metrics client gets to send its request before it's answered anyway.
+const READ_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Pause after a failed accept, which tends to fail again right away, e.g. when out of file
+/// descriptors.
+const ACCEPT_RETRY_DELAY: Duration = Duration::from_millis(500);
+
+/// Answers every HTTP request on `listener` with the metrics `report` returns, in the Prometheus
+/// text format.
+pub async fn serve(listener: TcpListener, report: impl Fn() -> MetricsReport) {
+    loop {
+        let mut stream = match listener.accept().await {
+            Ok((stream, _)) => stream,
+            Err(err) => {
+                tracing::warn!("accepting a metrics connection: {err}");
+                tokio::time::sleep(ACCEPT_RETRY_DELAY).await;
+                continue;
+            }
+        };
+        let body = report().to_prometheus();
+        // Each connection gets its own task so a slow client can't stall the others.
+        tokio::spawn(async move {
+            // The request itself doesn't matter; every path serves the metrics.
+            let mut request = [0; 1024];
+            let _ = timeout(READ_TIMEOUT, stream.read(&mut request)).await;
+            let response = format!(
+                "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\n\
+                 Connection: close\r\n\r\n{body}",
+                body.len()
+            );
+            let _ = stream.write_all(response.as_bytes()).await;
+        });
+    }
+}
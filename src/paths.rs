@@ -0,0 +1,57 @@
+use std::path::{Component, Path, PathBuf};
+
+use clap::ValueEnum;
+
+/// How paths from the client and the workspace walk are turned into the keys files are indexed
+/// and reported under. Every path is normalized once, where it enters the server.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+#[value(rename_all = "kebab_case")]
+pub enum PathStyle {
+    /// Resolve symlinks, so a file reached through several links is indexed once.
+    #[default]
+    Canonical,
+    /// Keep paths as the client spells them, only resolving `.` and `..`, so results refer to the
+    /// same paths the editor opened even inside a symlinked workspace.
+    Logical,
+}
+
+impl PathStyle {
+    pub fn normalize(self, path: &Path) -> PathBuf {
+        match self {
+            PathStyle::Canonical => canonicalize(path),
+            PathStyle::Logical => lexical_normalize(path),
+        }
+    }
+}
+
+/// Deleted files can't be canonicalized themselves, so their parent directory is instead; that
+/// keeps a removed file under the key it was indexed with.
+fn canonicalize(path: &Path) -> PathBuf {
+    if let Ok(canonical) = path.canonicalize() {
+        return canonical;
+    }
+    let path = lexical_normalize(path);
+    match (path.parent(), path.file_name()) {
+        (Some(parent), Some(name)) => match parent.canonicalize() {
+            Ok(parent) => parent.join(name),
+            Err(_) => path,
+        },
+        _ => path,
+    }
+}
+
+fn lexical_normalize(path: &Path) -> PathBuf {
+    let mut normalized = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir => {
+                if !normalized.pop() {
+                    normalized.push(component);
+                }
+            }
+            component => normalized.push(component),
+        }
+    }
+    normalized
+}
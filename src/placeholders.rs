@@ -0,0 +1,125 @@
+//! `${...}` placeholders in command line values, so one editor configuration works on machines
+//! with different build paths and for every target architecture.
+
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Context, Result};
+
+pub struct Placeholders {
+    /// Substituted for `${workspaceRoot}`; `None` while the root itself is being expanded.
+    pub workspace_root: Option<PathBuf>,
+    /// Substituted for `${arch}`.
+    pub arch: String,
+}
+
+impl Placeholders {
+    /// Replaces `${env:VAR}`, `${workspaceRoot}` and `${arch}` in `value`. Unknown placeholders
+    /// and unset variables are errors, since silently dropping them would yield a wrong path.
+    pub fn expand(&self, value: &str) -> Result<String> {
+        let mut expanded = String::new();
+        let mut rest = value;
+        while let Some(start) = rest.find("${") {
+            expanded.push_str(&rest[..start]);
+            let Some(length) = rest[start..].find('}') else {
+                bail!("unterminated placeholder in {value:?}");
+            };
+            let name = &rest[start + 2..start + length];
+            match name {
+                "workspaceRoot" => {
+                    let Some(root) = &self.workspace_root else {
+                        bail!("${{workspaceRoot}} can't be used in the workspace root itself");
+                    };
+                    expanded.push_str(&root.to_string_lossy());
+                }
+                "arch" => expanded.push_str(&self.arch),
+                _ => {
+                    let Some(variable) = name.strip_prefix("env:") else {
+                        bail!("unknown placeholder ${{{name}}} in {value:?}");
+                    };
+                    let text = std::env::var(variable)
+                        .with_context(|| format!("environment variable {variable} in {value:?}"))?;
+                    expanded.push_str(&text);
+                }
+            }
+            rest = &rest[start + length + 1..];
+        }
+        expanded.push_str(rest);
+        Ok(expanded)
+    }
+
+    /// Like [`expand`](Self::expand); paths that aren't valid UTF-8 are left as they are.
+    pub fn expand_path(&self, path: &Path) -> Result<PathBuf> {
+        match path.to_str() {
+            Some(text) => Ok(self.expand(text)?.into()),
+            None => Ok(path.to_path_buf()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn placeholders(workspace_root: Option<&str>) -> Placeholders {
+        Placeholders {
+            workspace_root: workspace_root.map(PathBuf::from),
+            arch: "x86_64".to_string(),
+        }
+    }
+
+    #[test]
+    fn expands_every_placeholder() {
+        std::env::set_var("ELYSIUM_LSP_TEST_SYSROOT", "/opt/sysroot");
+        let expanded = placeholders(Some("/src/elysium"))
+            .expand("${workspaceRoot}/build-${arch}:${env:ELYSIUM_LSP_TEST_SYSROOT}")
+            .unwrap();
+        assert_eq!(expanded, "/src/elysium/build-x86_64:/opt/sysroot");
+    }
+
+    #[test]
+    fn leaves_text_without_placeholders_alone() {
+        let expanded = placeholders(None).expand("$HOME/{x}").unwrap();
+        assert_eq!(expanded, "$HOME/{x}");
+    }
+
+    #[test]
+    fn rejects_an_unterminated_placeholder() {
+        let err = placeholders(None).expand("build-${arch").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "unterminated placeholder in \"build-${arch\""
+        );
+    }
+
+    #[test]
+    fn rejects_an_unknown_placeholder() {
+        let err = placeholders(None).expand("${home}/src").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "unknown placeholder ${home} in \"${home}/src\""
+        );
+    }
+
+    #[test]
+    fn rejects_an_unset_variable() {
+        std::env::remove_var("ELYSIUM_LSP_TEST_UNSET");
+        let err = placeholders(None)
+            .expand("${env:ELYSIUM_LSP_TEST_UNSET}")
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "environment variable ELYSIUM_LSP_TEST_UNSET in \"${env:ELYSIUM_LSP_TEST_UNSET}\""
+        );
+    }
+
+    #[test]
+    fn rejects_the_workspace_root_in_itself() {
+        let err = placeholders(None)
+            .expand("${workspaceRoot}/kernel")
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "${workspaceRoot} can't be used in the workspace root itself"
+        );
+    }
+}
@@ -0,0 +1,619 @@
+//! Safe wrappers over the parts of libclang the plugins use. All `unsafe` FFI lives here; each
+//! type disposes its libclang object on drop and borrows whatever it was created from, so nothing
+//! outlives the translation unit it points into. Tokens are copied into owned Rust data.
+
+use std::borrow::Cow;
+use std::ffi::{c_char, c_int, c_uint, c_ulong, CStr, CString};
+use std::marker::PhantomData;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, OnceLock};
+
+use anyhow::{anyhow, Context, Result};
+use clang_sys::{
+    clang_Cursor_isMacroFunctionLike, clang_Location_isFromMainFile, clang_createIndex,
+    clang_defaultReparseOptions, clang_disposeIndex, clang_disposeSourceRangeList,
+    clang_disposeString, clang_disposeTokens, clang_disposeTranslationUnit, clang_getCString,
+    clang_getCursorExtent, clang_getCursorKind, clang_getCursorLocation, clang_getCursorSpelling,
+    clang_getFile, clang_getFileContents, clang_getFileLocation, clang_getFileName,
+    clang_getRangeEnd, clang_getRangeStart, clang_getSkippedRanges, clang_getTokenExtent,
+    clang_getTokenKind, clang_getTokenSpelling, clang_getTranslationUnitCursor,
+    clang_isCursorDefinition, clang_parseTranslationUnit, clang_reparseTranslationUnit,
+    clang_tokenize, clang_visitChildren, CXChildVisitResult, CXChildVisit_Recurse, CXClientData,
+    CXCursor, CXCursor_FunctionDecl, CXCursor_MacroDefinition, CXCursor_MacroExpansion, CXFile,
+    CXIndex, CXSourceLocation, CXSourceRange, CXString, CXToken, CXToken_Comment,
+    CXToken_Identifier, CXToken_Keyword, CXToken_Literal, CXTranslationUnit,
+    CXTranslationUnit_Flags, CXUnsavedFile, SharedLibrary, Version,
+};
+use tower_lsp::lsp_types::{Position, Range};
+
+use super::PositionEncoding;
+
+/// Oldest libclang providing every function used here.
+const MIN_LIBCLANG_VERSION: Version = Version::V6_0;
+
+static LIBCLANG: OnceLock<std::result::Result<Arc<SharedLibrary>, String>> = OnceLock::new();
+
+/// Finds and loads libclang from wherever `LIBCLANG_PATH` (a file or a directory), `llvm-config`
+/// or the system search paths lead. Only the first call loads; later ones return its outcome.
+/// Returns a description of the library that was loaded.
+pub fn load_libclang() -> Result<String> {
+    let library = LIBCLANG.get_or_init(|| {
+        let library = clang_sys::load_manually().map_err(|err| {
+            format!("{err}\nInstall libclang or point --libclang-path at libclang.so.")
+        })?;
+        match library.version() {
+            Some(version) if version >= MIN_LIBCLANG_VERSION => Ok(Arc::new(library)),
+            version => Err(format!(
+                "libclang at {} is too old ({}); version {MIN_LIBCLANG_VERSION} or later is \
+                 required. Point --libclang-path at a newer libclang.so.",
+                library.path().display(),
+                version.map_or("3.4 or earlier".to_string(), |version| version.to_string()),
+            )),
+        }
+    });
+    match library {
+        Ok(library) => Ok(format!(
+            "libclang {} at {}",
+            library.version().map_or(String::new(), |v| v.to_string()),
+            library.path().display()
+        )),
+        Err(err) => Err(anyhow!(err.clone())),
+    }
+}
+
+/// libclang functions resolve through a per-thread handle, so every thread touching libclang
+/// gets the library loaded by `load_libclang` first.
+fn use_libclang() {
+    if !clang_sys::is_loaded() {
+        if let Some(Ok(library)) = LIBCLANG.get() {
+            clang_sys::set_library(Some(library.clone()));
+        }
+    }
+}
+
+/// Long-lived libclang index reused across every translation unit a plugin parses.
+pub struct Index(CXIndex);
+
+// SAFETY: an index is only ever used by one thread at a time; see `IndexPool`.
+unsafe impl Send for Index {}
+
+impl Index {
+    fn new() -> Self {
+        use_libclang();
+        Self(unsafe { clang_createIndex(0, 0) })
+    }
+
+    /// Parses `path` with the given compiler arguments, using `content` instead of the file on
+    /// disk when given.
+    pub fn parse(
+        &self,
+        path: &Path,
+        args: &[String],
+        content: Option<&str>,
+        flags: CXTranslationUnit_Flags,
+    ) -> Result<TranslationUnit<'_>> {
+        let filename =
+            CString::new(path.as_os_str().to_string_lossy().into_owned()).context("path encode")?;
+        let arg_cstrings: Vec<CString> = args
+            .iter()
+            .map(|a| CString::new(a.as_str()))
+            .collect::<std::result::Result<_, _>>()?;
+        let arg_ptrs: Vec<*const c_char> = arg_cstrings.iter().map(|s| s.as_ptr()).collect();
+
+        // Unsaved contents are passed by length, so they may contain NUL bytes.
+        let mut unsaved_files: Vec<CXUnsavedFile> = content
+            .iter()
+            .map(|text| CXUnsavedFile {
+                Filename: filename.as_ptr(),
+                Contents: text.as_ptr() as *const c_char,
+                Length: text.len() as c_ulong,
+            })
+            .collect();
+
+        let raw = unsafe {
+            clang_parseTranslationUnit(
+                self.0,
+                filename.as_ptr(),
+                if arg_ptrs.is_empty() {
+                    std::ptr::null()
+                } else {
+                    arg_ptrs.as_ptr()
+                },
+                arg_ptrs.len() as c_int,
+                if unsaved_files.is_empty() {
+                    std::ptr::null_mut()
+                } else {
+                    unsaved_files.as_mut_ptr()
+                },
+                unsaved_files.len() as c_uint,
+                flags,
+            )
+        };
+        if raw.is_null() {
+            return Err(anyhow!("Unable to parse {} with libclang", path.display()));
+        }
+        Ok(TranslationUnit {
+            raw,
+            _index: PhantomData,
+        })
+    }
+}
+
+impl Drop for Index {
+    fn drop(&mut self) {
+        use_libclang();
+        unsafe { clang_disposeIndex(self.0) };
+    }
+}
+
+/// Idle indexes handed out one per concurrent parse and returned afterwards for reuse.
+#[derive(Default)]
+pub struct IndexPool {
+    idle: Mutex<Vec<Index>>,
+}
+
+impl IndexPool {
+    pub fn with_index<T>(&self, f: impl FnOnce(&Index) -> T) -> T {
+        let index = self.idle.lock().unwrap().pop().unwrap_or_else(Index::new);
+        use_libclang();
+        let result = f(&index);
+        self.idle.lock().unwrap().push(index);
+        result
+    }
+}
+
+pub struct TranslationUnit<'i> {
+    raw: CXTranslationUnit,
+    _index: PhantomData<&'i Index>,
+}
+
+/// A translation unit together with its own index, kept between parses of a file so that
+/// [`OwnedTranslationUnit::reparse`] can build on it.
+pub struct OwnedTranslationUnit {
+    // Declared before `_index` so the unit is disposed of first.
+    unit: TranslationUnit<'static>,
+    _index: Index,
+}
+
+// SAFETY: a unit is only ever used by one thread at a time; see `UnitCache`.
+unsafe impl Send for OwnedTranslationUnit {}
+
+impl OwnedTranslationUnit {
+    pub fn parse(
+        path: &Path,
+        args: &[String],
+        content: Option<&str>,
+        flags: CXTranslationUnit_Flags,
+    ) -> Result<Self> {
+        let index = Index::new();
+        let parsed = index.parse(path, args, content, flags)?;
+        let raw = parsed.raw;
+        std::mem::forget(parsed);
+        Ok(Self {
+            unit: TranslationUnit {
+                raw,
+                _index: PhantomData,
+            },
+            _index: index,
+        })
+    }
+
+    /// Parses the unit again with `content` as the text of `path`, reusing the state libclang
+    /// kept from the previous parse. After a failure the unit can only be dropped.
+    pub fn reparse(&mut self, path: &Path, content: &str) -> Result<()> {
+        use_libclang();
+        let filename =
+            CString::new(path.as_os_str().to_string_lossy().into_owned()).context("path encode")?;
+        let mut unsaved = CXUnsavedFile {
+            Filename: filename.as_ptr(),
+            Contents: content.as_ptr() as *const c_char,
+            Length: content.len() as c_ulong,
+        };
+        let raw = self.unit.raw;
+        let code = unsafe {
+            clang_reparseTranslationUnit(raw, 1, &mut unsaved, clang_defaultReparseOptions(raw))
+        };
+        if code != 0 {
+            return Err(anyhow!(
+                "Unable to reparse {} with libclang (error {code})",
+                path.display()
+            ));
+        }
+        Ok(())
+    }
+
+    pub fn unit(&self) -> &TranslationUnit<'_> {
+        use_libclang();
+        &self.unit
+    }
+}
+
+impl TranslationUnit<'_> {
+    pub fn cursor(&self) -> Cursor<'_> {
+        Cursor {
+            raw: unsafe { clang_getTranslationUnitCursor(self.raw) },
+            tu: self.raw,
+            _tu: PhantomData,
+        }
+    }
+}
+
+impl TranslationUnit<'_> {
+    /// Preprocessor regions of `path` the parse skipped, such as the body of an `#if 0`.
+    pub fn skipped_ranges(&self, path: &Path) -> Vec<Range> {
+        let Ok(filename) = CString::new(path.as_os_str().to_string_lossy().into_owned()) else {
+            return Vec::new();
+        };
+        unsafe {
+            let file = clang_getFile(self.raw, filename.as_ptr());
+            if file.is_null() {
+                return Vec::new();
+            }
+            let list = clang_getSkippedRanges(self.raw, file);
+            if list.is_null() {
+                return Vec::new();
+            }
+            let ranges = if (*list).ranges.is_null() {
+                Vec::new()
+            } else {
+                std::slice::from_raw_parts((*list).ranges, (*list).count as usize)
+                    .iter()
+                    .filter_map(|&range| source_range(self.raw, range))
+                    .collect()
+            };
+            clang_disposeSourceRangeList(list);
+            ranges
+        }
+    }
+}
+
+impl Drop for TranslationUnit<'_> {
+    fn drop(&mut self) {
+        // Kept units can be dropped on a thread that hasn't touched libclang yet.
+        use_libclang();
+        unsafe { clang_disposeTranslationUnit(self.raw) };
+    }
+}
+
+#[derive(Clone, Copy)]
+pub struct Cursor<'tu> {
+    raw: CXCursor,
+    tu: CXTranslationUnit,
+    _tu: PhantomData<&'tu ()>,
+}
+
+impl<'tu> Cursor<'tu> {
+    pub fn is_macro_expansion(&self) -> bool {
+        unsafe { clang_getCursorKind(self.raw) == CXCursor_MacroExpansion }
+    }
+
+    pub fn is_macro_definition(&self) -> bool {
+        unsafe { clang_getCursorKind(self.raw) == CXCursor_MacroDefinition }
+    }
+
+    pub fn is_macro_function_like(&self) -> bool {
+        unsafe { clang_Cursor_isMacroFunctionLike(self.raw) != 0 }
+    }
+
+    pub fn is_function_definition(&self) -> bool {
+        unsafe {
+            clang_getCursorKind(self.raw) == CXCursor_FunctionDecl
+                && clang_isCursorDefinition(self.raw) != 0
+        }
+    }
+
+    /// Whether the cursor is in the parsed file itself rather than in a header it includes.
+    pub fn is_in_main_file(&self) -> bool {
+        unsafe { clang_Location_isFromMainFile(clang_getCursorLocation(self.raw)) != 0 }
+    }
+
+    pub fn spelling(&self) -> String {
+        unsafe { cxstring_to_string(clang_getCursorSpelling(self.raw)) }
+    }
+
+    /// The file the cursor is located in, as it was named to the parse or found on the include
+    /// path, along with the text libclang parsed for it.
+    pub fn file(&self) -> Option<(PathBuf, Cow<'tu, str>)> {
+        let mut file: CXFile = std::ptr::null_mut();
+        unsafe {
+            clang_getFileLocation(
+                clang_getCursorLocation(self.raw),
+                &mut file,
+                std::ptr::null_mut(),
+                std::ptr::null_mut(),
+                std::ptr::null_mut(),
+            );
+            let contents = file_contents(self.tu, file)?;
+            let name = cxstring_to_string(clang_getFileName(file));
+            Some((PathBuf::from(name), String::from_utf8_lossy(contents)))
+        }
+    }
+
+    pub fn range(&self) -> Option<Range> {
+        unsafe { source_range(self.tu, clang_getCursorExtent(self.raw)) }
+    }
+
+    /// The tokens making up this cursor's extent, copied out of libclang.
+    pub fn tokens(&self) -> TokenSet {
+        let mut ptr: *mut CXToken = std::ptr::null_mut();
+        let mut len: c_uint = 0;
+        unsafe { clang_tokenize(self.tu, clang_getCursorExtent(self.raw), &mut ptr, &mut len) };
+        if ptr.is_null() {
+            return TokenSet::default();
+        }
+        let tokens = unsafe {
+            let tokens = std::slice::from_raw_parts(ptr, len as usize)
+                .iter()
+                .map(|&raw| Token::extract(self.tu, raw))
+                .collect();
+            clang_disposeTokens(self.tu, ptr, len);
+            tokens
+        };
+        TokenSet { tokens }
+    }
+
+    /// Calls `f` for every cursor below this one, depth first.
+    pub fn visit_descendants(&self, mut f: impl FnMut(Cursor<'tu>)) {
+        let tu = self.tu;
+        let mut callback = |raw: CXCursor| {
+            f(Cursor {
+                raw,
+                tu,
+                _tu: PhantomData,
+            })
+        };
+        let mut callback: &mut dyn FnMut(CXCursor) = &mut callback;
+        unsafe {
+            clang_visitChildren(
+                self.raw,
+                visit_trampoline,
+                &mut callback as *mut &mut dyn FnMut(CXCursor) as CXClientData,
+            );
+        }
+    }
+}
+
+extern "C" fn visit_trampoline(
+    cursor: CXCursor,
+    _parent: CXCursor,
+    data: CXClientData,
+) -> CXChildVisitResult {
+    let callback = unsafe { &mut *(data as *mut &mut dyn FnMut(CXCursor)) };
+    callback(cursor);
+    CXChildVisit_Recurse
+}
+
+/// Tokens of a cursor with everything the plugins need already extracted, so they stay valid
+/// after libclang's token buffer is disposed.
+#[derive(Default)]
+pub struct TokenSet {
+    tokens: Vec<Token>,
+}
+
+impl TokenSet {
+    pub fn tokens(&self) -> &[Token] {
+        &self.tokens
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TokenKind {
+    Punctuation,
+    Keyword,
+    Identifier,
+    Literal,
+    Comment,
+}
+
+pub struct Token {
+    pub spelling: String,
+    pub kind: TokenKind,
+    pub range: Option<Range>,
+}
+
+impl Token {
+    unsafe fn extract(tu: CXTranslationUnit, raw: CXToken) -> Self {
+        #[allow(non_upper_case_globals)]
+        let kind = match clang_getTokenKind(raw) {
+            CXToken_Keyword => TokenKind::Keyword,
+            CXToken_Identifier => TokenKind::Identifier,
+            CXToken_Literal => TokenKind::Literal,
+            CXToken_Comment => TokenKind::Comment,
+            _ => TokenKind::Punctuation,
+        };
+        Self {
+            spelling: cxstring_to_string(clang_getTokenSpelling(tu, raw)),
+            kind,
+            range: source_range(tu, clang_getTokenExtent(tu, raw)),
+        }
+    }
+}
+
+/// Splits the tokens of a function-like macro invocation into its top-level arguments. Commas
+/// nested in parentheses, braces or brackets don't split, so a struct literal stays one argument.
+pub fn split_macro_args(tokens: &[Token]) -> Vec<&[Token]> {
+    let mut args = Vec::new();
+    let mut start = None;
+    let mut depth = 0;
+    for (i, token) in tokens.iter().enumerate() {
+        match token.spelling.as_str() {
+            "(" if start.is_none() => start = Some(i + 1),
+            "(" | "{" | "[" => depth += 1,
+            ")" if depth == 0 => {
+                args.push(&tokens[start.unwrap_or(i)..i]);
+                break;
+            }
+            ")" | "}" | "]" => depth -= 1,
+            "," if depth == 0 => {
+                if let Some(arg_start) = start {
+                    args.push(&tokens[arg_start..i]);
+                    start = Some(i + 1);
+                }
+            }
+            _ => {}
+        }
+    }
+    args
+}
+
+/// Splits a comma-separated list, such as the contents of an initializer, at its top-level
+/// commas. A trailing comma doesn't produce an empty entry.
+pub fn split_list(tokens: &[Token]) -> Vec<&[Token]> {
+    let mut entries = Vec::new();
+    let mut start = 0;
+    let mut depth = 0usize;
+    for (i, token) in tokens.iter().enumerate() {
+        match token.spelling.as_str() {
+            "(" | "{" | "[" => depth += 1,
+            ")" | "}" | "]" => depth = depth.saturating_sub(1),
+            "," if depth == 0 => {
+                entries.push(&tokens[start..i]);
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    if start < tokens.len() {
+        entries.push(&tokens[start..]);
+    }
+    entries
+}
+
+/// The contents of `{ ... }` when the tokens are exactly one braced initializer, optionally
+/// preceded by a compound literal cast like `(struct init_target)`.
+pub fn braced_contents(tokens: &[Token]) -> Option<&[Token]> {
+    let mut tokens = tokens;
+    if tokens.first()?.spelling == "(" {
+        let close = tokens.iter().position(|token| token.spelling == ")")?;
+        tokens = &tokens[close + 1..];
+    }
+    let (open, rest) = tokens.split_first()?;
+    let (close, contents) = rest.split_last()?;
+    if open.spelling != "{" || close.spelling != "}" {
+        return None;
+    }
+    // In `{ a } , { b }` the outer braces don't pair up.
+    let mut depth = 0usize;
+    for token in contents {
+        match token.spelling.as_str() {
+            "(" | "{" | "[" => depth += 1,
+            ")" | "}" | "]" => depth = depth.checked_sub(1)?,
+            _ => {}
+        }
+    }
+    Some(contents)
+}
+
+/// A `.field = value` entry of a designated initializer.
+pub struct DesignatedField<'a> {
+    pub name: &'a Token,
+    pub value: &'a [Token],
+}
+
+impl<'a> DesignatedField<'a> {
+    fn parse(entry: &'a [Token]) -> Option<Self> {
+        match entry {
+            [dot, name, equals, value @ ..]
+                if dot.spelling == "."
+                    && name.kind == TokenKind::Identifier
+                    && equals.spelling == "=" =>
+            {
+                Some(Self { name, value })
+            }
+            _ => None,
+        }
+    }
+}
+
+/// The designated fields of a struct literal passed to a macro, either spread over its arguments
+/// as in `REGISTER(.name = "x", .deps = { "a" })` or as one braced argument. Positional entries
+/// are left out; an empty result means the invocation uses no designators.
+pub fn designated_fields<'a>(args: &[&'a [Token]]) -> Vec<DesignatedField<'a>> {
+    let entries = match args {
+        [single] => match braced_contents(single) {
+            Some(contents) => split_list(contents),
+            None => args.to_vec(),
+        },
+        _ => args.to_vec(),
+    };
+    entries
+        .into_iter()
+        .filter_map(DesignatedField::parse)
+        .collect()
+}
+
+/// The value of the designated field called `name`, if present.
+pub fn field_value<'a>(fields: &[DesignatedField<'a>], name: &str) -> Option<&'a [Token]> {
+    fields
+        .iter()
+        .find(|field| field.name.spelling == name)
+        .map(|field| field.value)
+}
+
+pub fn tokens_to_string(tokens: &[Token]) -> String {
+    tokens.iter().map(|token| token.spelling.as_str()).collect()
+}
+
+pub fn tokens_range(tokens: &[Token]) -> Option<Range> {
+    let start = tokens.first()?.range?.start;
+    let end = tokens.last()?.range?.end;
+    Some(Range { start, end })
+}
+
+unsafe fn source_range(tu: CXTranslationUnit, extent: CXSourceRange) -> Option<Range> {
+    Some(Range {
+        start: location_to_position(tu, clang_getRangeStart(extent))?,
+        end: location_to_position(tu, clang_getRangeEnd(extent))?,
+    })
+}
+
+/// libclang reports byte columns; LSP positions may count UTF-16 code units instead, which differ
+/// on lines with non-ASCII text before the location.
+unsafe fn location_to_position(
+    tu: CXTranslationUnit,
+    location: CXSourceLocation,
+) -> Option<Position> {
+    let mut file: CXFile = std::ptr::null_mut();
+    let mut line = 0;
+    let mut column = 0;
+    let mut offset = 0;
+    clang_getFileLocation(location, &mut file, &mut line, &mut column, &mut offset);
+    let byte_column = column.saturating_sub(1);
+    let encoding = PositionEncoding::current();
+    if encoding == PositionEncoding::Utf8 {
+        return Some(Position::new(line.saturating_sub(1), byte_column));
+    }
+    let character = file_contents(tu, file)
+        .and_then(|contents| {
+            let end = offset as usize;
+            let line_prefix = contents.get(end.checked_sub(byte_column as usize)?..end)?;
+            // Invalid UTF-8 counts one unit per replacement character.
+            Some(encoding.len(&String::from_utf8_lossy(line_prefix)))
+        })
+        .unwrap_or(byte_column);
+    Some(Position::new(line.saturating_sub(1), character))
+}
+
+/// The buffer libclang parsed for `file`, which stays alive as long as `tu`.
+unsafe fn file_contents<'tu>(tu: CXTranslationUnit, file: CXFile) -> Option<&'tu [u8]> {
+    if file.is_null() {
+        return None;
+    }
+    let mut size = 0;
+    let data = clang_getFileContents(tu, file, &mut size);
+    if data.is_null() {
+        return None;
+    }
+    Some(std::slice::from_raw_parts(data as *const u8, size))
+}
+
+unsafe fn cxstring_to_string(s: CXString) -> String {
+    let c_str = clang_getCString(s);
+    let result = if c_str.is_null() {
+        String::new()
+    } else {
+        CStr::from_ptr(c_str).to_string_lossy().into_owned()
+    };
+    clang_disposeString(s);
+    result
+}
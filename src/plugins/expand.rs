@@ -0,0 +1,411 @@
+//! Textual macro expansion for previewing what registration macros like `HOOK` and `INIT_TARGET`
+//! emit. libclang doesn't expose the preprocessor's expansions, so the definitions it recorded are
+//! replayed here: argument substitution, `#`, `##`, `__VA_ARGS__` and rescanning, which covers the
+//! macros Cronus uses. The definitions also back the hovers on those macros' names.
+
+use std::collections::{HashMap, HashSet};
+use std::path::PathBuf;
+
+use serde::Serialize;
+use tower_lsp::lsp_types::{Position, Range};
+
+use super::clang::{TokenKind, TranslationUnit};
+use super::{doc_comment_above, range_contains};
+
+/// Nesting beyond this is left unexpanded, as a guard against runaway recursion.
+const MAX_DEPTH: usize = 64;
+
+/// Prefixed to a macro name met within its own expansion. C never expands such a name, even when
+/// it is rescanned once that expansion is done, and no definition is named like the prefixed one.
+const PAINTED: char = '\u{E000}';
+
+/// Result of the `elysium.expandMacro` command.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MacroExpansion {
+    #[serde(rename = "macro")]
+    pub name: String,
+    /// The invocation that was expanded.
+    pub range: Range,
+    pub expansion: String,
+}
+
+/// Where and how a registration macro is defined, for hovering its name.
+#[derive(Debug)]
+pub struct MacroSource {
+    pub name: String,
+    /// The macro name at the hovered invocation.
+    pub range: Range,
+    pub file: PathBuf,
+    /// The `#define` line, with any continuation lines.
+    pub definition: String,
+    /// The comment above the definition.
+    pub documentation: Option<String>,
+}
+
+struct MacroDefinition {
+    /// `None` for object-like macros.
+    params: Option<Vec<String>>,
+    body: Vec<String>,
+}
+
+/// Expands the invocation of one of `names` at `position` in the parsed file.
+pub fn expand_macro_at(
+    tu: &TranslationUnit,
+    position: &Position,
+    names: &[&str],
+) -> Option<MacroExpansion> {
+    let mut definitions = HashMap::new();
+    let mut invocation = None;
+    tu.cursor().visit_descendants(|cursor| {
+        if cursor.is_macro_definition() {
+            let tokens = cursor.tokens();
+            let spellings: Vec<String> = (tokens.tokens().iter())
+                .map(|token| token.spelling.clone())
+                .collect();
+            if let Some((name, definition)) =
+                parse_definition(spellings, cursor.is_macro_function_like())
+            {
+                definitions.insert(name, definition);
+            }
+        } else if invocation.is_none()
+            && cursor.is_macro_expansion()
+            && cursor.is_in_main_file()
+            && names.contains(&cursor.spelling().as_str())
+        {
+            if let Some(range) = cursor
+                .range()
+                .filter(|range| range_contains(range, position))
+            {
+                let tokens = cursor.tokens();
+                let tokens: Vec<String> = (tokens.tokens().iter())
+                    .filter(|token| token.kind != TokenKind::Comment)
+                    .map(|token| token.spelling.clone())
+                    .collect();
+                invocation = Some((cursor.spelling(), range, tokens));
+            }
+        }
+    });
+
+    let (name, range, tokens) = invocation?;
+    let expanded = expand(&tokens, &definitions, &mut HashSet::new(), 0);
+    Some(MacroExpansion {
+        name,
+        range,
+        expansion: join_tokens(&expanded),
+    })
+}
+
+/// Finds the definition of the macro among `names` whose name, at an invocation in the parsed
+/// file, is at `position`.
+pub fn macro_source_at(
+    tu: &TranslationUnit,
+    position: &Position,
+    names: &[&str],
+) -> Option<MacroSource> {
+    let mut definitions = HashMap::new();
+    let mut invocation = None;
+    tu.cursor().visit_descendants(|cursor| {
+        if cursor.is_macro_definition() {
+            let name = cursor.spelling();
+            if names.contains(&name.as_str()) {
+                if let (Some((file, text)), Some(range)) = (cursor.file(), cursor.range()) {
+                    let lines = text.lines().skip(range.start.line as usize);
+                    let lines: Vec<&str> = lines
+                        .take((range.end.line - range.start.line) as usize + 1)
+                        .collect();
+                    let source = MacroSource {
+                        name: name.clone(),
+                        range: Range::default(),
+                        file,
+                        definition: lines.join("\n"),
+                        documentation: doc_comment_above(&text, range.start.line),
+                    };
+                    definitions.insert(name, source);
+                }
+            }
+        } else if invocation.is_none() && cursor.is_macro_expansion() && cursor.is_in_main_file() {
+            let name = cursor.spelling();
+            let Some(start) = cursor.range().map(|range| range.start) else {
+                return;
+            };
+            let end = Position::new(start.line, start.character + name.len() as u32);
+            let range = Range::new(start, end);
+            if names.contains(&name.as_str()) && range_contains(&range, position) {
+                invocation = Some((name, range));
+            }
+        }
+    });
+
+    let (name, range) = invocation?;
+    let source = definitions.remove(&name)?;
+    Some(MacroSource { range, ..source })
+}
+
+/// Splits the tokens of a definition, which start at the macro name, into its parts.
+fn parse_definition(tokens: Vec<String>, function_like: bool) -> Option<(String, MacroDefinition)> {
+    let mut tokens = tokens.into_iter();
+    let name = tokens.next()?;
+    let mut rest: Vec<String> = tokens.collect();
+    let params = if function_like {
+        let close = rest.iter().position(|token| token == ")")?;
+        let params = rest[1..close]
+            .iter()
+            .filter(|token| *token != ",")
+            .map(|param| {
+                if param == "..." {
+                    "__VA_ARGS__".into()
+                } else {
+                    param.clone()
+                }
+            })
+            .collect();
+        rest.drain(..=close);
+        Some(params)
+    } else {
+        None
+    };
+    Some((name, MacroDefinition { params, body: rest }))
+}
+
+fn expand(
+    tokens: &[String],
+    definitions: &HashMap<String, MacroDefinition>,
+    disabled: &mut HashSet<String>,
+    depth: usize,
+) -> Vec<String> {
+    let mut output = Vec::new();
+    let mut i = 0;
+    while i < tokens.len() {
+        let token = &tokens[i];
+        if disabled.contains(token) {
+            output.push(format!("{PAINTED}{token}"));
+            i += 1;
+            continue;
+        }
+        let definition = (depth < MAX_DEPTH)
+            .then(|| definitions.get(token))
+            .flatten();
+        let Some(definition) = definition else {
+            output.push(token.clone());
+            i += 1;
+            continue;
+        };
+
+        let (replacement, consumed) = match &definition.params {
+            None => (definition.body.clone(), 1),
+            Some(params) => {
+                if tokens.get(i + 1).map(String::as_str) != Some("(") {
+                    output.push(token.clone());
+                    i += 1;
+                    continue;
+                }
+                let Some(close) = matching_paren(tokens, i + 1) else {
+                    output.push(token.clone());
+                    i += 1;
+                    continue;
+                };
+                let args = macro_args(&tokens[i + 1..=close], params.len());
+                let replacement =
+                    substitute(definition, params, &args, definitions, disabled, depth);
+                (replacement, close + 1 - i)
+            }
+        };
+
+        disabled.insert(token.clone());
+        output.extend(expand(&replacement, definitions, disabled, depth + 1));
+        disabled.remove(token);
+        i += consumed;
+    }
+    output
+}
+
+/// The body of a function-like macro with its parameters replaced by `args`.
+fn substitute(
+    definition: &MacroDefinition,
+    params: &[String],
+    args: &[Vec<String>],
+    definitions: &HashMap<String, MacroDefinition>,
+    disabled: &mut HashSet<String>,
+    depth: usize,
+) -> Vec<String> {
+    let arg = |name: &str| {
+        let index = params.iter().position(|param| param == name)?;
+        Some(args.get(index).cloned().unwrap_or_default())
+    };
+    let body = &definition.body;
+    let mut output: Vec<String> = Vec::new();
+    let mut i = 0;
+    while i < body.len() {
+        let token = &body[i];
+        if token == "#" {
+            if let Some(arg) = body.get(i + 1).and_then(|name| arg(name)) {
+                output.push(stringify(&arg));
+                i += 2;
+                continue;
+            }
+        }
+        if token == "##" {
+            let next = body.get(i + 1).map_or_else(Vec::new, |next| {
+                arg(next).unwrap_or_else(|| vec![next.clone()])
+            });
+            let mut next = next.into_iter();
+            match (output.pop(), next.next()) {
+                (Some(left), Some(right)) => {
+                    output.push(unpainted(&left).to_string() + unpainted(&right))
+                }
+                (left, right) => output.extend(left.into_iter().chain(right)),
+            }
+            output.extend(next);
+            i += 2;
+            continue;
+        }
+        match arg(token) {
+            // Operands of `##` are pasted unexpanded; everything else is expanded first.
+            Some(arg) if body.get(i + 1).map(String::as_str) == Some("##") => output.extend(arg),
+            Some(arg) => output.extend(expand(&arg, definitions, disabled, depth + 1)),
+            None => output.push(token.clone()),
+        }
+        i += 1;
+    }
+    output
+}
+
+fn matching_paren(tokens: &[String], open: usize) -> Option<usize> {
+    let mut depth = 0;
+    for (i, token) in tokens.iter().enumerate().skip(open) {
+        match token.as_str() {
+            "(" => depth += 1,
+            ")" => {
+                depth -= 1;
+                if depth == 0 {
+                    return Some(i);
+                }
+            }
+            _ => {}
+        }
+    }
+    None
+}
+
+/// The arguments of the parenthesized `tokens`. Arguments past the named parameters all belong to
+/// a trailing `__VA_ARGS__`.
+fn macro_args(tokens: &[String], param_count: usize) -> Vec<Vec<String>> {
+    let mut args = vec![Vec::new()];
+    let mut depth = 0;
+    for token in &tokens[1..tokens.len() - 1] {
+        match token.as_str() {
+            "(" | "[" | "{" => depth += 1,
+            ")" | "]" | "}" => depth -= 1,
+            "," if depth == 0 && args.len() < param_count => {
+                args.push(Vec::new());
+                continue;
+            }
+            _ => {}
+        }
+        args.last_mut().unwrap().push(token.clone());
+    }
+    args
+}
+
+fn unpainted(token: &str) -> &str {
+    token.strip_prefix(PAINTED).unwrap_or(token)
+}
+
+/// The string literal `#` makes of an argument: its spelling, with `"` and `\` escaped inside
+/// string and character literals only, as C does.
+fn stringify(tokens: &[String]) -> String {
+    let escaped: Vec<String> = (tokens.iter())
+        .map(|token| {
+            if token.contains(['"', '\'']) {
+                token.replace('\\', "\\\\").replace('"', "\\\"")
+            } else {
+                token.clone()
+            }
+        })
+        .collect();
+    format!("\"{}\"", join_tokens(&escaped))
+}
+
+/// Joins tokens with spaces, except around punctuation where C code usually has none.
+fn join_tokens(tokens: &[String]) -> String {
+    let mut text = String::new();
+    let mut previous: Option<&str> = None;
+    for token in tokens.iter().map(|token| unpainted(token)) {
+        let tight = matches!(token, "," | ";" | ")" | "]" | "." | "->")
+            || matches!(previous, Some("(" | "[" | "." | "->"))
+            || (token == "(" || token == "[") && previous.is_some_and(is_word);
+        if previous.is_some() && !tight {
+            text.push(' ');
+        }
+        text.push_str(token);
+        previous = Some(token);
+    }
+    text
+}
+
+fn is_word(token: &str) -> bool {
+    token.chars().all(|c| c.is_alphanumeric() || c == '_')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Expands `text` with `defines`, each a `#define` without the directive. Tokens are
+    /// separated by spaces.
+    fn expanded(defines: &[&str], text: &str) -> String {
+        let tokens = |text: &str| -> Vec<String> { text.split(' ').map(str::to_string).collect() };
+        let definitions = (defines.iter())
+            .filter_map(|define| {
+                let tokens = tokens(define);
+                let function_like = tokens.get(1).is_some_and(|token| token == "(");
+                parse_definition(tokens, function_like)
+            })
+            .collect();
+        join_tokens(&expand(&tokens(text), &definitions, &mut HashSet::new(), 0))
+    }
+
+    #[test]
+    fn stringifies_arguments() {
+        assert_eq!(expanded(&["STR ( x ) # x"], "STR ( a + b )"), r#""a + b""#);
+        assert_eq!(expanded(&["STR ( x ) # x"], "STR ( é )"), r#""é""#);
+    }
+
+    #[test]
+    fn stringifying_escapes_only_inside_literals() {
+        assert_eq!(
+            expanded(&["STR ( x ) # x"], r#"STR ( puts ( "a\n" ) )"#),
+            r#""puts(\"a\\n\")""#
+        );
+        assert_eq!(expanded(&["STR ( x ) # x"], r#"STR ( '"' )"#), r#""'\"'""#);
+    }
+
+    #[test]
+    fn pastes_unexpanded_operands() {
+        let defines = ["CAT ( a , b ) a ## b", "X y"];
+        assert_eq!(expanded(&defines, "CAT ( foo , bar )"), "foobar");
+        assert_eq!(expanded(&defines, "CAT ( X , 1 )"), "X1");
+        assert_eq!(expanded(&defines, "CAT ( , bar )"), "bar");
+    }
+
+    #[test]
+    fn passes_variadic_arguments() {
+        let defines = ["CALL ( f , ... ) f ( __VA_ARGS__ )"];
+        assert_eq!(
+            expanded(&defines, "CALL ( g , 1 , ( 2 , 3 ) )"),
+            "g(1, (2, 3))"
+        );
+        assert_eq!(expanded(&defines, "CALL ( g )"), "g()");
+    }
+
+    #[test]
+    fn rescans_replacements() {
+        let defines = ["A B", "B 42", "ID ( x ) x", "FOO FOO + 1"];
+        assert_eq!(expanded(&defines, "A"), "42");
+        assert_eq!(expanded(&defines, "ID ( A )"), "42");
+        // A macro isn't expanded again within its own replacement.
+        assert_eq!(expanded(&defines, "FOO"), "FOO + 1");
+        assert_eq!(expanded(&defines, "ID ( ID ( FOO ) )"), "FOO + 1");
+    }
+}
@@ -1,122 +1,579 @@
-use std::collections::{BTreeSet, HashMap};
-use std::ffi::{c_char, c_uint, c_ulong, CString};
+use std::cell::OnceCell;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fmt::Write as _;
 use std::path::{Path, PathBuf};
+use std::sync::Arc;
 
-use anyhow::{anyhow, Context, Result};
-use clang_sys::{
-    clang_createIndex, clang_disposeIndex, clang_disposeTranslationUnit, clang_getCursorKind,
-    clang_getCursorSpelling, clang_getTranslationUnitCursor, clang_parseTranslationUnit,
-    clang_visitChildren, CXChildVisitResult, CXChildVisit_Recurse, CXClientData, CXCursor,
-    CXCursor_MacroExpansion, CXToken, CXTranslationUnit, CXTranslationUnit_DetailedPreprocessingRecord,
-    CXUnsavedFile,
-};
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
 use tower_lsp::lsp_types::{
-    CompletionItem, CompletionItemKind, Diagnostic, DiagnosticSeverity, Position, Range,
+    CompletionItem, CompletionItemKind, Diagnostic, DiagnosticSeverity, DocumentSymbol,
+    Documentation, Hover, HoverContents, MarkupContent, MarkupKind, NumberOrString, Position,
+    Range, SymbolKind,
 };
 
 use crate::compile_commands::CompileCommands;
+use crate::symbol::Symbol;
 
-use super::clang_utils::{
-    cursor_range, cxstring_to_string, split_macro_args, token_range, tokenize_cursor,
-    tokens_range, tokens_to_string,
+use super::clang::{
+    split_macro_args, tokens_range, tokens_to_string, Cursor, IndexPool, Token, TranslationUnit,
+};
+use super::expand::{expand_macro_at, macro_source_at, MacroExpansion, MacroSource};
+use super::parser::UnitCache;
+use super::{
+    doc_comment_above, identifier_at, invocations_in_regions, may_contain, name_completion,
+    outline_symbol, range_contains, range_union, removal_impact, source_slice, source_text,
+    update_impact, DisabledInvocation, FileData, FileParser, HierarchyCall, HierarchyItem,
+    Highlight, HighlightKind, Impact, LspPlugin, MacroSignature, ParseOptions, ReferenceLens,
+    SourceLanguage, SymbolInfo, SymbolLocation, DEFAULT_CLANG_ARGS,
 };
-use super::{range_contains, LspPlugin, DEFAULT_CLANG_ARGS};
+
+/// Prefix of the priority constants `HOOK_PRIORITY` takes.
+const PRIORITY_PREFIX: &str = "HOOK_PRIO_";
+const HOOK_MACROS: &[&str] = &["HOOK", "HOOK_PRIORITY", "HOOK_RUN"];
 
 pub struct HookPlugin {
-    compile_commands: Option<CompileCommands>,
+    parser: Arc<HookParser>,
+    project_root: PathBuf,
     files: HashMap<PathBuf, HookFileData>,
+    priorities: HookPriorities,
+}
+
+/// Priority constants known besides the `HOOK_PRIO_*` macros found in headers, and the range
+/// numeric priorities must fall in.
+#[derive(Clone, Debug, Default)]
+pub struct HookPriorities {
+    pub constants: Vec<(String, Option<i64>)>,
+    /// Inclusive; defaults to the lowest and highest value of the known constants.
+    pub range: Option<(i64, i64)>,
+}
+
+struct HookParser {
+    compile_commands: Option<CompileCommands>,
+    parse_options: ParseOptions,
+    indexes: IndexPool,
+    units: UnitCache,
 }
 
-#[derive(Default)]
+#[derive(Default, PartialEq, Serialize, Deserialize)]
 struct HookFileData {
     definitions: Vec<HookDefinition>,
     invocations: Vec<HookInvocation>,
+    /// Hook macro invocations the preprocessor skipped.
+    disabled: Vec<DisabledInvocation>,
+    /// `HOOK_PRIO_*` macros defined in the file or the headers it includes.
+    priority_constants: Vec<PriorityConstant>,
+}
+
+#[derive(Clone, PartialEq, Serialize, Deserialize)]
+struct PriorityConstant {
+    name: Symbol,
+    /// `None` for definitions that aren't a plain integer.
+    value: Option<i64>,
 }
 
-#[derive(Clone)]
+#[derive(Clone, PartialEq, Serialize, Deserialize)]
 struct HookDefinition {
-    name: String,
+    name: Symbol,
+    name_range: Range,
+    documentation: Option<String>,
+    /// The macro invocation as written.
+    text: String,
+    /// The priority argument of a `HOOK_PRIORITY` definition.
+    priority: Option<HookPriority>,
+}
+
+#[derive(Clone, PartialEq, Serialize, Deserialize)]
+struct HookPriority {
+    spelling: String,
+    range: Range,
+    /// Between the comma and the closing parenthesis, where completions offer the constants.
+    region: Range,
 }
 
-#[derive(Clone, Copy, PartialEq, Eq)]
+#[derive(Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
 enum HookInvocationKind {
     Definition,
     Run,
 }
 
-#[derive(Clone)]
+#[derive(Clone, PartialEq, Serialize, Deserialize)]
 struct HookInvocation {
-    name: String,
+    name: Symbol,
     name_range: Range,
     argument_region: Range,
     kind: HookInvocationKind,
+    /// The function the invocation is in.
+    function: Option<Symbol>,
+    function_range: Option<Range>,
+}
+
+/// What an entry of the hooks' call hierarchy stands for.
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+enum CallEntry {
+    Hook(Symbol),
+    Function {
+        path: PathBuf,
+        name: Symbol,
+    },
+    /// The top level of a file, for hooks run outside of any function.
+    File(PathBuf),
+}
+
+impl HookPriorities {
+    /// Parses `--hook-priority <name>[=<value>]` and `--hook-priority-range <min>..<max>` values.
+    pub fn parse(constants: &[String], range: Option<&str>) -> Result<Self> {
+        let constants = (constants.iter())
+            .map(|spec| match spec.split_once('=') {
+                Some((name, value)) => {
+                    let value = parse_integer(value).with_context(|| {
+                        format!("--hook-priority {spec}: '{value}' is not an integer")
+                    })?;
+                    Ok((name.to_string(), Some(value)))
+                }
+                None => Ok((spec.clone(), None)),
+            })
+            .collect::<Result<_>>()?;
+        let range = match range {
+            Some(spec) => {
+                let bounds = spec
+                    .split_once("..")
+                    .and_then(|(min, max)| Some((parse_integer(min)?, parse_integer(max)?)));
+                match bounds {
+                    Some((min, max)) if min <= max => Some((min, max)),
+                    _ => bail!("--hook-priority-range {spec}: expected <min>..<max>"),
+                }
+            }
+            None => None,
+        };
+        Ok(Self { constants, range })
+    }
 }
 
 impl HookPlugin {
-    pub fn new(project_root: &Path) -> Result<Self> {
+    pub fn new(project_root: &Path, parse_options: ParseOptions) -> Result<Self> {
         let compile_commands = Some(CompileCommands::load(
             project_root.to_path_buf(),
-            DEFAULT_CLANG_ARGS
-                .iter()
-                .map(|s| s.to_string())
-                .collect(),
+            DEFAULT_CLANG_ARGS.iter().map(|s| s.to_string()).collect(),
         ));
+        let kept_units = parse_options.kept_units;
 
         Ok(Self {
-            compile_commands,
+            parser: Arc::new(HookParser {
+                compile_commands,
+                parse_options,
+                indexes: IndexPool::default(),
+                units: UnitCache::new(kept_units),
+            }),
+            project_root: project_root.to_path_buf(),
             files: HashMap::new(),
+            priorities: HookPriorities::default(),
         })
     }
 
+    /// Replaces the `compile_commands.json` found in the project root.
+    pub fn with_compile_commands(mut self, compile_commands: CompileCommands) -> Self {
+        Arc::get_mut(&mut self.parser)
+            .expect("compile commands are set before the parser is shared")
+            .compile_commands = Some(compile_commands);
+        self
+    }
+
+    pub fn with_priorities(mut self, priorities: HookPriorities) -> Self {
+        self.priorities = priorities;
+        self
+    }
+
+    /// Every known priority constant with its value, by name.
+    fn known_hooks(&self) -> BTreeSet<Symbol> {
+        self.iter_definitions().map(|d| d.name.clone()).collect()
+    }
+
+    /// The diagnostics of the file `data` was parsed from.
+    fn data_diagnostics(
+        &self,
+        data: &HookFileData,
+        known: &BTreeSet<Symbol>,
+        constants: &BTreeMap<&str, Option<i64>>,
+    ) -> Vec<Diagnostic> {
+        let mut diagnostics = Vec::new();
+        for priority in data.definitions.iter().filter_map(|d| d.priority.as_ref()) {
+            if let Some(diagnostic) = self.priority_diagnostic(priority, constants) {
+                diagnostics.push(diagnostic);
+            }
+        }
+        let runs = (data.invocations.iter())
+            .filter(|invocation| invocation.kind == HookInvocationKind::Run);
+        for invocation in runs {
+            if !invocation.name.is_empty() && !known.contains(&invocation.name) {
+                diagnostics.push(Diagnostic {
+                    range: invocation.name_range,
+                    severity: Some(DiagnosticSeverity::ERROR),
+                    code: Some(NumberOrString::String("hooks/unknown-hook".into())),
+                    message: format!("Unknown hook '{}'", invocation.name),
+                    source: Some("cronus-hooks".into()),
+                    ..Diagnostic::default()
+                });
+            }
+        }
+        for disabled in &data.disabled {
+            diagnostics.push(disabled.diagnostic("hooks/disabled-invocation", "cronus-hooks"));
+        }
+        diagnostics
+    }
+
+    fn priority_constants(&self) -> BTreeMap<&str, Option<i64>> {
+        let mut constants: BTreeMap<&str, Option<i64>> = (self.files.values())
+            .flat_map(|data| &data.priority_constants)
+            .map(|constant| (constant.name.as_str(), constant.value))
+            .collect();
+        for (name, value) in &self.priorities.constants {
+            constants.insert(name, *value);
+        }
+        constants
+    }
+
+    fn priority_completions(&self) -> Vec<CompletionItem> {
+        (self.priority_constants().into_iter())
+            .map(|(name, value)| CompletionItem {
+                label: name.to_string(),
+                kind: Some(CompletionItemKind::CONSTANT),
+                detail: value.map(|value| value.to_string()),
+                ..CompletionItem::default()
+            })
+            .collect()
+    }
+
+    /// Diagnoses priorities that name no known constant or lie outside the allowed range.
+    /// Nothing is checked while no constants are known, and expressions aren't evaluated.
+    fn priority_diagnostic(
+        &self,
+        priority: &HookPriority,
+        constants: &BTreeMap<&str, Option<i64>>,
+    ) -> Option<Diagnostic> {
+        let spelling = priority.spelling.as_str();
+        let (code, message) = if let Some(value) = parse_integer(spelling) {
+            let values = constants.values().flatten();
+            let (min, max) = self
+                .priorities
+                .range
+                .or_else(|| Some((*values.clone().min()?, *values.max()?)))?;
+            if (min..=max).contains(&value) {
+                return None;
+            }
+            (
+                "hooks/priority-out-of-range",
+                format!("Hook priority {value} is outside {min}..={max}"),
+            )
+        } else if spelling.starts_with(PRIORITY_PREFIX) && !constants.is_empty() {
+            if constants.contains_key(spelling) {
+                return None;
+            }
+            (
+                "hooks/unknown-priority",
+                format!("Unknown hook priority '{spelling}'"),
+            )
+        } else {
+            return None;
+        };
+        Some(Diagnostic {
+            range: priority.range,
+            severity: Some(DiagnosticSeverity::ERROR),
+            code: Some(NumberOrString::String(code.into())),
+            message,
+            source: Some("cronus-hooks".into()),
+            ..Diagnostic::default()
+        })
+    }
+
+    /// The hook invocation whose name is at `position` in `path`.
+    fn invocation_at(&self, path: &Path, position: &Position) -> Option<&HookInvocation> {
+        (self.files.get(path)?.invocations.iter())
+            .find(|invocation| range_contains(&invocation.name_range, position))
+    }
+
+    /// Every definition of the hook `name`, ordered by file and position.
+    fn definitions_of(&self, name: &Symbol) -> Vec<(&PathBuf, &HookDefinition)> {
+        let mut definitions: Vec<(&PathBuf, &HookDefinition)> = (self.files.iter())
+            .flat_map(|(file, data)| {
+                (data.definitions.iter())
+                    .filter(|definition| definition.name == *name)
+                    .map(move |definition| (file, definition))
+            })
+            .collect();
+        definitions.sort_by_key(|(file, definition)| (*file, definition.name_range.start));
+        definitions
+    }
+
+    /// Every `HOOK_RUN` of the hook `name`, ordered by file and position.
+    fn run_sites_of(&self, name: &Symbol) -> Vec<SymbolLocation> {
+        let mut sites: Vec<SymbolLocation> = (self.files.iter())
+            .flat_map(|(file, data)| {
+                (data.invocations.iter())
+                    .filter(|invocation| {
+                        invocation.kind == HookInvocationKind::Run && invocation.name == *name
+                    })
+                    .map(move |invocation| SymbolLocation {
+                        path: file.clone(),
+                        range: invocation.name_range,
+                        container: invocation.function.clone(),
+                    })
+            })
+            .collect();
+        sites.sort_by(|a, b| (&a.path, a.range.start).cmp(&(&b.path, b.range.start)));
+        sites
+    }
+
+    /// The hierarchy entry of the hook `name`, at its first definition or, for a hook defined
+    /// nowhere, at `site`.
+    fn hook_item(&self, name: &Symbol, site: (&Path, Range)) -> HierarchyItem {
+        let definitions = self.definitions_of(name);
+        let (path, range) = (definitions.first()).map_or(site, |(path, definition)| {
+            (path.as_path(), definition.name_range)
+        });
+        let plural = if definitions.len() == 1 { "" } else { "s" };
+        HierarchyItem {
+            name: name.to_string(),
+            kind: SymbolKind::EVENT,
+            detail: Some(format!("{} definition{plural}", definitions.len())),
+            path: path.to_path_buf(),
+            range,
+            selection_range: range,
+            data: json!(CallEntry::Hook(name.clone())),
+        }
+    }
+
+    /// The hierarchy entry of what runs `invocation`: its function, or its file at the top level.
+    fn caller_item(&self, path: &Path, invocation: &HookInvocation) -> HierarchyItem {
+        let relative = path.strip_prefix(&self.project_root).unwrap_or(path);
+        match (&invocation.function, invocation.function_range) {
+            (Some(name), Some(range)) => HierarchyItem {
+                name: name.to_string(),
+                kind: SymbolKind::FUNCTION,
+                detail: Some(relative.display().to_string()),
+                path: path.to_path_buf(),
+                range,
+                selection_range: range,
+                data: json!(CallEntry::Function {
+                    path: path.to_path_buf(),
+                    name: name.clone(),
+                }),
+            },
+            _ => HierarchyItem {
+                name: relative.display().to_string(),
+                kind: SymbolKind::FILE,
+                detail: None,
+                path: path.to_path_buf(),
+                range: invocation.name_range,
+                selection_range: invocation.name_range,
+                data: json!(CallEntry::File(path.to_path_buf())),
+            },
+        }
+    }
+
     fn iter_definitions(&self) -> impl Iterator<Item = &HookDefinition> {
         self.files.values().flat_map(|data| data.definitions.iter())
     }
 
     fn completion_items(&self) -> Vec<CompletionItem> {
-        let mut names: BTreeSet<String> = BTreeSet::new();
+        let mut names: BTreeSet<Symbol> = BTreeSet::new();
         for definition in self.iter_definitions() {
             names.insert(definition.name.clone());
         }
+        let mut run_counts: HashMap<&Symbol, usize> = HashMap::new();
+        for invocation in self.files.values().flat_map(|data| &data.invocations) {
+            if invocation.kind == HookInvocationKind::Run {
+                *run_counts.entry(&invocation.name).or_default() += 1;
+            }
+        }
 
         names
-            .into_iter()
-            .map(|name| CompletionItem {
-                label: name,
-                kind: Some(CompletionItemKind::FUNCTION),
-                detail: Some("hook".into()),
-                ..CompletionItem::default()
+            .iter()
+            .map(|name| {
+                let uses = run_counts.get(name).copied().unwrap_or_default();
+                name_completion(name, CompletionItemKind::FUNCTION, "hook".into(), uses)
             })
             .collect()
     }
 }
 
-impl LspPlugin for HookPlugin {
-    fn on_file_updated(&mut self, path: &Path, content: Option<&str>) -> Result<()> {
-        if path.extension().and_then(|s| s.to_str()) != Some("c") {
-            return Ok(());
+impl HookFileData {
+    fn definition_names(&self) -> BTreeSet<Symbol> {
+        self.definitions.iter().map(|d| d.name.clone()).collect()
+    }
+
+    fn estimated_size(&self) -> usize {
+        let definitions: usize = self
+            .definitions
+            .iter()
+            .map(|d| {
+                size_of::<HookDefinition>()
+                    + d.name.len()
+                    + d.documentation.as_ref().map_or(0, String::len)
+                    + d.text.len()
+            })
+            .sum();
+        let invocations: usize = self
+            .invocations
+            .iter()
+            .map(|i| size_of::<HookInvocation>() + i.name.len())
+            .sum();
+        definitions + invocations + self.disabled.len() * size_of::<DisabledInvocation>()
+    }
+}
+
+impl HookParser {
+    fn args_for(&self, path: &Path, language: SourceLanguage) -> Vec<String> {
+        let db = self.compile_commands.as_ref();
+        let inferred = db.and_then(|db| match db.entry_args(path) {
+            Some(args) => Some(args.to_vec()),
+            None if self.parse_options.is_header(path) => db.header_args(path),
+            None => None,
+        });
+        let mut args = inferred.unwrap_or_else(|| language.default_args());
+        args.extend_from_slice(db.map_or(&[], CompileCommands::extra_args));
+        args
+    }
+
+    /// Reparses `path` on its own, for requests about one position in it.
+    fn with_translation_unit<T>(
+        &self,
+        path: &Path,
+        content: Option<&str>,
+        f: impl FnOnce(&TranslationUnit) -> Option<T>,
+    ) -> Result<Option<T>> {
+        let language = self
+            .parse_options
+            .language_of(path)
+            .unwrap_or(SourceLanguage::C);
+        let args = self.args_for(path, language);
+        let flags = self.parse_options.translation_unit_flags();
+        (self.units).with_unit(&self.indexes, path, &args, content, flags, |tu| Ok(f(tu)))
+    }
+}
+
+impl FileParser for HookParser {
+    fn parse_file(&self, path: &Path, content: Option<&str>) -> Result<Option<FileData>> {
+        let Some(language) = self.parse_options.language_of(path) else {
+            return Ok(None);
+        };
+        if self.parse_options.prescan && !may_contain(path, content, &["HOOK"]) {
+            // Closed files don't keep units; open ones may need theirs again after the next edit.
+            if content.is_none() {
+                self.units.forget(path);
+            }
+            return Ok(Some(Box::new(HookFileData::default())));
         }
 
-        let canonical = path.canonicalize().unwrap_or_else(|_| path.to_path_buf());
-        let args = self
-            .compile_commands
-            .as_ref()
-            .map(|db| db.args_for(&canonical))
-            .unwrap_or_else(|| DEFAULT_CLANG_ARGS.iter().map(|s| s.to_string()).collect());
+        let args = self.args_for(path, language);
+
+        let flags = self.parse_options.translation_unit_flags();
+        let data = self
+            .units
+            .with_unit(&self.indexes, path, &args, content, flags, |tu| {
+                parse_hooks(tu, path, content)
+            })?;
+        Ok(Some(Box::new(data)))
+    }
 
-        let data = parse_hooks(&canonical, &args, content)?;
-        self.files.insert(canonical, data);
-        Ok(())
+    fn expand_macro(
+        &self,
+        path: &Path,
+        content: Option<&str>,
+        position: &Position,
+    ) -> Result<Option<MacroExpansion>> {
+        self.with_translation_unit(path, content, |tu| {
+            expand_macro_at(tu, position, HOOK_MACROS)
+        })
     }
 
-    fn on_file_removed(&mut self, path: &Path) {
-        if let Ok(canonical) = path.canonicalize() {
-            self.files.remove(&canonical);
+    fn macro_source(
+        &self,
+        path: &Path,
+        content: Option<&str>,
+        position: &Position,
+    ) -> Result<Option<MacroSource>> {
+        let source = source_text(path, content);
+        let hovered = source
+            .as_deref()
+            .and_then(|text| identifier_at(text, *position));
+        if !hovered.is_some_and(|name| HOOK_MACROS.contains(&name)) {
+            return Ok(None);
         }
+        self.with_translation_unit(path, content, |tu| {
+            macro_source_at(tu, position, HOOK_MACROS)
+        })
+    }
+}
+
+impl LspPlugin for HookPlugin {
+    fn name(&self) -> &'static str {
+        "hooks"
+    }
+
+    fn parser(&self) -> Arc<dyn FileParser> {
+        self.parser.clone()
+    }
+
+    fn apply_file(&mut self, path: &Path, data: FileData) -> Impact {
+        let Ok(data) = data.downcast::<HookFileData>() else {
+            return Impact::Unchanged;
+        };
+        let impact = update_impact(self.files.get(path), &data, HookFileData::definition_names);
+        self.files.insert(path.to_path_buf(), *data);
+        impact
+    }
+
+    fn on_file_removed(&mut self, path: &Path) -> Impact {
+        removal_impact(
+            self.files.remove(path).as_ref(),
+            HookFileData::definition_names,
+        )
+    }
+
+    fn save_file(&self, path: &Path) -> Option<serde_json::Value> {
+        serde_json::to_value(self.files.get(path)?).ok()
+    }
+
+    fn encode_file(&self, data: &FileData) -> Result<serde_json::Value> {
+        let data = data
+            .downcast_ref::<HookFileData>()
+            .context("unexpected parse data")?;
+        Ok(serde_json::to_value(data)?)
     }
 
-    fn completions(&self, path: &Path, position: &Position) -> Option<Vec<CompletionItem>> {
-        let canonical = path.canonicalize().unwrap_or_else(|_| path.to_path_buf());
-        let data = self.files.get(&canonical)?;
+    fn load_file(&self, value: serde_json::Value) -> Result<FileData> {
+        Ok(Box::new(serde_json::from_value::<HookFileData>(value)?))
+    }
+
+    fn memory_usage(&self) -> usize {
+        self.files.values().map(HookFileData::estimated_size).sum()
+    }
+
+    fn evict_file(&mut self, path: &Path) -> usize {
+        let Some(data) = self.files.get_mut(path) else {
+            return 0;
+        };
+        let before = data.estimated_size();
+        data.invocations = Vec::new();
+        before - data.estimated_size()
+    }
+
+    fn completions(
+        &self,
+        path: &Path,
+        position: &Position,
+        _text: Option<&str>,
+    ) -> Option<Vec<CompletionItem>> {
+        let data = self.files.get(path)?;
+        let in_priority = (data.definitions.iter())
+            .filter_map(|definition| definition.priority.as_ref())
+            .any(|priority| range_contains(&priority.region, position));
+        if in_priority {
+            return Some(self.priority_completions());
+        }
         let in_region = data
             .invocations
             .iter()
@@ -129,180 +586,465 @@ impl LspPlugin for HookPlugin {
         Some(self.completion_items())
     }
 
-    fn diagnostics(&self) -> HashMap<PathBuf, Vec<Diagnostic>> {
-        let known: BTreeSet<String> = self.iter_definitions().map(|d| d.name.clone()).collect();
-        let mut diag_map: HashMap<PathBuf, Vec<Diagnostic>> = HashMap::new();
-
-        for (file, data) in &self.files {
-            for invocation in data
-                .invocations
-                .iter()
-                .filter(|invocation| invocation.kind == HookInvocationKind::Run)
-            {
-                if invocation.name.is_empty() {
-                    continue;
-                }
+    fn hover(&self, path: &Path, position: &Position) -> Option<Hover> {
+        let invocation = self.invocation_at(path, position)?;
+        let definitions = self.definitions_of(&invocation.name);
+        let (_, first) = definitions.first()?;
+        let mut value = format!("```c\n{}\n```\n", first.text);
+        if let Some(documentation) = &first.documentation {
+            write!(value, "\n{documentation}\n").unwrap();
+        }
+        let places: Vec<String> = (definitions.iter())
+            .map(|(file, definition)| {
+                let file = file.strip_prefix(&self.project_root).unwrap_or(file);
+                format!(
+                    "`{}:{}`",
+                    file.display(),
+                    definition.name_range.start.line + 1
+                )
+            })
+            .collect();
+        match &places[..] {
+            [place] => write!(value, "\nDefined in {place}").unwrap(),
+            _ => write!(
+                value,
+                "\n{} definitions: {}",
+                places.len(),
+                places.join(", ")
+            )
+            .unwrap(),
+        }
+        Some(Hover {
+            contents: HoverContents::Markup(MarkupContent {
+                kind: MarkupKind::Markdown,
+                value,
+            }),
+            range: Some(invocation.name_range),
+        })
+    }
 
-                if !known.contains(&invocation.name) {
-                    diag_map.entry(file.clone()).or_default().push(Diagnostic {
-                        range: invocation.name_range.clone(),
-                        severity: Some(DiagnosticSeverity::ERROR),
-                        message: format!("Unknown hook '{}'", invocation.name),
-                        source: Some("cronus-hooks".into()),
-                        ..Diagnostic::default()
-                    });
-                }
+    fn definition(&self, path: &Path, position: &Position) -> Vec<SymbolLocation> {
+        let Some(invocation) = self.invocation_at(path, position) else {
+            return Vec::new();
+        };
+        (self.definitions_of(&invocation.name).into_iter())
+            .map(|(file, definition)| SymbolLocation {
+                path: file.clone(),
+                range: definition.name_range,
+                container: None,
+            })
+            .collect()
+    }
+
+    fn cross_file_codes(&self) -> &'static [&'static str] {
+        &["hooks/unknown-hook", "hooks/unknown-priority"]
+    }
+
+    fn document_symbols(&self, path: &Path) -> Vec<DocumentSymbol> {
+        let Some(data) = self.files.get(path) else {
+            return Vec::new();
+        };
+        let definitions = (data.definitions.iter()).map(|definition| {
+            outline_symbol(
+                definition.name.to_string(),
+                Some("hook".into()),
+                SymbolKind::EVENT,
+                definition.name_range,
+                definition.name_range,
+                Vec::new(),
+            )
+        });
+        let runs = (data.invocations.iter())
+            .filter(|invocation| {
+                invocation.kind == HookInvocationKind::Run && !invocation.name.is_empty()
+            })
+            .map(|invocation| {
+                let detail = match &invocation.function {
+                    Some(function) => format!("run in {function}"),
+                    None => "run".into(),
+                };
+                outline_symbol(
+                    invocation.name.to_string(),
+                    Some(detail),
+                    SymbolKind::EVENT,
+                    range_union(&invocation.name_range, &invocation.argument_region),
+                    invocation.name_range,
+                    Vec::new(),
+                )
+            });
+        definitions.chain(runs).collect()
+    }
+
+    fn highlights(&self, path: &Path) -> Vec<Highlight> {
+        let Some(data) = self.files.get(path) else {
+            return Vec::new();
+        };
+        (data.invocations.iter())
+            .filter(|invocation| !invocation.name.is_empty())
+            .map(|invocation| Highlight {
+                range: invocation.name_range,
+                kind: HighlightKind::Hook,
+                declaration: invocation.kind == HookInvocationKind::Definition,
+            })
+            .collect()
+    }
+
+    fn prepare_call_hierarchy(&self, path: &Path, position: &Position) -> Vec<HierarchyItem> {
+        let Some(invocation) = self.invocation_at(path, position) else {
+            return Vec::new();
+        };
+        vec![self.hook_item(&invocation.name, (path, invocation.name_range))]
+    }
+
+    fn incoming_calls(&self, data: &serde_json::Value) -> Vec<HierarchyCall> {
+        let Ok(CallEntry::Hook(name)) = serde_json::from_value(data.clone()) else {
+            return Vec::new();
+        };
+        let mut callers: BTreeMap<(&PathBuf, Option<&Symbol>), HierarchyCall> = BTreeMap::new();
+        for (path, data) in &self.files {
+            let runs = (data.invocations.iter()).filter(|invocation| {
+                invocation.kind == HookInvocationKind::Run && invocation.name == name
+            });
+            for invocation in runs {
+                callers
+                    .entry((path, invocation.function.as_ref()))
+                    .or_insert_with(|| HierarchyCall {
+                        item: self.caller_item(path, invocation),
+                        ranges: Vec::new(),
+                    })
+                    .ranges
+                    .push(invocation.name_range);
             }
         }
-
-        diag_map
+        callers.into_values().collect()
     }
-}
 
-fn parse_hooks(path: &Path, args: &[String], content: Option<&str>) -> Result<HookFileData> {
-    let filename =
-        CString::new(path.as_os_str().to_string_lossy().into_owned()).context("path encode")?;
-    let arg_cstrings: Vec<CString> = args
-        .iter()
-        .map(|a| CString::new(a.as_str()))
-        .collect::<std::result::Result<_, _>>()?;
-    let arg_ptrs: Vec<*const c_char> = arg_cstrings.iter().map(|s| s.as_ptr()).collect();
-
-    let mut unsaved_storage: Vec<CString> = Vec::new();
-    let mut unsaved_files: Vec<CXUnsavedFile> = Vec::new();
-    if let Some(text) = content {
-        let text_c = CString::new(text)?;
-        let len = text.len() as c_ulong;
-        unsaved_storage.push(text_c);
-        unsaved_files.push(CXUnsavedFile {
-            Filename: filename.as_ptr(),
-            Contents: unsaved_storage.last().unwrap().as_ptr(),
-            Length: len,
+    fn outgoing_calls(&self, data: &serde_json::Value) -> Vec<HierarchyCall> {
+        let (path, function) = match serde_json::from_value(data.clone()) {
+            Ok(CallEntry::Function { path, name }) => (path, Some(name)),
+            Ok(CallEntry::File(path)) => (path, None),
+            _ => return Vec::new(),
+        };
+        let Some(data) = self.files.get(&path) else {
+            return Vec::new();
+        };
+        let mut hooks: BTreeMap<&Symbol, HierarchyCall> = BTreeMap::new();
+        let runs = (data.invocations.iter()).filter(|invocation| {
+            invocation.kind == HookInvocationKind::Run && invocation.function == function
         });
+        for invocation in runs {
+            hooks
+                .entry(&invocation.name)
+                .or_insert_with(|| HierarchyCall {
+                    item: self.hook_item(&invocation.name, (&path, invocation.name_range)),
+                    ranges: Vec::new(),
+                })
+                .ranges
+                .push(invocation.name_range);
+        }
+        hooks.into_values().collect()
     }
 
-    unsafe {
-        let index = clang_createIndex(0, 0);
-        let tu = clang_parseTranslationUnit(
-            index,
-            filename.as_ptr(),
-            if arg_ptrs.is_empty() {
-                std::ptr::null()
-            } else {
-                arg_ptrs.as_ptr()
+    fn signatures(&self) -> &'static [MacroSignature] {
+        &[
+            MacroSignature {
+                name: "HOOK",
+                parameters: &["name"],
+                documentation: "Defines a function for the hook `name`.",
             },
-            arg_ptrs.len() as c_uint as i32,
-            if unsaved_files.is_empty() {
-                std::ptr::null_mut()
-            } else {
-                unsaved_files.as_mut_ptr()
+            MacroSignature {
+                name: "HOOK_PRIORITY",
+                parameters: &["name", "priority"],
+                documentation:
+                    "Defines a function for the hook `name`, ordered by a `HOOK_PRIO_*` priority.",
             },
-            unsaved_files.len() as c_uint,
-            CXTranslationUnit_DetailedPreprocessingRecord,
-        );
+            MacroSignature {
+                name: "HOOK_RUN",
+                parameters: &["name"],
+                documentation: "Runs the functions defined for the hook `name`.",
+            },
+        ]
+    }
 
-        if tu.is_null() {
-            clang_disposeIndex(index);
-            return Err(anyhow!("Unable to parse {} with libclang", path.display()));
+    fn resolve_completion(&self, mut item: CompletionItem) -> CompletionItem {
+        let name = Symbol::intern(&item.label);
+        let definitions = self.definitions_of(&name);
+        let Some((file, definition)) = definitions.first() else {
+            return item;
+        };
+        let file = file.strip_prefix(&self.project_root).unwrap_or(file);
+        let runs = self.run_sites_of(&name).len();
+        let plural = if runs == 1 { "" } else { "s" };
+        let mut value = format!(
+            "Run at {runs} site{plural}, defined in `{}`",
+            file.display()
+        );
+        if definitions.len() > 1 {
+            write!(value, " and {} more places", definitions.len() - 1).unwrap();
+        }
+        if let Some(documentation) = &definition.documentation {
+            write!(value, "\n\n{documentation}").unwrap();
         }
+        item.documentation = Some(Documentation::MarkupContent(MarkupContent {
+            kind: MarkupKind::Markdown,
+            value,
+        }));
+        item
+    }
 
-        let cursor = clang_getTranslationUnitCursor(tu);
-        let mut collector = HookCollector {
-            tu,
-            definitions: Vec::new(),
-            invocations: Vec::new(),
+    fn reference_lenses(&self, path: &Path) -> Vec<ReferenceLens> {
+        let Some(data) = self.files.get(path) else {
+            return Vec::new();
         };
+        (data.definitions.iter())
+            .map(|definition| {
+                let locations = self.run_sites_of(&definition.name);
+                let plural = if locations.len() == 1 { "" } else { "s" };
+                ReferenceLens {
+                    range: definition.name_range,
+                    title: format!("{} run site{plural}", locations.len()),
+                    locations,
+                }
+            })
+            .collect()
+    }
 
-        clang_visitChildren(
-            cursor,
-            visit_hooks,
-            &mut collector as *mut HookCollector as CXClientData,
-        );
+    fn symbols(&self) -> Vec<SymbolInfo> {
+        let mut run_sites: HashMap<&Symbol, Vec<SymbolLocation>> = HashMap::new();
+        for (path, data) in &self.files {
+            for invocation in &data.invocations {
+                if invocation.kind == HookInvocationKind::Run {
+                    run_sites
+                        .entry(&invocation.name)
+                        .or_default()
+                        .push(SymbolLocation {
+                            path: path.clone(),
+                            range: invocation.name_range,
+                            container: invocation.function.clone(),
+                        });
+                }
+            }
+        }
 
-        clang_disposeTranslationUnit(tu);
-        clang_disposeIndex(index);
-        Ok(HookFileData {
-            definitions: collector.definitions,
-            invocations: collector.invocations,
-        })
+        self.files
+            .iter()
+            .flat_map(|(path, data)| data.definitions.iter().map(move |d| (path, d)))
+            .map(|(path, definition)| SymbolInfo {
+                name: definition.name.clone(),
+                kind: SymbolKind::EVENT,
+                location: SymbolLocation {
+                    path: path.clone(),
+                    range: definition.name_range,
+                    container: None,
+                },
+                documentation: definition.documentation.clone(),
+                fields: (definition.priority.iter())
+                    .map(|priority| ("priority", priority.spelling.clone()))
+                    .collect(),
+                dependencies: Vec::new(),
+                references: run_sites.get(&definition.name).cloned().unwrap_or_default(),
+            })
+            .collect()
     }
-}
 
-struct HookCollector {
-    tu: CXTranslationUnit,
-    definitions: Vec<HookDefinition>,
-    invocations: Vec<HookInvocation>,
+    fn diagnostics(&self) -> HashMap<PathBuf, Vec<Diagnostic>> {
+        let known = self.known_hooks();
+        let constants = self.priority_constants();
+        (self.files.iter())
+            .map(|(file, data)| {
+                (
+                    file.clone(),
+                    self.data_diagnostics(data, &known, &constants),
+                )
+            })
+            .filter(|(_, diagnostics)| !diagnostics.is_empty())
+            .collect()
+    }
+
+    fn file_diagnostics(&self, path: &Path) -> Vec<Diagnostic> {
+        let Some(data) = self.files.get(path) else {
+            return Vec::new();
+        };
+        self.data_diagnostics(data, &self.known_hooks(), &self.priority_constants())
+    }
 }
 
-extern "C" fn visit_hooks(
-    cursor: CXCursor,
-    _parent: CXCursor,
-    data: CXClientData,
-) -> CXChildVisitResult {
-    unsafe {
-        let collector = &mut *(data as *mut HookCollector);
-        if clang_getCursorKind(cursor) == CXCursor_MacroExpansion {
-            let spelling = cxstring_to_string(clang_getCursorSpelling(cursor));
-            match spelling.as_str() {
-                "HOOK" => {
-                    if let Some(definition) = build_hook_definition(collector, cursor) {
-                        collector.definitions.push(definition);
-                    }
-                    if let Some(invocation) =
-                        build_hook_usage(collector, cursor, HookInvocationKind::Definition)
-                    {
-                        collector.invocations.push(invocation);
-                    }
+fn parse_hooks(tu: &TranslationUnit, path: &Path, content: Option<&str>) -> Result<HookFileData> {
+    let mut data = HookFileData::default();
+    let source = OnceCell::new();
+    let mut functions = Vec::new();
+    tu.cursor().visit_descendants(|cursor| {
+        if cursor.is_function_definition() && cursor.is_in_main_file() {
+            if let Some(range) = cursor.range() {
+                functions.push((Symbol::intern(&cursor.spelling()), range));
+            }
+            return;
+        }
+        if cursor.is_macro_definition() {
+            let name = cursor.spelling();
+            if name.starts_with(PRIORITY_PREFIX) {
+                let tokens = cursor.tokens();
+                let value = tokens.tokens().get(1..).and_then(|body| {
+                    let body: Vec<&str> = body.iter().map(|t| t.spelling.as_str()).collect();
+                    parse_integer(&body.concat())
+                });
+                data.priority_constants.push(PriorityConstant {
+                    name: Symbol::intern(&name),
+                    value,
+                });
+            }
+            return;
+        }
+        if !cursor.is_macro_expansion() {
+            return;
+        }
+        match cursor.spelling().as_str() {
+            "HOOK_PRIORITY" => {
+                let source = source.get_or_init(|| source_text(path, content));
+                if let Some((definition, invocation)) =
+                    build_priority_hook(cursor, source.as_deref())
+                {
+                    data.definitions.push(definition);
+                    data.invocations.push(invocation);
                 }
-                "HOOK_RUN" => {
-                    if let Some(invocation) =
-                        build_hook_usage(collector, cursor, HookInvocationKind::Run)
-                    {
-                        collector.invocations.push(invocation);
-                    }
+            }
+            "HOOK" => {
+                let source = source.get_or_init(|| source_text(path, content));
+                if let Some(definition) = build_hook_definition(cursor, source.as_deref()) {
+                    data.definitions.push(definition);
+                }
+                if let Some(invocation) = build_hook_usage(cursor, HookInvocationKind::Definition) {
+                    data.invocations.push(invocation);
                 }
-                _ => {}
             }
+            "HOOK_RUN" => {
+                if let Some(invocation) = build_hook_usage(cursor, HookInvocationKind::Run) {
+                    data.invocations.push(invocation);
+                }
+            }
+            _ => {}
         }
-        CXChildVisit_Recurse
+    });
+    for invocation in &mut data.invocations {
+        let function = (functions.iter())
+            .find(|(_, range)| range_contains(range, &invocation.name_range.start));
+        invocation.function = function.map(|(name, _)| name.clone());
+        invocation.function_range = function.map(|(_, range)| *range);
     }
+    let skipped = tu.skipped_ranges(path);
+    if !skipped.is_empty() {
+        if let Some(source) = source.get_or_init(|| source_text(path, content)) {
+            data.disabled = invocations_in_regions(source, &skipped, HOOK_MACROS);
+        }
+    }
+    Ok(data)
 }
 
-unsafe fn build_hook_definition(
-    collector: &HookCollector,
-    cursor: CXCursor,
-) -> Option<HookDefinition> {
-    let tokens = tokenize_cursor(collector.tu, cursor)?;
-    let args = split_macro_args(collector.tu, &tokens)?;
+fn build_hook_definition(cursor: Cursor, source: Option<&str>) -> Option<HookDefinition> {
+    let tokens = cursor.tokens();
+    let args = split_macro_args(tokens.tokens());
     if args.len() != 1 {
         return None;
     }
-    let name = tokens_to_string(collector.tu, &args[0])?.trim().to_string();
+    let name = tokens_to_string(args[0]);
+    let name = name.trim();
     if name.is_empty() {
         return None;
     }
-    Some(HookDefinition { name })
+    let range = cursor.range()?;
+    Some(HookDefinition {
+        name: Symbol::intern(name),
+        name_range: tokens_range(args[0]).unwrap_or(range),
+        documentation: source.and_then(|source| doc_comment_above(source, range.start.line)),
+        text: invocation_text(source, &range, tokens.tokens()),
+        priority: None,
+    })
 }
 
-unsafe fn build_hook_usage(
-    collector: &HookCollector,
-    cursor: CXCursor,
-    kind: HookInvocationKind,
-) -> Option<HookInvocation> {
-    let tokens = tokenize_cursor(collector.tu, cursor)?;
-    let args = split_macro_args(collector.tu, &tokens)?;
+/// A `HOOK_PRIORITY(name, priority)` definition, with the name as a definition site.
+fn build_priority_hook(
+    cursor: Cursor,
+    source: Option<&str>,
+) -> Option<(HookDefinition, HookInvocation)> {
+    let tokens = cursor.tokens();
+    let tokens = tokens.tokens();
+    let args = split_macro_args(tokens);
+    let [name_tokens, priority_tokens] = args[..] else {
+        return None;
+    };
+    let name = Symbol::intern(tokens_to_string(name_tokens).trim());
+    if name.is_empty() {
+        return None;
+    }
+    let range = cursor.range()?;
+    let name_range = tokens_range(name_tokens).unwrap_or(range);
+    // `HOOK_PRIORITY` `(` name... `,` priority... `)`
+    let comma = tokens.get(2 + name_tokens.len())?.range?;
+    let close = tokens.last()?.range?;
+    let region = Range::new(comma.end, close.start);
+    let definition = HookDefinition {
+        name: name.clone(),
+        name_range,
+        documentation: source.and_then(|source| doc_comment_above(source, range.start.line)),
+        text: invocation_text(source, &range, tokens),
+        priority: Some(HookPriority {
+            spelling: tokens_to_string(priority_tokens),
+            range: tokens_range(priority_tokens).unwrap_or(region),
+            region,
+        }),
+    };
+    let invocation = HookInvocation {
+        name,
+        name_range,
+        argument_region: Range::new(tokens.get(1)?.range?.end, comma.start),
+        kind: HookInvocationKind::Definition,
+        function: None,
+        function_range: None,
+    };
+    Some((definition, invocation))
+}
+
+/// The value of an integer literal such as `10`, `-0x10` or `(4u)`.
+fn parse_integer(text: &str) -> Option<i64> {
+    let mut text = text.trim();
+    while let Some(inner) = text.strip_prefix('(').and_then(|t| t.strip_suffix(')')) {
+        text = inner.trim();
+    }
+    let (negative, digits) = match text.strip_prefix('-') {
+        Some(rest) => (true, rest.trim_start()),
+        None => (false, text),
+    };
+    let digits = digits.trim_end_matches(['u', 'U', 'l', 'L']);
+    let value = if let Some(hex) = digits.strip_prefix("0x").or(digits.strip_prefix("0X")) {
+        i64::from_str_radix(hex, 16).ok()?
+    } else if digits.len() > 1 && digits.starts_with('0') {
+        i64::from_str_radix(&digits[1..], 8).ok()?
+    } else {
+        digits.parse().ok()?
+    };
+    Some(if negative { -value } else { value })
+}
+
+fn invocation_text(source: Option<&str>, range: &Range, tokens: &[Token]) -> String {
+    (source.and_then(|source| source_slice(source, range)))
+        .map_or_else(|| tokens_to_string(tokens), str::to_string)
+}
+
+fn build_hook_usage(cursor: Cursor, kind: HookInvocationKind) -> Option<HookInvocation> {
+    let tokens = cursor.tokens();
+    let tokens = tokens.tokens();
+    let args = split_macro_args(tokens);
     if args.len() != 1 {
         return None;
     }
 
-    let argument_region =
-        macro_argument_region(collector.tu, &tokens).or_else(|| cursor_range(cursor))?;
+    let argument_region = macro_argument_region(tokens).or_else(|| cursor.range())?;
     let name_tokens = &args[0];
     let (name, name_range) = if name_tokens.is_empty() {
-        (String::new(), argument_region.clone())
+        (Symbol::intern(""), argument_region)
     } else {
-        let name = tokens_to_string(collector.tu, name_tokens)?.trim().to_string();
-        let range = tokens_range(collector.tu, name_tokens).unwrap_or_else(|| argument_region.clone());
+        let name = Symbol::intern(tokens_to_string(name_tokens).trim());
+        let range = tokens_range(name_tokens).unwrap_or(argument_region);
         (name, range)
     };
 
@@ -311,18 +1053,19 @@ unsafe fn build_hook_usage(
         name_range,
         argument_region,
         kind,
+        function: None,
+        function_range: None,
     })
 }
 
-unsafe fn macro_argument_region(tu: CXTranslationUnit, tokens: &[CXToken]) -> Option<Range> {
+fn macro_argument_region(tokens: &[Token]) -> Option<Range> {
     let mut depth = 0;
     let mut start = None;
     for token in tokens {
-        let text = tokens_to_string(tu, &[*token]).unwrap_or_default();
-        match text.as_str() {
+        match token.spelling.as_str() {
             "(" => {
                 if depth == 0 {
-                    start = Some(token_range(tu, *token)?.end);
+                    start = Some(token.range?.end);
                 }
                 depth += 1;
             }
@@ -332,7 +1075,7 @@ unsafe fn macro_argument_region(tu: CXTranslationUnit, tokens: &[CXToken]) -> Op
                 }
                 depth -= 1;
                 if depth == 0 {
-                    let end = token_range(tu, *token)?.start;
+                    let end = token.range?.start;
                     if let Some(start_pos) = start {
                         return Some(Range {
                             start: start_pos,
@@ -1,285 +1,1976 @@
-use std::collections::{BTreeSet, HashMap};
-use std::ffi::{c_char, c_uint, c_ulong, CString};
+use std::cell::OnceCell;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::fmt::Write as _;
 use std::path::{Path, PathBuf};
+use std::sync::Arc;
 
-use anyhow::{anyhow, Context, Result};
-use clang_sys::{
-    clang_createIndex, clang_disposeIndex, clang_disposeTranslationUnit, clang_getCursorKind,
-    clang_getCursorSpelling, clang_getTranslationUnitCursor, clang_getTokenKind,
-    clang_parseTranslationUnit, clang_visitChildren, CXChildVisitResult, CXChildVisit_Recurse,
-    CXClientData, CXCursor, CXCursor_MacroExpansion, CXToken_Literal, CXTranslationUnit,
-    CXTranslationUnit_DetailedPreprocessingRecord, CXUnsavedFile,
-};
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
 use tower_lsp::lsp_types::{
-    CompletionItem, CompletionItemKind, Diagnostic, DiagnosticSeverity, Position, Range,
+    CompletionItem, CompletionItemKind, CompletionTextEdit, Diagnostic, DiagnosticSeverity,
+    DocumentSymbol, Documentation, Hover, HoverContents, InlayHint, InlayHintLabel,
+    InlayHintTooltip, MarkupContent, MarkupKind, NumberOrString, Position, Range, SymbolKind,
+    TextEdit,
 };
 
 use crate::compile_commands::CompileCommands;
+use crate::symbol::Symbol;
 
-use super::clang_utils::{
-    cursor_range, cxstring_to_string, split_macro_args, token_range, tokenize_cursor,
-    tokens_range, tokens_to_string,
+use super::clang::{
+    designated_fields, field_value, split_macro_args, tokens_range, tokens_to_string, Cursor,
+    IndexPool, Token, TokenKind, TranslationUnit,
+};
+use super::expand::{expand_macro_at, macro_source_at, MacroExpansion, MacroSource};
+use super::parser::UnitCache;
+use super::{
+    doc_comment_above, identifier_at, invocations_in_regions, may_contain, name_completion,
+    offset_at, outline_symbol, position_at, range_contains, range_union, removal_impact,
+    skip_literal_or_comment, source_text, update_impact, DisabledInvocation, FileData, FileParser,
+    Highlight, HighlightKind, Impact, LspPlugin, MacroSignature, NameLink, ParseOptions, QuickFix,
+    ReferenceLens, SourceLanguage, SymbolInfo, SymbolLocation, DEFAULT_CLANG_ARGS,
 };
-use super::{range_contains, LspPlugin, DEFAULT_CLANG_ARGS};
+
+const INIT_MACROS: &[&str] = &["INIT_TARGET"];
+const EXPLAIN_ORDER_COMMAND: &str = "elysium.explainInitOrder";
+const DEAD_TARGETS_COMMAND: &str = "elysium.findDeadInitTargets";
+const STAGE_TARGETS_COMMAND: &str = "elysium.listStageTargets";
 
 pub struct InitDependencyPlugin {
-    compile_commands: Option<CompileCommands>,
+    parser: Arc<InitTargetParser>,
+    project_root: PathBuf,
     targets_by_file: HashMap<PathBuf, Vec<InitTarget>>,
+    disabled_by_file: HashMap<PathBuf, Vec<DisabledInvocation>>,
+    /// Targets the boot path kicks off, the default roots of the dead target analysis.
+    roots: Vec<String>,
+    dependency_style: DependencyStyle,
+    stage_scopes: StageScopes,
+}
+
+struct InitTargetParser {
+    compile_commands: Option<CompileCommands>,
+    parse_options: ParseOptions,
+    dependency_forms: DependencyForms,
+    indexes: IndexPool,
+    units: UnitCache,
+}
+
+/// Which tokens of an `INIT_TARGET` dependency argument name dependencies, besides string
+/// literals.
+#[derive(Clone, Debug)]
+pub struct DependencyForms {
+    /// Bare identifiers, as in `INIT_DEPS(pmm, vmm)`.
+    pub identifiers: bool,
+    /// Wrapper macros taking a dependency name, as in `INIT_REF(pmm)`.
+    pub ref_macros: Vec<String>,
+}
+
+impl Default for DependencyForms {
+    fn default() -> Self {
+        Self {
+            identifiers: true,
+            ref_macros: vec!["INIT_REF".into()],
+        }
+    }
+}
+
+/// How dependency lists are laid out when a document is saved.
+#[derive(Clone, Debug)]
+pub struct DependencyStyle {
+    /// Lists that would make their line longer than this get one dependency per line.
+    pub max_line_length: usize,
+    /// Order dependencies alphabetically.
+    pub sort: bool,
+}
+
+impl Default for DependencyStyle {
+    fn default() -> Self {
+        Self {
+            max_line_length: 100,
+            sort: false,
+        }
+    }
+}
+
+/// The scopes allowed in each stage, for stages that don't allow every scope.
+#[derive(Clone, Debug, Default)]
+pub struct StageScopes {
+    pub allowed: BTreeMap<String, Vec<String>>,
+}
+
+impl StageScopes {
+    /// Parses `--init-stage-scopes <stage>=<scope>[,<scope>...]` values. A stage given more than
+    /// once allows the scopes of every occurrence.
+    pub fn parse(specs: &[String]) -> Result<Self> {
+        let mut allowed: BTreeMap<String, Vec<String>> = BTreeMap::new();
+        for spec in specs {
+            let Some((stage, scopes)) = spec.split_once('=') else {
+                bail!("--init-stage-scopes {spec}: expected <stage>=<scope>[,<scope>...]");
+            };
+            let scopes = scopes.split(',').map(str::trim).filter(|s| !s.is_empty());
+            (allowed.entry(stage.trim().to_string()).or_default())
+                .extend(scopes.map(str::to_string));
+        }
+        Ok(Self { allowed })
+    }
+
+    /// The scopes `stage` allows if it doesn't allow `scope`.
+    fn violation(&self, stage: &str, scope: &str) -> Option<&[String]> {
+        let allowed = self.allowed.get(stage)?;
+        (!allowed.iter().any(|s| s == scope)).then_some(allowed.as_slice())
+    }
+}
+
+#[derive(Default, Serialize, Deserialize)]
+struct InitFileData {
+    targets: Vec<InitTarget>,
+    /// `INIT_TARGET` invocations the preprocessor skipped.
+    disabled: Vec<DisabledInvocation>,
 }
 
-#[derive(Clone)]
+#[derive(Clone, PartialEq, Serialize, Deserialize)]
 struct DependencySlot {
-    name: String,
+    name: Symbol,
+    /// Includes the quotes of a string literal.
     range: Range,
+    quoted: bool,
+    /// The list item with a comma next to it, which deleting the dependency removes.
+    removal: Range,
 }
 
-#[derive(Clone)]
+#[derive(Clone, PartialEq, Serialize, Deserialize)]
 struct InitTarget {
-    name: String,
+    name: Symbol,
+    name_range: Range,
+    name_quoted: bool,
+    documentation: Option<String>,
     stage_expr: String,
+    stage_range: Range,
     scope_expr: String,
+    scope_range: Range,
     file: PathBuf,
     dependency_region: Range,
     dependency_slots: Vec<DependencySlot>,
 }
 
 impl InitDependencyPlugin {
-    pub fn new(project_root: &Path) -> Result<Self> {
+    pub fn new(project_root: &Path, parse_options: ParseOptions) -> Result<Self> {
         let compile_commands = Some(CompileCommands::load(
             project_root.to_path_buf(),
             DEFAULT_CLANG_ARGS.iter().map(|s| s.to_string()).collect(),
         ));
+        let kept_units = parse_options.kept_units;
 
         Ok(Self {
-            compile_commands,
+            parser: Arc::new(InitTargetParser {
+                compile_commands,
+                parse_options,
+                dependency_forms: DependencyForms::default(),
+                indexes: IndexPool::default(),
+                units: UnitCache::new(kept_units),
+            }),
+            project_root: project_root.to_path_buf(),
             targets_by_file: HashMap::new(),
+            disabled_by_file: HashMap::new(),
+            roots: Vec::new(),
+            dependency_style: DependencyStyle::default(),
+            stage_scopes: StageScopes::default(),
         })
     }
 
+    pub fn with_roots(mut self, roots: Vec<String>) -> Self {
+        self.roots = roots;
+        self
+    }
+
+    pub fn with_stage_scopes(mut self, stage_scopes: StageScopes) -> Self {
+        self.stage_scopes = stage_scopes;
+        self
+    }
+
+    pub fn with_dependency_style(mut self, style: DependencyStyle) -> Self {
+        self.dependency_style = style;
+        self
+    }
+
+    /// Replaces the `compile_commands.json` found in the project root.
+    pub fn with_compile_commands(mut self, compile_commands: CompileCommands) -> Self {
+        Arc::get_mut(&mut self.parser)
+            .expect("compile commands are set before the parser is shared")
+            .compile_commands = Some(compile_commands);
+        self
+    }
+
+    pub fn with_dependency_forms(mut self, forms: DependencyForms) -> Self {
+        Arc::get_mut(&mut self.parser)
+            .expect("dependency forms are set before the parser is shared")
+            .dependency_forms = forms;
+        self
+    }
+
     fn iter_targets(&self) -> impl Iterator<Item = &InitTarget> {
         self.targets_by_file.values().flatten()
     }
 
+    fn known_targets(&self) -> BTreeSet<Symbol> {
+        self.iter_targets().map(|t| t.name.clone()).collect()
+    }
+
+    /// Scope violations and unknown or repeated dependencies of `target`.
+    fn target_diagnostics(&self, target: &InitTarget, known: &BTreeSet<Symbol>) -> Vec<Diagnostic> {
+        let mut diagnostics = Vec::new();
+        if let Some(allowed) = (self.stage_scopes).violation(&target.stage_expr, &target.scope_expr)
+        {
+            diagnostics.push(Diagnostic {
+                range: target.scope_range,
+                severity: Some(DiagnosticSeverity::ERROR),
+                code: Some(NumberOrString::String("init/scope-not-allowed".into())),
+                message: format!(
+                    "Scope '{}' is not allowed in stage '{}' (allowed: {})",
+                    target.scope_expr,
+                    target.stage_expr,
+                    allowed.join(", ")
+                ),
+                source: Some("cronus-init".into()),
+                ..Diagnostic::default()
+            });
+        }
+
+        let mut counts: HashMap<&Symbol, usize> = HashMap::new();
+        for slot in &target.dependency_slots {
+            *counts.entry(&slot.name).or_default() += 1;
+        }
+
+        let mut seen: HashSet<&Symbol> = HashSet::new();
+        for slot in &target.dependency_slots {
+            let repeated = !seen.insert(&slot.name);
+            if !known.contains(&slot.name) {
+                diagnostics.push(Diagnostic {
+                    range: slot.range,
+                    severity: Some(DiagnosticSeverity::ERROR),
+                    code: Some(NumberOrString::String("init/unknown-dependency".into())),
+                    message: format!("Unknown init dependency '{}'", slot.name),
+                    source: Some("cronus-init".into()),
+                    ..Diagnostic::default()
+                });
+            } else if counts[&slot.name] > 1 {
+                let mut diagnostic = Diagnostic {
+                    range: slot.range,
+                    severity: Some(DiagnosticSeverity::WARNING),
+                    code: Some(NumberOrString::String("init/duplicate-dependency".into())),
+                    message: format!("Duplicate dependency '{}' in {}", slot.name, target.name),
+                    source: Some("cronus-init".into()),
+                    ..Diagnostic::default()
+                };
+                if repeated {
+                    QuickFix {
+                        title: format!("Remove duplicate dependency '{}'", slot.name),
+                        edits: vec![TextEdit {
+                            range: slot.removal,
+                            new_text: String::new(),
+                        }],
+                    }
+                    .attach(&mut diagnostic);
+                }
+                diagnostics.push(diagnostic);
+            }
+        }
+        diagnostics
+    }
+
+    /// The target named at `position` in `path`, by its definition or as a dependency, with the
+    /// range of the name.
+    fn name_at(&self, path: &Path, position: &Position) -> Option<(&Symbol, Range)> {
+        self.targets_by_file.get(path)?.iter().find_map(|target| {
+            if range_contains(&target.name_range, position) {
+                return Some((&target.name, target.name_range));
+            }
+            (target.dependency_slots.iter())
+                .find(|slot| range_contains(&slot.range, position))
+                .map(|slot| (&slot.name, slot.range))
+        })
+    }
+
+    /// Markdown describing `target`: its stage and scope, where it is defined and what it
+    /// depends on.
+    fn target_summary(&self, target: &InitTarget) -> String {
+        let file = (target.file)
+            .strip_prefix(&self.project_root)
+            .unwrap_or(&target.file);
+        let mut value = format!(
+            "**{}**: stage `{}`, scope `{}`\n\nDefined in `{}:{}`\n\n",
+            target.name,
+            target.stage_expr,
+            target.scope_expr,
+            file.display(),
+            target.name_range.start.line + 1
+        );
+        if target.dependency_slots.is_empty() {
+            value.push_str("No dependencies");
+        } else {
+            let dependencies: Vec<String> = (target.dependency_slots.iter())
+                .map(|slot| format!("`{}`", slot.name))
+                .collect();
+            write!(value, "Depends on {}", dependencies.join(", ")).unwrap();
+        }
+        if let Some(documentation) = &target.documentation {
+            write!(value, "\n\n{documentation}").unwrap();
+        }
+        value
+    }
+
+    /// Where a target is defined and how many targets depend on it, to tell apart similarly
+    /// named targets while completing, followed by its doc comment.
+    fn completion_documentation(&self, target: &InitTarget, dependents: usize) -> Documentation {
+        let file = target
+            .file
+            .strip_prefix(&self.project_root)
+            .unwrap_or(&target.file);
+        let plural = if dependents == 1 { "" } else { "s" };
+        let mut value = format!(
+            "Referenced by {dependents} target{plural}, defined in `{}`",
+            file.display()
+        );
+        if let Some(documentation) = &target.documentation {
+            write!(value, "\n\n{documentation}").unwrap();
+        }
+        Documentation::MarkupContent(MarkupContent {
+            kind: MarkupKind::Markdown,
+            value,
+        })
+    }
+
+    /// A Markdown report on where `name` runs within its stage and which of its dependencies
+    /// decide that. Targets of a stage run after those of earlier stages, and within a stage
+    /// after everything they depend on, so only dependencies in the same stage can delay one.
+    fn explain_order(&self, name: &str) -> Option<String> {
+        let targets: HashMap<&str, &InitTarget> = self
+            .iter_targets()
+            .map(|target| (target.name.as_str(), target))
+            .collect();
+        let target = *targets.get(name)?;
+        let mut levels = OrderLevels::new(&targets);
+        let level = levels.level(target);
+
+        let stage: Vec<&InitTarget> = (targets.values())
+            .filter(|other| other.stage_expr == target.stage_expr)
+            .copied()
+            .collect();
+        let deepest = stage
+            .iter()
+            .map(|other| levels.level(other))
+            .max()
+            .unwrap_or(0);
+        let mut before = BTreeSet::new();
+        levels.collect_same_stage_dependencies(target, &mut before);
+
+        let mut report = format!("# Init order of `{name}`\n\n");
+        let _ = writeln!(report, "- Stage: `{}`", target.stage_expr);
+        let _ = writeln!(report, "- Scope: `{}`", target.scope_expr);
+        let _ = writeln!(
+            report,
+            "- Level {level} of {deepest} in its stage of {} targets; runs after at least {} of \
+             them",
+            stage.len(),
+            before.len()
+        );
+        if !levels.cycle.is_empty() {
+            let _ = writeln!(
+                report,
+                "- Levels are approximate: dependency cycle through {}",
+                (levels.cycle.iter())
+                    .map(|name| format!("`{name}`"))
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            );
+        }
+
+        let chain = levels.binding_chain(target);
+        if chain.len() > 1 {
+            let _ = writeln!(report, "\n## Binding chain\n\n{}", chain.join(" → "));
+        }
+
+        if !target.dependency_slots.is_empty() {
+            let _ = writeln!(report, "\n## Dependencies\n");
+        }
+        for slot in &target.dependency_slots {
+            let reason = match targets.get(slot.name.as_str()) {
+                None => "unknown target, ignored".to_string(),
+                Some(dependency) if dependency.stage_expr != target.stage_expr => format!(
+                    "in stage `{}`, ordered by stage rather than by this edge",
+                    dependency.stage_expr
+                ),
+                Some(dependency) if levels.cycle.contains(dependency.name.as_str()) => {
+                    format!("level {}, on a dependency cycle", levels.level(dependency))
+                }
+                Some(dependency) => {
+                    let dependency_level = levels.level(dependency);
+                    if dependency_level + 1 == level {
+                        format!("level {dependency_level}, binding: it decides this level")
+                    } else {
+                        format!(
+                            "level {dependency_level}, not binding: {} level(s) of slack",
+                            level.saturating_sub(dependency_level + 1)
+                        )
+                    }
+                }
+            };
+            let _ = writeln!(report, "- `{}`: {reason}", slot.name);
+        }
+        Some(report)
+    }
+
+    /// The targets of `stage` in the order they run: by level, so each comes after the targets of
+    /// its stage it depends on, and alphabetically within a level.
+    fn stage_targets(&self, stage: &str) -> Option<serde_json::Value> {
+        let targets: HashMap<&str, &InitTarget> = self
+            .iter_targets()
+            .map(|target| (target.name.as_str(), target))
+            .collect();
+        let mut levels = OrderLevels::new(&targets);
+        let mut ordered: Vec<(usize, &InitTarget)> = (targets.values())
+            .filter(|target| target.stage_expr == stage)
+            .map(|target| (levels.level(target), *target))
+            .collect();
+        if ordered.is_empty() {
+            return None;
+        }
+        ordered.sort_by(|(a_level, a), (b_level, b)| {
+            a_level.cmp(b_level).then_with(|| a.name.cmp(&b.name))
+        });
+
+        let entries: Vec<serde_json::Value> = (ordered.iter())
+            .map(|(level, target)| {
+                json!({
+                    "target": target.name,
+                    "level": level,
+                    "scope": target.scope_expr,
+                    "file": target.file,
+                    "range": target.name_range,
+                })
+            })
+            .collect();
+        Some(json!({
+            "stage": stage,
+            "targets": entries,
+            "cycle": levels.cycle,
+        }))
+    }
+
+    /// Targets nothing reachable from `roots` depends on, grouped into subtrees: each entry is an
+    /// unreachable target no other unreachable target depends on, with the unreachable targets
+    /// below it.
+    fn dead_targets(&self, roots: &[String]) -> serde_json::Value {
+        let targets: HashMap<&str, &InitTarget> = self
+            .iter_targets()
+            .map(|target| (target.name.as_str(), target))
+            .collect();
+
+        let (known_roots, unknown_roots): (Vec<&str>, Vec<&str>) = roots
+            .iter()
+            .map(String::as_str)
+            .partition(|root| targets.contains_key(root));
+        let mut reachable: BTreeSet<&str> = BTreeSet::new();
+        let mut pending = known_roots;
+        while let Some(name) = pending.pop() {
+            if reachable.insert(name) {
+                pending.extend(known_dependencies(targets[name], &targets));
+            }
+        }
+        let unreachable: BTreeSet<&str> = (targets.keys().copied())
+            .filter(|name| !reachable.contains(name))
+            .collect();
+
+        let depended_on: BTreeSet<&str> = (unreachable.iter())
+            .flat_map(|name| known_dependencies(targets[name], &targets))
+            .collect();
+        let mut claimed: BTreeSet<&str> = BTreeSet::new();
+        let mut subtrees: Vec<(&str, BTreeSet<&str>)> = Vec::new();
+        // Tops first; targets left unclaimed after them only depend on each other in a cycle.
+        let tops = (unreachable.iter()).filter(|name| !depended_on.contains(*name));
+        for &top in tops.chain(&unreachable) {
+            if claimed.contains(top) {
+                continue;
+            }
+            let mut subtree = BTreeSet::new();
+            let mut pending = known_dependencies(targets[top], &targets);
+            while let Some(name) = pending.pop() {
+                if name != top && unreachable.contains(name) && subtree.insert(name) {
+                    pending.extend(known_dependencies(targets[name], &targets));
+                }
+            }
+            claimed.insert(top);
+            claimed.extend(&subtree);
+            subtrees.push((top, subtree));
+        }
+
+        let mut report = String::from("# Unreachable init targets\n\n");
+        let _ = writeln!(
+            report,
+            "{} of {} targets are not reachable from {}.",
+            unreachable.len(),
+            targets.len(),
+            (roots.iter())
+                .map(|root| format!("`{root}`"))
+                .collect::<Vec<_>>()
+                .join(", ")
+        );
+        if !unknown_roots.is_empty() {
+            let _ = writeln!(
+                report,
+                "\nUnknown roots, ignored: {}.",
+                (unknown_roots.iter())
+                    .map(|root| format!("`{root}`"))
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            );
+        }
+        for (top, subtree) in &subtrees {
+            let target = targets[top];
+            let _ = writeln!(
+                report,
+                "\n## `{top}`\n\n{}, stage `{}`",
+                target.file.display(),
+                target.stage_expr
+            );
+            if !subtree.is_empty() {
+                let _ = writeln!(report);
+            }
+            for name in subtree {
+                let _ = writeln!(report, "- `{name}`");
+            }
+        }
+
+        let subtrees: Vec<serde_json::Value> = (subtrees.iter())
+            .map(|(top, subtree)| {
+                let target = targets[top];
+                json!({
+                    "target": top,
+                    "file": target.file,
+                    "range": target.name_range,
+                    "subtree": subtree,
+                })
+            })
+            .collect();
+        json!({
+            "roots": roots,
+            "unknownRoots": unknown_roots,
+            "unreachable": subtrees,
+            "report": report,
+        })
+    }
+
+    /// The targets depending on each name, each listed once however often it names it.
+    fn reverse_dependencies(&self) -> HashMap<&Symbol, Vec<&InitTarget>> {
+        let mut dependents: HashMap<&Symbol, Vec<&InitTarget>> = HashMap::new();
+        for target in self.iter_targets() {
+            let names: BTreeSet<&Symbol> = (target.dependency_slots.iter())
+                .map(|slot| &slot.name)
+                .collect();
+            for name in names {
+                dependents.entry(name).or_default().push(target);
+            }
+        }
+        dependents
+    }
+
+    /// Each target's place in the order its stage runs targets, counting from 1, and the size of
+    /// the stage. The order is topological over same-stage dependencies, with ties broken by name;
+    /// targets on or behind a dependency cycle get no place.
+    fn stage_sequence(&self) -> HashMap<&Symbol, (usize, usize)> {
+        let mut targets: HashMap<&Symbol, &InitTarget> = HashMap::new();
+        for target in self.iter_targets() {
+            targets.entry(&target.name).or_insert(target);
+        }
+        let mut stages: BTreeMap<&str, Vec<&InitTarget>> = BTreeMap::new();
+        for target in targets.values() {
+            stages.entry(&target.stage_expr).or_default().push(target);
+        }
+
+        let mut sequence = HashMap::new();
+        for stage in stages.values() {
+            let mut pending: HashMap<&Symbol, usize> = HashMap::new();
+            let mut dependents: HashMap<&Symbol, Vec<&Symbol>> = HashMap::new();
+            for target in stage {
+                let dependencies: BTreeSet<&Symbol> = (target.dependency_slots.iter())
+                    .filter_map(|slot| targets.get(&slot.name))
+                    .filter(|dependency| dependency.stage_expr == target.stage_expr)
+                    .map(|dependency| &dependency.name)
+                    .collect();
+                pending.insert(&target.name, dependencies.len());
+                for dependency in dependencies {
+                    dependents.entry(dependency).or_default().push(&target.name);
+                }
+            }
+            let mut ready: BTreeSet<&Symbol> = (pending.iter())
+                .filter(|(_, count)| **count == 0)
+                .map(|(name, _)| *name)
+                .collect();
+            let mut place = 0;
+            while let Some(name) = ready.pop_first() {
+                place += 1;
+                sequence.insert(name, (place, stage.len()));
+                for dependent in dependents.get(name).into_iter().flatten() {
+                    let count = pending.get_mut(dependent).unwrap();
+                    *count -= 1;
+                    if *count == 0 {
+                        ready.insert(dependent);
+                    }
+                }
+            }
+        }
+        sequence
+    }
+
     fn completion_items(&self) -> Vec<CompletionItem> {
+        let dependents = self.reverse_dependencies();
         let mut items: Vec<CompletionItem> = self
             .iter_targets()
-            .map(|target| CompletionItem {
-                label: target.name.clone(),
-                kind: Some(CompletionItemKind::CONSTANT),
-                detail: Some(format!("{}/{}", target.stage_expr, target.scope_expr)),
-                ..CompletionItem::default()
+            .map(|target| {
+                let dependents = dependents.get(&target.name).map_or(0, Vec::len);
+                let mut item = name_completion(
+                    &target.name,
+                    CompletionItemKind::CONSTANT,
+                    format!("{}/{}", target.stage_expr, target.scope_expr),
+                    dependents,
+                );
+                item.data = Some(json!({ "file": target.file }));
+                item
             })
             .collect();
-        items.sort_by(|a, b| a.label.to_lowercase().cmp(&b.label.to_lowercase()));
+        items.sort_by_key(|item| item.label.to_lowercase());
         items
     }
 }
 
+impl InitTargetParser {
+    fn args_for(&self, path: &Path, language: SourceLanguage) -> Vec<String> {
+        let db = self.compile_commands.as_ref();
+        let inferred = db.and_then(|db| match db.entry_args(path) {
+            Some(args) => Some(args.to_vec()),
+            None if self.parse_options.is_header(path) => db.header_args(path),
+            None => None,
+        });
+        let mut args = inferred.unwrap_or_else(|| language.default_args());
+        args.extend_from_slice(db.map_or(&[], CompileCommands::extra_args));
+        args
+    }
+
+    /// Reparses `path` on its own, for requests about one position in it.
+    fn with_translation_unit<T>(
+        &self,
+        path: &Path,
+        content: Option<&str>,
+        f: impl FnOnce(&TranslationUnit) -> Option<T>,
+    ) -> Result<Option<T>> {
+        let language = self
+            .parse_options
+            .language_of(path)
+            .unwrap_or(SourceLanguage::C);
+        let args = self.args_for(path, language);
+        let flags = self.parse_options.translation_unit_flags();
+        (self.units).with_unit(&self.indexes, path, &args, content, flags, |tu| Ok(f(tu)))
+    }
+}
+
+impl FileParser for InitTargetParser {
+    fn parse_file(&self, path: &Path, content: Option<&str>) -> Result<Option<FileData>> {
+        let Some(language) = self.parse_options.language_of(path) else {
+            return Ok(None);
+        };
+        if self.parse_options.prescan && !may_contain(path, content, INIT_MACROS) {
+            // Closed files don't keep units; open ones may need theirs again after the next edit.
+            if content.is_none() {
+                self.units.forget(path);
+            }
+            return Ok(Some(Box::new(InitFileData::default())));
+        }
+
+        let args = self.args_for(path, language);
+
+        let flags = self.parse_options.translation_unit_flags();
+        let targets = self
+            .units
+            .with_unit(&self.indexes, path, &args, content, flags, |tu| {
+                parse_targets(self, tu, path, content)
+            })?;
+
+        Ok(Some(Box::new(targets)))
+    }
+
+    fn expand_macro(
+        &self,
+        path: &Path,
+        content: Option<&str>,
+        position: &Position,
+    ) -> Result<Option<MacroExpansion>> {
+        self.with_translation_unit(path, content, |tu| {
+            expand_macro_at(tu, position, INIT_MACROS)
+        })
+    }
+
+    fn macro_source(
+        &self,
+        path: &Path,
+        content: Option<&str>,
+        position: &Position,
+    ) -> Result<Option<MacroSource>> {
+        let source = source_text(path, content);
+        let hovered = source
+            .as_deref()
+            .and_then(|text| identifier_at(text, *position));
+        if !hovered.is_some_and(|name| INIT_MACROS.contains(&name)) {
+            return Ok(None);
+        }
+        self.with_translation_unit(path, content, |tu| {
+            macro_source_at(tu, position, INIT_MACROS)
+        })
+    }
+}
+
 impl LspPlugin for InitDependencyPlugin {
-    fn on_file_updated(&mut self, path: &Path, content: Option<&str>) -> Result<()> {
-        if path.extension().and_then(|s| s.to_str()) != Some("c") {
-            return Ok(());
+    fn name(&self) -> &'static str {
+        "init-deps"
+    }
+
+    fn parser(&self) -> Arc<dyn FileParser> {
+        self.parser.clone()
+    }
+
+    fn apply_file(&mut self, path: &Path, data: FileData) -> Impact {
+        let Ok(data) = data.downcast::<InitFileData>() else {
+            return Impact::Unchanged;
+        };
+        let InitFileData { targets, disabled } = *data;
+        let mut impact = update_impact(self.targets_by_file.get(path), &targets, |targets| {
+            target_names(targets)
+        });
+        if self
+            .disabled_by_file
+            .get(path)
+            .map_or(&[][..], Vec::as_slice)
+            != disabled
+        {
+            impact = impact.max(Impact::File);
+        }
+        self.targets_by_file.insert(path.to_path_buf(), targets);
+        if disabled.is_empty() {
+            self.disabled_by_file.remove(path);
+        } else {
+            self.disabled_by_file.insert(path.to_path_buf(), disabled);
+        }
+        impact
+    }
+
+    fn on_file_removed(&mut self, path: &Path) -> Impact {
+        let disabled = self.disabled_by_file.remove(path).is_some();
+        let impact = removal_impact(self.targets_by_file.remove(path).as_ref(), |targets| {
+            target_names(targets)
+        });
+        if disabled {
+            impact.max(Impact::File)
+        } else {
+            impact
         }
+    }
 
-        let canonical = path.canonicalize().unwrap_or_else(|_| path.to_path_buf());
-        let args = self
-            .compile_commands
-            .as_ref()
-            .map(|db| db.args_for(&canonical))
-            .unwrap_or_else(|| DEFAULT_CLANG_ARGS.iter().map(|s| s.to_string()).collect());
+    fn save_file(&self, path: &Path) -> Option<serde_json::Value> {
+        let targets = self.targets_by_file.get(path)?;
+        let disabled = self.disabled_by_file.get(path);
+        Some(json!({ "targets": targets, "disabled": disabled.unwrap_or(&Vec::new()) }))
+    }
 
-        let targets = parse_targets(&canonical, &args, content)?;
-        self.targets_by_file.insert(canonical, targets);
+    fn encode_file(&self, data: &FileData) -> Result<serde_json::Value> {
+        let data = data
+            .downcast_ref::<InitFileData>()
+            .context("unexpected parse data")?;
+        Ok(serde_json::to_value(data)?)
+    }
 
-        Ok(())
+    fn load_file(&self, value: serde_json::Value) -> Result<FileData> {
+        Ok(Box::new(serde_json::from_value::<InitFileData>(value)?))
     }
 
-    fn on_file_removed(&mut self, path: &Path) {
-        if let Ok(canonical) = path.canonicalize() {
-            self.targets_by_file.remove(&canonical);
+    fn memory_usage(&self) -> usize {
+        let disabled: usize = self.disabled_by_file.values().map(Vec::len).sum();
+        self.iter_targets()
+            .map(InitTarget::estimated_size)
+            .sum::<usize>()
+            + disabled * size_of::<DisabledInvocation>()
+    }
+
+    fn evict_file(&mut self, path: &Path) -> usize {
+        let Some(targets) = self.targets_by_file.get_mut(path) else {
+            return 0;
+        };
+        let mut freed = 0;
+        for target in targets {
+            let before = target.estimated_size();
+            target.dependency_slots = Vec::new();
+            freed += before - target.estimated_size();
         }
+        freed
     }
 
-    fn completions(&self, path: &Path, position: &Position) -> Option<Vec<CompletionItem>> {
-        let canonical = path.canonicalize().unwrap_or_else(|_| path.to_path_buf());
-        let targets = self.targets_by_file.get(&canonical)?;
-        let in_region = targets
-            .iter()
-            .any(|target| range_contains(&target.dependency_region, position));
+    fn completions(
+        &self,
+        path: &Path,
+        position: &Position,
+        text: Option<&str>,
+    ) -> Option<Vec<CompletionItem>> {
+        let targets = self.targets_by_file.get(path)?;
+        let target =
+            (targets.iter()).find(|target| range_contains(&target.dependency_region, position))?;
 
-        if !in_region {
-            return None;
+        let mut items = self.completion_items();
+        if let Some((range, suffix)) =
+            text.and_then(|text| quoted_insertion(text, target, position))
+        {
+            for item in &mut items {
+                item.text_edit = Some(CompletionTextEdit::Edit(TextEdit {
+                    range,
+                    new_text: format!("\"{}\"{suffix}", item.label),
+                }));
+            }
         }
+        Some(items)
+    }
 
-        Some(self.completion_items())
+    fn resolve_completion(&self, mut item: CompletionItem) -> CompletionItem {
+        let file = (item.data.as_ref())
+            .and_then(|data| serde_json::from_value::<PathBuf>(data.get("file")?.clone()).ok());
+        let target = (file.and_then(|file| self.targets_by_file.get(&file))).and_then(|targets| {
+            targets
+                .iter()
+                .find(|target| target.name.as_str() == item.label)
+        });
+        if let Some(target) = target {
+            let dependents = (self.iter_targets())
+                .filter(|other| (other.dependency_slots.iter()).any(|s| s.name == target.name))
+                .count();
+            item.documentation = Some(self.completion_documentation(target, dependents));
+        }
+        item
     }
 
-    fn diagnostics(&self) -> HashMap<PathBuf, Vec<Diagnostic>> {
-        let known: BTreeSet<String> = self.iter_targets().map(|t| t.name.clone()).collect();
-        let mut diag_map: HashMap<PathBuf, Vec<Diagnostic>> = HashMap::new();
+    fn hover(&self, path: &Path, position: &Position) -> Option<Hover> {
+        let (name, range) = self.name_at(path, position)?;
+        let mut targets: Vec<&InitTarget> = self
+            .iter_targets()
+            .filter(|target| target.name == *name)
+            .collect();
+        targets.sort_by_key(|target| (&target.file, target.name_range.start));
+        let value = (targets.iter())
+            .map(|target| self.target_summary(target))
+            .collect::<Vec<_>>()
+            .join("\n\n---\n\n");
+        (!value.is_empty()).then_some(Hover {
+            contents: HoverContents::Markup(MarkupContent {
+                kind: MarkupKind::Markdown,
+                value,
+            }),
+            range: Some(range),
+        })
+    }
 
+    fn references(
+        &self,
+        path: &Path,
+        position: &Position,
+        include_declaration: bool,
+    ) -> Vec<SymbolLocation> {
+        let Some((name, _)) = self.name_at(path, position) else {
+            return Vec::new();
+        };
+        let mut locations = Vec::new();
         for target in self.iter_targets() {
-            let mut counts: HashMap<&str, usize> = HashMap::new();
-            for slot in &target.dependency_slots {
-                *counts.entry(slot.name.as_str()).or_default() += 1;
+            if include_declaration && target.name == *name {
+                locations.push(SymbolLocation {
+                    path: target.file.clone(),
+                    range: target.name_range,
+                    container: None,
+                });
             }
+            let slots = (target.dependency_slots.iter()).filter(|slot| slot.name == *name);
+            locations.extend(slots.map(|slot| SymbolLocation {
+                path: target.file.clone(),
+                range: slot.range,
+                container: Some(target.name.clone()),
+            }));
+        }
+        locations.sort_by_key(|location| (location.path.clone(), location.range.start));
+        locations
+    }
 
+    fn highlights(&self, path: &Path) -> Vec<Highlight> {
+        let Some(targets) = self.targets_by_file.get(path) else {
+            return Vec::new();
+        };
+        let mut highlights = Vec::new();
+        for target in targets {
+            let mut highlight = |range, kind, declaration| {
+                highlights.push(Highlight {
+                    range,
+                    kind,
+                    declaration,
+                })
+            };
+            highlight(target.name_range, HighlightKind::InitTarget, true);
+            // Missing arguments fall back to the range of the whole invocation.
+            if !target.stage_expr.is_empty() {
+                highlight(target.stage_range, HighlightKind::Stage, false);
+            }
+            if !target.scope_expr.is_empty() {
+                highlight(target.scope_range, HighlightKind::Scope, false);
+            }
             for slot in &target.dependency_slots {
-                if !known.contains(&slot.name) {
-                    diag_map
-                        .entry(target.file.clone())
-                        .or_default()
-                        .push(Diagnostic {
-                            range: slot.range,
-                            severity: Some(DiagnosticSeverity::ERROR),
-                            message: format!("Unknown init dependency '{}'", slot.name),
-                            source: Some("cronus-init".into()),
-                            ..Diagnostic::default()
-                        });
-                } else if counts[slot.name.as_str()] > 1 {
-                    diag_map
-                        .entry(target.file.clone())
-                        .or_default()
-                        .push(Diagnostic {
-                            range: slot.range,
-                            severity: Some(DiagnosticSeverity::WARNING),
-                            message: format!(
-                                "Duplicate dependency '{}' in {}",
-                                slot.name, target.name
-                            ),
-                            source: Some("cronus-init".into()),
-                            ..Diagnostic::default()
-                        });
+                highlight(slot.range, HighlightKind::Dependency, false);
+            }
+        }
+        highlights
+    }
+
+    fn name_links(&self, path: &Path) -> Vec<NameLink> {
+        let Some(targets) = self.targets_by_file.get(path) else {
+            return Vec::new();
+        };
+        let mut definitions: HashMap<&Symbol, &InitTarget> = HashMap::new();
+        for target in self.iter_targets() {
+            let first = definitions.entry(&target.name).or_insert(target);
+            if (&target.file, target.name_range.start) < (&first.file, first.name_range.start) {
+                *first = target;
+            }
+        }
+        (targets.iter())
+            .flat_map(|target| &target.dependency_slots)
+            .filter_map(|slot| {
+                let definition = definitions.get(&slot.name)?;
+                Some(NameLink {
+                    range: slot.range,
+                    target: SymbolLocation {
+                        path: definition.file.clone(),
+                        range: definition.name_range,
+                        container: None,
+                    },
+                })
+            })
+            .collect()
+    }
+
+    fn inlay_hints(&self, path: &Path) -> Vec<InlayHint> {
+        let Some(targets) = self.targets_by_file.get(path) else {
+            return Vec::new();
+        };
+        let sequence = self.stage_sequence();
+        (targets.iter())
+            .filter_map(|target| {
+                let (place, size) = sequence.get(&target.name)?;
+                Some(InlayHint {
+                    position: target.name_range.end,
+                    label: InlayHintLabel::String(format!("#{place}")),
+                    kind: None,
+                    text_edits: None,
+                    tooltip: Some(InlayHintTooltip::String(format!(
+                        "Runs {place} of {size} in stage {}",
+                        target.stage_expr
+                    ))),
+                    padding_left: Some(true),
+                    padding_right: None,
+                    data: None,
+                })
+            })
+            .collect()
+    }
+
+    fn reference_lenses(&self, path: &Path) -> Vec<ReferenceLens> {
+        let Some(targets) = self.targets_by_file.get(path) else {
+            return Vec::new();
+        };
+        let dependents = self.reverse_dependencies();
+        (targets.iter())
+            .map(|target| {
+                let mut locations: Vec<SymbolLocation> = (dependents.get(&target.name))
+                    .into_iter()
+                    .flatten()
+                    .map(|dependent| SymbolLocation {
+                        path: dependent.file.clone(),
+                        range: dependent.name_range,
+                        container: None,
+                    })
+                    .collect();
+                locations.sort_by(|a, b| (&a.path, a.range.start).cmp(&(&b.path, b.range.start)));
+                let plural = if locations.len() == 1 { "" } else { "s" };
+                ReferenceLens {
+                    range: target.name_range,
+                    title: format!("depended on by {} target{plural}", locations.len()),
+                    locations,
                 }
+            })
+            .collect()
+    }
+
+    fn prepare_rename(&self, path: &Path, position: &Position) -> Option<Range> {
+        self.name_at(path, position).map(|(_, range)| range)
+    }
+
+    fn rename(
+        &self,
+        path: &Path,
+        position: &Position,
+        new_name: &str,
+    ) -> Result<Option<HashMap<PathBuf, Vec<TextEdit>>>> {
+        let Some((name, _)) = self.name_at(path, position) else {
+            return Ok(None);
+        };
+        let taken = |target: &InitTarget| target.name.as_str() == new_name;
+        if name.as_str() != new_name && self.iter_targets().any(taken) {
+            bail!("an init target named {new_name} already exists");
+        }
+        let mut edits: HashMap<PathBuf, Vec<TextEdit>> = HashMap::new();
+        let mut unquoted = false;
+        for target in self.iter_targets() {
+            let mut renamed = Vec::new();
+            if target.name == *name {
+                renamed.push((target.name_range, target.name_quoted));
+            }
+            let slots = (target.dependency_slots.iter()).filter(|slot| slot.name == *name);
+            renamed.extend(slots.map(|slot| (slot.range, slot.quoted)));
+            for (range, quoted) in renamed {
+                unquoted |= !quoted;
+                let new_text = if quoted {
+                    format!("\"{new_name}\"")
+                } else {
+                    new_name.to_string()
+                };
+                let file = edits.entry(target.file.clone()).or_default();
+                file.push(TextEdit { range, new_text });
             }
         }
+        if !valid_target_name(new_name, unquoted) {
+            bail!("{new_name:?} is not a valid init target name");
+        }
+        Ok(Some(edits))
+    }
+
+    fn signatures(&self) -> &'static [MacroSignature] {
+        &[MacroSignature {
+            name: "INIT_TARGET",
+            parameters: &["name", "stage", "scope", "deps"],
+            documentation: "Declares the init target `name` in `stage` and `scope`, to run after the targets listed in `deps`.",
+        }]
+    }
 
+    fn cross_file_codes(&self) -> &'static [&'static str] {
+        &["init/unknown-dependency"]
+    }
+
+    fn symbols(&self) -> Vec<SymbolInfo> {
+        let mut dependents: HashMap<&Symbol, Vec<SymbolLocation>> = HashMap::new();
+        for target in self.iter_targets() {
+            for slot in &target.dependency_slots {
+                dependents
+                    .entry(&slot.name)
+                    .or_default()
+                    .push(SymbolLocation {
+                        path: target.file.clone(),
+                        range: slot.range,
+                        container: Some(target.name.clone()),
+                    });
+            }
+        }
+
+        self.iter_targets()
+            .map(|target| SymbolInfo {
+                name: target.name.clone(),
+                kind: SymbolKind::CONSTANT,
+                location: SymbolLocation {
+                    path: target.file.clone(),
+                    range: target.name_range,
+                    container: None,
+                },
+                documentation: target.documentation.clone(),
+                fields: vec![
+                    ("stage", target.stage_expr.clone()),
+                    ("scope", target.scope_expr.clone()),
+                ],
+                dependencies: target
+                    .dependency_slots
+                    .iter()
+                    .map(|slot| slot.name.clone())
+                    .collect(),
+                references: dependents.get(&target.name).cloned().unwrap_or_default(),
+            })
+            .collect()
+    }
+
+    fn document_symbols(&self, path: &Path) -> Vec<DocumentSymbol> {
+        let Some(targets) = self.targets_by_file.get(path) else {
+            return Vec::new();
+        };
+        targets
+            .iter()
+            .map(|target| {
+                let dependencies = (target.dependency_slots.iter())
+                    .map(|slot| {
+                        outline_symbol(
+                            slot.name.to_string(),
+                            Some("dependency".into()),
+                            SymbolKind::KEY,
+                            slot.range,
+                            slot.range,
+                            Vec::new(),
+                        )
+                    })
+                    .collect();
+                outline_symbol(
+                    target.name.to_string(),
+                    Some(format!("{}/{}", target.stage_expr, target.scope_expr)),
+                    SymbolKind::CONSTANT,
+                    range_union(&target.name_range, &target.dependency_region),
+                    target.name_range,
+                    dependencies,
+                )
+            })
+            .collect()
+    }
+
+    fn commands(&self) -> &'static [&'static str] {
+        &[
+            EXPLAIN_ORDER_COMMAND,
+            DEAD_TARGETS_COMMAND,
+            STAGE_TARGETS_COMMAND,
+        ]
+    }
+
+    fn execute_command(
+        &self,
+        command: &str,
+        arguments: &[serde_json::Value],
+    ) -> Result<serde_json::Value> {
+        if command == DEAD_TARGETS_COMMAND {
+            let roots: Vec<String> = match arguments.first() {
+                Some(roots) => serde_json::from_value(roots.clone())
+                    .context("expected an array of root target names")?,
+                None => self.roots.clone(),
+            };
+            if roots.is_empty() {
+                bail!("no init roots given and none configured with --init-root");
+            }
+            return Ok(self.dead_targets(&roots));
+        }
+        if command == STAGE_TARGETS_COMMAND {
+            let stage = arguments
+                .first()
+                .and_then(|argument| argument.as_str())
+                .context("expected a stage constant")?;
+            return self
+                .stage_targets(stage.trim())
+                .with_context(|| format!("no init targets in stage '{stage}'"));
+        }
+        if command != EXPLAIN_ORDER_COMMAND {
+            bail!("unknown command {command}");
+        }
+        let name = arguments
+            .first()
+            .and_then(|argument| argument.as_str())
+            .context("expected the name of an init target")?;
+        let report = self
+            .explain_order(name)
+            .with_context(|| format!("unknown init target '{name}'"))?;
+        Ok(json!({ "target": name, "report": report }))
+    }
+
+    fn format_edits(&self, path: &Path, text: &str) -> Vec<TextEdit> {
+        let Some(targets) = self.targets_by_file.get(path) else {
+            return Vec::new();
+        };
+        (targets.iter())
+            .filter_map(|target| format_dependencies(text, target, &self.dependency_style))
+            .collect()
+    }
+
+    fn diagnostics(&self) -> HashMap<PathBuf, Vec<Diagnostic>> {
+        let known = self.known_targets();
+        let mut diag_map: HashMap<PathBuf, Vec<Diagnostic>> = HashMap::new();
+        for target in self.iter_targets() {
+            let diagnostics = self.target_diagnostics(target, &known);
+            if !diagnostics.is_empty() {
+                diag_map
+                    .entry(target.file.clone())
+                    .or_default()
+                    .extend(diagnostics);
+            }
+        }
+        for (file, disabled) in &self.disabled_by_file {
+            (diag_map.entry(file.clone()).or_default())
+                .extend((disabled.iter()).map(|invocation| {
+                    invocation.diagnostic("init/disabled-target", "cronus-init")
+                }));
+        }
         diag_map
     }
+
+    fn file_diagnostics(&self, path: &Path) -> Vec<Diagnostic> {
+        let known = self.known_targets();
+        let targets = self.targets_by_file.get(path).into_iter().flatten();
+        let disabled = self.disabled_by_file.get(path).into_iter().flatten();
+        (targets.flat_map(|target| self.target_diagnostics(target, &known)))
+            .chain(
+                disabled
+                    .map(|invocation| invocation.diagnostic("init/disabled-target", "cronus-init")),
+            )
+            .collect()
+    }
 }
 
-fn parse_targets(path: &Path, args: &[String], content: Option<&str>) -> Result<Vec<InitTarget>> {
-    let filename =
-        CString::new(path.as_os_str().to_string_lossy().into_owned()).context("path encode")?;
-    let arg_cstrings: Vec<CString> = args
-        .iter()
-        .map(|a| CString::new(a.as_str()))
-        .collect::<std::result::Result<_, _>>()?;
-    let arg_ptrs: Vec<*const c_char> = arg_cstrings.iter().map(|s| s.as_ptr()).collect();
-
-    let mut unsaved_storage: Vec<CString> = Vec::new();
-    let mut unsaved_files: Vec<CXUnsavedFile> = Vec::new();
-    if let Some(text) = content {
-        let text_c = CString::new(text)?;
-        let len = text.len() as c_ulong;
-        unsaved_storage.push(text_c);
-        unsaved_files.push(CXUnsavedFile {
-            Filename: filename.as_ptr(),
-            Contents: unsaved_storage.last().unwrap().as_ptr(),
-            Length: len,
+/// The dependencies named in the tokens of an `INIT_TARGET` dependency argument: string literals,
+/// and depending on `forms` bare identifiers and arguments of wrapper macros.
+fn dependency_slots(tokens: &[Token], forms: &DependencyForms) -> Option<Vec<DependencySlot>> {
+    let mut slots = Vec::new();
+    let mut i = 0;
+    while let Some(token) = tokens.get(i) {
+        let start = i;
+        i += 1;
+        let called = tokens.get(i).is_some_and(|next| next.spelling == "(");
+        let name_token = match token.kind {
+            TokenKind::Literal => token,
+            TokenKind::Identifier if called && forms.ref_macros.contains(&token.spelling) => {
+                match tokens.get(i..i + 3) {
+                    Some([_, argument, close])
+                        if close.spelling == ")"
+                            && matches!(
+                                argument.kind,
+                                TokenKind::Identifier | TokenKind::Literal
+                            ) =>
+                    {
+                        i += 3;
+                        argument
+                    }
+                    _ => continue,
+                }
+            }
+            TokenKind::Identifier if !called && forms.identifiers => token,
+            _ => continue,
+        };
+        slots.push(DependencySlot {
+            name: Symbol::intern(name_token.spelling.trim_matches('"')),
+            range: name_token.range?,
+            quoted: name_token.kind == TokenKind::Literal,
+            removal: removal_range(tokens, start, i)?,
         });
     }
+    Some(slots)
+}
 
-    unsafe {
-        let index = clang_createIndex(0, 0);
-        let tu = clang_parseTranslationUnit(
-            index,
-            filename.as_ptr(),
-            if arg_ptrs.is_empty() {
-                std::ptr::null()
-            } else {
-                arg_ptrs.as_ptr()
-            },
-            arg_ptrs.len() as c_uint as i32,
-            if unsaved_files.is_empty() {
-                std::ptr::null_mut()
-            } else {
-                unsaved_files.as_mut_ptr()
-            },
-            unsaved_files.len() as c_uint,
-            CXTranslationUnit_DetailedPreprocessingRecord,
-        );
+/// The range to delete to remove the list item made of `tokens[start..end]`: up to the next item
+/// if a comma follows it, otherwise from the end of the item before it.
+fn removal_range(tokens: &[Token], start: usize, end: usize) -> Option<Range> {
+    let item = Range::new(tokens[start].range?.start, tokens[end - 1].range?.end);
+    let is_comma = |i: usize| tokens.get(i).is_some_and(|token| token.spelling == ",");
+    if is_comma(end) {
+        if let Some(next) = tokens.get(end + 1) {
+            return Some(Range::new(item.start, next.range?.start));
+        }
+    }
+    if start >= 2 && is_comma(start - 1) {
+        return Some(Range::new(tokens[start - 2].range?.end, item.end));
+    }
+    Some(item)
+}
 
-        if tu.is_null() {
-            clang_disposeIndex(index);
-            return Err(anyhow!("Unable to parse {} with libclang", path.display()));
+/// An edit laying out the dependency list of `target` in `text` per `style`, or `None` if it is
+/// laid out already or `text` no longer matches what was indexed. Lists that fit on their line
+/// are joined onto it; longer ones get one dependency per line, indented one level further, with
+/// the line ending the file uses.
+fn format_dependencies(
+    text: &str,
+    target: &InitTarget,
+    style: &DependencyStyle,
+) -> Option<TextEdit> {
+    if target.dependency_slots.len() < 2 {
+        return None;
+    }
+    let mut slots = Vec::new();
+    for slot in &target.dependency_slots {
+        let start = offset_at(text, slot.range.start)?;
+        let end = offset_at(text, slot.range.end)?;
+        if text.get(start..end)?.trim_matches('"') != slot.name.as_str() {
+            return None;
         }
+        slots.push(start);
+    }
+    let from = offset_at(text, target.dependency_region.start)?;
+    let (open, close, commas) = enclosing_list(text, from, &slots)?;
 
-        let cursor = clang_getTranslationUnitCursor(tu);
-        let mut collector = TargetCollector {
-            tu,
-            file: path.to_path_buf(),
-            targets: Vec::new(),
+    let mut entries: Vec<&str> = (std::iter::once(open).chain(commas.iter().copied()))
+        .zip(commas.iter().copied().chain([close]))
+        .map(|(start, end)| text[start + 1..end].trim())
+        .collect();
+    let trailing_comma = entries.last() == Some(&"");
+    if trailing_comma {
+        entries.pop();
+    }
+    if entries.len() != slots.len() || entries.contains(&"") {
+        return None;
+    }
+    if style.sort {
+        let mut named: Vec<(&Symbol, &str)> = (target.dependency_slots.iter())
+            .map(|slot| &slot.name)
+            .zip(entries)
+            .collect();
+        named.sort_by_key(|(name, _)| name.to_lowercase());
+        entries = named.into_iter().map(|(_, entry)| entry).collect();
+    }
+
+    let line_start = text[..open].rfind('\n').map_or(0, |newline| newline + 1);
+    let line_end = text[close..]
+        .find('\n')
+        .map_or(text.len(), |newline| close + newline);
+    let indent_len = text[line_start..open]
+        .find(|c: char| !c.is_whitespace())
+        .unwrap_or(open - line_start);
+    let indent = &text[line_start..line_start + indent_len];
+    let comma = if trailing_comma { "," } else { "" };
+    let pad = if text.as_bytes()[open] == b'{' {
+        " "
+    } else {
+        ""
+    };
+    // A trailing comma is an extra macro argument but optional in a braced initializer.
+    let joined_comma = if pad.is_empty() { comma } else { "" };
+    let joined = format!("{pad}{}{joined_comma}{pad}", entries.join(", "));
+    let rest = text[close..line_end].trim_end_matches('\r');
+    let width = columns(&text[line_start..=open]) + columns(&joined) + columns(rest);
+    let laid_out = if width <= style.max_line_length {
+        joined
+    } else {
+        let unit = if indent.starts_with(' ') {
+            "    "
+        } else {
+            "\t"
         };
+        let newline = if text.contains("\r\n") { "\r\n" } else { "\n" };
+        let lines: Vec<String> = (entries.iter())
+            .map(|entry| format!("{indent}{unit}{entry}"))
+            .collect();
+        format!(
+            "{newline}{}{comma}{newline}{indent}",
+            lines.join(&format!(",{newline}"))
+        )
+    };
+    if laid_out == text[open + 1..close] {
+        return None;
+    }
+    Some(TextEdit {
+        range: Range::new(position_at(text, open + 1), position_at(text, close)),
+        new_text: laid_out,
+    })
+}
 
-        clang_visitChildren(
-            cursor,
-            visit_targets,
-            &mut collector as *mut TargetCollector as CXClientData,
-        );
+/// Where a completion at `position` has to supply the quotes itself: the range of the word being
+/// typed and what to put after the name, or `None` if the user typed the opening quote or the
+/// other dependencies of `target` are bare identifiers. A comma is added when another dependency
+/// follows.
+fn quoted_insertion(
+    text: &str,
+    target: &InitTarget,
+    position: &Position,
+) -> Option<(Range, &'static str)> {
+    let offset = offset_at(text, *position)?;
+    let bytes = text.as_bytes();
+    let is_word = |byte: &&u8| byte.is_ascii_alphanumeric() || **byte == b'_';
+    let start = offset - bytes[..offset].iter().rev().take_while(is_word).count();
+    let end = offset + bytes[offset..].iter().take_while(is_word).count();
+    if bytes[..start].last() == Some(&b'"') {
+        return None;
+    }
+    let mut others = (target.dependency_slots.iter())
+        .filter(|slot| !range_contains(&slot.range, position))
+        .peekable();
+    let bare = others.peek().is_some()
+        && others.all(|slot| {
+            offset_at(text, slot.range.start).and_then(|start| bytes.get(start)) != Some(&b'"')
+        });
+    if bare {
+        return None;
+    }
+    let next = text[end..].trim_start().chars().next();
+    let suffix = match next {
+        None | Some(',' | ')' | '}' | ']') => "",
+        Some(_) => ", ",
+    };
+    Some((
+        Range::new(position_at(text, start), position_at(text, end)),
+        suffix,
+    ))
+}
 
-        clang_disposeTranslationUnit(tu);
-        clang_disposeIndex(index);
-        Ok(collector.targets)
+/// Display width of `text`, with tabs stopping every 8 columns.
+fn columns(text: &str) -> usize {
+    text.chars().fold(0, |column, c| match c {
+        '\t' => column / 8 * 8 + 8,
+        _ => column + 1,
+    })
+}
+
+/// The brackets of the innermost list at or after `from` holding every offset in `items`, with
+/// the offsets of its top-level commas. `None` if there is no such list, or it has a comment a
+/// new layout couldn't keep in place.
+fn enclosing_list(text: &str, from: usize, items: &[usize]) -> Option<(usize, usize, Vec<usize>)> {
+    let bytes = text.as_bytes();
+    let mut open_brackets: Vec<usize> = Vec::new();
+    let mut common: Option<Vec<usize>> = None;
+    let mut pending = items.iter().peekable();
+    let mut i = from;
+    while let Some(&&item) = pending.peek() {
+        if i >= item {
+            common = Some(match common {
+                None => open_brackets.clone(),
+                Some(common) => (common.iter().zip(&open_brackets))
+                    .take_while(|(a, b)| a == b)
+                    .map(|(a, _)| *a)
+                    .collect(),
+            });
+            pending.next();
+            continue;
+        }
+        if let Some((next, _)) = skip_literal_or_comment(bytes, i) {
+            i = next;
+            continue;
+        }
+        match bytes.get(i)? {
+            b'(' | b'{' | b'[' => open_brackets.push(i),
+            b')' | b'}' | b']' => {
+                open_brackets.pop()?;
+            }
+            _ => {}
+        }
+        i += 1;
+    }
+    let open = *common?.last()?;
+
+    let mut commas = Vec::new();
+    let mut depth = 0;
+    let mut i = open + 1;
+    loop {
+        if let Some((next, comment)) = skip_literal_or_comment(bytes, i) {
+            if comment {
+                return None;
+            }
+            i = next;
+            continue;
+        }
+        match bytes.get(i)? {
+            b'(' | b'{' | b'[' => depth += 1,
+            b')' | b'}' | b']' if depth == 0 => return Some((open, i, commas)),
+            b')' | b'}' | b']' => depth -= 1,
+            b',' if depth == 0 => commas.push(i),
+            _ => {}
+        }
+        i += 1;
     }
 }
 
-struct TargetCollector {
-    tu: CXTranslationUnit,
-    file: PathBuf,
-    targets: Vec<InitTarget>,
+/// The dependencies of `target` that are indexed targets.
+fn known_dependencies<'a>(
+    target: &'a InitTarget,
+    targets: &HashMap<&str, &InitTarget>,
+) -> Vec<&'a str> {
+    (target.dependency_slots.iter())
+        .map(|slot| slot.name.as_str())
+        .filter(|name| targets.contains_key(name))
+        .collect()
 }
 
-extern "C" fn visit_targets(
-    cursor: CXCursor,
-    _parent: CXCursor,
-    data: CXClientData,
-) -> CXChildVisitResult {
-    unsafe {
-        let collector = &mut *(data as *mut TargetCollector);
-        if clang_getCursorKind(cursor) == CXCursor_MacroExpansion {
-            let spelling = cxstring_to_string(clang_getCursorSpelling(cursor));
-            if spelling == "INIT_TARGET" {
-                if let Some(target) = build_target(collector, cursor) {
-                    collector.targets.push(target);
-                }
+/// Levels of init targets within their stage: 0 for targets without dependencies in the stage,
+/// otherwise one more than their deepest such dependency.
+struct OrderLevels<'a> {
+    targets: &'a HashMap<&'a str, &'a InitTarget>,
+    levels: HashMap<&'a str, usize>,
+    /// Targets whose level is being computed, innermost last, to detect cycles.
+    stack: Vec<&'a str>,
+    /// Targets found on a dependency cycle, whose levels are approximate.
+    cycle: BTreeSet<&'a str>,
+}
+
+impl<'a> OrderLevels<'a> {
+    fn new(targets: &'a HashMap<&'a str, &'a InitTarget>) -> Self {
+        Self {
+            targets,
+            levels: HashMap::new(),
+            stack: Vec::new(),
+            cycle: BTreeSet::new(),
+        }
+    }
+
+    fn same_stage_dependencies(&self, target: &'a InitTarget) -> Vec<&'a InitTarget> {
+        (target.dependency_slots.iter())
+            .filter_map(|slot| self.targets.get(slot.name.as_str()).copied())
+            .filter(|dependency| dependency.stage_expr == target.stage_expr)
+            .collect()
+    }
+
+    fn level(&mut self, target: &'a InitTarget) -> usize {
+        let name = target.name.as_str();
+        if let Some(level) = self.levels.get(name) {
+            return *level;
+        }
+        if let Some(start) = self.stack.iter().position(|pending| *pending == name) {
+            self.cycle.extend(&self.stack[start..]);
+            return 0;
+        }
+        self.stack.push(name);
+        let level = (self.same_stage_dependencies(target).into_iter())
+            .map(|dependency| self.level(dependency) + 1)
+            .max()
+            .unwrap_or(0);
+        self.stack.pop();
+        self.levels.insert(name, level);
+        level
+    }
+
+    fn collect_same_stage_dependencies(
+        &self,
+        target: &'a InitTarget,
+        seen: &mut BTreeSet<&'a str>,
+    ) {
+        for dependency in self.same_stage_dependencies(target) {
+            if seen.insert(dependency.name.as_str()) {
+                self.collect_same_stage_dependencies(dependency, seen);
             }
         }
-        CXChildVisit_Recurse
+    }
+
+    /// The longest chain of same-stage dependencies ending at `target`, earliest first.
+    fn binding_chain(&mut self, target: &'a InitTarget) -> Vec<String> {
+        let mut chain = vec![target.name.to_string()];
+        let mut current = target;
+        while let Some(next) = (self.same_stage_dependencies(current).into_iter())
+            .find(|dependency| self.level(dependency) + 1 == self.level(current))
+        {
+            if chain.len() > self.targets.len() {
+                break;
+            }
+            chain.push(next.name.to_string());
+            current = next;
+        }
+        chain.reverse();
+        chain
     }
 }
 
-unsafe fn build_target(collector: &TargetCollector, cursor: CXCursor) -> Option<InitTarget> {
-    let tokens = tokenize_cursor(collector.tu, cursor)?;
-    let args = split_macro_args(collector.tu, &tokens)?;
-    if args.len() != 4 {
-        return None;
+impl InitTarget {
+    fn estimated_size(&self) -> usize {
+        let slots: usize = self
+            .dependency_slots
+            .iter()
+            .map(|slot| size_of::<DependencySlot>() + slot.name.len())
+            .sum();
+        size_of::<InitTarget>()
+            + self.name.len()
+            + self.documentation.as_ref().map_or(0, String::len)
+            + self.stage_expr.len()
+            + self.scope_expr.len()
+            + self.file.as_os_str().len()
+            + slots
     }
-    let name = tokens_to_string(collector.tu, &args[0])?;
-    let stage_expr = tokens_to_string(collector.tu, &args[1])?;
-    let scope_expr = tokens_to_string(collector.tu, &args[2])?;
-    let deps_tokens = &args[3];
-    let mut dependency_region =
-        tokens_range(collector.tu, deps_tokens).or_else(|| cursor_range(cursor))?;
-    let mut dependency_slots = Vec::new();
-    for token in deps_tokens {
-        if clang_getTokenKind(*token) == CXToken_Literal {
-            let literal_range = token_range(collector.tu, *token)?;
-            dependency_region.end = literal_range.end;
-            let literal = tokens_to_string(collector.tu, &[*token])?;
-            let name = literal.trim_matches('"').to_string();
-            dependency_slots.push(DependencySlot {
-                name,
-                range: literal_range,
-            });
+}
+
+/// Whether `name` can be spelled in a string literal without escapes and, if it also has to be
+/// spelled bare (`identifier`), is a C identifier.
+fn valid_target_name(name: &str, identifier: bool) -> bool {
+    let mut chars = name.chars();
+    let Some(first) = chars.next() else {
+        return false;
+    };
+    if identifier {
+        (first == '_' || first.is_ascii_alphabetic())
+            && chars.all(|c| c == '_' || c.is_ascii_alphanumeric())
+    } else {
+        !name.contains(['"', '\\']) && !name.contains(char::is_control)
+    }
+}
+
+fn target_names(targets: &[InitTarget]) -> BTreeSet<Symbol> {
+    targets.iter().map(|t| t.name.clone()).collect()
+}
+
+fn parse_targets(
+    parser: &InitTargetParser,
+    tu: &TranslationUnit,
+    path: &Path,
+    content: Option<&str>,
+) -> Result<InitFileData> {
+    let mut targets = Vec::new();
+    let source = OnceCell::new();
+    tu.cursor().visit_descendants(|cursor| {
+        if cursor.is_macro_expansion() && cursor.spelling() == "INIT_TARGET" {
+            let source = source.get_or_init(|| source_text(path, content));
+            let forms = &parser.dependency_forms;
+            if let Some(target) = build_target(path, cursor, source.as_deref(), forms) {
+                targets.push(target);
+            }
+        }
+    });
+    let skipped = tu.skipped_ranges(path);
+    let mut disabled = Vec::new();
+    if !skipped.is_empty() {
+        if let Some(source) = source.get_or_init(|| source_text(path, content)) {
+            disabled = invocations_in_regions(source, &skipped, INIT_MACROS);
         }
     }
+    Ok(InitFileData { targets, disabled })
+}
+
+fn build_target(
+    file: &Path,
+    cursor: Cursor,
+    source: Option<&str>,
+    forms: &DependencyForms,
+) -> Option<InitTarget> {
+    let tokens = cursor.tokens();
+    let args = split_macro_args(tokens.tokens());
+    let fields = designated_fields(&args);
+    let [name_tokens, stage_tokens, scope_tokens, deps_tokens] = if fields.is_empty() {
+        <[&[Token]; 4]>::try_from(args).ok()?
+    } else {
+        let field = |name| field_value(&fields, name).unwrap_or_default();
+        [field("name"), field("stage"), field("scope"), field("deps")]
+    };
+    let name = tokens_to_string(name_tokens);
+    let name = Symbol::intern(name.trim_matches('"'));
+    if name.as_str().is_empty() {
+        return None;
+    }
+    let stage_expr = tokens_to_string(stage_tokens);
+    let scope_expr = tokens_to_string(scope_tokens);
+    let range = cursor.range()?;
+    let mut dependency_region = tokens_range(deps_tokens).unwrap_or(range);
+    let dependency_slots = dependency_slots(deps_tokens, forms)?;
+    if let Some(last) = dependency_slots.last() {
+        dependency_region.end = last.range.end;
+    }
     Some(InitTarget {
         name,
+        name_range: tokens_range(name_tokens).unwrap_or(range),
+        name_quoted: (name_tokens.first()).is_some_and(|token| token.kind == TokenKind::Literal),
+        documentation: source.and_then(|source| doc_comment_above(source, range.start.line)),
         stage_expr,
+        stage_range: tokens_range(stage_tokens).unwrap_or(range),
         scope_expr,
-        file: collector.file.clone(),
+        scope_range: tokens_range(scope_tokens).unwrap_or(range),
+        file: file.to_path_buf(),
         dependency_region,
         dependency_slots,
     })
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn target(name: &str, stage: &str, dependencies: &[&str]) -> InitTarget {
+        let slot = |name: &&str| DependencySlot {
+            name: Symbol::intern(name),
+            range: Range::default(),
+            quoted: true,
+            removal: Range::default(),
+        };
+        InitTarget {
+            name: Symbol::intern(name),
+            name_range: Range::default(),
+            name_quoted: true,
+            documentation: None,
+            stage_expr: stage.into(),
+            stage_range: Range::default(),
+            scope_expr: "INIT_SCOPE_BSP".into(),
+            scope_range: Range::default(),
+            file: PathBuf::from("init.c"),
+            dependency_region: Range::default(),
+            dependency_slots: dependencies.iter().map(slot).collect(),
+        }
+    }
+
+    fn plugin(targets: Vec<InitTarget>) -> InitDependencyPlugin {
+        let options = ParseOptions {
+            skip_function_bodies: true,
+            prescan: true,
+            cpp: false,
+            header_extensions: vec!["h".into()],
+            kept_units: 0,
+        };
+        let mut plugin = InitDependencyPlugin::new(Path::new("/nonexistent"), options).unwrap();
+        plugin.targets_by_file.insert("init.c".into(), targets);
+        plugin
+    }
+
+    /// The unreachable subtrees `dead_targets` reports, as their tops and the targets below them.
+    fn unreachable(plugin: &InitDependencyPlugin, roots: &[&str]) -> Vec<(String, Vec<String>)> {
+        let roots: Vec<String> = roots.iter().map(|root| root.to_string()).collect();
+        let report = plugin.dead_targets(&roots);
+        (report["unreachable"].as_array().unwrap().iter())
+            .map(|entry| {
+                let top = entry["target"].as_str().unwrap().to_string();
+                (
+                    top,
+                    serde_json::from_value(entry["subtree"].clone()).unwrap(),
+                )
+            })
+            .collect()
+    }
+
+    fn levels(targets: &[InitTarget]) -> (BTreeMap<String, usize>, BTreeSet<String>) {
+        let by_name: HashMap<&str, &InitTarget> = (targets.iter())
+            .map(|target| (target.name.as_str(), target))
+            .collect();
+        let mut order = OrderLevels::new(&by_name);
+        let levels = (targets.iter())
+            .map(|target| (target.name.to_string(), order.level(target)))
+            .collect();
+        let cycle = order.cycle.iter().map(|name| name.to_string()).collect();
+        (levels, cycle)
+    }
+
+    #[test]
+    fn levels_follow_same_stage_chains() {
+        let targets = [
+            target("sched", "MAIN", &["vmm"]),
+            target("vmm", "MAIN", &["pmm"]),
+            target("pmm", "MAIN", &["acpi"]),
+            target("acpi", "EARLY", &[]),
+        ];
+        let (levels, cycle) = levels(&targets);
+        assert_eq!(
+            levels,
+            BTreeMap::from([
+                ("acpi".into(), 0),
+                ("pmm".into(), 0),
+                ("vmm".into(), 1),
+                ("sched".into(), 2)
+            ])
+        );
+        assert!(cycle.is_empty());
+    }
+
+    #[test]
+    fn levels_report_every_cycle_member() {
+        let targets = [
+            target("a", "MAIN", &["b"]),
+            target("b", "MAIN", &["c"]),
+            target("c", "MAIN", &["a"]),
+            target("d", "MAIN", &["c"]),
+        ];
+        let (_, cycle) = levels(&targets);
+        assert_eq!(cycle, BTreeSet::from(["a".into(), "b".into(), "c".into()]));
+    }
+
+    #[test]
+    fn levels_report_self_dependencies() {
+        let targets = [target("a", "MAIN", &["a"])];
+        let (levels, cycle) = levels(&targets);
+        assert_eq!(levels, BTreeMap::from([("a".into(), 1)]));
+        assert_eq!(cycle, BTreeSet::from(["a".into()]));
+    }
+
+    #[test]
+    fn explains_the_order_of_targets_on_cycles() {
+        let plugin = plugin(vec![
+            target("a", "MAIN", &["a", "b"]),
+            target("b", "MAIN", &["a"]),
+        ]);
+        for name in ["a", "b"] {
+            let report = plugin.explain_order(name).unwrap();
+            assert!(
+                report.contains("dependency cycle through `a`, `b`"),
+                "{report}"
+            );
+            assert!(report.contains("on a dependency cycle\n"), "{report}");
+        }
+    }
+
+    #[test]
+    fn dependencies_of_roots_are_reachable() {
+        let plugin = plugin(vec![
+            target("kmain", "MAIN", &["sched"]),
+            target("sched", "MAIN", &["vmm"]),
+            target("vmm", "EARLY", &["pmm"]),
+            target("pmm", "EARLY", &[]),
+        ]);
+        assert!(unreachable(&plugin, &["kmain"]).is_empty());
+    }
+
+    #[test]
+    fn groups_unreachable_targets_under_their_tops() {
+        let plugin = plugin(vec![
+            target("kmain", "MAIN", &["pmm"]),
+            target("pmm", "EARLY", &[]),
+            target("legacy", "MAIN", &["old_a", "pmm"]),
+            target("old_a", "MAIN", &["old_b"]),
+            target("old_b", "EARLY", &[]),
+            target("orphan", "MAIN", &[]),
+        ]);
+        assert_eq!(
+            unreachable(&plugin, &["kmain"]),
+            [
+                ("legacy".into(), vec!["old_a".into(), "old_b".into()]),
+                ("orphan".into(), vec![]),
+            ]
+        );
+    }
+
+    #[test]
+    fn unreachable_cycles_form_one_subtree() {
+        let plugin = plugin(vec![
+            target("kmain", "MAIN", &[]),
+            target("a", "MAIN", &["b"]),
+            target("b", "MAIN", &["a"]),
+        ]);
+        assert_eq!(
+            unreachable(&plugin, &["kmain"]),
+            [("a".into(), vec!["b".into()])]
+        );
+    }
+
+    #[test]
+    fn unknown_roots_are_reported_and_ignored() {
+        let plugin = plugin(vec![target("kmain", "MAIN", &[])]);
+        let report = plugin.dead_targets(&["kmain".into(), "nope".into()]);
+        assert_eq!(report["unknownRoots"], json!(["nope"]));
+        assert_eq!(report["unreachable"], json!([]));
+    }
+
+    /// `target`, with its dependencies located at their quoted names in `text`.
+    fn listed(text: &str, dependencies: &[&str]) -> InitTarget {
+        let mut target = target("t", "MAIN", dependencies);
+        let mut from = 0;
+        for slot in &mut target.dependency_slots {
+            let quoted = format!("\"{}\"", slot.name);
+            let start = from + text[from..].find(&quoted).unwrap();
+            from = start + quoted.len();
+            slot.range = Range::new(position_at(text, start), position_at(text, from));
+        }
+        target
+    }
+
+    /// `text` laid out per `style`, or `None` if it needs no edit.
+    fn formatted(text: &str, dependencies: &[&str], style: &DependencyStyle) -> Option<String> {
+        let edit = format_dependencies(text, &listed(text, dependencies), style)?;
+        let start = offset_at(text, edit.range.start).unwrap();
+        let end = offset_at(text, edit.range.end).unwrap();
+        Some(format!(
+            "{}{}{}",
+            &text[..start],
+            edit.new_text,
+            &text[end..]
+        ))
+    }
+
+    fn max_line_length(max_line_length: usize) -> DependencyStyle {
+        DependencyStyle {
+            max_line_length,
+            sort: false,
+        }
+    }
+
+    #[test]
+    fn joins_lists_that_fit_on_their_line() {
+        let text = "INIT_TARGET(t, MAIN, BSP, {\"b\",\n    \"a\"}) {}\n";
+        assert_eq!(
+            formatted(text, &["b", "a"], &DependencyStyle::default()).as_deref(),
+            Some("INIT_TARGET(t, MAIN, BSP, { \"b\", \"a\" }) {}\n")
+        );
+    }
+
+    #[test]
+    fn splits_lists_longer_than_the_line() {
+        let text = "    INIT_TARGET(t, MAIN, BSP, { \"alpha\", \"beta\" }) {}\n";
+        assert_eq!(
+            formatted(text, &["alpha", "beta"], &max_line_length(40)).as_deref(),
+            Some("    INIT_TARGET(t, MAIN, BSP, {\n        \"alpha\",\n        \"beta\"\n    }) {}\n")
+        );
+    }
+
+    #[test]
+    fn leaves_laid_out_lists_alone() {
+        let joined = "INIT_TARGET(t, MAIN, BSP, { \"a\", \"b\" }) {}\n";
+        assert_eq!(
+            formatted(joined, &["a", "b"], &DependencyStyle::default()),
+            None
+        );
+        let split = "\tINIT_TARGET(t, MAIN, BSP, {\n\t\t\"alpha\",\n\t\t\"beta\"\n\t}) {}\n";
+        assert_eq!(
+            formatted(split, &["alpha", "beta"], &max_line_length(40)),
+            None
+        );
+    }
+
+    #[test]
+    fn keeps_trailing_commas_where_they_matter() {
+        // Optional in a braced list, so only kept when split.
+        let braced = "INIT_TARGET(t, MAIN, BSP, {\"a\", \"b\",}) {}\n";
+        assert_eq!(
+            formatted(braced, &["a", "b"], &DependencyStyle::default()).as_deref(),
+            Some("INIT_TARGET(t, MAIN, BSP, { \"a\", \"b\" }) {}\n")
+        );
+        assert_eq!(
+            formatted(braced, &["a", "b"], &max_line_length(20)).as_deref(),
+            Some("INIT_TARGET(t, MAIN, BSP, {\n\t\"a\",\n\t\"b\",\n}) {}\n")
+        );
+        // An extra macro argument, so always kept.
+        let macro_list = "INIT_TARGET(t, MAIN, BSP, INIT_DEPS(\"a\",  \"b\",)) {}\n";
+        assert_eq!(
+            formatted(macro_list, &["a", "b"], &DependencyStyle::default()).as_deref(),
+            Some("INIT_TARGET(t, MAIN, BSP, INIT_DEPS(\"a\", \"b\",)) {}\n")
+        );
+    }
+
+    #[test]
+    fn macro_lists_are_not_padded() {
+        let text = "INIT_TARGET(t, MAIN, BSP, INIT_DEPS( \"a\",\n\"b\" )) {}\n";
+        assert_eq!(
+            formatted(text, &["a", "b"], &DependencyStyle::default()).as_deref(),
+            Some("INIT_TARGET(t, MAIN, BSP, INIT_DEPS(\"a\", \"b\")) {}\n")
+        );
+    }
+
+    #[test]
+    fn sorts_dependencies_when_asked() {
+        let text = "INIT_TARGET(t, MAIN, BSP, { \"vmm\", \"Heap\", \"pmm\" }) {}\n";
+        let style = DependencyStyle {
+            sort: true,
+            ..DependencyStyle::default()
+        };
+        assert_eq!(
+            formatted(text, &["vmm", "Heap", "pmm"], &style).as_deref(),
+            Some("INIT_TARGET(t, MAIN, BSP, { \"Heap\", \"pmm\", \"vmm\" }) {}\n")
+        );
+    }
+
+    #[test]
+    fn leaves_lists_with_comments_alone() {
+        let text = "INIT_TARGET(t, MAIN, BSP, {\"a\", /* early */ \"b\"}) {}\n";
+        assert_eq!(
+            formatted(text, &["a", "b"], &DependencyStyle::default()),
+            None
+        );
+        let text = "INIT_TARGET(t, MAIN, BSP, {\n\t\"a\", // early\n\t\"b\"}) {}\n";
+        assert_eq!(
+            formatted(text, &["a", "b"], &DependencyStyle::default()),
+            None
+        );
+    }
+
+    #[test]
+    fn keeps_crlf_line_endings() {
+        let text = "INIT_TARGET(t, MAIN, BSP, { \"alpha\", \"beta\" }) {}\r\n";
+        assert_eq!(
+            formatted(text, &["alpha", "beta"], &max_line_length(40)).as_deref(),
+            Some("INIT_TARGET(t, MAIN, BSP, {\r\n\t\"alpha\",\r\n\t\"beta\"\r\n}) {}\r\n")
+        );
+        let split = "INIT_TARGET(t, MAIN, BSP, {\r\n\t\"a\",\r\n\t\"b\"\r\n}) {}\r\n";
+        assert_eq!(
+            formatted(split, &["a", "b"], &DependencyStyle::default()).as_deref(),
+            Some("INIT_TARGET(t, MAIN, BSP, { \"a\", \"b\" }) {}\r\n")
+        );
+    }
+}
@@ -1,19 +1,1040 @@
-use std::collections::HashMap;
+use std::any::Any;
+use std::borrow::Cow;
+use std::cell::Cell;
+use std::cmp::Ordering;
+use std::collections::{BTreeSet, HashMap};
+use std::fmt;
+use std::fs::{self, File};
+use std::io::Read;
 use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering as AtomicOrdering};
+use std::sync::Arc;
+use std::time::Duration;
 
-use anyhow::Result;
-use tower_lsp::lsp_types::{CompletionItem, Diagnostic, Position, Range};
+use anyhow::{anyhow, Result};
+use clang_sys::{
+    CXTranslationUnit_DetailedPreprocessingRecord, CXTranslationUnit_Flags,
+    CXTranslationUnit_SkipFunctionBodies,
+};
+use memchr::memmem;
+use serde::{Deserialize, Serialize};
+use tower_lsp::lsp_types::{
+    CompletionItem, CompletionItemKind, Diagnostic, DiagnosticSeverity, DiagnosticTag,
+    DocumentSymbol, Hover, InlayHint, NumberOrString, Position, PositionEncodingKind, Range,
+    SemanticTokenType, SymbolKind, TextEdit,
+};
 
-pub(crate) const DEFAULT_CLANG_ARGS: &[&str] = &["-Iinclude", "-std=gnu23"];
+use crate::symbol::Symbol;
+
+pub const DEFAULT_CLANG_ARGS: &[&str] = &["-Iinclude", "-std=gnu23"];
+pub const DEFAULT_CPP_CLANG_ARGS: &[&str] = &["-Iinclude", "-x", "c++", "-std=gnu++20"];
+/// Extensions of the C++ sources parsed with [`ParseOptions::cpp`].
+pub const CPP_EXTENSIONS: &[&str] = &["cpp", "cc", "hpp"];
+
+#[derive(Clone, Debug)]
+pub struct ParseOptions {
+    pub skip_function_bodies: bool,
+    /// Skip the libclang parse for files that don't mention a plugin's macros at all.
+    pub prescan: bool,
+    /// Parse C++ sources too, not only `.c` files.
+    pub cpp: bool,
+    /// Extensions of the headers parsed as C alongside `.c` files.
+    pub header_extensions: Vec<String>,
+    /// Translation units of open documents each plugin keeps for reparsing.
+    pub kept_units: usize,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SourceLanguage {
+    C,
+    Cpp,
+}
+
+impl SourceLanguage {
+    /// Arguments for files without a `compile_commands.json` entry.
+    pub fn default_args(self) -> Vec<String> {
+        let args = match self {
+            Self::C => DEFAULT_CLANG_ARGS,
+            Self::Cpp => DEFAULT_CPP_CLANG_ARGS,
+        };
+        args.iter().map(|arg| arg.to_string()).collect()
+    }
+}
+
+impl ParseOptions {
+    /// The language plugins parse `path` as, or `None` for files they leave alone.
+    pub fn language_of(&self, path: &Path) -> Option<SourceLanguage> {
+        match path.extension()?.to_str()? {
+            "c" => Some(SourceLanguage::C),
+            ext if self.cpp && CPP_EXTENSIONS.contains(&ext) => Some(SourceLanguage::Cpp),
+            ext if self.header_extensions.iter().any(|e| e == ext) => Some(SourceLanguage::C),
+            _ => None,
+        }
+    }
+
+    /// Whether `path` is a header, which `compile_commands.json` usually has no entry for.
+    pub fn is_header(&self, path: &Path) -> bool {
+        path.extension()
+            .and_then(|ext| ext.to_str())
+            .is_some_and(|ext| ext == "hpp" || self.header_extensions.iter().any(|e| e == ext))
+    }
+
+    pub fn translation_unit_flags(&self) -> CXTranslationUnit_Flags {
+        let mut flags = CXTranslationUnit_DetailedPreprocessingRecord;
+        if self.skip_function_bodies {
+            flags |= CXTranslationUnit_SkipFunctionBodies;
+        }
+        flags
+    }
+}
+
+/// How far the diagnostics affected by a file update reach.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Impact {
+    Unchanged,
+    /// Only the updated file's own diagnostics may have changed.
+    File,
+    /// Names other files refer to changed, so any file's diagnostics may have changed.
+    Workspace,
+}
+
+/// A parse that was abandoned because it ran longer than the configured limit.
+#[derive(Debug)]
+pub struct ParseTimeout(pub Duration);
+
+impl fmt::Display for ParseTimeout {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "parsing timed out after {}s", self.0.as_secs_f32())
+    }
+}
+
+impl std::error::Error for ParseTimeout {}
+
+/// A parse that was abandoned because its result was no longer wanted.
+#[derive(Debug)]
+pub struct ParseCancelled;
+
+impl fmt::Display for ParseCancelled {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("parse cancelled")
+    }
+}
+
+impl std::error::Error for ParseCancelled {}
+
+/// Shared flag telling a parse its result is no longer wanted. Clones observe the same flag.
+#[derive(Clone, Debug, Default)]
+pub struct CancelToken(Arc<AtomicBool>);
+
+impl CancelToken {
+    pub fn cancel(&self) {
+        self.0.store(true, AtomicOrdering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(AtomicOrdering::Relaxed)
+    }
+
+    /// Returns `Err(ParseCancelled)` once cancelled, for checks between units of work.
+    pub fn check(&self) -> Result<()> {
+        if self.is_cancelled() {
+            return Err(ParseCancelled.into());
+        }
+        Ok(())
+    }
+
+    /// Cancels the token when the returned guard is dropped, e.g. along with the future of a
+    /// request the client cancelled.
+    pub fn cancel_on_drop(&self) -> CancelOnDrop {
+        CancelOnDrop(self.clone())
+    }
+}
+
+pub struct CancelOnDrop(CancelToken);
+
+impl Drop for CancelOnDrop {
+    fn drop(&mut self) {
+        self.0.cancel();
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct SymbolLocation {
+    pub path: PathBuf,
+    pub range: Range,
+    /// What the location is inside of, such as the function a hook is run from.
+    pub container: Option<Symbol>,
+}
+
+/// A count shown above a definition, such as how often a hook is run, listing the places counted
+/// when clicked.
+#[derive(Clone, Debug)]
+pub struct ReferenceLens {
+    pub range: Range,
+    pub title: String,
+    pub locations: Vec<SymbolLocation>,
+}
+
+/// What a highlighted range names, one semantic token type each.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HighlightKind {
+    Hook,
+    InitTarget,
+    Dependency,
+    Stage,
+    Scope,
+}
+
+impl HighlightKind {
+    /// In the order of the semantic token legend.
+    pub const ALL: [Self; 5] = [
+        Self::Hook,
+        Self::InitTarget,
+        Self::Dependency,
+        Self::Stage,
+        Self::Scope,
+    ];
+
+    pub fn token_type(self) -> SemanticTokenType {
+        match self {
+            Self::Hook => SemanticTokenType::EVENT,
+            Self::InitTarget => SemanticTokenType::CLASS,
+            Self::Dependency => SemanticTokenType::ENUM_MEMBER,
+            Self::Stage => SemanticTokenType::NAMESPACE,
+            Self::Scope => SemanticTokenType::ENUM,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Highlight {
+    pub range: Range,
+    pub kind: HighlightKind,
+    /// Whether the range is where the name is defined.
+    pub declaration: bool,
+}
+
+/// A range of a document linking to where the name in it is defined.
+#[derive(Clone, Debug)]
+pub struct NameLink {
+    pub range: Range,
+    pub target: SymbolLocation,
+}
+
+/// An entry of a call hierarchy. `data` is the plugin's own key for it, handed back when the
+/// editor expands the entry.
+#[derive(Clone, Debug)]
+pub struct HierarchyItem {
+    pub name: String,
+    pub kind: SymbolKind,
+    pub detail: Option<String>,
+    pub path: PathBuf,
+    pub range: Range,
+    pub selection_range: Range,
+    pub data: serde_json::Value,
+}
+
+/// A call involving another hierarchy entry, made at `ranges` in the calling entry's file.
+#[derive(Clone, Debug)]
+pub struct HierarchyCall {
+    pub item: HierarchyItem,
+    pub ranges: Vec<Range>,
+}
+
+/// The parameters of one of a plugin's macros, for signature help.
+#[derive(Debug)]
+pub struct MacroSignature {
+    pub name: &'static str,
+    pub parameters: &'static [&'static str],
+    pub documentation: &'static str,
+}
+
+/// A name a plugin indexes, such as a hook or an init target, with everything known about it.
+#[derive(Clone, Debug)]
+pub struct SymbolInfo {
+    pub name: Symbol,
+    pub kind: SymbolKind,
+    /// Where the name is defined.
+    pub location: SymbolLocation,
+    pub documentation: Option<String>,
+    /// Plugin-specific properties, e.g. an init target's stage and scope.
+    pub fields: Vec<(&'static str, String)>,
+    /// Other symbols of the same plugin this one depends on.
+    pub dependencies: Vec<Symbol>,
+    /// Places outside the definition that refer to the name.
+    pub references: Vec<SymbolLocation>,
+}
+
+impl SymbolInfo {
+    /// Lowercase name of `kind`, as used by the exporters.
+    pub fn kind_name(&self) -> &'static str {
+        match self.kind {
+            SymbolKind::EVENT => "event",
+            SymbolKind::CONSTANT => "constant",
+            SymbolKind::FUNCTION => "function",
+            _ => "symbol",
+        }
+    }
+}
+
+/// An invocation of a plugin macro in a preprocessor region the parse skipped, such as the body
+/// of an `#if 0`, so whatever it registers is compiled out.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct DisabledInvocation {
+    pub name: String,
+    pub range: Range,
+}
+
+impl DisabledInvocation {
+    pub fn diagnostic(&self, code: &str, source: &str) -> Diagnostic {
+        Diagnostic {
+            range: self.range,
+            severity: Some(DiagnosticSeverity::INFORMATION),
+            code: Some(NumberOrString::String(code.into())),
+            message: format!(
+                "This {} is in a disabled preprocessor region and is compiled out",
+                self.name
+            ),
+            source: Some(source.into()),
+            tags: Some(vec![DiagnosticTag::UNNECESSARY]),
+            ..Diagnostic::default()
+        }
+    }
+}
+
+/// A fix for a diagnostic, carried in its `data` so the code action request can offer it without
+/// asking the plugin again. The edits apply to the diagnostic's file.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct QuickFix {
+    pub title: String,
+    pub edits: Vec<TextEdit>,
+}
+
+impl QuickFix {
+    pub fn attach(self, diagnostic: &mut Diagnostic) {
+        diagnostic.data = serde_json::to_value([self]).ok();
+    }
+
+    pub fn from_diagnostic(diagnostic: &Diagnostic) -> Vec<Self> {
+        (diagnostic.data.clone())
+            .and_then(|data| serde_json::from_value(data).ok())
+            .unwrap_or_default()
+    }
+}
+
+/// Plugin-specific parse results, handed back to the same plugin's `apply_file`.
+pub type FileData = Box<dyn Any + Send>;
+
+/// The parsing half of a plugin. It holds no per-file state, so parses run without locking the
+/// plugin and an abandoned parse can't block anything else.
+pub trait FileParser: Send + Sync {
+    /// Returns `None` for files the plugin ignores.
+    fn parse_file(&self, path: &Path, content: Option<&str>) -> Result<Option<FileData>>;
+    /// Expands the invocation of one of the plugin's macros at `position`, if there is one.
+    fn expand_macro(
+        &self,
+        _path: &Path,
+        _content: Option<&str>,
+        _position: &Position,
+    ) -> Result<Option<MacroExpansion>> {
+        Ok(None)
+    }
+    /// The definition of the plugin's macro whose name is at `position`, if any.
+    fn macro_source(
+        &self,
+        _path: &Path,
+        _content: Option<&str>,
+        _position: &Position,
+    ) -> Result<Option<MacroSource>> {
+        Ok(None)
+    }
+}
 
 pub trait LspPlugin: Send + Sync {
-    fn on_file_updated(&mut self, path: &Path, content: Option<&str>) -> Result<()>;
-    fn on_file_removed(&mut self, path: &Path);
-    fn completions(&self, path: &Path, position: &Position) -> Option<Vec<CompletionItem>>;
+    /// Stable identifier keying the plugin's entries in the persistent index cache.
+    fn name(&self) -> &'static str;
+    fn parser(&self) -> Arc<dyn FileParser>;
+    fn apply_file(&mut self, path: &Path, data: FileData) -> Impact;
+    fn on_file_removed(&mut self, path: &Path) -> Impact;
+    /// Serializes the data held for `path`, or `None` if the plugin has none.
+    fn save_file(&self, path: &Path) -> Option<serde_json::Value>;
+    /// Serializes freshly parsed data, for handing it back from a parse worker.
+    fn encode_file(&self, data: &FileData) -> Result<serde_json::Value>;
+    /// Turns a value produced by `save_file` or `encode_file` back into data for `apply_file`.
+    fn load_file(&self, value: serde_json::Value) -> Result<FileData>;
+    /// Approximate number of bytes held for all indexed files.
+    fn memory_usage(&self) -> usize;
+    /// Drops what the file refers to, keeping what it defines; returns the bytes freed.
+    fn evict_file(&mut self, path: &Path) -> usize;
+    /// Completions at `position`; `text` is the document's current contents if it is open.
+    fn completions(
+        &self,
+        path: &Path,
+        position: &Position,
+        text: Option<&str>,
+    ) -> Option<Vec<CompletionItem>>;
+    /// Fills in the documentation of one of the plugin's completion items, left out of
+    /// `completions` to keep long lists cheap.
+    fn resolve_completion(&self, item: CompletionItem) -> CompletionItem {
+        item
+    }
     fn diagnostics(&self) -> HashMap<PathBuf, Vec<Diagnostic>>;
+    /// The diagnostics of `path` alone, for a client pulling one document's.
+    fn file_diagnostics(&self, path: &Path) -> Vec<Diagnostic> {
+        self.diagnostics().remove(path).unwrap_or_default()
+    }
+    /// Every symbol defined in the indexed files.
+    fn symbols(&self) -> Vec<SymbolInfo>;
+    /// Outline entries of `path`, with nested parts such as dependency slots as children.
+    fn document_symbols(&self, _path: &Path) -> Vec<DocumentSymbol> {
+        Vec::new()
+    }
+    /// Information about the name at `position` in `path`.
+    fn hover(&self, _path: &Path, _position: &Position) -> Option<Hover> {
+        None
+    }
+    /// Where the name at `position` in `path` is defined; empty if there is no name there.
+    fn definition(&self, _path: &Path, _position: &Position) -> Vec<SymbolLocation> {
+        Vec::new()
+    }
+    /// Places referring to the name at `position` in `path`, with its definitions if
+    /// `include_declaration` is set.
+    fn references(
+        &self,
+        _path: &Path,
+        _position: &Position,
+        _include_declaration: bool,
+    ) -> Vec<SymbolLocation> {
+        Vec::new()
+    }
+    /// Ranges in `path` to highlight, in any order.
+    fn highlights(&self, _path: &Path) -> Vec<Highlight> {
+        Vec::new()
+    }
+    /// Call hierarchy entries for the name at `position` in `path`.
+    fn prepare_call_hierarchy(&self, _path: &Path, _position: &Position) -> Vec<HierarchyItem> {
+        Vec::new()
+    }
+    /// Entries calling the entry keyed by `data`.
+    fn incoming_calls(&self, _data: &serde_json::Value) -> Vec<HierarchyCall> {
+        Vec::new()
+    }
+    /// Entries called by the entry keyed by `data`.
+    fn outgoing_calls(&self, _data: &serde_json::Value) -> Vec<HierarchyCall> {
+        Vec::new()
+    }
+    /// Signatures of the plugin's macros.
+    fn signatures(&self) -> &'static [MacroSignature] {
+        &[]
+    }
+    /// Names in `path` to turn into links to their definitions.
+    fn name_links(&self, _path: &Path) -> Vec<NameLink> {
+        Vec::new()
+    }
+    /// Hints to show inline in `path`.
+    fn inlay_hints(&self, _path: &Path) -> Vec<InlayHint> {
+        Vec::new()
+    }
+    /// Counts to show above the definitions in `path`.
+    fn reference_lenses(&self, _path: &Path) -> Vec<ReferenceLens> {
+        Vec::new()
+    }
+    /// The range of the name at `position` in `path` that `rename` would change, if any.
+    fn prepare_rename(&self, _path: &Path, _position: &Position) -> Option<Range> {
+        None
+    }
+    /// Edits renaming the name at `position` in `path` to `new_name` everywhere it is used,
+    /// keyed by file; `None` if there is no name there.
+    fn rename(
+        &self,
+        _path: &Path,
+        _position: &Position,
+        _new_name: &str,
+    ) -> Result<Option<HashMap<PathBuf, Vec<TextEdit>>>> {
+        Ok(None)
+    }
+    /// Codes of diagnostics about names defined in other files, which are held back until the
+    /// workspace has been indexed.
+    fn cross_file_codes(&self) -> &'static [&'static str] {
+        &[]
+    }
+    /// Edits bringing the plugin's macro invocations in `text`, the contents of `path` about to
+    /// be saved, to the project style.
+    fn format_edits(&self, _path: &Path, _text: &str) -> Vec<TextEdit> {
+        Vec::new()
+    }
+    /// `workspace/executeCommand` commands the plugin handles.
+    fn commands(&self) -> &'static [&'static str] {
+        &[]
+    }
+    fn execute_command(
+        &self,
+        command: &str,
+        _arguments: &[serde_json::Value],
+    ) -> Result<serde_json::Value> {
+        Err(anyhow!("unknown command {command}"))
+    }
+}
+
+/// Cheap byte scan telling whether `path` could contain any of `needles`. Files that can't be read
+/// are assumed to match so the real parse can report the problem.
+pub fn may_contain(path: &Path, content: Option<&str>, needles: &[&str]) -> bool {
+    let bytes = match content {
+        Some(text) => Cow::Borrowed(text.as_bytes()),
+        None => match fs::read(path) {
+            Ok(bytes) => Cow::Owned(bytes),
+            Err(_) => return true,
+        },
+    };
+    needles
+        .iter()
+        .any(|needle| memmem::find(&bytes, needle.as_bytes()).is_some())
+}
+
+/// The text of `path`, or `content` if given.
+pub fn source_text<'a>(path: &Path, content: Option<&'a str>) -> Option<Cow<'a, str>> {
+    match content {
+        Some(text) => Some(Cow::Borrowed(text)),
+        None => Some(Cow::Owned(
+            String::from_utf8_lossy(&fs::read(path).ok()?).into_owned(),
+        )),
+    }
+}
+
+/// How positions count characters within a line, as negotiated with the client. Plugins compute
+/// positions with [`PositionEncoding::current`], the encoding their caller entered.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum PositionEncoding {
+    /// Bytes, which is what libclang reports, so no conversion is needed.
+    Utf8,
+    #[default]
+    Utf16,
+}
+
+thread_local! {
+    static ENCODING: Cell<PositionEncoding> = const { Cell::new(PositionEncoding::Utf16) };
+}
+
+/// Restores the encoding entered before [`PositionEncoding::enter`] when dropped.
+pub struct EncodingGuard(PositionEncoding);
+
+impl Drop for EncodingGuard {
+    fn drop(&mut self) {
+        ENCODING.with(|encoding| encoding.set(self.0));
+    }
+}
+
+impl PositionEncoding {
+    /// Picks UTF-8 if the client offers it and the UTF-16 every client must support otherwise.
+    pub fn negotiate(offered: Option<&[PositionEncodingKind]>) -> Self {
+        if offered.is_some_and(|kinds| kinds.contains(&PositionEncodingKind::UTF8)) {
+            Self::Utf8
+        } else {
+            Self::Utf16
+        }
+    }
+
+    /// The encoding entered on this thread, UTF-16 if none was.
+    pub fn current() -> Self {
+        ENCODING.with(Cell::get)
+    }
+
+    /// Makes this the encoding of the positions computed on this thread until the guard is
+    /// dropped. Each server enters its client's encoding around its calls into the plugins.
+    pub fn enter(self) -> EncodingGuard {
+        EncodingGuard(ENCODING.with(|encoding| encoding.replace(self)))
+    }
+
+    pub fn kind(self) -> PositionEncodingKind {
+        match self {
+            Self::Utf8 => PositionEncodingKind::UTF8,
+            Self::Utf16 => PositionEncodingKind::UTF16,
+        }
+    }
+
+    /// The number of code units `text` takes up.
+    pub fn len(self, text: &str) -> u32 {
+        match self {
+            Self::Utf8 => text.len() as u32,
+            Self::Utf16 => text.encode_utf16().count() as u32,
+        }
+    }
+
+    fn char_len(self, c: char) -> u32 {
+        match self {
+            Self::Utf8 => c.len_utf8() as u32,
+            Self::Utf16 => c.len_utf16() as u32,
+        }
+    }
+
+    /// Suffix for cache keys, since cached positions are only valid in the encoding they were
+    /// computed in.
+    pub fn cache_tag(self) -> &'static str {
+        match self {
+            Self::Utf8 => "utf-8",
+            Self::Utf16 => "utf-16",
+        }
+    }
+}
+
+/// The byte offset of `position` in `text`, or `None` if it isn't on a character boundary of an
+/// existing line.
+pub fn offset_at(text: &str, position: Position) -> Option<usize> {
+    let mut line_start = 0;
+    for _ in 0..position.line {
+        line_start += text[line_start..].find('\n')? + 1;
+    }
+    let line = text[line_start..].split('\n').next().unwrap_or_default();
+    let encoding = PositionEncoding::current();
+    let mut units = 0;
+    for (byte, c) in line.char_indices() {
+        match units.cmp(&position.character) {
+            Ordering::Equal => return Some(line_start + byte),
+            Ordering::Greater => return None,
+            Ordering::Less => units += encoding.char_len(c),
+        }
+    }
+    (units == position.character).then_some(line_start + line.len())
+}
+
+/// The position of byte `offset` in `text`.
+pub fn position_at(text: &str, offset: usize) -> Position {
+    let before = &text[..offset];
+    let line_start = before.rfind('\n').map_or(0, |newline| newline + 1);
+    Position::new(
+        before.matches('\n').count() as u32,
+        PositionEncoding::current().len(&before[line_start..]),
+    )
+}
+
+/// The text `range` covers in `source`.
+pub fn source_slice<'a>(source: &'a str, range: &Range) -> Option<&'a str> {
+    source.get(offset_at(source, range.start)?..offset_at(source, range.end)?)
+}
+
+/// The identifier `position` is on or right after in `text`.
+pub fn identifier_at(text: &str, position: Position) -> Option<&str> {
+    let offset = offset_at(text, position)?;
+    let is_identifier = |c: char| c.is_ascii_alphanumeric() || c == '_';
+    let start = text[..offset]
+        .rfind(|c| !is_identifier(c))
+        .map_or(0, |i| i + 1);
+    let end = text[offset..]
+        .find(|c| !is_identifier(c))
+        .map_or(text.len(), |i| offset + i);
+    (start < end).then(|| &text[start..end])
+}
+
+/// An unclosed call of one of a plugin's macros around an offset.
+#[derive(Debug)]
+pub struct EnclosingCall<'t, 'n> {
+    pub name: &'n str,
+    /// Index of the argument the offset is in.
+    pub argument: usize,
+    /// The field set by the argument if it is a designator, as in `.name = ...`.
+    pub field: Option<&'t str>,
+}
+
+/// The innermost call of one of `names` that is still open at `offset` in `text`, as while typing
+/// its arguments. Brackets are matched through the arguments, and literals and comments skipped.
+pub fn enclosing_call<'t, 'n>(
+    text: &'t str,
+    offset: usize,
+    names: &[&'n str],
+) -> Option<EnclosingCall<'t, 'n>> {
+    let is_identifier = |c: &char| c.is_ascii_alphanumeric() || *c == '_';
+    let bytes = text.as_bytes();
+    // Each open bracket: the macro it calls, if any, its argument count so far and where the
+    // current argument starts.
+    let mut open: Vec<(Option<&'n str>, usize, usize)> = Vec::new();
+    let mut i = 0;
+    while i < offset.min(bytes.len()) {
+        if let Some((next, _)) = skip_literal_or_comment(bytes, i) {
+            i = next;
+            continue;
+        }
+        match bytes[i] {
+            b'(' => {
+                let before = text[..i].trim_end();
+                let length: usize = (before.chars().rev())
+                    .take_while(is_identifier)
+                    .map(char::len_utf8)
+                    .sum();
+                let callee = &before[before.len() - length..];
+                let name = names.iter().find(|name| **name == callee).copied();
+                open.push((name, 0, i + 1));
+            }
+            b'{' | b'[' => open.push((None, 0, i + 1)),
+            b')' | b'}' | b']' => {
+                open.pop();
+            }
+            b',' => {
+                if let Some((_, argument, start)) = open.last_mut() {
+                    *argument += 1;
+                    *start = i + 1;
+                }
+            }
+            // Statements don't continue inside macro arguments; drop brackets left unclosed.
+            b';' => open.clear(),
+            _ => {}
+        }
+        i += 1;
+    }
+    let (name, argument, start) = open.iter().rev().find(|(name, ..)| name.is_some())?;
+    let field = text[*start..offset]
+        .trim_start()
+        .strip_prefix('.')
+        .map(|rest| {
+            let length: usize = rest
+                .chars()
+                .take_while(is_identifier)
+                .map(char::len_utf8)
+                .sum();
+            &rest[..length]
+        });
+    Some(EnclosingCall {
+        name: (*name)?,
+        argument: *argument,
+        field: field.filter(|field| !field.is_empty()),
+    })
+}
+
+/// If a string or character literal or a comment starts at `i`, the offset just past it and
+/// whether it is a comment.
+pub fn skip_literal_or_comment(bytes: &[u8], i: usize) -> Option<(usize, bool)> {
+    match bytes.get(i)? {
+        quote @ (b'"' | b'\'') => {
+            let mut j = i + 1;
+            while j < bytes.len() && bytes[j] != *quote && bytes[j] != b'\n' {
+                j += if bytes[j] == b'\\' { 2 } else { 1 };
+            }
+            Some(((j + 1).min(bytes.len()), false))
+        }
+        b'/' if bytes.get(i + 1) == Some(&b'/') => {
+            let end = memchr::memchr(b'\n', &bytes[i..]).map_or(bytes.len(), |n| i + n);
+            Some((end, true))
+        }
+        b'/' if bytes.get(i + 1) == Some(&b'*') => {
+            let end =
+                memchr::memmem::find(&bytes[i + 2..], b"*/").map_or(bytes.len(), |n| i + n + 4);
+            Some((end, true))
+        }
+        _ => None,
+    }
+}
+
+/// The comment directly above `line` (zero-based), without its comment markers.
+pub fn doc_comment_above(source: &str, line: u32) -> Option<String> {
+    let lines: Vec<&str> = source.lines().take(line as usize).collect();
+    let mut comment = Vec::new();
+    let mut in_block = false;
+    for text in lines.iter().rev().map(|text| text.trim()) {
+        if in_block {
+            let start = text.find("/*");
+            let body = start.map_or(text, |start| &text[start + 2..]);
+            comment.push(body.trim_start_matches('*').trim());
+            if start.is_some() {
+                break;
+            }
+        } else if let Some(body) = text.strip_prefix("//") {
+            comment.push(body.trim_start_matches('/').trim());
+        } else if let Some(body) = text.strip_suffix("*/") {
+            match body.find("/*") {
+                Some(start) => {
+                    comment.push(body[start + 2..].trim_start_matches('*').trim());
+                    break;
+                }
+                None => {
+                    comment.push(body.trim_start_matches('*').trim());
+                    in_block = true;
+                }
+            }
+        } else {
+            break;
+        }
+    }
+    comment.reverse();
+    let comment = comment.join("\n").trim().to_string();
+    (!comment.is_empty()).then_some(comment)
+}
+
+/// Invocations of the macros `names` within `regions` of `source`. Preprocessor directives,
+/// comments and string literals are skipped.
+pub fn invocations_in_regions(
+    source: &str,
+    regions: &[Range],
+    names: &[&str],
+) -> Vec<DisabledInvocation> {
+    let lines: Vec<&str> = source.lines().collect();
+    let encoding = PositionEncoding::current();
+    let mut invocations = Vec::new();
+    for region in regions {
+        let mut in_comment = false;
+        for line_number in region.start.line..=region.end.line {
+            let Some(line) = lines.get(line_number as usize) else {
+                break;
+            };
+            if !in_comment && line.trim_start().starts_with('#') {
+                continue;
+            }
+            let column = |byte: usize| encoding.len(&line[..byte]);
+            for (name, start, end) in macro_calls(line, names, &mut in_comment) {
+                invocations.push(DisabledInvocation {
+                    name: name.to_string(),
+                    range: Range::new(
+                        Position::new(line_number, column(start)),
+                        Position::new(line_number, column(end)),
+                    ),
+                });
+            }
+        }
+    }
+    invocations
+}
+
+/// Byte spans of the identifiers among `names` followed by `(` on `line`, tracking block
+/// comments across lines in `in_comment`.
+fn macro_calls<'n>(
+    line: &str,
+    names: &[&'n str],
+    in_comment: &mut bool,
+) -> Vec<(&'n str, usize, usize)> {
+    let bytes = line.as_bytes();
+    let is_identifier = |byte: u8| byte.is_ascii_alphanumeric() || byte == b'_';
+    let mut calls = Vec::new();
+    let mut i = 0;
+    while i < bytes.len() {
+        if *in_comment {
+            if bytes[i..].starts_with(b"*/") {
+                *in_comment = false;
+                i += 1;
+            }
+            i += 1;
+            continue;
+        }
+        match bytes[i] {
+            b'/' if bytes.get(i + 1) == Some(&b'/') => break,
+            b'/' if bytes.get(i + 1) == Some(&b'*') => {
+                *in_comment = true;
+                i += 2;
+            }
+            quote @ (b'"' | b'\'') => {
+                i += 1;
+                while i < bytes.len() && bytes[i] != quote {
+                    i += if bytes[i] == b'\\' { 2 } else { 1 };
+                }
+                i += 1;
+            }
+            byte if is_identifier(byte) => {
+                let start = i;
+                while i < bytes.len() && is_identifier(bytes[i]) {
+                    i += 1;
+                }
+                let called = line[i..].trim_start().starts_with('(');
+                if let Some(name) = names.iter().find(|name| **name == &line[start..i]) {
+                    if called {
+                        calls.push((*name, start, i));
+                    }
+                }
+            }
+            _ => i += 1,
+        }
+    }
+    calls
+}
+
+/// How far into a file the disable pragma is looked for.
+const PRAGMA_SCAN_BYTES: u64 = 2048;
+
+/// Names a disable pragma accepts, along with the plugin each one disables.
+const PRAGMA_NAMES: &[(&str, &str)] = &[
+    ("init-deps", "init-deps"),
+    ("init", "init-deps"),
+    ("hooks", "hooks"),
+];
+
+/// Names of the plugins a file opts out of with a comment like
+/// `// elysium-lsp: disable=init-deps,hooks` near its top.
+pub fn disabled_plugins(path: &Path, content: Option<&str>) -> Vec<String> {
+    let (disabled, _) = parse_disable_pragma(&pragma_head(path, content));
+    disabled
+}
+
+/// Names in a file's disable pragma that no plugin goes by.
+pub fn unknown_disabled_plugins(path: &Path, content: Option<&str>) -> Vec<String> {
+    let (_, unknown) = parse_disable_pragma(&pragma_head(path, content));
+    unknown
+}
+
+/// The start of the file the disable pragma is looked for in.
+fn pragma_head(path: &Path, content: Option<&str>) -> String {
+    let head = match content {
+        Some(text) => Cow::Borrowed(text.as_bytes()),
+        None => {
+            let mut head = Vec::new();
+            if let Ok(file) = File::open(path) {
+                let _ = file.take(PRAGMA_SCAN_BYTES).read_to_end(&mut head);
+            }
+            Cow::Owned(head)
+        }
+    };
+    let head = &head[..head.len().min(PRAGMA_SCAN_BYTES as usize)];
+    String::from_utf8_lossy(head).into_owned()
+}
+
+/// The plugins the disable pragmas in `head` name, and the names they list that aren't in
+/// [`PRAGMA_NAMES`].
+fn parse_disable_pragma(head: &str) -> (Vec<String>, Vec<String>) {
+    let names = head
+        .split("elysium-lsp: disable=")
+        .skip(1)
+        .flat_map(|rest| {
+            // The list runs to the end of the line or of a block comment, and may have spaces.
+            let line = rest.lines().next().unwrap_or_default();
+            let list = line.split("*/").next().unwrap_or_default();
+            list.split(',').map(str::trim)
+        })
+        .filter(|name| !name.is_empty());
+    let (mut disabled, mut unknown) = (Vec::new(), Vec::new());
+    for name in names {
+        match PRAGMA_NAMES.iter().find(|(alias, _)| *alias == name) {
+            Some((_, plugin)) if !disabled.iter().any(|disabled| disabled == plugin) => {
+                disabled.push(plugin.to_string())
+            }
+            Some(_) => {}
+            None => unknown.push(name.to_string()),
+        }
+    }
+    (disabled, unknown)
+}
+
+pub fn update_impact<T: PartialEq>(
+    previous: Option<&T>,
+    current: &T,
+    exported: impl Fn(&T) -> BTreeSet<Symbol>,
+) -> Impact {
+    match previous {
+        Some(previous) if previous == current => Impact::Unchanged,
+        Some(previous) if exported(previous) == exported(current) => Impact::File,
+        None if exported(current).is_empty() => Impact::File,
+        _ => Impact::Workspace,
+    }
+}
+
+pub fn removal_impact<T>(
+    previous: Option<&T>,
+    exported: impl Fn(&T) -> BTreeSet<Symbol>,
+) -> Impact {
+    match previous {
+        None => Impact::Unchanged,
+        Some(previous) if exported(previous).is_empty() => Impact::File,
+        Some(_) => Impact::Workspace,
+    }
+}
+
+/// Typing one of these after a dependency or hook name accepts the completion.
+const NAME_COMMIT_CHARACTERS: &[&str] = &["\"", ",", ")"];
+
+/// A completion item for an indexed name. Names referenced more often (`uses`) sort first, ties
+/// alphabetically.
+pub fn name_completion(
+    name: &Symbol,
+    kind: CompletionItemKind,
+    detail: String,
+    uses: usize,
+) -> CompletionItem {
+    let rank = u32::MAX - u32::try_from(uses).unwrap_or(u32::MAX);
+    CompletionItem {
+        label: name.to_string(),
+        kind: Some(kind),
+        detail: Some(detail),
+        sort_text: Some(format!("{rank:010}{}", name.to_lowercase())),
+        filter_text: Some(name.to_string()),
+        commit_characters: Some(
+            NAME_COMMIT_CHARACTERS
+                .iter()
+                .map(|c| c.to_string())
+                .collect(),
+        ),
+        ..CompletionItem::default()
+    }
+}
+
+#[allow(deprecated)] // `DocumentSymbol::deprecated` has to be set, if only to `None`.
+pub fn outline_symbol(
+    name: String,
+    detail: Option<String>,
+    kind: SymbolKind,
+    range: Range,
+    selection_range: Range,
+    children: Vec<DocumentSymbol>,
+) -> DocumentSymbol {
+    DocumentSymbol {
+        name,
+        detail,
+        kind,
+        tags: None,
+        deprecated: None,
+        range,
+        selection_range,
+        children: (!children.is_empty()).then_some(children),
+    }
 }
 
-pub(crate) fn range_contains(range: &Range, pos: &Position) -> bool {
+/// Nests outline entries sharing a subsystem prefix, the part of their name before the first `_`,
+/// under an entry for the prefix. Prefixes of a single entry are left alone.
+pub fn group_by_prefix(mut symbols: Vec<DocumentSymbol>) -> Vec<DocumentSymbol> {
+    fn prefix(symbol: &DocumentSymbol) -> Option<&str> {
+        let (prefix, _) = symbol.name.split_once('_')?;
+        (!prefix.is_empty()).then_some(prefix)
+    }
+    let mut counts: HashMap<String, usize> = HashMap::new();
+    for prefix in symbols.iter().filter_map(prefix) {
+        *counts.entry(prefix.to_string()).or_default() += 1;
+    }
+    symbols.sort_by_key(|symbol| (symbol.range.start.line, symbol.range.start.character));
+
+    let mut outline: Vec<DocumentSymbol> = Vec::new();
+    let mut groups: HashMap<String, usize> = HashMap::new();
+    for symbol in symbols {
+        let Some(prefix) = prefix(&symbol).filter(|prefix| counts[*prefix] > 1) else {
+            outline.push(symbol);
+            continue;
+        };
+        match groups.get(prefix) {
+            Some(&index) => {
+                let group = &mut outline[index];
+                group.range = range_union(&group.range, &symbol.range);
+                group.children.get_or_insert_with(Vec::new).push(symbol);
+            }
+            None => {
+                groups.insert(prefix.to_string(), outline.len());
+                let (name, range, selection_range) =
+                    (prefix.to_string(), symbol.range, symbol.selection_range);
+                outline.push(outline_symbol(
+                    name,
+                    None,
+                    SymbolKind::NAMESPACE,
+                    range,
+                    selection_range,
+                    vec![symbol],
+                ));
+            }
+        }
+    }
+    outline
+}
+
+/// The smallest range covering both `a` and `b`.
+pub fn range_union(a: &Range, b: &Range) -> Range {
+    let key = |position: &Position| (position.line, position.character);
+    Range {
+        start: std::cmp::min_by_key(a.start, b.start, key),
+        end: std::cmp::max_by_key(a.end, b.end, key),
+    }
+}
+
+pub fn range_contains(range: &Range, pos: &Position) -> bool {
     if pos.line < range.start.line || pos.line > range.end.line {
         return false;
     }
@@ -26,9 +1047,73 @@ pub(crate) fn range_contains(range: &Range, pos: &Position) -> bool {
     true
 }
 
-mod clang_utils;
+pub mod clang;
+pub use clang::load_libclang;
+pub mod expand;
+pub use expand::{MacroExpansion, MacroSource};
 
-pub mod init;
 pub mod hooks;
-pub use hooks::HookPlugin;
-pub use init::InitDependencyPlugin;
+pub mod init;
+pub mod parser;
+pub use hooks::{HookPlugin, HookPriorities};
+pub use init::{DependencyForms, DependencyStyle, InitDependencyPlugin, StageScopes};
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn disable_pragma_in_a_line_comment() {
+        let head = "// elysium-lsp: disable=init-deps,hooks\nint x;\n";
+        assert_eq!(
+            parse_disable_pragma(head),
+            (vec!["init-deps".into(), "hooks".into()], vec![])
+        );
+    }
+
+    #[test]
+    fn disable_pragma_in_a_block_comment() {
+        let head = "/* elysium-lsp: disable=hooks */ int x;\n";
+        assert_eq!(parse_disable_pragma(head), (vec!["hooks".into()], vec![]));
+    }
+
+    #[test]
+    fn disable_pragma_allows_spaces() {
+        let head = "/* elysium-lsp: disable= init-deps , hooks  */\n";
+        assert_eq!(
+            parse_disable_pragma(head),
+            (vec!["init-deps".into(), "hooks".into()], vec![])
+        );
+    }
+
+    #[test]
+    fn disable_pragma_accepts_init_for_init_deps() {
+        let head = "// elysium-lsp: disable=init,init-deps\n";
+        assert_eq!(
+            parse_disable_pragma(head),
+            (vec!["init-deps".into()], vec![])
+        );
+    }
+
+    #[test]
+    fn disable_pragma_reports_unknown_names() {
+        let head = "// elysium-lsp: disable=hook,hooks,\n";
+        assert_eq!(
+            parse_disable_pragma(head),
+            (vec!["hooks".into()], vec!["hook".into()])
+        );
+    }
+
+    #[test]
+    fn disable_pragma_is_only_looked_for_near_the_top() {
+        let path = Path::new("/nonexistent.c");
+        let padding = "/".repeat(PRAGMA_SCAN_BYTES as usize);
+        let late = format!("{padding}\n// elysium-lsp: disable=hooks,bogus\n");
+        assert!(disabled_plugins(path, Some(&late)).is_empty());
+        assert!(unknown_disabled_plugins(path, Some(&late)).is_empty());
+
+        let early = format!("// elysium-lsp: disable=hooks,bogus\n{padding}\n");
+        assert_eq!(disabled_plugins(path, Some(&early)), ["hooks"]);
+        assert_eq!(unknown_disabled_plugins(path, Some(&early)), ["bogus"]);
+    }
+}
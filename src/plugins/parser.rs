@@ -0,0 +1,131 @@
+//! Translation units of open documents kept alive between parses. An edit reparses the unit the
+//! previous parse left behind with `clang_reparseTranslationUnit` instead of building a new index
+//! and unit, which skips most of the work for the headers a kernel source pulls in. The units are
+//! built with a precompiled preamble, so reparses don't lex and parse those headers again either.
+
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime};
+
+use anyhow::Result;
+use clang_sys::{CXTranslationUnit_Flags, CXTranslationUnit_PrecompiledPreamble};
+
+use super::clang::{IndexPool, OwnedTranslationUnit, TranslationUnit};
+
+/// libclang names the preamble files it writes to the temporary directory `preamble-*.pch`.
+const PREAMBLE_PREFIX: &str = "preamble-";
+const PREAMBLE_EXTENSION: &str = "pch";
+
+pub struct UnitCache {
+    units: Mutex<HashMap<PathBuf, CachedUnit>>,
+    /// Units kept at most; the least recently parsed one goes first.
+    max_units: usize,
+}
+
+struct CachedUnit {
+    unit: OwnedTranslationUnit,
+    args: Vec<String>,
+    flags: CXTranslationUnit_Flags,
+    last_used: Instant,
+}
+
+impl UnitCache {
+    pub fn new(max_units: usize) -> Self {
+        Self {
+            units: Mutex::new(HashMap::new()),
+            max_units,
+        }
+    }
+
+    /// Drops the unit kept for `path`, for files that no longer need parsing with libclang.
+    pub fn forget(&self, path: &Path) {
+        self.units.lock().unwrap().remove(path);
+    }
+
+    /// Runs `f` on a translation unit of `path`. With `content`, the text of a document open in
+    /// the editor, the unit kept for `path` is reparsed if it was built with the same arguments
+    /// and kept again afterwards. Without it the file is parsed from disk with an index from
+    /// `indexes`, and any unit kept for it is dropped, as the document was closed.
+    pub fn with_unit<T>(
+        &self,
+        indexes: &IndexPool,
+        path: &Path,
+        args: &[String],
+        content: Option<&str>,
+        flags: CXTranslationUnit_Flags,
+        f: impl FnOnce(&TranslationUnit) -> Result<T>,
+    ) -> Result<T> {
+        let Some(content) = content else {
+            self.forget(path);
+            return indexes.with_index(|index| f(&index.parse(path, args, None, flags)?));
+        };
+
+        // Taken out of the cache while in use, so a concurrent parse of the same file builds its
+        // own unit instead of sharing this one.
+        let cached = (self.units.lock().unwrap().remove(path))
+            .filter(|cached| cached.args == args && cached.flags == flags);
+        let unit_flags = flags | CXTranslationUnit_PrecompiledPreamble;
+        let unit = match cached {
+            Some(mut cached) => match cached.unit.reparse(path, content) {
+                Ok(()) => cached.unit,
+                Err(err) => {
+                    tracing::debug!("{err:#}; parsing from scratch");
+                    OwnedTranslationUnit::parse(path, args, Some(content), unit_flags)?
+                }
+            },
+            None => OwnedTranslationUnit::parse(path, args, Some(content), unit_flags)?,
+        };
+        let result = f(unit.unit());
+
+        if self.max_units == 0 {
+            return result;
+        }
+        let mut units = self.units.lock().unwrap();
+        if units.len() >= self.max_units && !units.contains_key(path) {
+            let oldest = (units.iter())
+                .min_by_key(|(_, cached)| cached.last_used)
+                .map(|(path, _)| path.clone());
+            if let Some(oldest) = oldest {
+                units.remove(&oldest);
+            }
+        }
+        units.insert(
+            path.to_path_buf(),
+            CachedUnit {
+                unit,
+                args: args.to_vec(),
+                flags,
+                last_used: Instant::now(),
+            },
+        );
+        result
+    }
+}
+
+/// Deletes preambles older than `max_age` from `dir`, returning how many. libclang deletes a
+/// preamble along with its unit, so old ones were left behind by sessions that crashed or were
+/// killed.
+pub fn evict_stale_preambles(dir: &Path, max_age: Duration) -> usize {
+    let Ok(entries) = fs::read_dir(dir) else {
+        return 0;
+    };
+    let now = SystemTime::now();
+    let mut evicted = 0;
+    for entry in entries.flatten() {
+        let path = entry.path();
+        let is_preamble = (path.file_name().and_then(|name| name.to_str()))
+            .is_some_and(|name| name.starts_with(PREAMBLE_PREFIX))
+            && path
+                .extension()
+                .is_some_and(|ext| ext == PREAMBLE_EXTENSION);
+        let modified = entry.metadata().and_then(|metadata| metadata.modified());
+        let stale = modified
+            .is_ok_and(|modified| now.duration_since(modified).is_ok_and(|age| age >= max_age));
+        if is_preamble && stale && fs::remove_file(&path).is_ok() {
+            evicted += 1;
+        }
+    }
+    evicted
+}
@@ -0,0 +1,97 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use serde::Serialize;
+
+/// Timing samples collected with `--profile`, keyed by what was timed (e.g.
+/// `request/textDocument/completion`, `parse/hooks`, `queue/background`).
+#[derive(Default)]
+pub struct Profiler {
+    samples: Mutex<HashMap<String, Samples>>,
+}
+
+#[derive(Default)]
+struct Samples {
+    count: u64,
+    total: Duration,
+    max: Duration,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ProfileEntry {
+    pub name: String,
+    pub count: u64,
+    pub total_ms: f64,
+    pub mean_ms: f64,
+    pub max_ms: f64,
+}
+
+/// Records the time from its creation to its drop.
+pub struct ProfileTimer {
+    profiler: Arc<Profiler>,
+    name: String,
+    start: Instant,
+}
+
+impl Profiler {
+    pub fn start(self: &Arc<Self>, name: impl Into<String>) -> ProfileTimer {
+        ProfileTimer {
+            profiler: self.clone(),
+            name: name.into(),
+            start: Instant::now(),
+        }
+    }
+
+    pub fn record(&self, name: &str, elapsed: Duration) {
+        let mut samples = self.samples.lock().unwrap();
+        let entry = match samples.get_mut(name) {
+            Some(entry) => entry,
+            None => samples.entry(name.to_string()).or_default(),
+        };
+        entry.count += 1;
+        entry.total += elapsed;
+        entry.max = entry.max.max(elapsed);
+    }
+
+    /// All samples, most total time first.
+    pub fn summary(&self) -> Vec<ProfileEntry> {
+        let samples = self.samples.lock().unwrap();
+        let mut entries: Vec<ProfileEntry> = samples
+            .iter()
+            .map(|(name, samples)| ProfileEntry {
+                name: name.clone(),
+                count: samples.count,
+                total_ms: millis(samples.total),
+                mean_ms: millis(samples.total) / samples.count.max(1) as f64,
+                max_ms: millis(samples.max),
+            })
+            .collect();
+        entries.sort_by(|a, b| b.total_ms.total_cmp(&a.total_ms));
+        entries
+    }
+
+    pub fn log_summary(&self) {
+        for entry in self.summary() {
+            tracing::info!(
+                "{:<40} {:>8} calls {:>12.1}ms total {:>10.2}ms mean {:>10.2}ms max",
+                entry.name,
+                entry.count,
+                entry.total_ms,
+                entry.mean_ms,
+                entry.max_ms
+            );
+        }
+    }
+}
+
+impl Drop for ProfileTimer {
+    fn drop(&mut self) {
+        self.profiler.record(&self.name, self.start.elapsed());
+    }
+}
+
+fn millis(duration: Duration) -> f64 {
+    duration.as_secs_f64() * 1000.0
+}
@@ -0,0 +1,128 @@
+//! `$/progress` reports for individual slow requests, so editors can show them and let the user
+//! cancel them instead of waiting on a server that looks hung.
+
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+use tokio::sync::Notify;
+use tower_lsp::jsonrpc::{Error, Result as LspResult};
+use tower_lsp::lsp_types::notification::Progress;
+use tower_lsp::lsp_types::request::WorkDoneProgressCreate;
+use tower_lsp::lsp_types::{
+    ProgressParams, ProgressParamsValue, ProgressToken, WorkDoneProgress, WorkDoneProgressBegin,
+    WorkDoneProgressCreateParams, WorkDoneProgressEnd,
+};
+use tower_lsp::Client;
+
+#[derive(Default)]
+pub struct RequestProgress {
+    /// Whether the client accepts tokens created by the server (`window.workDoneProgress`).
+    server_tokens: AtomicBool,
+    next_token: AtomicU64,
+    /// Notified when the user cancels the request reporting under a token.
+    running: Mutex<HashMap<ProgressToken, Arc<Notify>>>,
+}
+
+impl RequestProgress {
+    pub fn set_server_tokens(&self, supported: bool) {
+        self.server_tokens.store(supported, Ordering::Relaxed);
+    }
+
+    /// Runs `work`, reporting it as `title` under the token the client sent along with the
+    /// request or, failing that, one the server creates. If the user cancels it, the request
+    /// fails with `RequestCancelled` right away and `work` is dropped.
+    pub async fn run<T>(
+        &self,
+        client: &Client,
+        title: &str,
+        token: Option<ProgressToken>,
+        work: impl Future<Output = LspResult<T>>,
+    ) -> LspResult<T> {
+        let token = match token {
+            Some(token) => Some(token),
+            None => self.create_token(client).await,
+        };
+        self.report_run(client, title, token, work).await
+    }
+
+    /// Like [`run`](Self::run), for work quick enough not to be worth creating a token: reports
+    /// only under a token the client sent.
+    pub async fn run_quick<T>(
+        &self,
+        client: &Client,
+        title: &str,
+        token: Option<ProgressToken>,
+        work: impl Future<Output = LspResult<T>>,
+    ) -> LspResult<T> {
+        self.report_run(client, title, token, work).await
+    }
+
+    async fn report_run<T>(
+        &self,
+        client: &Client,
+        title: &str,
+        token: Option<ProgressToken>,
+        work: impl Future<Output = LspResult<T>>,
+    ) -> LspResult<T> {
+        let Some(token) = token else {
+            return work.await;
+        };
+
+        let cancelled = Arc::new(Notify::new());
+        (self.running.lock().unwrap()).insert(token.clone(), cancelled.clone());
+        let begin = WorkDoneProgress::Begin(WorkDoneProgressBegin {
+            title: title.to_string(),
+            cancellable: Some(true),
+            message: None,
+            percentage: None,
+        });
+        report(client, &token, begin).await;
+        let result = tokio::select! {
+            result = work => result,
+            () = cancelled.notified() => Err(Error::request_cancelled()),
+        };
+        self.running.lock().unwrap().remove(&token);
+        let message = result.as_ref().err().map(|err| err.message.to_string());
+        report(
+            client,
+            &token,
+            WorkDoneProgress::End(WorkDoneProgressEnd { message }),
+        )
+        .await;
+        result
+    }
+
+    /// Handles `window/workDoneProgress/cancel`.
+    pub fn cancel(&self, token: &ProgressToken) {
+        if let Some(cancelled) = self.running.lock().unwrap().get(token) {
+            cancelled.notify_one();
+        }
+    }
+
+    async fn create_token(&self, client: &Client) -> Option<ProgressToken> {
+        if !self.server_tokens.load(Ordering::Relaxed) {
+            return None;
+        }
+        let id = self.next_token.fetch_add(1, Ordering::Relaxed);
+        let token = ProgressToken::String(format!("elysium-lsp/request/{id}"));
+        let params = WorkDoneProgressCreateParams {
+            token: token.clone(),
+        };
+        client
+            .send_request::<WorkDoneProgressCreate>(params)
+            .await
+            .ok()?;
+        Some(token)
+    }
+}
+
+async fn report(client: &Client, token: &ProgressToken, progress: WorkDoneProgress) {
+    client
+        .send_notification::<Progress>(ProgressParams {
+            token: token.clone(),
+            value: ProgressParamsValue::WorkDone(progress),
+        })
+        .await;
+}
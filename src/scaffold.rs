@@ -0,0 +1,164 @@
+//! New source files generated from templates, for `elysium.newInitTargetFile`.
+
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::{Component, Path, PathBuf};
+
+use anyhow::{bail, ensure, Context, Result};
+use serde::Deserialize;
+
+/// The template used without `--init-template`. `{{name}}`, `{{stage}}` and `{{scope}}` are
+/// replaced by the request's values and `{{hooks}}` by a definition per requested hook.
+pub const DEFAULT_INIT_TARGET_TEMPLATE: &str = "\
+#include <init.h>
+{{hooks}}
+INIT_TARGET({{name}}, {{stage}}, {{scope}}, INIT_DEPS()) {
+}
+";
+
+/// The arguments of `elysium.newInitTargetFile`.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct NewInitTargetFile {
+    /// Where to create the file, relative to the project root.
+    pub path: PathBuf,
+    /// The init target's name; defaults to the file name without its extension.
+    pub name: Option<String>,
+    pub stage: String,
+    pub scope: String,
+    /// Hooks to define in the new file.
+    #[serde(default)]
+    pub hooks: Vec<String>,
+}
+
+impl NewInitTargetFile {
+    /// Fills in `template`, checking that every name is a C identifier.
+    pub fn render(&self, template: &str) -> Result<String> {
+        let name = match &self.name {
+            Some(name) => name.clone(),
+            None => (self.path.file_stem())
+                .and_then(|stem| stem.to_str())
+                .context("path has no file name")?
+                .to_string(),
+        };
+        for identifier in [&name, &self.stage, &self.scope]
+            .into_iter()
+            .chain(&self.hooks)
+        {
+            ensure!(
+                is_identifier(identifier),
+                "'{identifier}' is not a C identifier"
+            );
+        }
+        let hooks: String = (self.hooks.iter())
+            .map(|hook| format!("\nHOOK({hook}) {{\n}}\n"))
+            .collect();
+        Ok(template
+            .replace("{{name}}", &name)
+            .replace("{{stage}}", &self.stage)
+            .replace("{{scope}}", &self.scope)
+            .replace("{{hooks}}", &hooks))
+    }
+
+    /// Writes the rendered file below `project_root`, refusing to overwrite an existing file, to
+    /// leave the project root or to create a file `indexed` says wouldn't be indexed. Returns the
+    /// new file's path and contents.
+    pub fn create(
+        &self,
+        project_root: &Path,
+        template: &str,
+        indexed: impl FnOnce(&Path) -> bool,
+    ) -> Result<(PathBuf, String)> {
+        if (self.path.components()).any(|part| !matches!(part, Component::Normal(_))) {
+            bail!(
+                "{} is not a path relative to the project root",
+                self.path.display()
+            );
+        }
+        let text = self.render(template)?;
+        let path = project_root.join(&self.path);
+        ensure!(
+            indexed(&path),
+            "{} wouldn't be indexed: it is outside the index roots, excluded or not a source file",
+            self.path.display()
+        );
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).with_context(|| format!("creating {}", parent.display()))?;
+        }
+        OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&path)
+            .and_then(|mut file| file.write_all(text.as_bytes()))
+            .with_context(|| format!("creating {}", path.display()))?;
+        Ok((path, text))
+    }
+}
+
+fn is_identifier(text: &str) -> bool {
+    let mut chars = text.chars();
+    chars
+        .next()
+        .is_some_and(|first| first.is_ascii_alphabetic() || first == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(path: &str, name: Option<&str>, hooks: &[&str]) -> NewInitTargetFile {
+        NewInitTargetFile {
+            path: PathBuf::from(path),
+            name: name.map(str::to_string),
+            stage: "early".to_string(),
+            scope: "bsp".to_string(),
+            hooks: hooks.iter().map(|hook| hook.to_string()).collect(),
+        }
+    }
+
+    #[test]
+    fn render_fills_in_the_default_template() {
+        let text = request("kernel/pmm.c", None, &["on_boot"])
+            .render(DEFAULT_INIT_TARGET_TEMPLATE)
+            .unwrap();
+        assert_eq!(
+            text,
+            "#include <init.h>\n\nHOOK(on_boot) {\n}\n\nINIT_TARGET(pmm, early, bsp, INIT_DEPS()) {\n}\n"
+        );
+    }
+
+    #[test]
+    fn render_prefers_the_given_name() {
+        let text = request("kernel/pmm.c", Some("memory"), &[])
+            .render("{{name}}/{{stage}}/{{scope}}{{hooks}}")
+            .unwrap();
+        assert_eq!(text, "memory/early/bsp");
+    }
+
+    #[test]
+    fn render_rejects_names_that_are_not_identifiers() {
+        let err = request("kernel/pmm.c", Some("2pmm"), &[])
+            .render(DEFAULT_INIT_TARGET_TEMPLATE)
+            .unwrap_err();
+        assert_eq!(err.to_string(), "'2pmm' is not a C identifier");
+        assert!(request("kernel/pmm.c", None, &["on-boot"])
+            .render(DEFAULT_INIT_TARGET_TEMPLATE)
+            .is_err());
+        assert!(request("kernel/pmm-init.c", None, &[])
+            .render(DEFAULT_INIT_TARGET_TEMPLATE)
+            .is_err());
+    }
+
+    #[test]
+    fn create_refuses_unindexed_paths() {
+        let root = Path::new("/nonexistent");
+        let err = request("docs/pmm.c", None, &[])
+            .create(root, DEFAULT_INIT_TARGET_TEMPLATE, |_| false)
+            .unwrap_err();
+        assert!(err.to_string().contains("wouldn't be indexed"), "{err}");
+        assert!(request("../pmm.c", None, &[])
+            .create(root, DEFAULT_INIT_TARGET_TEMPLATE, |_| true)
+            .is_err());
+    }
+}
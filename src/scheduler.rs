@@ -0,0 +1,169 @@
+use std::collections::{HashMap, VecDeque};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::Instant;
+
+use tokio::sync::Notify;
+
+use crate::plugins::CancelToken;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Priority {
+    /// Open documents the user is editing.
+    Foreground,
+    /// Workspace crawl and on-disk changes.
+    Background,
+}
+
+pub enum JobKind {
+    Update(Option<String>),
+    Remove,
+}
+
+pub struct Job {
+    pub path: PathBuf,
+    pub kind: JobKind,
+    pub priority: Priority,
+    generation: u64,
+    queued_at: Instant,
+    cancel: CancelToken,
+}
+
+#[derive(Default)]
+struct Queues {
+    foreground: VecDeque<Job>,
+    background: VecDeque<Job>,
+    /// Generation of the newest job for each path, and the token cancelling it once superseded.
+    latest: HashMap<PathBuf, (u64, CancelToken)>,
+    next_generation: u64,
+}
+
+#[derive(Default)]
+pub struct JobQueue {
+    queues: Mutex<Queues>,
+    notify: Notify,
+}
+
+impl Job {
+    pub fn update(path: PathBuf, content: Option<String>, priority: Priority) -> Self {
+        Self {
+            path,
+            kind: JobKind::Update(content),
+            priority,
+            generation: 0,
+            queued_at: Instant::now(),
+            cancel: CancelToken::default(),
+        }
+    }
+
+    pub fn generation(&self) -> u64 {
+        self.generation
+    }
+
+    pub fn queued_at(&self) -> Instant {
+        self.queued_at
+    }
+
+    /// Cancelled as soon as a newer job for the same path is queued or the queue is cleared.
+    pub fn cancel_token(&self) -> &CancelToken {
+        &self.cancel
+    }
+
+    pub fn remove(path: PathBuf, priority: Priority) -> Self {
+        Self {
+            path,
+            kind: JobKind::Remove,
+            priority,
+            generation: 0,
+            queued_at: Instant::now(),
+            cancel: CancelToken::default(),
+        }
+    }
+}
+
+impl JobQueue {
+    pub fn push(&self, mut job: Job) {
+        let mut queues = self.queues.lock().unwrap();
+        queues.next_generation += 1;
+        job.generation = queues.next_generation;
+        job.queued_at = Instant::now();
+        job.cancel = CancelToken::default();
+        let latest = (job.generation, job.cancel.clone());
+        if let Some((_, superseded)) = queues.latest.insert(job.path.clone(), latest) {
+            superseded.cancel();
+        }
+        match job.priority {
+            Priority::Foreground => queues.foreground.push_back(job),
+            Priority::Background => queues.background.push_back(job),
+        }
+        drop(queues);
+        self.notify.notify_one();
+    }
+
+    pub fn extend(&self, jobs: impl IntoIterator<Item = Job>) {
+        for job in jobs {
+            self.push(job);
+        }
+    }
+
+    /// Waits for the next batch of work: a single foreground job, or up to `max` background jobs
+    /// once no foreground work is pending.
+    pub async fn pop_batch(&self, max: usize) -> Vec<Job> {
+        loop {
+            let batch = self.try_pop_batch(max.max(1));
+            if !batch.is_empty() {
+                return batch;
+            }
+            self.notify.notified().await;
+        }
+    }
+
+    /// Whether a newer job for the same path has been queued since `job`.
+    pub fn is_superseded(&self, job: &Job) -> bool {
+        let queues = self.queues.lock().unwrap();
+        queues
+            .latest
+            .get(&job.path)
+            .map(|(generation, _)| *generation)
+            != Some(job.generation)
+    }
+
+    /// Marks `job` as done. Returns `false` if it was superseded while running, in which case its
+    /// results are stale and the newer job will publish instead.
+    pub fn complete(&self, path: &Path, generation: u64) -> bool {
+        let mut queues = self.queues.lock().unwrap();
+        if queues.latest.get(path).map(|(latest, _)| *latest) != Some(generation) {
+            return false;
+        }
+        queues.latest.remove(path);
+        true
+    }
+
+    /// Drops every queued job. Jobs already running count as superseded and are cancelled.
+    pub fn clear(&self) {
+        let mut queues = self.queues.lock().unwrap();
+        queues.foreground.clear();
+        queues.background.clear();
+        for (_, (_, cancel)) in queues.latest.drain() {
+            cancel.cancel();
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn len(&self) -> usize {
+        let queues = self.queues.lock().unwrap();
+        queues.foreground.len() + queues.background.len()
+    }
+
+    fn try_pop_batch(&self, max: usize) -> Vec<Job> {
+        let mut queues = self.queues.lock().unwrap();
+        if let Some(job) = queues.foreground.pop_front() {
+            return vec![job];
+        }
+        let count = queues.background.len().min(max);
+        queues.background.drain(..count).collect()
+    }
+}
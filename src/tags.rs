@@ -0,0 +1,103 @@
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use clap::ValueEnum;
+
+use crate::plugins::SymbolInfo;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+#[value(rename_all = "kebab_case")]
+pub enum TagsFormat {
+    /// Sorted `tags` file as read by vim and universal-ctags tooling.
+    Ctags,
+    /// `TAGS` file as read by emacs.
+    Etags,
+}
+
+/// Writes a tags file for `symbols` to `out`. Files below the directory of `out` are referred to
+/// relative to it, as both formats expect; anything else keeps its absolute path.
+pub fn write(out: &Path, format: TagsFormat, symbols: &[(&'static str, SymbolInfo)]) -> Result<()> {
+    let base = std::path::absolute(out)?
+        .parent()
+        .map(Path::to_path_buf)
+        .unwrap_or_default();
+    let text = match format {
+        TagsFormat::Ctags => ctags(&base, symbols),
+        TagsFormat::Etags => etags(&base, symbols),
+    };
+    fs::write(out, text).with_context(|| format!("writing {}", out.display()))
+}
+
+fn ctags(base: &Path, symbols: &[(&'static str, SymbolInfo)]) -> String {
+    let mut lines: Vec<String> = symbols
+        .iter()
+        .map(|(plugin, symbol)| {
+            format!(
+                "{}\t{}\t{};\"\tkind:{}\tplugin:{plugin}",
+                symbol.name,
+                tag_path(base, &symbol.location.path).display(),
+                symbol.location.range.start.line + 1,
+                symbol.kind_name(),
+            )
+        })
+        .collect();
+    lines.sort();
+
+    let mut text = String::from(concat!(
+        "!_TAG_FILE_FORMAT\t2\t/extended format/\n",
+        "!_TAG_FILE_SORTED\t1\t/0=unsorted, 1=sorted, 2=foldcase/\n",
+        "!_TAG_PROGRAM_NAME\telysium-lsp\t//\n",
+    ));
+    for line in lines {
+        text.push_str(&line);
+        text.push('\n');
+    }
+    text
+}
+
+fn etags(base: &Path, symbols: &[(&'static str, SymbolInfo)]) -> String {
+    let mut by_file: BTreeMap<&PathBuf, Vec<&SymbolInfo>> = BTreeMap::new();
+    for (_, symbol) in symbols {
+        by_file
+            .entry(&symbol.location.path)
+            .or_default()
+            .push(symbol);
+    }
+
+    let mut text = String::new();
+    for (path, mut symbols) in by_file {
+        symbols.sort_by_key(|symbol| symbol.location.range.start);
+        // Each tag repeats the start of its definition line and its byte offset, which emacs uses
+        // to find the definition again after the file was edited.
+        let source = fs::read_to_string(path).unwrap_or_default();
+        let mut line_starts = vec![0];
+        line_starts.extend(source.match_indices('\n').map(|(offset, _)| offset + 1));
+
+        let mut section = String::new();
+        for symbol in symbols {
+            let line = symbol.location.range.start.line as usize;
+            let start = line_starts.get(line).copied().unwrap_or(source.len());
+            let line_text = source[start..].lines().next().unwrap_or_default();
+            let _ = writeln!(
+                section,
+                "{line_text}\x7f{}\x01{},{start}",
+                symbol.name,
+                line + 1
+            );
+        }
+        let _ = write!(
+            text,
+            "\x0c\n{},{}\n{section}",
+            tag_path(base, path).display(),
+            section.len()
+        );
+    }
+    text
+}
+
+fn tag_path(base: &Path, path: &Path) -> PathBuf {
+    path.strip_prefix(base).unwrap_or(path).to_path_buf()
+}
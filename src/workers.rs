@@ -0,0 +1,216 @@
+use std::ffi::OsString;
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Child, ChildStdin, ChildStdout, Command, Stdio};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::{Arc, Mutex, Weak};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, Context, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::plugins::{
+    disabled_plugins, CancelToken, LspPlugin, ParseCancelled, ParseTimeout, PositionEncoding,
+};
+
+/// One line of JSON on a worker's stdin.
+#[derive(Serialize, Deserialize)]
+struct ParseRequest {
+    path: PathBuf,
+    content: Option<String>,
+    encoding: PositionEncoding,
+}
+
+/// How often a parse checks whether it was cancelled.
+const CANCEL_POLL: Duration = Duration::from_millis(50);
+
+/// One line of JSON on a worker's stdout: the encoded data of each plugin, or why it failed, in
+/// plugin order.
+type ParseResponse = Vec<std::result::Result<Option<serde_json::Value>, String>>;
+
+/// Subprocesses running libclang parses, so a crash in libclang only loses the parse it happened
+/// in instead of the whole server.
+pub struct WorkerPool {
+    program: PathBuf,
+    args: Vec<OsString>,
+    timeout: Option<Duration>,
+    idle: Mutex<Vec<Worker>>,
+    /// Every worker started, including ones busy parsing, so shutdown can reach them all.
+    started: Mutex<Vec<Weak<Mutex<Child>>>>,
+}
+
+struct Worker {
+    child: Arc<Mutex<Child>>,
+    stdin: ChildStdin,
+    stdout: BufReader<ChildStdout>,
+}
+
+impl WorkerPool {
+    /// Workers are started on demand as `program args...`, which must end up in [`serve`]. A
+    /// worker still parsing after `timeout` is killed.
+    pub fn new(program: PathBuf, args: Vec<OsString>, timeout: Option<Duration>) -> Self {
+        Self {
+            program,
+            args,
+            timeout,
+            idle: Mutex::default(),
+            started: Mutex::default(),
+        }
+    }
+
+    /// Parses `path` with every plugin in a worker, computing positions in `encoding`, and returns
+    /// each plugin's data or why it failed in plugin order. If the worker died, the whole parse fails and the
+    /// worker is replaced on the next parse. Cancelling `cancel` kills the worker and fails the
+    /// parse with [`ParseCancelled`].
+    pub fn parse(
+        &self,
+        path: &Path,
+        content: Option<&str>,
+        encoding: PositionEncoding,
+        cancel: &CancelToken,
+    ) -> Result<Vec<Result<Option<serde_json::Value>>>> {
+        cancel.check()?;
+        let idle = self.idle.lock().unwrap().pop();
+        let mut worker = match idle {
+            Some(worker) => worker,
+            None => self.spawn()?,
+        };
+
+        let request = ParseRequest {
+            path: path.to_path_buf(),
+            content: content.map(str::to_string),
+            encoding,
+        };
+        match worker.request(&request, self.timeout, cancel) {
+            Ok(response) => {
+                self.idle.lock().unwrap().push(worker);
+                Ok((response.into_iter())
+                    .map(|result| result.map_err(|err| anyhow!(err)))
+                    .collect())
+            }
+            Err(err) if err.is::<ParseTimeout>() || err.is::<ParseCancelled>() => Err(err),
+            Err(err) => Err(err.context("parse worker crashed")),
+        }
+    }
+
+    fn spawn(&self) -> Result<Worker> {
+        let mut child = Command::new(&self.program)
+            .args(&self.args)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::inherit())
+            .spawn()
+            .with_context(|| format!("starting parse worker {}", self.program.display()))?;
+        let stdin = child.stdin.take().context("parse worker stdin")?;
+        let stdout = BufReader::new(child.stdout.take().context("parse worker stdout")?);
+        let child = Arc::new(Mutex::new(child));
+        let mut started = self.started.lock().unwrap();
+        started.retain(|child| child.strong_count() > 0);
+        started.push(Arc::downgrade(&child));
+        Ok(Worker {
+            child,
+            stdin,
+            stdout,
+        })
+    }
+
+    /// Kills every worker, failing any parse still running in one.
+    pub fn shut_down(&self) {
+        self.idle.lock().unwrap().clear();
+        for child in self.started.lock().unwrap().drain(..) {
+            if let Some(child) = child.upgrade() {
+                let _ = child.lock().unwrap().kill();
+            }
+        }
+    }
+}
+
+impl Worker {
+    fn request(
+        &mut self,
+        request: &ParseRequest,
+        timeout: Option<Duration>,
+        cancel: &CancelToken,
+    ) -> Result<ParseResponse> {
+        let mut line = serde_json::to_string(request)?;
+        line.push('\n');
+        self.stdin.write_all(line.as_bytes())?;
+        self.stdin.flush()?;
+
+        // Killing the worker unblocks the read below.
+        let (done, finished) = mpsc::channel::<()>();
+        let child = self.child.clone();
+        let cancel = cancel.clone();
+        let watchdog = thread::spawn(move || {
+            let started = Instant::now();
+            let interrupted = loop {
+                if cancel.is_cancelled() {
+                    break Some(anyhow::Error::from(ParseCancelled));
+                }
+                let left = timeout.map(|limit| limit.saturating_sub(started.elapsed()));
+                match finished.recv_timeout(left.map_or(CANCEL_POLL, |left| left.min(CANCEL_POLL)))
+                {
+                    Err(RecvTimeoutError::Timeout) => {}
+                    _ => break None,
+                }
+                if let Some(limit) = timeout.filter(|limit| started.elapsed() >= *limit) {
+                    break Some(ParseTimeout(limit).into());
+                }
+            };
+            if interrupted.is_some() {
+                let _ = child.lock().unwrap().kill();
+            }
+            interrupted
+        });
+
+        line.clear();
+        let read = self.stdout.read_line(&mut line);
+        drop(done);
+        if let Some(err) = watchdog.join().ok().flatten() {
+            return Err(err);
+        }
+        if read? == 0 {
+            let status = self.child.lock().unwrap().wait()?;
+            return Err(anyhow!("worker exited ({status})"));
+        }
+        Ok(serde_json::from_str(&line)?)
+    }
+}
+
+impl Drop for Worker {
+    fn drop(&mut self) {
+        let mut child = self.child.lock().unwrap();
+        let _ = child.kill();
+        let _ = child.wait();
+    }
+}
+
+/// Worker side of the protocol: answers parse requests on stdin until it is closed.
+pub fn serve(plugins: Vec<Box<dyn LspPlugin>>) -> Result<()> {
+    let stdin = std::io::stdin().lock();
+    let mut stdout = std::io::stdout().lock();
+    for line in stdin.lines() {
+        let request: ParseRequest = serde_json::from_str(&line?)?;
+        let _encoding = request.encoding.enter();
+        let disabled = disabled_plugins(&request.path, request.content.as_deref());
+        let response: ParseResponse = plugins
+            .iter()
+            .map(|plugin| {
+                if disabled.iter().any(|name| name == plugin.name()) {
+                    return Ok(None);
+                }
+                let data = plugin
+                    .parser()
+                    .parse_file(&request.path, request.content.as_deref())?;
+                data.map(|data| plugin.encode_file(&data)).transpose()
+            })
+            .map(|result| result.map_err(|err| format!("{err:?}")))
+            .collect();
+
+        serde_json::to_writer(&mut stdout, &response)?;
+        stdout.write_all(b"\n")?;
+        stdout.flush()?;
+    }
+    Ok(())
+}
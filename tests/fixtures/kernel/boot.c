@@ -0,0 +1,12 @@
+#include "kernel.h"
+
+/// Runs once the kernel has booted.
+HOOK(boot) {}
+
+INIT_TARGET(pmm, INIT_STAGE_EARLY, INIT_SCOPE_BSP, {}) {}
+INIT_TARGET(sched, INIT_STAGE_MAIN, INIT_SCOPE_BSP, {"pmm", "vmm"}) {}
+
+void kmain(void) {
+    HOOK_RUN(boot);
+    HOOK_RUN(shutdown);
+}
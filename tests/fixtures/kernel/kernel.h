@@ -0,0 +1,5 @@
+#pragma once
+
+#define HOOK(NAME) static void __hook_##NAME(void)
+#define HOOK_RUN(NAME) ((void) 0)
+#define INIT_TARGET(NAME, STAGE, SCOPE, ...) static void __init_##NAME(void)
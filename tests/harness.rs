@@ -0,0 +1,134 @@
+//! Drives the server end to end through [`elysium_lsp::harness`] on the fixture in
+//! `tests/fixtures/kernel`. The tests need libclang and are skipped when it can't be loaded.
+
+use std::path::{Path, PathBuf};
+
+use anyhow::Result;
+use elysium_lsp::config::Config;
+use elysium_lsp::harness::TestServer;
+use elysium_lsp::plugins::{
+    load_libclang, HookPlugin, InitDependencyPlugin, LspPlugin, ParseOptions,
+};
+use tower_lsp::lsp_types::{Diagnostic, NumberOrString, Position};
+
+fn fixture() -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/kernel")
+}
+
+fn libclang_available() -> bool {
+    match load_libclang() {
+        Ok(_) => true,
+        Err(err) => {
+            eprintln!("skipping, libclang is unavailable: {err}");
+            false
+        }
+    }
+}
+
+async fn start() -> Result<TestServer> {
+    start_with(Config::default()).await
+}
+
+async fn start_with(config: Config) -> Result<TestServer> {
+    let root = fixture();
+    let options = ParseOptions {
+        skip_function_bodies: true,
+        prescan: true,
+        cpp: false,
+        header_extensions: vec!["h".into()],
+        kept_units: 8,
+    };
+    let plugins: Vec<Box<dyn LspPlugin>> = vec![
+        Box::new(HookPlugin::new(&root, options.clone())?),
+        Box::new(InitDependencyPlugin::new(&root, options)?),
+    ];
+    TestServer::start(root, config, plugins).await
+}
+
+fn with_code<'a>(diagnostics: &'a [Diagnostic], code: &str) -> Vec<&'a Diagnostic> {
+    (diagnostics.iter())
+        .filter(|d| d.code == Some(NumberOrString::String(code.into())))
+        .collect()
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn publishes_unknown_names() -> Result<()> {
+    if !libclang_available() {
+        return Ok(());
+    }
+    let mut server = start().await?;
+    let uri = server.uri(Path::new("boot.c"))?;
+    let diagnostics = server.diagnostics(&uri).await?;
+
+    let unknown_hooks = with_code(&diagnostics, "hooks/unknown-hook");
+    assert_eq!(unknown_hooks.len(), 1, "{diagnostics:#?}");
+    assert_eq!(unknown_hooks[0].range.start.line, 10);
+    assert!(unknown_hooks[0].message.contains("shutdown"));
+
+    let unknown_dependencies = with_code(&diagnostics, "init/unknown-dependency");
+    assert_eq!(unknown_dependencies.len(), 1, "{diagnostics:#?}");
+    assert_eq!(unknown_dependencies[0].range.start.line, 6);
+    assert!(unknown_dependencies[0].message.contains("vmm"));
+
+    server.shut_down().await
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn clears_diagnostics_once_fixed() -> Result<()> {
+    if !libclang_available() {
+        return Ok(());
+    }
+    let mut server = start().await?;
+    let text = std::fs::read_to_string(fixture().join("boot.c"))?;
+    let uri = server.open_text("boot.c", &text).await?;
+    assert!(!server.diagnostics(&uri).await?.is_empty());
+
+    let fixed = text
+        .replace("HOOK_RUN(shutdown);", "HOOK_RUN(boot);")
+        .replace(", \"vmm\"", "");
+    server.change(&uri, 2, &fixed).await?;
+    let diagnostics = server.diagnostics(&uri).await?;
+    assert!(diagnostics.is_empty(), "{diagnostics:#?}");
+
+    server.shut_down().await
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn completes_hook_names() -> Result<()> {
+    if !libclang_available() {
+        return Ok(());
+    }
+    let mut server = start().await?;
+    let text = std::fs::read_to_string(fixture().join("boot.c"))?;
+    let text = text.replace("HOOK_RUN(shutdown);", "HOOK_RUN(bo);");
+    let uri = server.open_text("boot.c", &text).await?;
+    server.diagnostics(&uri).await?;
+
+    let items = server.completions(&uri, Position::new(10, 15)).await?;
+    let boot = items.iter().find(|item| item.label == "boot");
+    assert!(boot.is_some(), "{items:#?}");
+
+    server.shut_down().await
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn reports_plugins_degraded_without_libclang() -> Result<()> {
+    let config = Config {
+        libclang_error: Some("not installed".into()),
+        ..Config::default()
+    };
+    let mut server = start_with(config).await?;
+    let uri = server.open("boot.c").await?;
+    let items = server.completions(&uri, Position::new(9, 14)).await?;
+    assert!(items.is_empty(), "{items:#?}");
+
+    let state = server.wait_for_state().await?;
+    let mut degraded: Vec<&str> = (state.degraded_plugins.iter())
+        .map(|plugin| plugin.name.as_str())
+        .collect();
+    degraded.sort_unstable();
+    assert_eq!(degraded, ["hooks", "init-deps"]);
+    assert!(state.degraded_plugins[0].reason.contains("not installed"));
+
+    server.shut_down().await
+}